@@ -0,0 +1,28 @@
+{
+    Value list = *(stack_ptr--);
+    Value block = *(stack_ptr--);
+
+    assert_type("any?", "stack", TAG_STACK, &list, scope);
+    assert_type("any?", "block", TAG_BLOCK, &block, scope);
+    assert_block_arity("any?", &block.as_block, 1, 1);
+
+    ValueStack *values = list.as_stack;
+    Value v = {.type = TAG_BOOLEAN, .as_boolean = false};
+
+    for (size_t i = 0; i < values->size; i++)
+    {
+        bool result = list_test("any?", block.as_block, &values->values[i], scope);
+        if (loop_control)
+        {
+            goto block_return;
+        }
+
+        if (result)
+        {
+            v.as_boolean = true;
+            break;
+        }
+    }
+
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -0,0 +1,6 @@
+{
+    Value expected = *(stack_ptr--);
+    Value actual = *(stack_ptr--);
+
+    assert_equal(SPAN, &actual, &expected);
+}
\ No newline at end of file
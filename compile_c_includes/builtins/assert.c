@@ -0,0 +1,11 @@
+{
+    Value v = *(stack_ptr--);
+
+    assert_type("assert", "boolean", TAG_BOOLEAN, &v, scope);
+
+    if (!v.as_boolean)
+    {
+        fprintf(stderr, "assert failed at %s\n", SPAN);
+        error_exit();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,9 @@
+{
+    if (loop_depth == 0)
+    {
+        fprintf(stderr, "break outside of a loop\n");
+        error_exit();
+    }
+
+    loop_control = LOOP_BREAK;
+}
\ No newline at end of file
@@ -0,0 +1,18 @@
+{
+    Value b = *(stack_ptr--);
+    Value a = *(stack_ptr--);
+
+    assert_type("concat", "stack", TAG_STACK, &a, scope);
+    assert_type("concat", "stack", TAG_STACK, &b, scope);
+
+    ValueStack *result = list_copy(a.as_stack);
+    ValueStack *bs = b.as_stack;
+
+    for (size_t i = 0; i < bs->size; i++)
+    {
+        vs_push(result, bs->values[i]);
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -1,14 +1,14 @@
 {
     Value *c = stack_ptr--;
 
-    assert_type("cond", "stack", TAG_STACK, c, names);
+    assert_type("cond", "stack", TAG_STACK, c, scope);
 
     ValueStack *cases = c->as_stack;
 
     if (cases->size % 2 != 1)
     {
         printf("cond: expected odd number of arguments, got %zu\n", cases->size);
-        exit(1);
+        error_exit();
     }
 
     bool found = false;
@@ -17,21 +17,23 @@
         Value *test = vs_get(cases, i);
         Value *body = vs_get(cases, i + 1);
 
-        assert_type("cond (test block)", "block", TAG_BLOCK, test, names);
+        assert_type("cond (test block)", "block", TAG_BLOCK, test, scope);
 
-        void *f = test->as_block;
-        ((void (*)(Name *))f)(names);
+        call_block(test->as_block);
+        if (loop_control)
+        {
+            goto block_return;
+        }
 
         Value *test_result = stack_ptr--;
 
-        assert_type("cond (test result)", "boolean", TAG_BOOLEAN, test_result, names);
+        assert_type("cond (test result)", "boolean", TAG_BOOLEAN, test_result, scope);
 
         if (test_result->as_boolean)
         {
             if (body->type == TAG_BLOCK)
             {
-                void *f = body->as_block;
-                ((void (*)(Name *))f)(names);
+                call_block(body->as_block);
             }
             else
             {
@@ -49,8 +51,7 @@
 
         if (default_body->type == TAG_BLOCK)
         {
-            void *f = default_body->as_block;
-            ((void (*)(Name *))f)(names);
+            call_block(default_body->as_block);
         }
         else
         {
@@ -0,0 +1,9 @@
+{
+    if (loop_depth == 0)
+    {
+        fprintf(stderr, "continue outside of a loop\n");
+        error_exit();
+    }
+
+    loop_control = LOOP_CONTINUE;
+}
\ No newline at end of file
@@ -0,0 +1,4 @@
+{
+    Value v = {.type = TAG_NUMBER_INTEGER, .as_integer = stack_ptr - stack};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -0,0 +1,3 @@
+{
+    stack_ptr--;
+}
\ No newline at end of file
@@ -0,0 +1,4 @@
+{
+    Value v = *stack_ptr;
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
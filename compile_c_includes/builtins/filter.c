@@ -0,0 +1,28 @@
+{
+    Value list = *(stack_ptr--);
+    Value block = *(stack_ptr--);
+
+    assert_type("filter", "stack", TAG_STACK, &list, scope);
+    assert_type("filter", "block", TAG_BLOCK, &block, scope);
+    assert_block_arity("filter", &block.as_block, 1, 1);
+
+    ValueStack *values = list.as_stack;
+    ValueStack *result = vs_init();
+
+    for (size_t i = 0; i < values->size; i++)
+    {
+        bool keep = list_test("filter", block.as_block, &values->values[i], scope);
+        if (loop_control)
+        {
+            goto block_return;
+        }
+
+        if (keep)
+        {
+            vs_push(result, values->values[i]);
+        }
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -0,0 +1,28 @@
+{
+    Value list = *(stack_ptr--);
+    Value block = *(stack_ptr--);
+
+    assert_type("find", "stack", TAG_STACK, &list, scope);
+    assert_type("find", "block", TAG_BLOCK, &block, scope);
+    assert_block_arity("find", &block.as_block, 1, 1);
+
+    ValueStack *values = list.as_stack;
+    Value v = {.type = TAG_BOOLEAN, .as_boolean = false};
+
+    for (size_t i = 0; i < values->size; i++)
+    {
+        bool keep = list_test("find", block.as_block, &values->values[i], scope);
+        if (loop_control)
+        {
+            goto block_return;
+        }
+
+        if (keep)
+        {
+            v = values->values[i];
+            break;
+        }
+    }
+
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
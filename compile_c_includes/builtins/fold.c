@@ -0,0 +1,21 @@
+{
+    Value list = *(stack_ptr--);
+    Value acc = *(stack_ptr--);
+    Value block = *(stack_ptr--);
+
+    assert_type("fold", "stack", TAG_STACK, &list, scope);
+    assert_type("fold", "block", TAG_BLOCK, &block, scope);
+    assert_block_arity("fold", &block.as_block, 2, 1);
+
+    ValueStack *values = list.as_stack;
+    for (size_t i = 0; i < values->size; i++)
+    {
+        acc = list_call(block.as_block, &acc, &values->values[i]);
+        if (loop_control)
+        {
+            goto block_return;
+        }
+    }
+
+    *(++stack_ptr) = acc;
+}
\ No newline at end of file
@@ -0,0 +1,11 @@
+{
+    Value block = *(stack_ptr--);
+
+    assert_type("forever", "block", TAG_BLOCK, &block, scope);
+
+    loop_depth++;
+    while (loop_body(block.as_block))
+    {
+    }
+    loop_depth--;
+}
\ No newline at end of file
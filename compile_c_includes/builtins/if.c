@@ -3,14 +3,13 @@
     Value if_false = *(stack_ptr--);
     Value if_true = *(stack_ptr--);
 
-    assert_type("if", "boolean", TAG_BOOLEAN, &cond, names);
+    assert_type("if", "boolean", TAG_BOOLEAN, &cond, scope);
 
     Value v = (cond.as_boolean ? if_true : if_false);
 
     if (v.type == TAG_BLOCK)
     {
-        void *f = v.as_block;
-        ((void (*)(Name *))f)(names);
+        call_block(v.as_block);
     }
     else
     {
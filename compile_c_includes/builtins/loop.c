@@ -2,22 +2,26 @@
     Value iter = *(stack_ptr--);
     Value block = *(stack_ptr--);
 
-    assert_type("loop", "block", TAG_BLOCK, &block, names);
+    assert_type("loop", "block", TAG_BLOCK, &block, scope);
 
     if (iter.type == TAG_NUMBER_INTEGER)
     {
+        loop_depth++;
         for (int i = 0; i < iter.as_integer; i++)
         {
             Value v = {.type = TAG_NUMBER_INTEGER, .as_integer = i};
             *(++stack_ptr) = v;
 
-            void *f = block.as_block;
-            ((void (*)(Name *))f)(names);
+            if (!loop_body(block.as_block))
+            {
+                break;
+            }
         }
+        loop_depth--;
     }
     else
     {
         fprintf(stderr, "Error: loop iterator must be an integer (others todo)\n");
-        exit(1);
+        error_exit();
     }
 }
\ No newline at end of file
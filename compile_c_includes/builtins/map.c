@@ -0,0 +1,25 @@
+{
+    Value list = *(stack_ptr--);
+    Value block = *(stack_ptr--);
+
+    assert_type("map", "stack", TAG_STACK, &list, scope);
+    assert_type("map", "block", TAG_BLOCK, &block, scope);
+    assert_block_arity("map", &block.as_block, 1, 1);
+
+    ValueStack *values = list.as_stack;
+    ValueStack *result = vs_init();
+
+    for (size_t i = 0; i < values->size; i++)
+    {
+        Value mapped = list_call(block.as_block, &values->values[i], NULL);
+        if (loop_control)
+        {
+            goto block_return;
+        }
+
+        vs_push(result, mapped);
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
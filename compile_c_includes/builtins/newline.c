@@ -0,0 +1,3 @@
+{
+    printf("\n");
+}
\ No newline at end of file
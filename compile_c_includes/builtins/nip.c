@@ -0,0 +1,4 @@
+{
+    *(stack_ptr - 1) = *stack_ptr;
+    stack_ptr--;
+}
\ No newline at end of file
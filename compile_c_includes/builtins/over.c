@@ -0,0 +1,4 @@
+{
+    Value a = *(stack_ptr - 1);
+    *(++stack_ptr) = a;
+}
\ No newline at end of file
@@ -0,0 +1,14 @@
+{
+    Value n = *(stack_ptr--);
+
+    assert_type("pick", "integer", TAG_NUMBER_INTEGER, &n, scope);
+
+    if (n.as_integer < 0 || n.as_integer >= stack_ptr - stack)
+    {
+        fprintf(stderr, "Error in pick, cannot pick %lld, only %ld values are on the stack\n", n.as_integer, (long)(stack_ptr - stack));
+        error_exit();
+    }
+
+    Value v = *(stack_ptr - n.as_integer);
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
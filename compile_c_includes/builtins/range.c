@@ -0,0 +1,18 @@
+{
+    Value end = *(stack_ptr--);
+    Value start = *(stack_ptr--);
+
+    assert_type("range", "integer", TAG_NUMBER_INTEGER, &start, scope);
+    assert_type("range", "integer", TAG_NUMBER_INTEGER, &end, scope);
+
+    ValueStack *result = vs_init();
+
+    for (int64_t i = start.as_integer; i < end.as_integer; i++)
+    {
+        Value n = {.type = TAG_NUMBER_INTEGER, .as_integer = i};
+        vs_push(result, n);
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
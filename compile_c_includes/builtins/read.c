@@ -5,7 +5,7 @@
     if (fgets(line, 1024, stdin) == NULL)
     {
         fprintf(stderr, "Error reading from stdin\n");
-        exit(1);
+        error_exit();
     }
 
     // Strip newline
@@ -0,0 +1,16 @@
+{
+    Value list = *(stack_ptr--);
+
+    assert_type("reverse", "stack", TAG_STACK, &list, scope);
+
+    ValueStack *values = list.as_stack;
+    ValueStack *result = vs_init();
+
+    for (size_t i = values->size; i > 0; i--)
+    {
+        vs_push(result, values->values[i - 1]);
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
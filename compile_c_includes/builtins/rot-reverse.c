@@ -0,0 +1,6 @@
+{
+    Value c = *stack_ptr;
+    *stack_ptr = *(stack_ptr - 1);
+    *(stack_ptr - 1) = *(stack_ptr - 2);
+    *(stack_ptr - 2) = c;
+}
\ No newline at end of file
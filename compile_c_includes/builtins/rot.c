@@ -0,0 +1,6 @@
+{
+    Value a = *(stack_ptr - 2);
+    *(stack_ptr - 2) = *(stack_ptr - 1);
+    *(stack_ptr - 1) = *stack_ptr;
+    *stack_ptr = a;
+}
\ No newline at end of file
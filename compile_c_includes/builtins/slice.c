@@ -0,0 +1,26 @@
+{
+    Value end = *(stack_ptr--);
+    Value start = *(stack_ptr--);
+    Value list = *(stack_ptr--);
+
+    assert_type("slice", "stack", TAG_STACK, &list, scope);
+    assert_type("slice", "integer", TAG_NUMBER_INTEGER, &start, scope);
+    assert_type("slice", "integer", TAG_NUMBER_INTEGER, &end, scope);
+
+    ValueStack *values = list.as_stack;
+    if (start.as_integer < 0 || end.as_integer < start.as_integer || end.as_integer > (int64_t)values->size)
+    {
+        fprintf(stderr, "slice: cannot slice %lld..%lld from a list of %lu values\n", start.as_integer, end.as_integer, values->size);
+        error_exit();
+    }
+
+    ValueStack *result = vs_init();
+
+    for (int64_t i = start.as_integer; i < end.as_integer; i++)
+    {
+        vs_push(result, values->values[i]);
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -0,0 +1,27 @@
+{
+    Value list = *(stack_ptr--);
+    Value block = *(stack_ptr--);
+
+    assert_type("sort-by", "stack", TAG_STACK, &list, scope);
+    assert_type("sort-by", "block", TAG_BLOCK, &block, scope);
+    assert_block_arity("sort-by", &block.as_block, 1, 1);
+
+    ValueStack *values = list.as_stack;
+    ValueStack *keys = vs_init();
+    ValueStack *result = list_copy(values);
+
+    for (size_t i = 0; i < values->size; i++)
+    {
+        Value key = list_call(block.as_block, &values->values[i], NULL);
+        if (loop_control)
+        {
+            goto block_return;
+        }
+
+        vs_push(keys, key);
+    }
+    list_sort("sort-by", keys, result);
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -0,0 +1,12 @@
+{
+    Value list = *(stack_ptr--);
+
+    assert_type("sort", "stack", TAG_STACK, &list, scope);
+
+    ValueStack *keys = list_copy(list.as_stack);
+    ValueStack *result = list_copy(list.as_stack);
+    list_sort("sort", keys, result);
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
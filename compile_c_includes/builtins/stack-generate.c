@@ -7,26 +7,33 @@
     if (block.type != TAG_BLOCK)
     {
         printf("Error: loop block must be a block\n");
-        exit(1);
+        error_exit();
     }
 
     if (iter.type == TAG_NUMBER_INTEGER)
     {
+        loop_depth++;
         for (int i = 0; i < iter.as_integer; i++)
         {
             Value v = {.type = TAG_NUMBER_INTEGER, .as_integer = i};
             *(++stack_ptr) = v;
 
-            void *f = block.as_block;
-            ((void (*)(Name *))f)(names);
+            // The value returned by the call that breaks is not collected
+            bool more = loop_body(block.as_block);
+            Value result = *(stack_ptr--);
+            if (!more)
+            {
+                break;
+            }
 
-            vs_push(s, *(stack_ptr--));
+            vs_push(s, result);
         }
+        loop_depth--;
     }
     else
     {
         fprintf(stderr, "Error: loop iterator must be an integer (others todo)\n");
-        exit(1);
+        error_exit();
     }
 
     Value v = {.type = TAG_STACK, .as_stack = s};
@@ -1,7 +1,7 @@
 {
     Value *s = stack_ptr--;
 
-    assert_type("stack-pop!", "stack", TAG_STACK, s, names);
+    assert_type("stack-pop!", "stack", TAG_STACK, s, scope);
 
     ValueStack *stack = s->as_stack;
     Value *v = vs_pop(stack);
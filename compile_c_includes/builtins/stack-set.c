@@ -3,8 +3,8 @@
     Value *v = stack_ptr--;
     Value *s = stack_ptr--;
 
-    assert_type("stack-set!", "stack", TAG_STACK, s, names);
-    assert_type("stack-set!", "integer", TAG_NUMBER_INTEGER, i, names);
+    assert_type("stack-set!", "stack", TAG_STACK, s, scope);
+    assert_type("stack-set!", "integer", TAG_NUMBER_INTEGER, i, scope);
 
     ValueStack *stack = s->as_stack;
     vs_set(stack, i->as_integer, *v);
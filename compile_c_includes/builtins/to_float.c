@@ -11,7 +11,7 @@
     }
     else if (v->type == TAG_STRING)
     {
-        v->as_float = strtod(v->as_string);
+        v->as_float = strtod(v->as_string, NULL);
     }
     else if (v->type == TAG_BOOLEAN)
     {
@@ -20,12 +20,12 @@
     else if (v->type == TAG_BLOCK)
     {
         fprintf(stderr, "error: cannot cast block to float");
-        exit(1);
+        error_exit();
     }
     else
     {
         fprintf(stderr, "error: unknown type to cast to float");
-        exit(1);
+        error_exit();
     }
 
     v->type = TAG_NUMBER_FLOAT;
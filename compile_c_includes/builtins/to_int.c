@@ -20,12 +20,12 @@
     else if (v->type == TAG_BLOCK)
     {
         fprintf(stderr, "error: cannot cast block to int");
-        exit(1);
+        error_exit();
     }
     else
     {
         fprintf(stderr, "error: unknown type to cast to int");
-        exit(1);
+        error_exit();
     }
 
     v->type = TAG_NUMBER_INTEGER;
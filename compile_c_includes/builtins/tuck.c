@@ -0,0 +1,7 @@
+{
+    Value a = *(stack_ptr - 1);
+    Value b = *stack_ptr;
+    *(stack_ptr - 1) = b;
+    *stack_ptr = a;
+    *(++stack_ptr) = b;
+}
\ No newline at end of file
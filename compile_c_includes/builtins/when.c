@@ -2,15 +2,14 @@
     Value cond = *(stack_ptr--);
     Value block = *(stack_ptr--);
 
-    assert_type("when", "boolean", TAG_BOOLEAN, &cond, names);
-    assert_type("when", "block", TAG_BLOCK, &block, names);
+    assert_type("when", "boolean", TAG_BOOLEAN, &cond, scope);
+    assert_type("when", "block", TAG_BLOCK, &block, scope);
 
     if (cond.as_boolean)
     {
         if (block.type == TAG_BLOCK)
         {
-            void *f = block.as_block;
-            ((void (*)(Name *))f)(names);
+            call_block(block.as_block);
         }
         else
         {
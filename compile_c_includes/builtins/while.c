@@ -0,0 +1,20 @@
+{
+    Value body = *(stack_ptr--);
+    Value cond = *(stack_ptr--);
+
+    assert_type("while", "block", TAG_BLOCK, &cond, scope);
+    assert_type("while", "block", TAG_BLOCK, &body, scope);
+
+    loop_depth++;
+    while (loop_body(cond.as_block))
+    {
+        Value *result = stack_ptr--;
+        assert_type("while (condition result)", "boolean", TAG_BOOLEAN, result, scope);
+
+        if (!result->as_boolean || !loop_body(body.as_block))
+        {
+            break;
+        }
+    }
+    loop_depth--;
+}
\ No newline at end of file
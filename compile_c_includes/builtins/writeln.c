@@ -0,0 +1,5 @@
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
\ No newline at end of file
@@ -0,0 +1,24 @@
+{
+    Value b = *(stack_ptr--);
+    Value a = *(stack_ptr--);
+
+    assert_type("zip", "stack", TAG_STACK, &a, scope);
+    assert_type("zip", "stack", TAG_STACK, &b, scope);
+
+    ValueStack *as = a.as_stack;
+    ValueStack *bs = b.as_stack;
+    ValueStack *result = vs_init();
+
+    for (size_t i = 0; i < as->size && i < bs->size; i++)
+    {
+        ValueStack *pair = vs_init();
+        vs_push(pair, as->values[i]);
+        vs_push(pair, bs->values[i]);
+
+        Value p = {.type = TAG_STACK, .as_stack = pair};
+        vs_push(result, p);
+    }
+
+    Value v = {.type = TAG_STACK, .as_stack = result};
+    *(++stack_ptr) = v;
+}
\ No newline at end of file
@@ -8,6 +8,13 @@
 /*{DEBUG}*/
 // #endregion
 
+// #region Generated profile flag
+/*{PROFILE}*/
+// #endregion
+
+// Print the stack and the blocks that were called then exit, for runtime errors (defined below)
+void error_exit();
+
 // #region Values on the stack
 #define TAG_NUMBER 0
 #define TAG_NUMBER_INTEGER 1
@@ -21,6 +28,24 @@
 
 #define TAG_STACK 32
 
+// Scopes hold named values for each block, see below
+typedef struct Scope Scope;
+
+// A block is a function along with the scope it was defined in and its arity
+// The scope is only valid while the block it belongs to is running, see call_block
+// It is NULL for blocks that don't use any names from it
+typedef struct
+{
+    void (*call)(Scope *);
+    Scope *scope;
+    size_t scope_depth;
+    size_t scope_id;
+    // Where the block is defined
+    char *site;
+    size_t arity_in;
+    size_t arity_out;
+} Block;
+
 typedef struct
 {
     uint8_t type;
@@ -31,7 +56,7 @@ typedef struct
 
         char *as_string;
         bool as_boolean;
-        void *as_block;
+        Block as_block;
 
         void *as_stack;
     };
@@ -100,7 +125,7 @@ Value *vs_pop(ValueStack *stack)
     if (stack->size == 0)
     {
         fprintf(stderr, "Attempted to pop from an empty stack\n");
-        exit(1);
+        error_exit();
     }
 
     return &stack->values[--stack->size];
@@ -112,7 +137,7 @@ Value *vs_get(ValueStack *stack, size_t index)
     if (index >= stack->size)
     {
         fprintf(stderr, "Attempted to get a value from the stack at an invalid index (%lu, size is %lu)\n", index, stack->size);
-        exit(1);
+        error_exit();
     }
 
     return &stack->values[index];
@@ -124,7 +149,7 @@ void vs_set(ValueStack *stack, size_t index, Value value)
     if (index >= stack->size)
     {
         fprintf(stderr, "Attempted to get a value from the stack at an invalid index (%lu, size is %lu)\n", index, stack->size);
-        exit(1);
+        error_exit();
     }
 
     stack->values[index] = value;
@@ -139,74 +164,204 @@ Value *stack_ptr;
 // Frames holding the stack pointer for each block
 Value **frames;
 Value **frame_ptr;
-// #endregion
 
-// #region Generated name constants
-/*{NAMES}*/
+// Set by break and continue, blocks return early until the enclosing loop handles it
+#define LOOP_NONE 0
+#define LOOP_BREAK 1
+#define LOOP_CONTINUE 2
+int loop_control = LOOP_NONE;
+
+// The number of loops currently running, break and continue outside of any loop is an error
+int loop_depth = 0;
 // #endregion
 
 // #region Data and functions for naming variables on the stack
-// Names linked list
-typedef struct Name Name;
-struct Name
-{
-    bool boundary;
-    uint8_t name;
-    Value *value;
-    Name *prev;
+// Each block has a scope with a slot for each name bound in it
+// Scopes point to the scope of the block they were defined in
+struct Scope
+{
+    Scope *parent;
+    // Unique to each call, so a block can tell if the call it was defined in has returned
+    size_t id;
+    size_t size;
+    Value **slots;
+    char **names;
 };
 
-Name *names_bind(Name *names, uint8_t name, Value *value)
+// #region Blocks that have been called and not returned, for backtraces
+#define MAX_CALLS 10240
+
+typedef struct
+{
+    // The name the block was called by and where, NULL if a builtin called it
+    char *name;
+    char *site;
+    // Where the block is defined
+    char *block;
+    Scope *scope;
+} Call;
+
+Call calls[MAX_CALLS];
+size_t call_depth = 0;
+size_t next_scope_id = 0;
+
+// Set just before calling a block by name
+char *calling_name = NULL;
+char *calling_site = NULL;
+
+// Called as each block starts, the first call is the top level
+void call_enter(char *block, Scope *scope)
+{
+    if (call_depth < MAX_CALLS)
+    {
+        Call call = {.name = calling_name, .site = calling_site, .block = block, .scope = scope};
+        calls[call_depth] = call;
+    }
+    call_depth++;
+
+    calling_name = NULL;
+    calling_site = NULL;
+}
+
+void call_exit()
+{
+    call_depth--;
+}
+// #endregion
+
+// Find the scope depth blocks out from this one
+Scope *scope_at(Scope *scope, size_t depth)
 {
-    Name *new_name = malloc(sizeof(Name));
-    if (new_name == NULL)
+    for (size_t i = 0; i < depth; i++)
     {
-        fprintf(stderr, "Out of memory");
-        exit(1);
+        scope = scope->parent;
     }
 
-    new_name->boundary = false;
-    new_name->name = name;
-    new_name->value = value;
-    new_name->prev = names;
-    return new_name;
+    return scope;
+}
+
+// Name a value on the stack
+void scope_bind(Scope *scope, size_t slot, Value *value)
+{
+    scope->slots[slot] = value;
 }
 
 // Lookup a value on the stack by name
-Value *names_lookup(Name *names, uint8_t name)
+Value *scope_lookup(Scope *scope, size_t depth, size_t slot)
 {
-    while (names != NULL)
+    Scope *s = scope_at(scope, depth);
+
+    if (s->slots[slot] == NULL)
     {
-        if (names->name == name)
-        {
-            return names->value;
-        }
-        names = names->prev;
+        fprintf(stderr, "Error in scope_lookup(); name used before it was bound: %s\n", s->names[slot]);
+        error_exit();
     }
 
-    fprintf(stderr, "Error in names_lookup(); name not found: %d", name);
-    exit(1);
+    return s->slots[slot];
 }
 
 // Update a value on the stack by name
-void names_update(Name *names, uint8_t name, Value *value)
+void scope_update(Scope *scope, size_t depth, size_t slot, Value *value)
+{
+    *scope_lookup(scope, depth, slot) = *value;
+}
+
+// Call a block with the scope it was defined in
+// The block it was defined in must still be running, otherwise the scope is gone
+void call_block(Block block)
 {
-    while (names != NULL)
+    if (block.scope != NULL && (block.scope_depth >= call_depth || (block.scope_depth < MAX_CALLS && calls[block.scope_depth].scope->id != block.scope_id)))
     {
-        if (names->name == name)
-        {
-            *names->value = *value;
-            return;
-        }
-        names = names->prev;
+        fprintf(stderr, "block at %s called after the block it was defined in returned\n", block.site);
+        error_exit();
     }
 
-    fprintf(stderr, "Error in names_update(); name not found: %d", name);
-    exit(1);
+    block.call(block.scope);
+}
+
+// Call the body of a loop, handling break and continue
+// Returns false if the body breaks out of the loop
+bool loop_body(Block block)
+{
+    call_block(block);
+
+    int control = loop_control;
+    loop_control = LOOP_NONE;
+    return control != LOOP_BREAK;
 }
 // #endregion
 
 // #region Printing values or the stack
+// Write a float the way Rust's Display does: the fewest digits that read back as the same value, without an exponent
+void float_write(FILE *f, double x)
+{
+    if (x != x)
+    {
+        fprintf(f, "NaN");
+        return;
+    }
+    if (x == 1.0 / 0.0 || x == -1.0 / 0.0)
+    {
+        fprintf(f, x < 0 ? "-inf" : "inf");
+        return;
+    }
+
+    // Find the shortest precision that round trips, as d.ddde[+-]x
+    char buffer[32];
+    for (int precision = 0; precision < 17; precision++)
+    {
+        snprintf(buffer, sizeof(buffer), "%.*e", precision, x);
+        if (strtod(buffer, NULL) == x)
+        {
+            break;
+        }
+    }
+
+    // Split it into the digits (without the point) and the exponent
+    char digits[32];
+    size_t count = 0;
+    char *c = buffer;
+    if (*c == '-')
+    {
+        fputc('-', f);
+        c++;
+    }
+    for (; *c != 'e'; c++)
+    {
+        if (*c != '.')
+        {
+            digits[count++] = *c;
+        }
+    }
+    int exponent = atoi(c + 1);
+    while (count > 1 && digits[count - 1] == '0')
+    {
+        count--;
+    }
+
+    if (exponent < 0)
+    {
+        fprintf(f, "0.");
+        for (int i = -1; i > exponent; i--)
+        {
+            fputc('0', f);
+        }
+        fwrite(digits, 1, count, f);
+        return;
+    }
+
+    // Digits before the point, padded with zeros if needed, then any left over after it
+    for (int i = 0; i <= exponent; i++)
+    {
+        fputc(i < (int)count ? digits[i] : '0', f);
+    }
+    if ((int)count > exponent + 1)
+    {
+        fputc('.', f);
+        fwrite(digits + exponent + 1, 1, count - exponent - 1, f);
+    }
+}
+
 void value_write(FILE *f, Value *v)
 {
     if (v->type == TAG_NUMBER_INTEGER)
@@ -215,7 +370,7 @@ void value_write(FILE *f, Value *v)
     }
     else if (v->type == TAG_NUMBER_FLOAT)
     {
-        fprintf(f, "%f", v->as_float);
+        float_write(f, v->as_float);
     }
     else if (v->type == TAG_STRING)
     {
@@ -253,7 +408,30 @@ void value_write(FILE *f, Value *v)
     }
 }
 
-void stack_dump(Name *names)
+// Print the names bound in a scope and the scopes it is in, with | between them
+void stack_dump_names(Scope *scope)
+{
+    while (scope != NULL)
+    {
+        for (size_t i = 0; i < scope->size; i++)
+        {
+            if (scope->slots[i] != NULL)
+            {
+                fprintf(stderr, " %s=", scope->names[i]);
+                value_write(stderr, scope->slots[i]);
+            }
+        }
+
+        if (scope->parent != NULL)
+        {
+            fprintf(stderr, " |");
+        }
+
+        scope = scope->parent;
+    }
+}
+
+void stack_dump(Scope *scope)
 {
     if (stack_ptr == stack)
     {
@@ -269,41 +447,113 @@ void stack_dump(Name *names)
         }
     }
 
-    if (names != NULL)
+    if (scope != NULL)
+    {
+        fprintf(stderr, "NAMES:");
+        stack_dump_names(scope);
+    }
+
+    fprintf(stderr, "\n");
+}
+
+// Backtraces longer than this only show the calls at each end
+#define BACKTRACE_ENDS 10
+
+void error_exit()
+{
+    fprintf(stderr, "stack: [");
+    for (Value *ptr = stack + 1; ptr <= stack_ptr; ptr++)
+    {
+        if (ptr != stack + 1)
+        {
+            fprintf(stderr, ", ");
+        }
+        value_write(stderr, ptr);
+    }
+    fprintf(stderr, "]\n");
+
+    // Names bound in the current block and the blocks it is in
+    size_t depth = call_depth < MAX_CALLS ? call_depth : MAX_CALLS;
+    if (depth > 0)
+    {
+        fprintf(stderr, "names:");
+        stack_dump_names(calls[depth - 1].scope);
+        fprintf(stderr, "\n");
+    }
+
+    // The first call is the top level, which isn't shown
+    if (depth > 1)
     {
-        fprintf(stderr, "NAMES: ");
-        while (names != NULL)
+        fprintf(stderr, "backtrace (most recent call first):\n");
+        size_t count = depth - 1;
+        for (size_t i = 0; i < count; i++)
         {
-            fprintf(stderr, "%s=", get_name(names->name));
-            value_write(stderr, names->value);
+            if (count > 2 * BACKTRACE_ENDS && i >= BACKTRACE_ENDS && i < count - BACKTRACE_ENDS)
+            {
+                if (i == BACKTRACE_ENDS)
+                {
+                    fprintf(stderr, "  ... %lu more calls\n", count - 2 * BACKTRACE_ENDS);
+                }
+                continue;
+            }
 
-            if (names->boundary && names->prev != NULL)
+            Call *call = &calls[depth - 1 - i];
+            if (call->name != NULL)
             {
-                fprintf(stderr, " | ");
+                fprintf(stderr, "  %s at %s (block at %s)\n", call->name, call->site, call->block);
             }
             else
             {
-                fprintf(stderr, " ");
+                fprintf(stderr, "  block at %s\n", call->block);
             }
-
-            names = names->prev;
         }
     }
 
-    fprintf(stderr, "\n");
+    exit(1);
 }
 // #endregion
 
 // #region Assertions that print an error and exit if they fail
-void assert_type(char *name, char *type_name, uint8_t type_tag, Value *value, Name *names)
+void assert_type(char *name, char *type_name, uint8_t type_tag, Value *value, Scope *scope)
 {
     if (value->type != type_tag)
     {
         fprintf(stderr, "Error in %s, expected a %s, got: ", name, type_name);
         value_write(stderr, value);
-        fprintf(stderr, " with");
-        stack_dump(names);
-        exit(1);
+        fprintf(stderr, "\n");
+        error_exit();
+    }
+}
+
+// Check a block passed to a builtin takes and returns the number of values it will be called with
+void assert_block_arity(char *name, Block *block, size_t arity_in, size_t arity_out)
+{
+    if (block->arity_in != arity_in || block->arity_out != arity_out)
+    {
+        fprintf(stderr, "%s expects a block that takes %lu and returns %lu, got {%lu->%lu}\n", name, arity_in, arity_out, block->arity_in, block->arity_out);
+        error_exit();
+    }
+}
+
+// Check there are enough values on the stack to enter a block
+void assert_arity_in(char *block, size_t arity_in)
+{
+    long available = stack_ptr - stack;
+    if (available < (long)arity_in)
+    {
+        fprintf(stderr, "block at %s expects %lu values on entry, but only %ld are on the stack\n", block, arity_in, available);
+        error_exit();
+    }
+}
+
+// Check there are enough values in the current frame to return from a block
+void assert_arity_out(char *block, size_t arity_out)
+{
+    long available = stack_ptr - *frame_ptr;
+    if (available < (long)arity_out)
+    {
+        fprintf(stderr, "block at %s expects %lu values on exit, but only %ld are left\n", block, arity_out, available < 0 ? 0 : available);
+        error_exit();
     }
 }
 // #endregion
@@ -331,6 +581,312 @@ void coerce(Value *a, Value *b)
 }
 // #endregion
 
+// #region Assertions made by the program itself (assert and assert-eq)
+// Compare two values, numbers are coerced and lists are compared by value
+bool value_equal(Value *a, Value *b)
+{
+    Value x = *a;
+    Value y = *b;
+    coerce(&x, &y);
+
+    if (x.type != y.type)
+    {
+        return false;
+    }
+
+    switch (x.type)
+    {
+    case TAG_NUMBER_INTEGER:
+        return x.as_integer == y.as_integer;
+    case TAG_NUMBER_FLOAT:
+        return x.as_float == y.as_float;
+    case TAG_STRING:
+        return strcmp(x.as_string, y.as_string) == 0;
+    case TAG_BOOLEAN:
+        return x.as_boolean == y.as_boolean;
+    case TAG_BLOCK:
+        return x.as_block.call == y.as_block.call && x.as_block.scope == y.as_block.scope;
+    case TAG_STACK:
+    {
+        ValueStack *xs = x.as_stack;
+        ValueStack *ys = y.as_stack;
+        if (xs->size != ys->size)
+        {
+            return false;
+        }
+        for (size_t i = 0; i < xs->size; i++)
+        {
+            if (!value_equal(&xs->values[i], &ys->values[i]))
+            {
+                return false;
+            }
+        }
+        return true;
+    }
+    default:
+        return false;
+    }
+}
+
+// Fail unless two values are equal, span is where assert-eq was called
+void assert_equal(char *span, Value *actual, Value *expected)
+{
+    if (!value_equal(actual, expected))
+    {
+        fprintf(stderr, "assert-eq failed: ");
+        value_write(stderr, actual);
+        fprintf(stderr, " != ");
+        value_write(stderr, expected);
+        fprintf(stderr, " at %s\n", span);
+        error_exit();
+    }
+}
+// #endregion
+
+// #region Helpers for list operations
+// Call a block with one or two arguments, returning the value it returns
+Value list_call(Block block, Value *a, Value *b)
+{
+    *(++stack_ptr) = *a;
+    if (b != NULL)
+    {
+        *(++stack_ptr) = *b;
+    }
+
+    call_block(block);
+
+    // If the block breaks or continues, the value it returned is left on the stack
+    if (loop_control)
+    {
+        return *stack_ptr;
+    }
+    return *(stack_ptr--);
+}
+
+// Call a block that tests a value, it must return a boolean
+bool list_test(char *name, Block block, Value *v, Scope *scope)
+{
+    Value result = list_call(block, v, NULL);
+    if (loop_control)
+    {
+        return false;
+    }
+
+    assert_type(name, "boolean", TAG_BOOLEAN, &result, scope);
+    return result.as_boolean;
+}
+
+// Compare two sort keys, which must both be numbers or both be strings
+int list_compare(char *name, Value *a, Value *b)
+{
+    if (a->type == TAG_STRING && b->type == TAG_STRING)
+    {
+        return strcmp(a->as_string, b->as_string);
+    }
+
+    if ((a->type == TAG_NUMBER_INTEGER || a->type == TAG_NUMBER_FLOAT) && (b->type == TAG_NUMBER_INTEGER || b->type == TAG_NUMBER_FLOAT))
+    {
+        Value x = *a;
+        Value y = *b;
+        coerce(&x, &y);
+
+        if (x.type == TAG_NUMBER_INTEGER)
+        {
+            return (x.as_integer > y.as_integer) - (x.as_integer < y.as_integer);
+        }
+        return (x.as_float > y.as_float) - (x.as_float < y.as_float);
+    }
+
+    fprintf(stderr, "%s can only sort numbers or strings\n", name);
+    error_exit();
+}
+
+// Sort values by the matching keys (a stable insertion sort, both stacks are sorted in place)
+void list_sort(char *name, ValueStack *keys, ValueStack *values)
+{
+    for (size_t i = 1; i < keys->size; i++)
+    {
+        Value key = keys->values[i];
+        Value value = values->values[i];
+
+        size_t j = i;
+        while (j > 0 && list_compare(name, &keys->values[j - 1], &key) > 0)
+        {
+            keys->values[j] = keys->values[j - 1];
+            values->values[j] = values->values[j - 1];
+            j--;
+        }
+
+        keys->values[j] = key;
+        values->values[j] = value;
+    }
+}
+
+// Copy the values of a list into a new list
+ValueStack *list_copy(ValueStack *list)
+{
+    ValueStack *copy = vs_init();
+    for (size_t i = 0; i < list->size; i++)
+    {
+        vs_push(copy, list->values[i]);
+    }
+    return copy;
+}
+// #endregion
+
+// #region Counting calls and time in each named block and builtin
+#ifdef PROFILE
+#include <time.h>
+
+#define PROFILE_MAX_ENTRIES 1024
+#define PROFILE_MAX_DEPTH 10240
+
+typedef struct
+{
+    char *name;
+    char *kind;
+    uint64_t calls;
+    uint64_t inclusive;
+    uint64_t exclusive;
+    // Calls that haven't returned yet, recursive calls only count towards inclusive time once
+    size_t active;
+} ProfileEntry;
+
+typedef struct
+{
+    size_t entry;
+    uint64_t start;
+    uint64_t children;
+} ProfileFrame;
+
+ProfileEntry profile_entries[PROFILE_MAX_ENTRIES];
+size_t profile_entry_count = 0;
+ProfileFrame profile_frames[PROFILE_MAX_DEPTH];
+size_t profile_depth = 0;
+uint64_t profile_start;
+
+// The current time in nanoseconds
+uint64_t profile_now()
+{
+    struct timespec ts;
+    clock_gettime(CLOCK_MONOTONIC, &ts);
+    return (uint64_t)ts.tv_sec * 1000000000 + ts.tv_nsec;
+}
+
+// Find or add the entry for a name, each call site does this once
+size_t profile_register(char *name, char *kind)
+{
+    for (size_t i = 0; i < profile_entry_count; i++)
+    {
+        if (strcmp(profile_entries[i].name, name) == 0 && strcmp(profile_entries[i].kind, kind) == 0)
+        {
+            return i;
+        }
+    }
+
+    if (profile_entry_count >= PROFILE_MAX_ENTRIES)
+    {
+        fprintf(stderr, "too many names to profile\n");
+        exit(1);
+    }
+
+    ProfileEntry entry = {.name = name, .kind = kind};
+    profile_entries[profile_entry_count] = entry;
+    return profile_entry_count++;
+}
+
+void profile_enter(size_t entry)
+{
+    if (profile_depth >= PROFILE_MAX_DEPTH)
+    {
+        fprintf(stderr, "calls nested too deeply to profile\n");
+        exit(1);
+    }
+
+    ProfileFrame frame = {.entry = entry, .start = profile_now(), .children = 0};
+    profile_frames[profile_depth++] = frame;
+    profile_entries[entry].calls++;
+    profile_entries[entry].active++;
+}
+
+void profile_exit()
+{
+    if (profile_depth == 0)
+    {
+        return;
+    }
+
+    ProfileFrame frame = profile_frames[--profile_depth];
+    ProfileEntry *entry = &profile_entries[frame.entry];
+    uint64_t inclusive = profile_now() - frame.start;
+
+    entry->active--;
+    if (entry->active == 0)
+    {
+        entry->inclusive += inclusive;
+    }
+    entry->exclusive += inclusive > frame.children ? inclusive - frame.children : 0;
+
+    if (profile_depth > 0)
+    {
+        profile_frames[profile_depth - 1].children += inclusive;
+    }
+}
+
+// Return from any calls made since the depth was depth, for blocks that return early (break and continue)
+void profile_unwind(size_t depth)
+{
+    while (profile_depth > depth)
+    {
+        profile_exit();
+    }
+}
+
+int profile_compare(const void *a, const void *b)
+{
+    uint64_t x = ((ProfileEntry *)a)->exclusive;
+    uint64_t y = ((ProfileEntry *)b)->exclusive;
+    return (x < y) - (x > y);
+}
+
+// Print the table when the program exits (even with an error), the most exclusive time first
+void profile_report()
+{
+    profile_unwind(0);
+    double total = (double)(profile_now() - profile_start);
+
+    qsort(profile_entries, profile_entry_count, sizeof(ProfileEntry), profile_compare);
+
+    fprintf(stderr, "total: %.3fms\n", total / 1e6);
+    fprintf(stderr, "%12s %6s %12s %10s  %-7s  name\n", "exclusive", "%", "inclusive", "calls", "kind");
+    for (size_t i = 0; i < profile_entry_count; i++)
+    {
+        ProfileEntry *entry = &profile_entries[i];
+        fprintf(
+            stderr,
+            "%10.3fms %5.1f%% %10.3fms %10lu  %-7s  %s\n",
+            entry->exclusive / 1e6,
+            total > 0 ? 100.0 * entry->exclusive / total : 0.0,
+            entry->inclusive / 1e6,
+            (unsigned long)entry->calls,
+            entry->kind,
+            entry->name);
+    }
+}
+
+// Each call site registers its name the first time it runs
+#define PROFILE_ENTER(name, kind)                          \
+    {                                                      \
+        static size_t profile_id = PROFILE_MAX_ENTRIES;    \
+        if (profile_id == PROFILE_MAX_ENTRIES)             \
+        {                                                  \
+            profile_id = profile_register(name, kind);     \
+        }                                                  \
+        profile_enter(profile_id);                         \
+    }
+#endif
+// #endregion
+
 // #region Generated block definitions
 /*{BLOCKS}*/
 // #endregion
@@ -346,6 +902,11 @@ int main(int argc, char *argv[])
     frames = malloc(10240 * sizeof(Value **));
     frame_ptr = frames;
 
+    #ifdef PROFILE
+    profile_start = profile_now();
+    atexit(profile_report);
+    #endif
+
     block_0(NULL);
 
     return 0;
@@ -0,0 +1,1497 @@
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+// #region Generated debug flag
+// #define DEBUG 1
+// #endregion
+
+// #region Generated profile flag
+// #define PROFILE 1
+// #endregion
+
+// Print the stack and the blocks that were called then exit, for runtime errors (defined below)
+void error_exit();
+
+// #region Values on the stack
+#define TAG_NUMBER 0
+#define TAG_NUMBER_INTEGER 1
+#define TAG_NUMBER_RATIONAL 2
+#define TAG_NUMBER_FLOAT 3
+#define TAG_NUMBER_COMPLEX 4
+
+#define TAG_STRING 16
+#define TAG_BOOLEAN 17
+#define TAG_BLOCK 18
+
+#define TAG_STACK 32
+
+// Scopes hold named values for each block, see below
+typedef struct Scope Scope;
+
+// A block is a function along with the scope it was defined in and its arity
+// The scope is only valid while the block it belongs to is running, see call_block
+// It is NULL for blocks that don't use any names from it
+typedef struct
+{
+    void (*call)(Scope *);
+    Scope *scope;
+    size_t scope_depth;
+    size_t scope_id;
+    // Where the block is defined
+    char *site;
+    size_t arity_in;
+    size_t arity_out;
+} Block;
+
+typedef struct
+{
+    uint8_t type;
+    union
+    {
+        int64_t as_integer;
+        double as_float;
+
+        char *as_string;
+        bool as_boolean;
+        Block as_block;
+
+        void *as_stack;
+    };
+} Value;
+// #endregion
+
+// #region A dynamically sized vector/stack of Values
+typedef struct
+{
+    size_t capacity;
+    size_t size;
+    Value *values;
+
+} ValueStack;
+
+#define VS_INITIAL_CAPACITY 8
+
+// Initialize the stack with default capacity
+ValueStack *vs_init()
+{
+    ValueStack *stack = malloc(sizeof(ValueStack));
+    Value *values = malloc(sizeof(Value) * VS_INITIAL_CAPACITY);
+
+    if (!stack || !values)
+    {
+        fprintf(stderr, "Failed to allocate memory for a ValueStack\n");
+        exit(1);
+    }
+
+    stack->capacity = VS_INITIAL_CAPACITY;
+    stack->size = 0;
+    stack->values = values;
+
+    return stack;
+}
+
+// Ensure there's room to push another value onto the stack
+void vs_ensure_capacity(ValueStack *stack, size_t new_size)
+{
+    if (new_size >= stack->capacity)
+    {
+        size_t new_capacity = stack->capacity * 2;
+        Value *new_values = realloc(stack->values, sizeof(Value) * new_capacity);
+
+        if (!new_values)
+        {
+            fprintf(stderr, "Failed to re-allocate memory for a ValueStack\n");
+            exit(1);
+        }
+
+        stack->capacity = new_capacity;
+        stack->values = new_values;
+    }
+}
+
+// Push a value onto the stack
+void vs_push(ValueStack *stack, Value val)
+{
+    vs_ensure_capacity(stack, stack->size + 1);
+    stack->values[stack->size++] = val;
+}
+
+// Pop and return a  value from the stack
+Value *vs_pop(ValueStack *stack)
+{
+    if (stack->size == 0)
+    {
+        fprintf(stderr, "Attempted to pop from an empty stack\n");
+        error_exit();
+    }
+
+    return &stack->values[--stack->size];
+}
+
+// Get a value from the stack by index without removing it
+Value *vs_get(ValueStack *stack, size_t index)
+{
+    if (index >= stack->size)
+    {
+        fprintf(stderr, "Attempted to get a value from the stack at an invalid index (%lu, size is %lu)\n", index, stack->size);
+        error_exit();
+    }
+
+    return &stack->values[index];
+}
+
+// Set a value in the stack at a given index
+void vs_set(ValueStack *stack, size_t index, Value value)
+{
+    if (index >= stack->size)
+    {
+        fprintf(stderr, "Attempted to get a value from the stack at an invalid index (%lu, size is %lu)\n", index, stack->size);
+        error_exit();
+    }
+
+    stack->values[index] = value;
+}
+// #endregion
+
+// #region Global data structures
+// The stack holding all values
+Value *stack;
+Value *stack_ptr;
+
+// Frames holding the stack pointer for each block
+Value **frames;
+Value **frame_ptr;
+
+// Set by break and continue, blocks return early until the enclosing loop handles it
+#define LOOP_NONE 0
+#define LOOP_BREAK 1
+#define LOOP_CONTINUE 2
+int loop_control = LOOP_NONE;
+
+// The number of loops currently running, break and continue outside of any loop is an error
+int loop_depth = 0;
+// #endregion
+
+// #region Data and functions for naming variables on the stack
+// Each block has a scope with a slot for each name bound in it
+// Scopes point to the scope of the block they were defined in
+struct Scope
+{
+    Scope *parent;
+    // Unique to each call, so a block can tell if the call it was defined in has returned
+    size_t id;
+    size_t size;
+    Value **slots;
+    char **names;
+};
+
+// #region Blocks that have been called and not returned, for backtraces
+#define MAX_CALLS 10240
+
+typedef struct
+{
+    // The name the block was called by and where, NULL if a builtin called it
+    char *name;
+    char *site;
+    // Where the block is defined
+    char *block;
+    Scope *scope;
+} Call;
+
+Call calls[MAX_CALLS];
+size_t call_depth = 0;
+size_t next_scope_id = 0;
+
+// Set just before calling a block by name
+char *calling_name = NULL;
+char *calling_site = NULL;
+
+// Called as each block starts, the first call is the top level
+void call_enter(char *block, Scope *scope)
+{
+    if (call_depth < MAX_CALLS)
+    {
+        Call call = {.name = calling_name, .site = calling_site, .block = block, .scope = scope};
+        calls[call_depth] = call;
+    }
+    call_depth++;
+
+    calling_name = NULL;
+    calling_site = NULL;
+}
+
+void call_exit()
+{
+    call_depth--;
+}
+// #endregion
+
+// Find the scope depth blocks out from this one
+Scope *scope_at(Scope *scope, size_t depth)
+{
+    for (size_t i = 0; i < depth; i++)
+    {
+        scope = scope->parent;
+    }
+
+    return scope;
+}
+
+// Name a value on the stack
+void scope_bind(Scope *scope, size_t slot, Value *value)
+{
+    scope->slots[slot] = value;
+}
+
+// Lookup a value on the stack by name
+Value *scope_lookup(Scope *scope, size_t depth, size_t slot)
+{
+    Scope *s = scope_at(scope, depth);
+
+    if (s->slots[slot] == NULL)
+    {
+        fprintf(stderr, "Error in scope_lookup(); name used before it was bound: %s\n", s->names[slot]);
+        error_exit();
+    }
+
+    return s->slots[slot];
+}
+
+// Update a value on the stack by name
+void scope_update(Scope *scope, size_t depth, size_t slot, Value *value)
+{
+    *scope_lookup(scope, depth, slot) = *value;
+}
+
+// Call a block with the scope it was defined in
+// The block it was defined in must still be running, otherwise the scope is gone
+void call_block(Block block)
+{
+    if (block.scope != NULL && (block.scope_depth >= call_depth || (block.scope_depth < MAX_CALLS && calls[block.scope_depth].scope->id != block.scope_id)))
+    {
+        fprintf(stderr, "block at %s called after the block it was defined in returned\n", block.site);
+        error_exit();
+    }
+
+    block.call(block.scope);
+}
+
+// Call the body of a loop, handling break and continue
+// Returns false if the body breaks out of the loop
+bool loop_body(Block block)
+{
+    call_block(block);
+
+    int control = loop_control;
+    loop_control = LOOP_NONE;
+    return control != LOOP_BREAK;
+}
+// #endregion
+
+// #region Printing values or the stack
+// Write a float the way Rust's Display does: the fewest digits that read back as the same value, without an exponent
+void float_write(FILE *f, double x)
+{
+    if (x != x)
+    {
+        fprintf(f, "NaN");
+        return;
+    }
+    if (x == 1.0 / 0.0 || x == -1.0 / 0.0)
+    {
+        fprintf(f, x < 0 ? "-inf" : "inf");
+        return;
+    }
+
+    // Find the shortest precision that round trips, as d.ddde[+-]x
+    char buffer[32];
+    for (int precision = 0; precision < 17; precision++)
+    {
+        snprintf(buffer, sizeof(buffer), "%.*e", precision, x);
+        if (strtod(buffer, NULL) == x)
+        {
+            break;
+        }
+    }
+
+    // Split it into the digits (without the point) and the exponent
+    char digits[32];
+    size_t count = 0;
+    char *c = buffer;
+    if (*c == '-')
+    {
+        fputc('-', f);
+        c++;
+    }
+    for (; *c != 'e'; c++)
+    {
+        if (*c != '.')
+        {
+            digits[count++] = *c;
+        }
+    }
+    int exponent = atoi(c + 1);
+    while (count > 1 && digits[count - 1] == '0')
+    {
+        count--;
+    }
+
+    if (exponent < 0)
+    {
+        fprintf(f, "0.");
+        for (int i = -1; i > exponent; i--)
+        {
+            fputc('0', f);
+        }
+        fwrite(digits, 1, count, f);
+        return;
+    }
+
+    // Digits before the point, padded with zeros if needed, then any left over after it
+    for (int i = 0; i <= exponent; i++)
+    {
+        fputc(i < (int)count ? digits[i] : '0', f);
+    }
+    if ((int)count > exponent + 1)
+    {
+        fputc('.', f);
+        fwrite(digits + exponent + 1, 1, count - exponent - 1, f);
+    }
+}
+
+void value_write(FILE *f, Value *v)
+{
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        fprintf(f, "%lld", v->as_integer);
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        float_write(f, v->as_float);
+    }
+    else if (v->type == TAG_STRING)
+    {
+        fprintf(f, "%s", v->as_string);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        fprintf(f, "%s", v->as_boolean ? "true" : "false");
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(f, "{block}");
+    }
+    else if (v->type == TAG_STACK)
+    {
+        fprintf(f, "[");
+        ValueStack *stack = v->as_stack;
+        Value *ptr = stack->values;
+
+        for (int i = 0; i < stack->size; i++)
+        {
+            value_write(f, ptr++);
+
+            if (i != stack->size - 1)
+            {
+                fprintf(f, ", ");
+            }
+        }
+        fprintf(f, "]");
+    }
+    else
+    {
+        fprintf(stderr, "Error: unknown type on stack: %u\n", v->type);
+        exit(1);
+    }
+}
+
+// Print the names bound in a scope and the scopes it is in, with | between them
+void stack_dump_names(Scope *scope)
+{
+    while (scope != NULL)
+    {
+        for (size_t i = 0; i < scope->size; i++)
+        {
+            if (scope->slots[i] != NULL)
+            {
+                fprintf(stderr, " %s=", scope->names[i]);
+                value_write(stderr, scope->slots[i]);
+            }
+        }
+
+        if (scope->parent != NULL)
+        {
+            fprintf(stderr, " |");
+        }
+
+        scope = scope->parent;
+    }
+}
+
+void stack_dump(Scope *scope)
+{
+    if (stack_ptr == stack)
+    {
+        fprintf(stderr, " STACK: <empty> ");
+    }
+    else
+    {
+        fprintf(stderr, " STACK: ");
+        for (Value *ptr = stack_ptr; ptr != stack; ptr--)
+        {
+            value_write(stderr, ptr);
+            fprintf(stderr, " ");
+        }
+    }
+
+    if (scope != NULL)
+    {
+        fprintf(stderr, "NAMES:");
+        stack_dump_names(scope);
+    }
+
+    fprintf(stderr, "\n");
+}
+
+// Backtraces longer than this only show the calls at each end
+#define BACKTRACE_ENDS 10
+
+void error_exit()
+{
+    fprintf(stderr, "stack: [");
+    for (Value *ptr = stack + 1; ptr <= stack_ptr; ptr++)
+    {
+        if (ptr != stack + 1)
+        {
+            fprintf(stderr, ", ");
+        }
+        value_write(stderr, ptr);
+    }
+    fprintf(stderr, "]\n");
+
+    // Names bound in the current block and the blocks it is in
+    size_t depth = call_depth < MAX_CALLS ? call_depth : MAX_CALLS;
+    if (depth > 0)
+    {
+        fprintf(stderr, "names:");
+        stack_dump_names(calls[depth - 1].scope);
+        fprintf(stderr, "\n");
+    }
+
+    // The first call is the top level, which isn't shown
+    if (depth > 1)
+    {
+        fprintf(stderr, "backtrace (most recent call first):\n");
+        size_t count = depth - 1;
+        for (size_t i = 0; i < count; i++)
+        {
+            if (count > 2 * BACKTRACE_ENDS && i >= BACKTRACE_ENDS && i < count - BACKTRACE_ENDS)
+            {
+                if (i == BACKTRACE_ENDS)
+                {
+                    fprintf(stderr, "  ... %lu more calls\n", count - 2 * BACKTRACE_ENDS);
+                }
+                continue;
+            }
+
+            Call *call = &calls[depth - 1 - i];
+            if (call->name != NULL)
+            {
+                fprintf(stderr, "  %s at %s (block at %s)\n", call->name, call->site, call->block);
+            }
+            else
+            {
+                fprintf(stderr, "  block at %s\n", call->block);
+            }
+        }
+    }
+
+    exit(1);
+}
+// #endregion
+
+// #region Assertions that print an error and exit if they fail
+void assert_type(char *name, char *type_name, uint8_t type_tag, Value *value, Scope *scope)
+{
+    if (value->type != type_tag)
+    {
+        fprintf(stderr, "Error in %s, expected a %s, got: ", name, type_name);
+        value_write(stderr, value);
+        fprintf(stderr, "\n");
+        error_exit();
+    }
+}
+
+// Check a block passed to a builtin takes and returns the number of values it will be called with
+void assert_block_arity(char *name, Block *block, size_t arity_in, size_t arity_out)
+{
+    if (block->arity_in != arity_in || block->arity_out != arity_out)
+    {
+        fprintf(stderr, "%s expects a block that takes %lu and returns %lu, got {%lu->%lu}\n", name, arity_in, arity_out, block->arity_in, block->arity_out);
+        error_exit();
+    }
+}
+
+// Check there are enough values on the stack to enter a block
+void assert_arity_in(char *block, size_t arity_in)
+{
+    long available = stack_ptr - stack;
+    if (available < (long)arity_in)
+    {
+        fprintf(stderr, "block at %s expects %lu values on entry, but only %ld are on the stack\n", block, arity_in, available);
+        error_exit();
+    }
+}
+
+// Check there are enough values in the current frame to return from a block
+void assert_arity_out(char *block, size_t arity_out)
+{
+    long available = stack_ptr - *frame_ptr;
+    if (available < (long)arity_out)
+    {
+        fprintf(stderr, "block at %s expects %lu values on exit, but only %ld are left\n", block, arity_out, available < 0 ? 0 : available);
+        error_exit();
+    }
+}
+// #endregion
+
+// #region Functions for converting between types
+// Convert two values to have the same type by upgrading if necessary
+void coerce(Value *a, Value *b)
+{
+    if (a->type == b->type)
+    {
+        return;
+    }
+
+    if (a->type == TAG_NUMBER_INTEGER && b->type == TAG_NUMBER_FLOAT)
+    {
+        a->type = TAG_NUMBER_FLOAT;
+        a->as_float = (double)a->as_integer;
+    }
+
+    if (a->type == TAG_NUMBER_FLOAT && b->type == TAG_NUMBER_INTEGER)
+    {
+        b->type = TAG_NUMBER_FLOAT;
+        b->as_float = (double)b->as_integer;
+    }
+}
+// #endregion
+
+// #region Assertions made by the program itself (assert and assert-eq)
+// Compare two values, numbers are coerced and lists are compared by value
+bool value_equal(Value *a, Value *b)
+{
+    Value x = *a;
+    Value y = *b;
+    coerce(&x, &y);
+
+    if (x.type != y.type)
+    {
+        return false;
+    }
+
+    switch (x.type)
+    {
+    case TAG_NUMBER_INTEGER:
+        return x.as_integer == y.as_integer;
+    case TAG_NUMBER_FLOAT:
+        return x.as_float == y.as_float;
+    case TAG_STRING:
+        return strcmp(x.as_string, y.as_string) == 0;
+    case TAG_BOOLEAN:
+        return x.as_boolean == y.as_boolean;
+    case TAG_BLOCK:
+        return x.as_block.call == y.as_block.call && x.as_block.scope == y.as_block.scope;
+    case TAG_STACK:
+    {
+        ValueStack *xs = x.as_stack;
+        ValueStack *ys = y.as_stack;
+        if (xs->size != ys->size)
+        {
+            return false;
+        }
+        for (size_t i = 0; i < xs->size; i++)
+        {
+            if (!value_equal(&xs->values[i], &ys->values[i]))
+            {
+                return false;
+            }
+        }
+        return true;
+    }
+    default:
+        return false;
+    }
+}
+
+// Fail unless two values are equal, span is where assert-eq was called
+void assert_equal(char *span, Value *actual, Value *expected)
+{
+    if (!value_equal(actual, expected))
+    {
+        fprintf(stderr, "assert-eq failed: ");
+        value_write(stderr, actual);
+        fprintf(stderr, " != ");
+        value_write(stderr, expected);
+        fprintf(stderr, " at %s\n", span);
+        error_exit();
+    }
+}
+// #endregion
+
+// #region Helpers for list operations
+// Call a block with one or two arguments, returning the value it returns
+Value list_call(Block block, Value *a, Value *b)
+{
+    *(++stack_ptr) = *a;
+    if (b != NULL)
+    {
+        *(++stack_ptr) = *b;
+    }
+
+    call_block(block);
+
+    // If the block breaks or continues, the value it returned is left on the stack
+    if (loop_control)
+    {
+        return *stack_ptr;
+    }
+    return *(stack_ptr--);
+}
+
+// Call a block that tests a value, it must return a boolean
+bool list_test(char *name, Block block, Value *v, Scope *scope)
+{
+    Value result = list_call(block, v, NULL);
+    if (loop_control)
+    {
+        return false;
+    }
+
+    assert_type(name, "boolean", TAG_BOOLEAN, &result, scope);
+    return result.as_boolean;
+}
+
+// Compare two sort keys, which must both be numbers or both be strings
+int list_compare(char *name, Value *a, Value *b)
+{
+    if (a->type == TAG_STRING && b->type == TAG_STRING)
+    {
+        return strcmp(a->as_string, b->as_string);
+    }
+
+    if ((a->type == TAG_NUMBER_INTEGER || a->type == TAG_NUMBER_FLOAT) && (b->type == TAG_NUMBER_INTEGER || b->type == TAG_NUMBER_FLOAT))
+    {
+        Value x = *a;
+        Value y = *b;
+        coerce(&x, &y);
+
+        if (x.type == TAG_NUMBER_INTEGER)
+        {
+            return (x.as_integer > y.as_integer) - (x.as_integer < y.as_integer);
+        }
+        return (x.as_float > y.as_float) - (x.as_float < y.as_float);
+    }
+
+    fprintf(stderr, "%s can only sort numbers or strings\n", name);
+    error_exit();
+}
+
+// Sort values by the matching keys (a stable insertion sort, both stacks are sorted in place)
+void list_sort(char *name, ValueStack *keys, ValueStack *values)
+{
+    for (size_t i = 1; i < keys->size; i++)
+    {
+        Value key = keys->values[i];
+        Value value = values->values[i];
+
+        size_t j = i;
+        while (j > 0 && list_compare(name, &keys->values[j - 1], &key) > 0)
+        {
+            keys->values[j] = keys->values[j - 1];
+            values->values[j] = values->values[j - 1];
+            j--;
+        }
+
+        keys->values[j] = key;
+        values->values[j] = value;
+    }
+}
+
+// Copy the values of a list into a new list
+ValueStack *list_copy(ValueStack *list)
+{
+    ValueStack *copy = vs_init();
+    for (size_t i = 0; i < list->size; i++)
+    {
+        vs_push(copy, list->values[i]);
+    }
+    return copy;
+}
+// #endregion
+
+// #region Counting calls and time in each named block and builtin
+#ifdef PROFILE
+#include <time.h>
+
+#define PROFILE_MAX_ENTRIES 1024
+#define PROFILE_MAX_DEPTH 10240
+
+typedef struct
+{
+    char *name;
+    char *kind;
+    uint64_t calls;
+    uint64_t inclusive;
+    uint64_t exclusive;
+    // Calls that haven't returned yet, recursive calls only count towards inclusive time once
+    size_t active;
+} ProfileEntry;
+
+typedef struct
+{
+    size_t entry;
+    uint64_t start;
+    uint64_t children;
+} ProfileFrame;
+
+ProfileEntry profile_entries[PROFILE_MAX_ENTRIES];
+size_t profile_entry_count = 0;
+ProfileFrame profile_frames[PROFILE_MAX_DEPTH];
+size_t profile_depth = 0;
+uint64_t profile_start;
+
+// The current time in nanoseconds
+uint64_t profile_now()
+{
+    struct timespec ts;
+    clock_gettime(CLOCK_MONOTONIC, &ts);
+    return (uint64_t)ts.tv_sec * 1000000000 + ts.tv_nsec;
+}
+
+// Find or add the entry for a name, each call site does this once
+size_t profile_register(char *name, char *kind)
+{
+    for (size_t i = 0; i < profile_entry_count; i++)
+    {
+        if (strcmp(profile_entries[i].name, name) == 0 && strcmp(profile_entries[i].kind, kind) == 0)
+        {
+            return i;
+        }
+    }
+
+    if (profile_entry_count >= PROFILE_MAX_ENTRIES)
+    {
+        fprintf(stderr, "too many names to profile\n");
+        exit(1);
+    }
+
+    ProfileEntry entry = {.name = name, .kind = kind};
+    profile_entries[profile_entry_count] = entry;
+    return profile_entry_count++;
+}
+
+void profile_enter(size_t entry)
+{
+    if (profile_depth >= PROFILE_MAX_DEPTH)
+    {
+        fprintf(stderr, "calls nested too deeply to profile\n");
+        exit(1);
+    }
+
+    ProfileFrame frame = {.entry = entry, .start = profile_now(), .children = 0};
+    profile_frames[profile_depth++] = frame;
+    profile_entries[entry].calls++;
+    profile_entries[entry].active++;
+}
+
+void profile_exit()
+{
+    if (profile_depth == 0)
+    {
+        return;
+    }
+
+    ProfileFrame frame = profile_frames[--profile_depth];
+    ProfileEntry *entry = &profile_entries[frame.entry];
+    uint64_t inclusive = profile_now() - frame.start;
+
+    entry->active--;
+    if (entry->active == 0)
+    {
+        entry->inclusive += inclusive;
+    }
+    entry->exclusive += inclusive > frame.children ? inclusive - frame.children : 0;
+
+    if (profile_depth > 0)
+    {
+        profile_frames[profile_depth - 1].children += inclusive;
+    }
+}
+
+// Return from any calls made since the depth was depth, for blocks that return early (break and continue)
+void profile_unwind(size_t depth)
+{
+    while (profile_depth > depth)
+    {
+        profile_exit();
+    }
+}
+
+int profile_compare(const void *a, const void *b)
+{
+    uint64_t x = ((ProfileEntry *)a)->exclusive;
+    uint64_t y = ((ProfileEntry *)b)->exclusive;
+    return (x < y) - (x > y);
+}
+
+// Print the table when the program exits (even with an error), the most exclusive time first
+void profile_report()
+{
+    profile_unwind(0);
+    double total = (double)(profile_now() - profile_start);
+
+    qsort(profile_entries, profile_entry_count, sizeof(ProfileEntry), profile_compare);
+
+    fprintf(stderr, "total: %.3fms\n", total / 1e6);
+    fprintf(stderr, "%12s %6s %12s %10s  %-7s  name\n", "exclusive", "%", "inclusive", "calls", "kind");
+    for (size_t i = 0; i < profile_entry_count; i++)
+    {
+        ProfileEntry *entry = &profile_entries[i];
+        fprintf(
+            stderr,
+            "%10.3fms %5.1f%% %10.3fms %10lu  %-7s  %s\n",
+            entry->exclusive / 1e6,
+            total > 0 ? 100.0 * entry->exclusive / total : 0.0,
+            entry->inclusive / 1e6,
+            (unsigned long)entry->calls,
+            entry->kind,
+            entry->name);
+    }
+}
+
+// Each call site registers its name the first time it runs
+#define PROFILE_ENTER(name, kind)                          \
+    {                                                      \
+        static size_t profile_id = PROFILE_MAX_ENTRIES;    \
+        if (profile_id == PROFILE_MAX_ENTRIES)             \
+        {                                                  \
+            profile_id = profile_register(name, kind);     \
+        }                                                  \
+        profile_enter(profile_id);                         \
+    }
+#endif
+// #endregion
+
+// #region Generated block definitions
+void block_0(Scope *parent);
+
+// block: 3 2.7 + writeln 3 2.7 * writeln 3 to_float 2.7 + writeln 3 to_float 2.7 * writeln 3 2.7 to_int + writeln 3 2.7 to_int * writeln 3 to_float 2.7 + to_int writeln 3 to_float 2.7 * to_int writeln
+void block_0(Scope *parent) {
+    Value *slots[1] = {NULL};
+    static char *names[] = {NULL};
+    Scope block_scope = {.parent = parent, .id = next_scope_id++, .size = 0, .slots = slots, .names = names};
+    Scope *scope = &block_scope;
+    call_enter("examples/numerics.stack:1:0:1", scope);
+
+    #ifdef DEBUG
+    fprintf(stderr, "[DEBUG] block_0 called --");
+    stack_dump(scope);
+    #endif
+
+    // Store the current stack pointer with arity_in=0
+    assert_arity_in("examples/numerics.stack:1:0:1", 0);
+    *(++frame_ptr) = (stack_ptr - 0);
+
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // +
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer + b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float + b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // *
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer * b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float * b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // to_float
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        v->as_float = (double)v->as_integer;
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        // Do nothing, already a float
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_float = strtod(v->as_string, NULL);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_float = v->as_boolean ? 1.0 : 0.0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to float");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to float");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_FLOAT;
+}
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // +
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer + b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float + b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // to_float
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        v->as_float = (double)v->as_integer;
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        // Do nothing, already a float
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_float = strtod(v->as_string, NULL);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_float = v->as_boolean ? 1.0 : 0.0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to float");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to float");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_FLOAT;
+}
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // *
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer * b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float * b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // to_int
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        // Do nothing, already an int
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        v->as_integer = (int64_t)v->as_float;
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_integer = atoi(v->as_string);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_integer = v->as_boolean ? 1 : 0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to int");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to int");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_INTEGER;
+}
+    // +
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer + b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float + b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // to_int
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        // Do nothing, already an int
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        v->as_integer = (int64_t)v->as_float;
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_integer = atoi(v->as_string);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_integer = v->as_boolean ? 1 : 0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to int");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to int");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_INTEGER;
+}
+    // *
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer * b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float * b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // to_float
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        v->as_float = (double)v->as_integer;
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        // Do nothing, already a float
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_float = strtod(v->as_string, NULL);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_float = v->as_boolean ? 1.0 : 0.0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to float");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to float");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_FLOAT;
+}
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // +
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer + b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float + b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // to_int
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        // Do nothing, already an int
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        v->as_integer = (int64_t)v->as_float;
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_integer = atoi(v->as_string);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_integer = v->as_boolean ? 1 : 0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to int");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to int");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_INTEGER;
+}
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+    // 3
+
+        {
+            Value v = {.type=TAG_NUMBER_INTEGER, .as_integer=3};
+            *(++stack_ptr) = v;
+        }
+    
+    // to_float
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        v->as_float = (double)v->as_integer;
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        // Do nothing, already a float
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_float = strtod(v->as_string, NULL);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_float = v->as_boolean ? 1.0 : 0.0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to float");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to float");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_FLOAT;
+}
+    // 2.7
+
+        {
+            Value v = {.type=TAG_NUMBER_FLOAT, .as_float=2.7};
+            *(++stack_ptr) = v;
+        }
+    
+    // *
+
+    {
+        Value *b = stack_ptr--;
+        Value *a = stack_ptr--;
+        coerce(a, b);
+
+        if (a->type == TAG_NUMBER_INTEGER) {
+            Value result = {.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer * b->as_integer};
+            *(++stack_ptr) = result;
+        } else if (a->type == TAG_NUMBER_FLOAT) {
+            Value result = {.type=TAG_NUMBER_FLOAT, .as_float=a->as_float * b->as_float};
+            *(++stack_ptr) = result;
+        }
+    }
+
+    // to_int
+{
+    Value *v = stack_ptr;
+
+    if (v->type == TAG_NUMBER_INTEGER)
+    {
+        // Do nothing, already an int
+    }
+    else if (v->type == TAG_NUMBER_FLOAT)
+    {
+        v->as_integer = (int64_t)v->as_float;
+    }
+    else if (v->type == TAG_STRING)
+    {
+        v->as_integer = atoi(v->as_string);
+    }
+    else if (v->type == TAG_BOOLEAN)
+    {
+        v->as_integer = v->as_boolean ? 1 : 0;
+    }
+    else if (v->type == TAG_BLOCK)
+    {
+        fprintf(stderr, "error: cannot cast block to int");
+        error_exit();
+    }
+    else
+    {
+        fprintf(stderr, "error: unknown type to cast to int");
+        error_exit();
+    }
+
+    v->type = TAG_NUMBER_INTEGER;
+}
+    // writeln
+{
+    Value *v = stack_ptr--;
+    value_write(stdout, v);
+    printf("\n");
+}
+block_return:
+    // Pop the block off the stack, preserving arity_out=0 values
+    assert_arity_out("examples/numerics.stack:1:0:1", 0);
+    Value* return_ptr = (stack_ptr - 0);
+    stack_ptr =  *(frame_ptr--);
+    call_exit();
+
+    #ifdef DEBUG
+    fprintf(stderr, "[DEBUG] block_0 returning --");
+    stack_dump(scope);
+    #endif
+}
+
+// #endregion
+
+// #region The main function
+int main(int argc, char *argv[])
+{
+    // The stack holding all values
+    stack = malloc(10240 * sizeof(Value));
+    stack_ptr = stack;
+
+    // Frames holding the stack pointer for each block
+    frames = malloc(10240 * sizeof(Value **));
+    frame_ptr = frames;
+
+    #ifdef PROFILE
+    profile_start = profile_now();
+    atexit(profile_report);
+    #endif
+
+    block_0(NULL);
+
+    return 0;
+}
+// #endregion
\ No newline at end of file
@@ -1,111 +1,838 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::builtins;
 use crate::numbers::Number;
-use crate::types::{Expression, Value};
+use crate::types::{Expression, ExpressionKind, Span, Value};
+
+/// Read the arity markers at the start of a block's children, if they are present
+///
+/// If these are present, they must be the first 1 or 2 children and must be before any other kinds of children
+/// @name means the block takes 1 input named name
+/// @# means the block takes # inputs
+/// @[] means the block takes the number of inputs in the list
+/// !# means the block returns # outputs
+pub fn declared_arity(children: &[Expression]) -> (Option<usize>, Option<usize>) {
+    fn arity_in(expr: Option<&Expression>) -> Option<usize> {
+        match &expr?.kind {
+            ExpressionKind::At(body) => match &body.kind {
+                ExpressionKind::Literal(Value::Number(Number::Integer(v))) => Some(*v as usize),
+                ExpressionKind::Identifier(_) | ExpressionKind::Local { .. } => Some(1),
+                ExpressionKind::List(values) => Some(values.len()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn arity_out(expr: Option<&Expression>) -> Option<usize> {
+        match &expr?.kind {
+            ExpressionKind::Bang(body) => match &body.kind {
+                ExpressionKind::Literal(Value::Number(Number::Integer(v))) => Some(*v as usize),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // If the first child is an @ check for a ! after it
+    if let Some(declared_in) = arity_in(children.first()) {
+        return (Some(declared_in), arity_out(children.get(1)));
+    }
+
+    // If the first child is an ! check for an @ after it
+    if let Some(declared_out) = arity_out(children.first()) {
+        return (arity_in(children.get(1)), Some(declared_out));
+    }
+
+    (None, None)
+}
 
 #[allow(dead_code)]
 pub fn calculate_arity(expression: &Expression) -> (usize, usize) {
     log::debug!("calculate_arity({expression})");
 
-    match expression {
-        Expression::Identifier(id) => match id.as_str() {
-            "+" | "-" | "*" | "/" | "%" => (2, 1),
-            "<" | "<=" | ">" | ">=" | "==" | "!=" => (2, 1),
+    match &expression.kind {
+        ExpressionKind::Identifier(id) | ExpressionKind::Builtin(id) => {
+            match builtins::lookup(id).and_then(|b| b.arity) {
+                Some(arity) => arity,
+                None => panic!(
+                    "unknown id to calculate arity of: {} (may need to explicitly specify it)",
+                    id
+                ),
+            }
+        }
+        ExpressionKind::DottedIdentifier(ids) => {
+            unimplemented!("calculate_arity for dotted identifiers: {:?}", ids)
+        }
+        ExpressionKind::Literal(_) => (0, 1),
+        ExpressionKind::Block {
+            arity: Some(arity), ..
+        } => *arity,
+        ExpressionKind::Block { children, .. } => {
+            // Set the arity based on the At and Bang nodes, see declared_arity
+            // No @ means the block takes 0 inputs, no ! means the block returns 1 output
+            // TODO: Named output?
+            let (arity_in, arity_out) = declared_arity(children);
+            let arity_in = arity_in.unwrap_or(0);
+            let arity_out = arity_out.unwrap_or(1);
+
+            log::debug!("calculate_arity({expression}) is ({arity_in}, {arity_out})");
+
+            (arity_in, arity_out)
+        }
+        ExpressionKind::List(_) => panic!("Cannot calculate the arity of a list: {}", expression),
+        ExpressionKind::Group(_) => {
+            panic!("Cannot calculate the arity of a group: {}", expression)
+        }
+        ExpressionKind::At(body) => match &body.kind {
+            ExpressionKind::Identifier(_) | ExpressionKind::Local { .. } => (0, 1),
             _ => panic!(
-                "unknown id to calculate arity of: {} (may need to explicitly specify it)",
-                id
+                "Cannot calculate the arity of a non-named @ expression: {}",
+                expression
             ),
         },
-        Expression::DottedIdentifier(ids) => {
-            unimplemented!("calculate_arity for dotted identifiers: {:?}", ids)
-        }
-        Expression::Literal(_) => (0, 1),
-        Expression::Block(children) => {
-            // Set the arity based on the At and Bang nodes
-            // If these are present, they must be the first 1 or 2 children and must be before any other kinds of children
+        ExpressionKind::Bang(body) => match &body.kind {
+            ExpressionKind::Identifier(_) | ExpressionKind::Local { .. } => (1, 0),
+            _ => panic!(
+                "Cannot calculate the arity of a non-named ! expression: {}",
+                expression
+            ),
+        },
+        ExpressionKind::Dollar(body) => match &body.kind {
+            ExpressionKind::Identifier(_) | ExpressionKind::Local { .. } => (0, 1),
+            _ => panic!(
+                "Cannot calculate the arity of a non-named $ expression: {}",
+                expression
+            ),
+        },
+        ExpressionKind::Local { name, .. } => panic!(
+            "unknown id to calculate arity of: {} (may need to explicitly specify it)",
+            name
+        ),
+    }
+}
 
-            // @name means the block takes 1 input named name
-            // @# means the block takes # inputs
-            // @[] means the block takes the number of inputs in the list
-            // !# means the block returns # outputs
-            // No !# means the block returns 1 output
-            // TODO: Named output?
+/// A block whose declared arity disagrees with its body
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArityError {
+    pub span: Span,
+    pub message: String,
+}
 
-            let mut arity_in = 0;
-            let mut arity_out = 1;
+impl Display for ArityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/// The arity of each function registered with VM::register, by name (see VM::host_arities)
+pub type HostArities = HashMap<String, (usize, usize)>;
+
+/// Infer the arity of each block in a resolved program from its body
+///
+/// The arity is stored on each block, so calculate_arity returns it (markers in the body take precedence)
+/// Blocks with markers that disagree with their body are returned as errors (but otherwise left alone)
+/// If the arity of a block can't be determined (for example, it calls a block passed to it), it's left as is
+/// Host functions resolve to builtins, so their arity is looked up in host first
+pub fn infer(ast: Expression, host: &HostArities) -> (Expression, Vec<ArityError>) {
+    let mut inference = Inference {
+        host: host.clone(),
+        ..Inference::default()
+    };
+
+    let ast = match ast.kind {
+        ExpressionKind::Group(children) => {
+            inference.frames.push(Frame::new(&children));
+            let children = children
+                .into_iter()
+                .map(|child| inference.walk(child))
+                .collect();
+            inference.frames.pop();
+
+            Expression::new(ExpressionKind::Group(children), ast.span)
+        }
+        _ => ast,
+    };
 
-            // If the first child is an @ set the arity in from that
-            if let Some(Expression::At(body)) = children.first() {
-                match body.as_ref() {
-                    Expression::Literal(Value::Number(Number::Integer(v))) => {
-                        arity_in = *v as usize
+    (ast, inference.errors)
+}
+
+/// Find the slots bound in a block that are used with $, including in nested blocks
+///
+/// Values bound to any other slot are only ever called by name, so are assumed not to be blocks
+pub(crate) fn dollar_slots(children: &[Expression]) -> HashSet<usize> {
+    fn scan(children: &[Expression], depth: usize, slots: &mut HashSet<usize>) {
+        for child in children {
+            match &child.kind {
+                ExpressionKind::Dollar(name) => {
+                    if let ExpressionKind::Local { depth: d, slot, .. } = name.kind {
+                        if d == depth {
+                            slots.insert(slot);
+                        }
                     }
-                    Expression::Identifier(_) => arity_in = 1,
-                    Expression::List(values) => {
-                        arity_in = values.len();
+                }
+                ExpressionKind::Block { children, .. } => scan(children, depth + 1, slots),
+                ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                    scan(children, depth, slots)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut slots = HashSet::new();
+    scan(children, 0, &mut slots);
+    slots
+}
+
+/// Find blocks with a known arity (declared or inferred) that are immediately bound to a name ({ ... } @name)
+///
+/// These can be called before they are bound (for recursion), with the declared arity
+pub(crate) fn declared_blocks(children: &[Expression]) -> HashMap<usize, (usize, usize)> {
+    let mut blocks = HashMap::new();
+
+    for pair in children.windows(2) {
+        if let (
+            ExpressionKind::Block {
+                children: body,
+                arity,
+            },
+            ExpressionKind::At(name),
+        ) = (&pair[0].kind, &pair[1].kind)
+        {
+            if let ExpressionKind::Local { slot, .. } = name.kind {
+                if arity.is_some() || declared_arity(body) != (None, None) {
+                    blocks.insert(slot, calculate_arity(&pair[0]));
+                }
+            }
+        }
+    }
+
+    blocks
+}
+
+/// What is known about a value on the stack while inferring arity
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Item {
+    /// A value that isn't a block
+    Value,
+    /// An integer literal, used by pick and roll
+    Integer(i64),
+    /// A value passed into the block, assumed not to be a block unless it's ever used with $
+    Input,
+    /// A block with a known arity
+    Block(usize, usize),
+    /// A list of items, used by cond
+    List(Vec<Item>),
+    /// Anything else
+    Unknown,
+}
+
+/// The effect of a single block (or the top level) on the stack so far
+#[derive(Debug, Default)]
+struct Frame {
+    // Items on the stack in this block, including any inputs used so far
+    stack: Vec<Item>,
+    // The index into stack of each slot that has been bound
+    slots: HashMap<usize, usize>,
+    // The number of inputs used so far
+    inputs: usize,
+    // Slots used with $ anywhere in the block, these might be bound to blocks
+    dollar: HashSet<usize>,
+    // Slots bound to a block with a known arity, so they can be called before they are bound (for recursion)
+    forward: HashMap<usize, (usize, usize)>,
+    // Set once the arity can't be determined
+    failed: bool,
+    // Slots written after failing, nothing is known about them
+    poisoned: HashSet<usize>,
+}
+
+impl Frame {
+    fn new(children: &[Expression]) -> Self {
+        Frame {
+            dollar: dollar_slots(children),
+            forward: declared_blocks(children),
+            ..Frame::default()
+        }
+    }
+
+    /// Make sure there are at least n items on the stack, using more inputs if needed
+    fn ensure(&mut self, n: usize) {
+        while self.stack.len() < n {
+            self.stack.insert(0, Item::Input);
+            self.inputs += 1;
+            for index in self.slots.values_mut() {
+                *index += 1;
+            }
+        }
+    }
+
+    /// Pop an item, any names for it are no longer known
+    fn pop(&mut self) -> Item {
+        self.ensure(1);
+        let item = self.stack.pop().unwrap();
+
+        let len = self.stack.len();
+        self.slots.retain(|_, index| *index < len);
+        item
+    }
+
+    /// Pop arity_in items and push arity_out values
+    fn apply(&mut self, arity_in: usize, arity_out: usize) {
+        for _ in 0..arity_in {
+            self.pop();
+        }
+        for _ in 0..arity_out {
+            self.stack.push(Item::Value);
+        }
+    }
+
+    /// Name the item at the given index
+    fn bind(&mut self, slot: usize, index: usize) {
+        if self.failed {
+            self.poisoned.insert(slot);
+        }
+        self.slots.insert(slot, index);
+    }
+
+    /// Get the item bound to a slot
+    fn get(&self, slot: usize) -> Item {
+        if self.poisoned.contains(&slot) {
+            return Item::Unknown;
+        }
+
+        let item = match self.slots.get(&slot) {
+            Some(index) => self.stack.get(*index).cloned(),
+            None => self.forward.get(&slot).map(|(i, o)| Item::Block(*i, *o)),
+        };
+
+        match item {
+            Some(Item::Input) if self.dollar.contains(&slot) => Item::Unknown,
+            Some(item) => item,
+            None => Item::Unknown,
+        }
+    }
+
+    /// The number of items above the topmost named item, these are returned from the block
+    ///
+    /// A named item on top is returned too (blocks return the top values whether or not they are named),
+    /// unless it is an input that was only named
+    fn outputs(&self) -> usize {
+        match self
+            .slots
+            .values()
+            .copied()
+            .filter(|i| *i < self.stack.len())
+            .max()
+        {
+            Some(index) if self.stack[index] == Item::Input => self.stack.len() - index - 1,
+            Some(index) => (self.stack.len() - index - 1).max(1),
+            None => self.stack.len(),
+        }
+    }
+}
+
+/// Walks a program, tracking the effect of each block on the stack
+#[derive(Debug, Default)]
+struct Inference {
+    // A frame for each block currently being walked, innermost last
+    frames: Vec<Frame>,
+    errors: Vec<ArityError>,
+    host: HostArities,
+}
+
+impl Inference {
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    /// Give up on determining the arity of the current block
+    fn fail(&mut self) {
+        self.frame().failed = true;
+    }
+
+    /// The arity of evaluating an item, as a builtin would (see VM::evaluate_value)
+    fn arity_of(item: &Item) -> Option<(usize, usize)> {
+        match item {
+            Item::Block(arity_in, arity_out) => Some((*arity_in, *arity_out)),
+            Item::Unknown => None,
+            _ => Some((0, 1)),
+        }
+    }
+
+    /// Evaluate an item: blocks are called and anything else is pushed
+    fn evaluate(&mut self, item: Item) {
+        match item {
+            Item::Block(arity_in, arity_out) => self.frame().apply(arity_in, arity_out),
+            Item::Unknown => self.fail(),
+            item => self.frame().stack.push(item),
+        }
+    }
+
+    /// Apply the effect of a builtin
+    fn builtin(&mut self, name: &str) {
+        if let Some(&(arity_in, arity_out)) = self.host.get(name) {
+            self.frame().apply(arity_in, arity_out);
+            return;
+        }
+
+        match name {
+            "apply" => match self.frame().pop() {
+                Item::Block(arity_in, arity_out) => self.frame().apply(arity_in, arity_out),
+                _ => self.fail(),
+            },
+            "if" => {
+                self.frame().pop();
+                let false_branch = self.frame().pop();
+                let true_branch = self.frame().pop();
+
+                match (Self::arity_of(&true_branch), Self::arity_of(&false_branch)) {
+                    (Some(t), Some(f)) if t == f => self.frame().apply(t.0, t.1),
+                    _ => self.fail(),
+                }
+            }
+            "when" => {
+                self.frame().pop();
+
+                // The branch might not be run, so it can't change the size of the stack
+                match self.frame().pop() {
+                    Item::Block(arity_in, arity_out) if arity_in == arity_out => {
+                        self.frame().apply(arity_in, arity_out)
                     }
-                    _ => {}
+                    _ => self.fail(),
                 }
+            }
+            "cond" => {
+                let branches = match self.frame().pop() {
+                    Item::List(items) if items.len() % 2 == 1 => items,
+                    _ => return self.fail(),
+                };
 
-                if let Some(Expression::Bang(body)) = children.get(1) {
-                    match body.as_ref() {
-                        Expression::Literal(Value::Number(Number::Integer(v))) => {
-                            arity_out = *v as usize;
+                // Each test pushes a boolean (which cond pops) on top of what it takes
+                let mut tests_in = 0;
+                for test in branches.iter().step_by(2).take(branches.len() / 2) {
+                    match test {
+                        Item::Block(arity_in, arity_out) if *arity_out == arity_in + 1 => {
+                            tests_in = tests_in.max(*arity_in)
                         }
-                        _ => {}
+                        _ => return self.fail(),
                     }
                 }
+
+                // Each value (and the default) must have the same effect
+                let values = branches
+                    .iter()
+                    .skip(1)
+                    .step_by(2)
+                    .chain(branches.last())
+                    .map(Self::arity_of)
+                    .collect::<Vec<_>>();
+
+                match values.first() {
+                    Some(Some(arity)) if values.iter().all(|v| *v == Some(*arity)) => {
+                        self.frame().ensure(tests_in);
+                        self.frame().apply(arity.0, arity.1);
+                    }
+                    _ => self.fail(),
+                }
             }
+            "pick" | "roll" => match self.frame().pop() {
+                // These need n values below the index, pick then pushes a copy
+                Item::Integer(n) if n >= 0 => {
+                    self.frame().ensure(n as usize + 1);
+                    if name == "pick" {
+                        self.frame().stack.push(Item::Value);
+                    }
+                }
+                _ => self.fail(),
+            },
+            "loop" | "generate-stack" => {
+                self.frame().pop();
 
-            // If the first child is an ! set the arity out from that
-            if let Some(Expression::Bang(body)) = children.first() {
-                match body.as_ref() {
-                    Expression::Literal(Value::Number(Number::Integer(v))) => {
-                        arity_out = *v as usize;
+                // Each iteration pushes a value then calls the block
+                // The stack must end up the same size, after generate-stack collects the top value
+                let collect = usize::from(name == "generate-stack");
+                match self.frame().pop() {
+                    Item::Block(arity_in, arity_out) if arity_in + collect == arity_out + 1 => {
+                        self.frame().stack.push(Item::Value);
+                        self.frame().apply(arity_in, arity_out);
+                        self.frame().apply(collect, collect);
                     }
-                    _ => {}
+                    _ => self.fail(),
                 }
+            }
+            "try" => {
+                // The handler takes the error instead of the body's inputs, and returns the same number of values
+                let handler = self.frame().pop();
+                match (self.frame().pop(), handler) {
+                    (Item::Block(arity_in, arity_out), Item::Block(1, handler_out))
+                        if arity_out == handler_out =>
+                    {
+                        self.frame().apply(arity_in, arity_out)
+                    }
+                    _ => self.fail(),
+                }
+            }
+            "while" | "forever" => {
+                // The body must leave the stack the same size, the condition adds a boolean
+                let body = self.frame().pop();
+                let condition = match name {
+                    "while" => self.frame().pop(),
+                    _ => Item::Block(0, 1),
+                };
+                match (condition, body) {
+                    (Item::Block(c_in, c_out), Item::Block(b_in, b_out))
+                        if c_out == c_in + 1 && b_in == b_out =>
+                    {
+                        let arity = c_in.max(b_in);
+                        self.frame().apply(arity, arity);
+                    }
+                    _ => self.fail(),
+                }
+            }
+            _ => match builtins::lookup(name).and_then(|b| b.arity) {
+                Some((arity_in, arity_out)) => self.frame().apply(arity_in, arity_out),
+                None => self.fail(),
+            },
+        }
+    }
 
-                if let Some(Expression::At(body)) = children.get(1) {
-                    match body.as_ref() {
-                        Expression::Literal(Value::Number(Number::Integer(v))) => {
-                            arity_in = *v as usize;
-                        }
-                        Expression::Identifier(_) => {
-                            arity_in = 1;
+    /// Infer the arity of a block, storing it on the block
+    fn block(&mut self, children: Vec<Expression>, span: Span) -> Expression {
+        let (declared_in, declared_out) = declared_arity(&children);
+
+        self.frames.push(Frame::new(&children));
+        if let Some(declared_in) = declared_in {
+            self.frame().ensure(declared_in);
+        }
+
+        let children = children
+            .into_iter()
+            .map(|child| self.walk(child))
+            .collect::<Vec<_>>();
+
+        let frame = self.frames.pop().unwrap();
+        if frame.failed {
+            return Expression::new(
+                ExpressionKind::Block {
+                    children,
+                    arity: None,
+                },
+                span,
+            );
+        }
+
+        let arity_in = frame.inputs;
+        let arity_out = frame.outputs();
+
+        if let Some(declared_in) = declared_in {
+            if arity_in > declared_in {
+                self.errors.push(ArityError {
+                    span: span.clone(),
+                    message: format!(
+                        "block declares {} inputs, but uses {}",
+                        declared_in, arity_in
+                    ),
+                });
+            }
+        }
+
+        if let Some(declared_out) = declared_out {
+            if declared_out > frame.stack.len() {
+                self.errors.push(ArityError {
+                    span: span.clone(),
+                    message: format!(
+                        "block declares {} outputs, but only has {} values",
+                        declared_out,
+                        frame.stack.len()
+                    ),
+                });
+            }
+        }
+
+        // Declared arity is used as is, even if it disagrees with the body
+        let arity = (
+            declared_in.unwrap_or(arity_in),
+            declared_out.unwrap_or(arity_out),
+        );
+        Expression::new(
+            ExpressionKind::Block {
+                children,
+                arity: Some(arity),
+            },
+            span,
+        )
+    }
+
+    /// Apply the effect of an expression to the current frame
+    ///
+    /// Returns the expression with the arity stored on any blocks in it
+    fn walk(&mut self, expr: Expression) -> Expression {
+        let span = expr.span.clone();
+
+        let kind = match expr.kind {
+            ExpressionKind::Block { children, .. } => {
+                let block = self.block(children, span);
+                let (arity_in, arity_out) = calculate_arity(&block);
+                self.frame().stack.push(Item::Block(arity_in, arity_out));
+                return block;
+            }
+            ExpressionKind::List(children) => {
+                let mut items = vec![];
+                let mut list = vec![];
+                for child in children {
+                    list.push(self.walk(child));
+                    items.push(self.frame().pop());
+                }
+                self.frame().stack.push(Item::List(items));
+                ExpressionKind::List(list)
+            }
+            ExpressionKind::Group(children) => {
+                ExpressionKind::Group(children.into_iter().map(|child| self.walk(child)).collect())
+            }
+            kind => {
+                self.effect(&kind);
+                kind
+            }
+        };
+
+        Expression::new(kind, span)
+    }
+
+    /// Apply the effect of an expression without children to the current frame
+    fn effect(&mut self, kind: &ExpressionKind) {
+        match kind {
+            ExpressionKind::Literal(Value::Number(Number::Integer(n))) => {
+                self.frame().stack.push(Item::Integer(*n))
+            }
+            ExpressionKind::Literal(_) => self.frame().stack.push(Item::Value),
+            ExpressionKind::Builtin(name) => self.builtin(name),
+            ExpressionKind::Local { depth, slot, .. } => {
+                let item = self.lookup(*depth, *slot);
+                self.evaluate(item);
+            }
+            ExpressionKind::At(inner) => match &inner.kind {
+                ExpressionKind::Local { slot, .. } => {
+                    let frame = self.frame();
+                    frame.ensure(1);
+                    frame.bind(*slot, frame.stack.len() - 1);
+                }
+                ExpressionKind::List(names) => {
+                    let frame = self.frame();
+                    frame.ensure(names.len());
+                    let base = frame.stack.len() - names.len();
+                    for (i, name) in names.iter().enumerate() {
+                        if let ExpressionKind::Local { slot, .. } = name.kind {
+                            frame.bind(slot, base + i);
                         }
-                        Expression::List(values) => {
-                            arity_in = values.len();
+                    }
+                }
+                // Arity markers are read in block
+                _ => {}
+            },
+            ExpressionKind::Bang(inner) => {
+                if let ExpressionKind::Local { depth, slot, .. } = inner.kind {
+                    let item = self.frame().pop();
+                    // Names from outside of the program (see VM::evaluate_in_scope) aren't tracked
+                    let Some(index) = self.frames.len().checked_sub(1 + depth) else {
+                        return;
+                    };
+                    let frame = &mut self.frames[index];
+
+                    if item == Item::Unknown || frame.failed {
+                        frame.poisoned.insert(slot);
+                    } else if let Some(index) = frame.slots.get(&slot).copied() {
+                        if let Some(old) = frame.stack.get_mut(index) {
+                            *old = item;
                         }
-                        _ => {}
                     }
                 }
             }
+            ExpressionKind::Dollar(inner) => {
+                if let ExpressionKind::Local { depth, slot, .. } = inner.kind {
+                    let item = self.lookup(depth, slot);
+                    self.frame().stack.push(item);
+                }
+            }
+            // Names should already have been resolved
+            _ => self.fail(),
+        }
+    }
 
-            log::debug!("calculate_arity({expression}) is ({arity_in}, {arity_out})");
-
-            (arity_in, arity_out)
+    /// Get the item bound to a name in this or an enclosing frame
+    fn lookup(&self, depth: usize, slot: usize) -> Item {
+        match self.frames.len().checked_sub(1 + depth) {
+            Some(index) => self.frames[index].get(slot),
+            None => Item::Unknown,
         }
-        Expression::List(_) => panic!("Cannot calculate the arity of a list: {}", expression),
-        Expression::Group(_) => panic!("Cannot calculate the arity of a group: {}", expression),
-        Expression::At(body) => match body.as_ref() {
-            Expression::Identifier(_) => (0, 1),
-            _ => panic!(
-                "Cannot calculate the arity of a non-named @ expression: {}",
-                expression
-            ),
-        },
-        Expression::Bang(body) => match body.as_ref() {
-            Expression::Identifier(_) => (1, 0),
-            _ => panic!(
-                "Cannot calculate the arity of a non-named ! expression: {}",
-                expression
-            ),
-        },
-        Expression::Dollar(body) => match body.as_ref() {
-            Expression::Identifier(_) => (0, 1),
-            _ => panic!(
-                "Cannot calculate the arity of a non-named $ expression: {}",
-                expression
-            ),
-        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{calculate_arity, infer, HostArities};
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use crate::resolver::Resolver;
+    use crate::types::{ExpressionKind, Value};
+    use crate::vm::VM;
+
+    /// Infer a program, returning the arity of each top level block and any errors
+    fn arities(input: &str) -> (Vec<(usize, usize)>, Vec<String>) {
+        let ast = Resolver::new()
+            .resolve(parse(tokenize(input.as_bytes())))
+            .unwrap();
+        let (ast, errors) = infer(ast, &HostArities::new());
+
+        let ExpressionKind::Group(children) = ast.kind else {
+            panic!("expected a group, got {}", ast);
+        };
+
+        (
+            children
+                .iter()
+                .filter(|c| matches!(c.kind, ExpressionKind::Block { .. }))
+                .map(calculate_arity)
+                .collect(),
+            errors.into_iter().map(|e| e.message).collect(),
+        )
+    }
+
+    #[test]
+    fn test_literals_and_builtins() {
+        assert_eq!(arities("{ 1 2 }").0, vec![(0, 2)]);
+        assert_eq!(arities("{ 1 + }").0, vec![(1, 1)]);
+        assert_eq!(arities("{ + * }").0, vec![(3, 1)]);
+        assert_eq!(arities("{ writeln }").0, vec![(1, 0)]);
+        assert_eq!(arities("{ swap - }").0, vec![(2, 1)]);
+        assert_eq!(arities("{ 2 pick }").0, vec![(3, 4)]);
+    }
+
+    #[test]
+    fn test_named_inputs_are_not_returned() {
+        assert_eq!(arities("{ @n n n * }").0, vec![(1, 1)]);
+        assert_eq!(arities("{ @[a b] a b + a b - }").0, vec![(2, 2)]);
+        assert_eq!(arities("{ @n n writeln }").0, vec![(1, 0)]);
+        assert_eq!(arities("{ @n 2 + }").0, vec![(1, 1)]);
+    }
+
+    #[test]
+    fn test_named_top_value_is_returned() {
+        assert_eq!(arities("{ @n n 1 + @m }").0, vec![(1, 1)]);
+        assert_eq!(arities("{ @n n 1 + !n }").0, vec![(1, 1)]);
+        assert_eq!(arities("{ 1 @a 2 @b }").0, vec![(0, 1)]);
+
+        let mut vm = VM::new();
+        vm.evaluate_str("5 { @n n 1 + @m } apply { @n n 1 + @m } @inc 7 inc")
+            .unwrap();
+        assert_eq!(vm.pop(), Ok(Value::from(8)));
+        vm.pop().unwrap(); // inc
+        assert_eq!(vm.pop(), Ok(Value::from(6)));
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let mut vm = VM::new();
+        vm.register("pair", 0, 2, |vm| {
+            vm.push(Value::from(1));
+            vm.push(Value::from(2));
+            Ok(())
+        });
+
+        let (ast, errors) = vm.prepare(parse(tokenize("{ pair }".as_bytes()))).unwrap();
+        let ExpressionKind::Group(children) = ast.kind else {
+            panic!("expected a group, got {}", ast);
+        };
+        assert_eq!(calculate_arity(&children[0]), (0, 2));
+        assert!(errors.is_empty());
+
+        vm.evaluate_str("{ pair } apply +").unwrap();
+        assert_eq!(vm.pop(), Ok(Value::from(3)));
+    }
+
+    #[test]
+    fn test_named_blocks() {
+        let (arity, errors) = arities("{ @[a b] a b + } @add { add add } @add3");
+        assert_eq!(arity, vec![(2, 1), (3, 1)]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_control_flow() {
+        assert_eq!(arities("{ @n { 1 } { n } n 0 = if }").0, vec![(1, 1)]);
+        assert_eq!(arities("{ @n { n writeln } n 0 > when }").0, vec![(1, 0)]);
+        assert_eq!(arities("{ @n 1 { @2 1 + * } n loop }").0, vec![(1, 1)]);
+        assert_eq!(
+            arities("{ @n { @i i i * } n generate-stack }").0,
+            vec![(1, 1)]
+        );
+        assert_eq!(
+            arities("{ @n [ { n 0 < } \"neg\" { n 0 > } \"pos\" \"zero\" ] cond }").0,
+            vec![(1, 1)]
+        );
+        assert_eq!(
+            arities("{ [ { true } { 1 2 } { 3 } ] cond }").0,
+            vec![(0, 1)]
+        );
+        assert_eq!(arities("{ 0 { dup 5 < } { 1 + } while }").0, vec![(0, 1)]);
+        assert_eq!(arities("{ { 1 + break } forever }").0, vec![(1, 1)]);
+        assert_eq!(arities("{ @x { x 2 / } { drop 0 } try }").0, vec![(1, 1)]);
+    }
+
+    #[test]
+    fn test_recursion() {
+        // Recursion works if the block declares its arity
+        let (arity, errors) = arities("{ @n !1 { 1 } { n 1 - fact n * } n 1 < if } @fact");
+        assert_eq!(arity, vec![(1, 1)]);
+        assert!(errors.is_empty());
+
+        // Otherwise the block is left alone (and defaults to returning 1 value)
+        let (arity, errors) = arities("{ @n { 1 } { n 1 - fact n * } n 1 < if } @fact");
+        assert_eq!(arity, vec![(1, 1)]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_arity() {
+        // fib is passed in with $, so it could be any block
+        assert_eq!(arities("{ @[n fib] n $fib fib } @fib").0, vec![(2, 1)]);
+
+        // Branches with different effects
+        assert_eq!(arities("{ { 1 } { 1 2 } true if }").0, vec![(0, 1)]);
+        assert_eq!(arities("{ { 1 2 } true when }").0, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        let ast = Resolver::new()
+            .resolve(parse(tokenize("{ { 1 + } @inc 5 inc inc }".as_bytes())))
+            .unwrap();
+        let (ast, _) = infer(ast, &HostArities::new());
+
+        let ExpressionKind::Group(children) = ast.kind else {
+            panic!("expected a group, got {}", ast);
+        };
+        assert_eq!(calculate_arity(&children[0]), (0, 1));
+
+        let ExpressionKind::Block { children: body, .. } = &children[0].kind else {
+            panic!("expected a block, got {}", children[0]);
+        };
+        assert_eq!(calculate_arity(&body[0]), (1, 1));
+        assert_eq!(body.len(), 5);
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let (arity, errors) = arities("{ @a + }");
+        assert_eq!(arity, vec![(1, 1)]);
+        assert_eq!(errors, vec!["block declares 1 inputs, but uses 2"]);
+
+        let (arity, errors) = arities("{ !2 1 }");
+        assert_eq!(arity, vec![(0, 2)]);
+        assert_eq!(
+            errors,
+            vec!["block declares 2 outputs, but only has 1 values"]
+        );
     }
 }
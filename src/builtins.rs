@@ -0,0 +1,1686 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+use std::sync::OnceLock;
+
+use crate::error::{fail, Control, ErrorKind, RuntimeError};
+use crate::numbers::Number;
+use crate::types::Value;
+use crate::vm::VM;
+
+/// How a builtin is implemented when compiling to C
+#[derive(Debug, Clone, Copy)]
+pub enum CCode {
+    /// A snippet of C code inserted as is, see compile_c_includes/builtins
+    Snippet(&'static str),
+    /// A binary operator applied to two integers or two floats
+    NumericBinop(&'static str),
+    /// A comparison operator applied to two numbers (coerced to the same type)
+    NumericCompare(&'static str),
+    /// A snippet that reports where it was called from, SPAN is replaced with the span of the call
+    SpannedSnippet(&'static str),
+}
+
+/// A single built in function
+///
+/// This is the one place a builtin is defined, the resolver, VM, arity calculation, and C compiler all use it
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    /// The name the builtin is called by
+    pub name: &'static str,
+    /// The stack effect (arity_in, arity_out), None if it depends on the blocks passed to it
+    pub arity: Option<(usize, usize)>,
+    /// The implementation used by the VM
+    pub vm: fn(&mut VM) -> Result<(), RuntimeError>,
+    /// The implementation used when compiling to C, None if not supported
+    pub c: Option<CCode>,
+    /// A short description of what the builtin does
+    pub doc: &'static str,
+}
+
+impl std::fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Builtin")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .field("c", &self.c)
+            .finish()
+    }
+}
+
+impl Display for Builtin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arity = match self.arity {
+            Some((arity_in, arity_out)) => format!("({arity_in} -- {arity_out})"),
+            None => "(varies)".to_string(),
+        };
+        let c = if self.c.is_some() { "" } else { " [vm only]" };
+
+        write!(f, "{:<16}{:<12}{}{}", self.name, arity, self.doc, c)
+    }
+}
+
+/// Find a builtin by name
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    static BY_NAME: OnceLock<HashMap<&'static str, &'static Builtin>> = OnceLock::new();
+
+    BY_NAME
+        .get_or_init(|| BUILTINS.iter().map(|b| (b.name, b)).collect())
+        .get(name)
+        .copied()
+}
+
+/// A helper macro to generate functions that operate on two integers and floats
+/// The function returns None if the result overflows
+macro_rules! numeric_binop {
+    ($f:expr) => {
+        |vm: &mut VM| {
+            // TODO: Check we have enough values
+            let b = vm.pop()?;
+            let a = vm.pop()?;
+
+            match (a.clone(), b.clone()) {
+                (Value::Number(an), Value::Number(bn)) => match $f(an, bn) {
+                    Some(result) => vm.push(Value::Number(result)),
+                    None => fail!(Value, "integer overflow computing with {} and {}", a, b),
+                },
+                _ => fail!(
+                    Type,
+                    "cannot perform numeric operation on non-numeric values, got {} and {}",
+                    a,
+                    b
+                ),
+            };
+            Ok(())
+        }
+    };
+}
+
+/// Like numeric_binop, but fails instead of panicking when dividing an integer or rational by zero
+macro_rules! division_binop {
+    ($f:expr) => {
+        |vm: &mut VM| {
+            if let (Some(Value::Number(a)), Some(Value::Number(b))) =
+                (vm.stack().peek(1), vm.stack().peek(0))
+            {
+                if let (_, Number::Integer(0) | Number::Rational { numerator: 0, .. }) =
+                    Number::coerce(a, b)
+                {
+                    fail!(Value, "cannot divide {} by zero", a);
+                }
+            }
+            numeric_binop!($f)(vm)
+        }
+    };
+}
+
+/// A helper macro to generate functions that compare two integers and floats
+macro_rules! comparison_binop {
+    ($f:expr) => {
+        |vm: &mut VM| {
+            // TODO: Check we have enough values
+            let b = vm.pop()?;
+            let a = vm.pop()?;
+
+            match (a.clone(), b.clone()) {
+                (Value::Number(a), Value::Number(b)) => {
+                    vm.push(Value::Boolean($f(a, b)));
+                }
+                // TODO: Handle other types
+                _ => fail!(
+                    Type,
+                    "cannot perform comparison operation on non-numeric values, got {} and {}",
+                    a,
+                    b
+                ),
+            };
+            Ok(())
+        }
+    };
+}
+
+/// A helper macro to generate functions that operate on two booleans
+macro_rules! boolean_binop {
+    ($f:expr) => {
+        |vm: &mut VM| {
+            let b = vm.pop()?;
+            let a = vm.pop()?;
+
+            match (a.clone(), b.clone()) {
+                (Value::Boolean(a), Value::Boolean(b)) => {
+                    vm.push(Value::Boolean($f(a, b)));
+                }
+                _ => fail!(
+                    Type,
+                    "cannot perform logical operation on non-boolean values, got {} and {}",
+                    a,
+                    b
+                ),
+            };
+            Ok(())
+        }
+    };
+}
+
+/// A helper macro to include a C snippet for a builtin
+macro_rules! c_snippet {
+    ($file:literal) => {
+        Some(CCode::Snippet(include_str!(concat!(
+            "../compile_c_includes/builtins/",
+            $file
+        ))))
+    };
+}
+
+/// A helper macro to generate functions that rearrange the top values on the stack
+///
+/// The top $arity_in values are replaced by the values at each index, where 0 is the deepest of them
+macro_rules! shuffle {
+    ($name:literal, $arity_in:literal => [$($index:literal),*]) => {
+        |vm: &mut VM| {
+            if vm.stack.len() < $arity_in {
+                fail!(
+                    Stack,
+                    "{} expects {} values, but only {} are on the stack",
+                    $name,
+                    $arity_in,
+                    vm.stack.len()
+                );
+            }
+            vm.stack.shuffle($arity_in, &[$($index),*]);
+            Ok(())
+        }
+    };
+}
+
+/// All built in functions, these are used if a name isn't otherwise bound
+pub static BUILTINS: &[Builtin] = &[
+    // Built in numeric functions
+    Builtin {
+        name: "+",
+        arity: Some((2, 1)),
+        vm: numeric_binop!(Number::checked_add),
+        c: Some(CCode::NumericBinop("+")),
+        doc: "Add two numbers",
+    },
+    Builtin {
+        name: "-",
+        arity: Some((2, 1)),
+        vm: numeric_binop!(Number::checked_sub),
+        c: Some(CCode::NumericBinop("-")),
+        doc: "Subtract the top number from the one below it",
+    },
+    Builtin {
+        name: "*",
+        arity: Some((2, 1)),
+        vm: numeric_binop!(Number::checked_mul),
+        c: Some(CCode::NumericBinop("*")),
+        doc: "Multiply two numbers",
+    },
+    Builtin {
+        name: "/",
+        arity: Some((2, 1)),
+        vm: division_binop!(Number::checked_div),
+        c: Some(CCode::NumericBinop("/")),
+        doc: "Divide the number below the top by the top number",
+    },
+    Builtin {
+        name: "%",
+        arity: Some((2, 1)),
+        vm: division_binop!(Number::checked_rem),
+        c: c_snippet!("mod.c"),
+        doc: "Remainder after dividing the number below the top by the top number",
+    },
+    // Built in comparisons
+    Builtin {
+        name: "<",
+        arity: Some((2, 1)),
+        vm: comparison_binop!(|a, b| a < b),
+        c: Some(CCode::NumericCompare("<")),
+        doc: "Less than",
+    },
+    Builtin {
+        name: "<=",
+        arity: Some((2, 1)),
+        vm: comparison_binop!(|a, b| a <= b),
+        c: Some(CCode::NumericCompare("<=")),
+        doc: "Less than or equal to",
+    },
+    Builtin {
+        name: "=",
+        arity: Some((2, 1)),
+        vm: comparison_binop!(|a, b| a == b),
+        c: Some(CCode::NumericCompare("==")),
+        doc: "Equal to",
+    },
+    Builtin {
+        name: "!=",
+        arity: Some((2, 1)),
+        vm: comparison_binop!(|a, b| a != b),
+        c: Some(CCode::NumericCompare("!=")),
+        doc: "Not equal to",
+    },
+    Builtin {
+        name: ">=",
+        arity: Some((2, 1)),
+        vm: comparison_binop!(|a, b| a >= b),
+        c: Some(CCode::NumericCompare(">=")),
+        doc: "Greater than or equal to",
+    },
+    Builtin {
+        name: ">",
+        arity: Some((2, 1)),
+        vm: comparison_binop!(|a, b| a > b),
+        c: Some(CCode::NumericCompare(">")),
+        doc: "Greater than",
+    },
+    // Logical operators
+    Builtin {
+        name: "and",
+        arity: Some((2, 1)),
+        vm: boolean_binop!(|a, b| a && b),
+        c: c_snippet!("and.c"),
+        doc: "True if both booleans are true",
+    },
+    Builtin {
+        name: "or",
+        arity: Some((2, 1)),
+        vm: boolean_binop!(|a, b| a || b),
+        c: c_snippet!("or.c"),
+        doc: "True if either boolean is true",
+    },
+    Builtin {
+        name: "xor",
+        arity: Some((2, 1)),
+        vm: boolean_binop!(|a: bool, b: bool| a && !b || !a && b),
+        c: c_snippet!("xor.c"),
+        doc: "True if exactly one boolean is true",
+    },
+    Builtin {
+        name: "nand",
+        arity: Some((2, 1)),
+        vm: boolean_binop!(|a, b| !(a && b)),
+        c: c_snippet!("nand.c"),
+        doc: "True unless both booleans are true",
+    },
+    Builtin {
+        name: "not",
+        arity: Some((1, 1)),
+        vm: builtin_not,
+        c: c_snippet!("not.c"),
+        doc: "Negate a boolean",
+    },
+    // Conversions
+    Builtin {
+        name: "to_int",
+        arity: Some((1, 1)),
+        vm: builtin_to_int,
+        c: c_snippet!("to_int.c"),
+        doc: "Convert a number or string to an integer",
+    },
+    Builtin {
+        name: "to_float",
+        arity: Some((1, 1)),
+        vm: builtin_to_float,
+        c: c_snippet!("to_float.c"),
+        doc: "Convert a number or string to a float",
+    },
+    // Stack manipulation
+    Builtin {
+        name: "dup",
+        arity: Some((1, 2)),
+        vm: shuffle!("dup", 1 => [0, 0]),
+        c: c_snippet!("dup.c"),
+        doc: "Duplicate the top value (a -- a a)",
+    },
+    Builtin {
+        name: "drop",
+        arity: Some((1, 0)),
+        vm: shuffle!("drop", 1 => []),
+        c: c_snippet!("drop.c"),
+        doc: "Remove the top value (a --)",
+    },
+    Builtin {
+        name: "swap",
+        arity: Some((2, 2)),
+        vm: shuffle!("swap", 2 => [1, 0]),
+        c: c_snippet!("swap.c"),
+        doc: "Swap the top two values (a b -- b a)",
+    },
+    Builtin {
+        name: "over",
+        arity: Some((2, 3)),
+        vm: shuffle!("over", 2 => [0, 1, 0]),
+        c: c_snippet!("over.c"),
+        doc: "Copy the second value to the top (a b -- a b a)",
+    },
+    Builtin {
+        name: "rot",
+        arity: Some((3, 3)),
+        vm: shuffle!("rot", 3 => [1, 2, 0]),
+        c: c_snippet!("rot.c"),
+        doc: "Move the third value to the top (a b c -- b c a)",
+    },
+    Builtin {
+        name: "-rot",
+        arity: Some((3, 3)),
+        vm: shuffle!("-rot", 3 => [2, 0, 1]),
+        c: c_snippet!("rot-reverse.c"),
+        doc: "Move the top value to third (a b c -- c a b)",
+    },
+    Builtin {
+        name: "nip",
+        arity: Some((2, 1)),
+        vm: shuffle!("nip", 2 => [1]),
+        c: c_snippet!("nip.c"),
+        doc: "Remove the second value (a b -- b)",
+    },
+    Builtin {
+        name: "tuck",
+        arity: Some((2, 3)),
+        vm: shuffle!("tuck", 2 => [1, 0, 1]),
+        c: c_snippet!("tuck.c"),
+        doc: "Copy the top value below the second (a b -- b a b)",
+    },
+    Builtin {
+        name: "pick",
+        arity: None,
+        vm: builtin_pick,
+        c: c_snippet!("pick.c"),
+        doc: "Copy the value n below the top to the top (0 pick is dup)",
+    },
+    Builtin {
+        name: "roll",
+        arity: None,
+        vm: builtin_roll,
+        c: c_snippet!("roll.c"),
+        doc: "Move the value n below the top to the top (1 roll is swap)",
+    },
+    Builtin {
+        name: "depth",
+        arity: Some((0, 1)),
+        vm: |vm| {
+            vm.push(Value::Number(Number::Integer(vm.stack.len() as i64)));
+            Ok(())
+        },
+        c: c_snippet!("depth.c"),
+        doc: "Push the number of values on the stack",
+    },
+    // Blocks
+    Builtin {
+        name: "apply",
+        arity: None,
+        vm: builtin_apply,
+        c: None,
+        doc: "Evaluate the block on top of the stack",
+    },
+    // Input and output
+    Builtin {
+        name: "read",
+        arity: Some((0, 1)),
+        vm: builtin_read,
+        c: c_snippet!("read.c"),
+        doc: "Read a line from stdin as a string",
+    },
+    Builtin {
+        name: "write",
+        arity: Some((1, 0)),
+        vm: |vm| {
+            print!("{}", vm.pop()?);
+            Ok(())
+        },
+        c: c_snippet!("write.c"),
+        doc: "Pop and write a value to stdout",
+    },
+    Builtin {
+        name: "writeln",
+        arity: Some((1, 0)),
+        vm: |vm| {
+            println!("{}", vm.pop()?);
+            Ok(())
+        },
+        c: c_snippet!("writeln.c"),
+        doc: "Pop and write a value to stdout with a newline",
+    },
+    Builtin {
+        name: "newline",
+        arity: Some((0, 0)),
+        vm: |_| {
+            println!();
+            Ok(())
+        },
+        c: c_snippet!("newline.c"),
+        doc: "Write a newline to stdout",
+    },
+    // Control flow
+    Builtin {
+        name: "loop",
+        arity: None,
+        vm: builtin_loop,
+        c: c_snippet!("loop.c"),
+        doc: "Call a block with each integer below n, each character of a string, or each value of a stack",
+    },
+    Builtin {
+        name: "generate-stack",
+        arity: None,
+        vm: builtin_generate_stack,
+        c: c_snippet!("stack-generate.c"),
+        doc: "Like loop, but collect the value each call returns into a stack",
+    },
+    Builtin {
+        name: "while",
+        arity: None,
+        vm: builtin_while,
+        c: c_snippet!("while.c"),
+        doc: "Call the body block for as long as the condition block returns true",
+    },
+    Builtin {
+        name: "forever",
+        arity: None,
+        vm: builtin_forever,
+        c: c_snippet!("forever.c"),
+        doc: "Call a block over and over, until it breaks",
+    },
+    Builtin {
+        name: "break",
+        arity: Some((0, 0)),
+        vm: |_| Err(RuntimeError::control(Control::Break)),
+        c: c_snippet!("break.c"),
+        doc: "Return from each block out to the enclosing loop, then stop looping",
+    },
+    Builtin {
+        name: "continue",
+        arity: Some((0, 0)),
+        vm: |_| Err(RuntimeError::control(Control::Continue)),
+        c: c_snippet!("continue.c"),
+        doc: "Return from each block out to the enclosing loop, then keep looping",
+    },
+    Builtin {
+        name: "throw",
+        arity: Some((1, 0)),
+        vm: |vm| Err(RuntimeError::thrown(vm.pop()?)),
+        c: None,
+        doc: "Raise an error with any value, to be caught by try",
+    },
+    Builtin {
+        name: "try",
+        arity: None,
+        vm: builtin_try,
+        c: None,
+        doc: "Call the body block, if it fails call the handler block with the error",
+    },
+    Builtin {
+        name: "assert",
+        arity: Some((1, 0)),
+        vm: |vm| match vm.pop()? {
+            Value::Boolean(true) => Ok(()),
+            Value::Boolean(false) => fail!(Assertion, "assert failed"),
+            value => fail!(Type, "assert expects a boolean, got {}", value),
+        },
+        c: Some(CCode::SpannedSnippet(include_str!(
+            "../compile_c_includes/builtins/assert.c"
+        ))),
+        doc: "Fail unless the value is true",
+    },
+    Builtin {
+        name: "assert-eq",
+        arity: Some((2, 0)),
+        vm: |vm| {
+            let expected = vm.pop()?;
+            let actual = vm.pop()?;
+            if actual != expected {
+                fail!(Assertion, "assert-eq failed: {} != {}", actual, expected);
+            }
+            Ok(())
+        },
+        c: Some(CCode::SpannedSnippet(include_str!(
+            "../compile_c_includes/builtins/assert-eq.c"
+        ))),
+        doc: "Fail unless the two values are equal",
+    },
+    Builtin {
+        name: "if",
+        arity: None,
+        vm: builtin_if,
+        c: c_snippet!("if.c"),
+        doc: "Evaluate the true or false branch depending on a boolean",
+    },
+    Builtin {
+        name: "when",
+        arity: None,
+        vm: builtin_when,
+        c: c_snippet!("when.c"),
+        doc: "Evaluate a branch only if a boolean is true",
+    },
+    Builtin {
+        name: "cond",
+        arity: None,
+        vm: builtin_cond,
+        c: c_snippet!("cond.c"),
+        doc: "Evaluate the value for the first test that returns true from [test value ... default]",
+    },
+    // Stacks (lists)
+    Builtin {
+        name: "make-stack",
+        arity: Some((0, 1)),
+        vm: |vm| {
+            vm.push(Value::Stack(Rc::new(RefCell::new(vec![]))));
+            Ok(())
+        },
+        c: c_snippet!("stack-new.c"),
+        doc: "Create a new empty stack",
+    },
+    Builtin {
+        name: "stack-size",
+        arity: Some((1, 1)),
+        vm: builtin_stack_size,
+        c: c_snippet!("stack-size.c"),
+        doc: "The number of values in a stack",
+    },
+    Builtin {
+        name: "stack-push!",
+        arity: Some((2, 0)),
+        vm: builtin_stack_push,
+        c: c_snippet!("stack-push.c"),
+        doc: "Push a value onto a stack",
+    },
+    Builtin {
+        name: "stack-pop!",
+        arity: Some((1, 1)),
+        vm: builtin_stack_pop,
+        c: c_snippet!("stack-pop.c"),
+        doc: "Pop the top value off of a stack",
+    },
+    Builtin {
+        name: "stack-ref",
+        arity: Some((2, 1)),
+        vm: builtin_stack_ref,
+        c: c_snippet!("stack-ref.c"),
+        doc: "Get the value at an index in a stack",
+    },
+    Builtin {
+        name: "stack-set!",
+        arity: Some((3, 0)),
+        vm: builtin_stack_set,
+        c: c_snippet!("stack-set.c"),
+        doc: "Set the value at an index in a stack",
+    },
+    // List operations, these return new lists and leave their arguments unchanged
+    Builtin {
+        name: "map",
+        arity: Some((2, 1)),
+        vm: builtin_map,
+        c: c_snippet!("map.c"),
+        doc: "Call a block on each value in a list, collecting the results",
+    },
+    Builtin {
+        name: "filter",
+        arity: Some((2, 1)),
+        vm: builtin_filter,
+        c: c_snippet!("filter.c"),
+        doc: "Keep the values in a list that a block returns true for",
+    },
+    Builtin {
+        name: "fold",
+        arity: Some((3, 1)),
+        vm: builtin_fold,
+        c: c_snippet!("fold.c"),
+        doc: "Combine each value in a list into an accumulator with a block",
+    },
+    Builtin {
+        name: "any?",
+        arity: Some((2, 1)),
+        vm: |vm| builtin_any_all(vm, "any?", true),
+        c: c_snippet!("any.c"),
+        doc: "Test if a block returns true for any value in a list",
+    },
+    Builtin {
+        name: "all?",
+        arity: Some((2, 1)),
+        vm: |vm| builtin_any_all(vm, "all?", false),
+        c: c_snippet!("all.c"),
+        doc: "Test if a block returns true for every value in a list",
+    },
+    Builtin {
+        name: "find",
+        arity: Some((2, 1)),
+        vm: builtin_find,
+        c: c_snippet!("find.c"),
+        doc: "The first value in a list a block returns true for (or false)",
+    },
+    Builtin {
+        name: "zip",
+        arity: Some((2, 1)),
+        vm: builtin_zip,
+        c: c_snippet!("zip.c"),
+        doc: "Pair up the values in two lists (as two element lists)",
+    },
+    Builtin {
+        name: "reverse",
+        arity: Some((1, 1)),
+        vm: |vm| {
+            let mut values = pop_list(vm, "reverse")?;
+            values.reverse();
+            push_list(vm, values);
+            Ok(())
+        },
+        c: c_snippet!("reverse.c"),
+        doc: "Reverse a list",
+    },
+    Builtin {
+        name: "range",
+        arity: Some((2, 1)),
+        vm: builtin_range,
+        c: c_snippet!("range.c"),
+        doc: "A list of the integers from start up to (but not including) end",
+    },
+    Builtin {
+        name: "slice",
+        arity: Some((3, 1)),
+        vm: builtin_slice,
+        c: c_snippet!("slice.c"),
+        doc: "The values in a list from start up to (but not including) end",
+    },
+    Builtin {
+        name: "concat",
+        arity: Some((2, 1)),
+        vm: |vm| {
+            let b = pop_list(vm, "concat")?;
+            let mut a = pop_list(vm, "concat")?;
+            vm.check_size("concat", a.len() + b.len())?;
+            a.extend(b);
+            push_list(vm, a);
+            Ok(())
+        },
+        c: c_snippet!("concat.c"),
+        doc: "Join two lists",
+    },
+    Builtin {
+        name: "sort",
+        arity: Some((1, 1)),
+        vm: |vm| {
+            let values = pop_list(vm, "sort")?;
+            let sorted = sort_by_keys("sort", values.clone(), values)?;
+            push_list(vm, sorted);
+            Ok(())
+        },
+        c: c_snippet!("sort.c"),
+        doc: "Sort a list of numbers or strings",
+    },
+    Builtin {
+        name: "sort-by",
+        arity: Some((2, 1)),
+        vm: builtin_sort_by,
+        c: c_snippet!("sort-by.c"),
+        doc: "Sort a list by the number or string a block returns for each value",
+    },
+    // Hashes
+    Builtin {
+        name: "make-hash",
+        arity: Some((0, 1)),
+        vm: |vm| {
+            vm.push(Value::Hash(Rc::new(RefCell::new(HashMap::new()))));
+            Ok(())
+        },
+        c: None,
+        doc: "Create a new empty hash with string keys",
+    },
+    Builtin {
+        name: "make-int-hash",
+        arity: Some((0, 1)),
+        vm: |vm| {
+            vm.push(Value::IntHash(Rc::new(RefCell::new(HashMap::new()))));
+            Ok(())
+        },
+        c: None,
+        doc: "Create a new empty hash with integer keys",
+    },
+    Builtin {
+        name: "hash-has?",
+        arity: Some((2, 1)),
+        vm: builtin_hash_has,
+        c: None,
+        doc: "Check if a hash contains a key",
+    },
+    Builtin {
+        name: "hash-get",
+        arity: Some((2, 1)),
+        vm: builtin_hash_get,
+        c: None,
+        doc: "Get the value for a key in a hash",
+    },
+    Builtin {
+        name: "hash-set!",
+        arity: Some((3, 0)),
+        vm: builtin_hash_set,
+        c: None,
+        doc: "Set the value for a key in a hash",
+    },
+];
+
+fn builtin_not(vm: &mut VM) -> Result<(), RuntimeError> {
+    let a = vm.pop()?;
+
+    match a {
+        Value::Boolean(a) => vm.push(Value::Boolean(!a)),
+        _ => fail!(
+            Type,
+            "cannot perform logical operation on non-boolean values, got {}",
+            a
+        ),
+    };
+    Ok(())
+}
+
+// Convert a value to an int if possible
+fn builtin_to_int(vm: &mut VM) -> Result<(), RuntimeError> {
+    let value = vm.pop()?;
+    match value {
+        Value::String(s) => match s.parse() {
+            Ok(v) => vm.push(Value::Number(Number::Integer(v))),
+            Err(_) => fail!(Value, "to_int: cannot parse {:?} as an integer", s),
+        },
+        Value::Number(n) => vm.push(Value::Number(n.to_integer())),
+        _ => fail!(Type, "int cannot, got {}", value),
+    }
+    Ok(())
+}
+
+// Convert a value to a float if possible
+fn builtin_to_float(vm: &mut VM) -> Result<(), RuntimeError> {
+    let value = vm.pop()?;
+    match value {
+        Value::String(s) => match s.parse() {
+            Ok(v) => vm.push(Value::Number(Number::Float(v))),
+            Err(_) => fail!(Value, "to_float: cannot parse {:?} as a float", s),
+        },
+        Value::Number(n) => vm.push(Value::Number(n.to_float())),
+        _ => fail!(Type, "int cannot, got {}", value),
+    }
+    Ok(())
+}
+
+// Pop an index for pick or roll, which must be a non-negative integer
+fn pop_index(vm: &mut VM, name: &str) -> Result<usize, RuntimeError> {
+    match vm.pop()? {
+        Value::Number(Number::Integer(n)) if n >= 0 => Ok(n as usize),
+        value => fail!(
+            Value,
+            "{} expects a non-negative integer, got {}",
+            name,
+            value
+        ),
+    }
+}
+
+// Copy the value n below the top of the stack to the top
+fn builtin_pick(vm: &mut VM) -> Result<(), RuntimeError> {
+    let n = pop_index(vm, "pick")?;
+    match vm.stack.peek(n) {
+        Some(value) => vm.push(value),
+        None => fail!(
+            Stack,
+            "pick: cannot pick {}, only {} values are on the stack",
+            n,
+            vm.stack.len()
+        ),
+    }
+    Ok(())
+}
+
+// Move the value n below the top of the stack to the top
+fn builtin_roll(vm: &mut VM) -> Result<(), RuntimeError> {
+    let n = pop_index(vm, "roll")?;
+    if !vm.stack.roll(n) {
+        fail!(
+            Stack,
+            "roll: cannot roll {}, only {} values are on the stack",
+            n,
+            vm.stack.len()
+        );
+    }
+    Ok(())
+}
+
+// Apply a block to the stack
+fn builtin_apply(vm: &mut VM) -> Result<(), RuntimeError> {
+    let block = vm.pop()?;
+    match block {
+        Value::Block { .. } => vm.evaluate_value(block),
+        _ => fail!(Type, "apply expects a block, got {}", block),
+    }
+}
+
+// Read a line from stdin as a string
+fn builtin_read(vm: &mut VM) -> Result<(), RuntimeError> {
+    vm.check_io("read")?;
+    let mut input = String::new();
+    match std::io::stdin().read_line(&mut input) {
+        Ok(_) => vm.push(Value::String(input.trim_end_matches('\n').to_string())),
+        Err(e) => fail!("failed to read from stdin: {e}"),
+    };
+    Ok(())
+}
+
+/// Call the body of a loop, handling break and continue
+///
+/// Returns false if the body breaks out of the loop
+fn loop_body(vm: &mut VM, block: &Value) -> Result<bool, RuntimeError> {
+    match vm.evaluate_value(block.clone()) {
+        Ok(()) => Ok(true),
+        Err(e) => match e.control {
+            Some(Control::Break) => Ok(false),
+            Some(Control::Continue) => Ok(true),
+            None => Err(e),
+        },
+    }
+}
+
+/// Call a block for each value in an iterable (used by loop and generate-stack)
+///
+/// after is called after each time the block returns, with true if it broke out of the loop
+fn for_each(
+    vm: &mut VM,
+    name: &str,
+    mut after: impl FnMut(&mut VM, bool) -> Result<(), RuntimeError>,
+) -> Result<(), RuntimeError> {
+    let iterable = vm.pop()?;
+    let block = vm.pop()?;
+
+    if !matches!(block, Value::Block { .. }) {
+        fail!(Type, "{} must have a block, got {}", name, block);
+    }
+
+    // Integers are iterated lazily, so large loops don't need a collection of that size
+    let values: Box<dyn Iterator<Item = Value>> = match iterable {
+        Value::Number(Number::Integer(n)) => {
+            if n < 0 {
+                fail!(
+                    Value,
+                    "numeric loops must have a positive integer, got {}",
+                    n
+                );
+            }
+
+            Box::new((0..n).map(|i| Value::Number(Number::Integer(i))))
+        }
+        Value::String(s) => Box::new(
+            s.chars()
+                .map(|c| Value::String(c.to_string()))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        ),
+        Value::Stack(l) => Box::new(l.borrow().clone().into_iter()),
+        _ => fail!(
+            Type,
+            "{} must have an iterable (currently an integer, string, or stack), got {}",
+            name,
+            iterable
+        ),
+    };
+
+    for value in values {
+        vm.push(value);
+        // Blocks get evaluated lazily (now)
+        let broke = !loop_body(vm, &block)?;
+        after(vm, broke)?;
+        if broke {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Loop over an iterable, expects a block and an iterable
+fn builtin_loop(vm: &mut VM) -> Result<(), RuntimeError> {
+    for_each(vm, "loop", |_, _| Ok(()))
+}
+
+// Loop over an iterable and store the results as a list
+// The value returned by the call that breaks (if any) is not collected
+fn builtin_generate_stack(vm: &mut VM) -> Result<(), RuntimeError> {
+    let mut result = vec![];
+    for_each(vm, "generate-stack", |vm, broke| {
+        let value = vm.pop()?;
+        if !broke {
+            vm.check_size("generate-stack", result.len() + 1)?;
+            result.push(value);
+        }
+        Ok(())
+    })?;
+    vm.push(Value::Stack(Rc::new(RefCell::new(result))));
+    Ok(())
+}
+
+// Loop while a condition block returns true, expects a condition block and a body block
+fn builtin_while(vm: &mut VM) -> Result<(), RuntimeError> {
+    let body = vm.pop()?;
+    let condition = vm.pop()?;
+
+    for block in [&condition, &body] {
+        if !matches!(block, Value::Block { .. }) {
+            fail!(
+                Type,
+                "while must have a condition and a body block, got {}",
+                block
+            );
+        }
+    }
+
+    loop {
+        if !loop_body(vm, &condition)? {
+            break;
+        }
+        match vm.pop()? {
+            Value::Boolean(true) => {}
+            Value::Boolean(false) => break,
+            value => fail!(Type, "while condition must return a boolean, got {}", value),
+        }
+
+        if !loop_body(vm, &body)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Loop until the block breaks
+fn builtin_forever(vm: &mut VM) -> Result<(), RuntimeError> {
+    let block = vm.pop()?;
+
+    if !matches!(block, Value::Block { .. }) {
+        fail!(Type, "forever must have a block, got {}", block);
+    }
+
+    while loop_body(vm, &block)? {}
+    Ok(())
+}
+
+// Call a body block, calling the handler block with the error if it fails
+// The body's inputs are consumed either way, the handler gets the thrown value or a hash of kind and message
+fn builtin_try(vm: &mut VM) -> Result<(), RuntimeError> {
+    let handler = vm.pop()?;
+    let body = vm.pop()?;
+
+    let arity_in = match (&body, &handler) {
+        (Value::Block { arity_in, .. }, Value::Block { .. }) => *arity_in,
+        _ => fail!(
+            Type,
+            "try must have a body and a handler block, got {} and {}",
+            body,
+            handler
+        ),
+    };
+
+    // Limits can't be caught, otherwise a sandboxed program could keep running past them
+    let (frames, len) = vm.stack.mark();
+    match vm.evaluate_value(body) {
+        Err(e) if e.control.is_none() && e.kind != ErrorKind::Limit => {
+            vm.stack.unwind((frames, len.saturating_sub(arity_in)));
+            vm.push(e.to_value());
+            vm.evaluate_value(handler)
+        }
+        result => result,
+    }
+}
+
+// If statement, expects two blocks or literals and a conditional (must be boolean)
+fn builtin_if(vm: &mut VM) -> Result<(), RuntimeError> {
+    let condition = vm.pop()?;
+    let false_branch = vm.pop()?;
+    let true_branch = vm.pop()?;
+
+    log::debug!(
+        "if condition: {}, true: {}, false: {}",
+        condition,
+        true_branch,
+        false_branch
+    );
+
+    let branch = match condition {
+        Value::Boolean(value) => {
+            if value {
+                true_branch
+            } else {
+                false_branch
+            }
+        }
+        _ => fail!(Type, "if condition must be a boolean, got {}", condition),
+    };
+
+    log::debug!("if selected: {}", branch);
+    vm.evaluate_value(branch)
+}
+
+// One armed if statement, primarily used for side effect
+fn builtin_when(vm: &mut VM) -> Result<(), RuntimeError> {
+    let condition = vm.pop()?;
+    let branch = vm.pop()?;
+
+    match condition {
+        Value::Boolean(true) => vm.evaluate_value(branch),
+        Value::Boolean(false) => Ok(()),
+        _ => fail!(Type, "when condition must be a boolean, got {}", condition),
+    }
+}
+
+// Cond statements are like if statements, but with multiple branches
+// They expect only an odd numbered list of blocks as input:
+// [ test1 value1 test2 value2 ... default ]
+// For each test/value pair. If test is true, value is returned and the cond exits
+// If no other block returns, return the result of the last block (default)
+// All tests should be blocks; values can be blocks or values
+fn builtin_cond(vm: &mut VM) -> Result<(), RuntimeError> {
+    let branches = vm.pop()?;
+
+    let l = match branches {
+        Value::Stack(l) => l.borrow().clone(),
+        _ => fail!(Type, "cond branches must be a list, got {}", branches),
+    };
+
+    for pair in l.chunks_exact(2) {
+        let (test, value) = (&pair[0], &pair[1]);
+
+        let test_result = match test {
+            Value::Block { .. } => {
+                vm.evaluate_value(test.clone())?;
+                vm.pop()?
+            }
+            _ => fail!(Type, "cond test must be a block, got {}", test),
+        };
+
+        match test_result {
+            Value::Boolean(true) => return vm.evaluate_value(value.clone()),
+            Value::Boolean(false) => {}
+            _ => fail!(Type, "cond test must return a boolean, got {}", test_result),
+        }
+    }
+
+    // If we didn't return from the cond, evaluate the default
+    match l.last() {
+        Some(default) => vm.evaluate_value(default.clone()),
+        None => fail!(Value, "cond branches must not be empty"),
+    }
+}
+
+fn builtin_stack_size(vm: &mut VM) -> Result<(), RuntimeError> {
+    let list = vm.pop()?;
+
+    match list {
+        Value::Stack(l) => vm.push(Value::Number(Number::Integer(l.borrow().len() as i64))),
+        _ => fail!(Type, "stack-size: expected list, got {}", list),
+    }
+    Ok(())
+}
+
+fn builtin_stack_push(vm: &mut VM) -> Result<(), RuntimeError> {
+    let value = vm.pop()?;
+    let list = vm.pop()?;
+
+    match list {
+        Value::Stack(l) => {
+            vm.check_size("stack-push!", l.borrow().len() + 1)?;
+            l.borrow_mut().push(value)
+        }
+        _ => fail!(Type, "stack-push!: expected list, got {}", list),
+    }
+    Ok(())
+}
+
+fn builtin_stack_pop(vm: &mut VM) -> Result<(), RuntimeError> {
+    let list = vm.pop()?;
+
+    match list {
+        Value::Stack(l) => {
+            let value = l.borrow_mut().pop();
+            match value {
+                Some(value) => vm.push(value),
+                None => fail!(Value, "stack-pop!: list is empty"),
+            }
+        }
+        _ => fail!(Type, "stack-pop!: expected list, got {}", list),
+    }
+    Ok(())
+}
+
+fn builtin_stack_ref(vm: &mut VM) -> Result<(), RuntimeError> {
+    let index = vm.pop()?;
+    let list = vm.pop()?;
+
+    match list {
+        Value::Stack(l) => match index {
+            Value::Number(Number::Integer(i)) => {
+                let value = l.borrow().get(i as usize).cloned();
+                match value {
+                    Some(value) => vm.push(value),
+                    None => fail!(Index, "stack-ref: index out of bounds: {}", i),
+                }
+            }
+            _ => fail!(Type, "stack-ref: index must be an integer, got {}", index),
+        },
+        _ => fail!(Type, "stack-ref: expected list, got {}", list),
+    }
+    Ok(())
+}
+
+fn builtin_stack_set(vm: &mut VM) -> Result<(), RuntimeError> {
+    let value = vm.pop()?;
+    let index = vm.pop()?;
+    let list = vm.pop()?;
+
+    match list {
+        Value::Stack(l) => match index {
+            Value::Number(Number::Integer(i)) => match l.borrow_mut().get_mut(i as usize) {
+                Some(old_value) => *old_value = value,
+                None => fail!(Index, "stack-set!: index out of bounds: {}", i),
+            },
+            _ => fail!(Type, "stack-set!: index must be an integer, got {}", index),
+        },
+        _ => fail!(Type, "stack-set!: expected list, got {}", list),
+    }
+    Ok(())
+}
+
+// Pop a list for a list operation, returning a copy of its values
+fn pop_list(vm: &mut VM, name: &str) -> Result<Vec<Value>, RuntimeError> {
+    match vm.pop()? {
+        Value::Stack(l) => Ok(l.borrow().clone()),
+        value => fail!(Type, "{} expects a list, got {}", name, value),
+    }
+}
+
+// Push a new list
+fn push_list(vm: &mut VM, values: Vec<Value>) {
+    vm.push(Value::Stack(Rc::new(RefCell::new(values))));
+}
+
+// Pop a block for a list operation, it must take and return the given number of values
+fn pop_block(vm: &mut VM, name: &str, arity: (usize, usize)) -> Result<Value, RuntimeError> {
+    let block = vm.pop()?;
+    match block {
+        Value::Block {
+            arity_in,
+            arity_out,
+            ..
+        } if (arity_in, arity_out) == arity => Ok(block),
+        _ => fail!(
+            Type,
+            "{} expects a block that takes {} and returns {}, got {}",
+            name,
+            arity.0,
+            arity.1,
+            block
+        ),
+    }
+}
+
+// Call a block with the given arguments, returning the value it returns
+fn call(vm: &mut VM, block: &Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    for arg in args {
+        vm.push(arg);
+    }
+    vm.evaluate_value(block.clone())?;
+    vm.pop()
+}
+
+// Call a block that tests a value, it must return a boolean
+fn test(vm: &mut VM, name: &str, block: &Value, value: Value) -> Result<bool, RuntimeError> {
+    match call(vm, block, vec![value])? {
+        Value::Boolean(b) => Ok(b),
+        result => fail!(
+            Type,
+            "{} expects a block that returns a boolean, got {}",
+            name,
+            result
+        ),
+    }
+}
+
+// Sort values by the matching keys, which must be all numbers or all strings
+fn sort_by_keys(
+    name: &str,
+    keys: Vec<Value>,
+    values: Vec<Value>,
+) -> Result<Vec<Value>, RuntimeError> {
+    let numbers = keys.iter().all(|k| matches!(k, Value::Number(_)));
+    let strings = keys.iter().all(|k| matches!(k, Value::String(_)));
+    if !numbers && !strings {
+        fail!(Type, "{} can only sort numbers or strings", name);
+    }
+
+    let mut pairs = keys.into_iter().zip(values).collect::<Vec<_>>();
+    pairs.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => unreachable!(),
+    });
+    Ok(pairs.into_iter().map(|(_, v)| v).collect())
+}
+
+// Call a block on each value in a list, collecting the results
+fn builtin_map(vm: &mut VM) -> Result<(), RuntimeError> {
+    let values = pop_list(vm, "map")?;
+    let block = pop_block(vm, "map", (1, 1))?;
+
+    let mut result = vec![];
+    for value in values {
+        result.push(call(vm, &block, vec![value])?);
+    }
+    push_list(vm, result);
+    Ok(())
+}
+
+// Keep the values in a list that a block returns true for
+fn builtin_filter(vm: &mut VM) -> Result<(), RuntimeError> {
+    let values = pop_list(vm, "filter")?;
+    let block = pop_block(vm, "filter", (1, 1))?;
+
+    let mut result = vec![];
+    for value in values {
+        if test(vm, "filter", &block, value.clone())? {
+            result.push(value);
+        }
+    }
+    push_list(vm, result);
+    Ok(())
+}
+
+// Combine each value in a list into an accumulator, the block takes the accumulator then the value
+fn builtin_fold(vm: &mut VM) -> Result<(), RuntimeError> {
+    let values = pop_list(vm, "fold")?;
+    let mut acc = vm.pop()?;
+    let block = pop_block(vm, "fold", (2, 1))?;
+
+    for value in values {
+        acc = call(vm, &block, vec![acc, value])?;
+    }
+    vm.push(acc);
+    Ok(())
+}
+
+// Test if a block returns true for any (or all) values in a list, stopping as soon as the answer is known
+fn builtin_any_all(vm: &mut VM, name: &str, any: bool) -> Result<(), RuntimeError> {
+    let values = pop_list(vm, name)?;
+    let block = pop_block(vm, name, (1, 1))?;
+
+    for value in values {
+        if test(vm, name, &block, value)? == any {
+            vm.push(Value::Boolean(any));
+            return Ok(());
+        }
+    }
+    vm.push(Value::Boolean(!any));
+    Ok(())
+}
+
+// Find the first value in a list that a block returns true for, or false if there isn't one
+fn builtin_find(vm: &mut VM) -> Result<(), RuntimeError> {
+    let values = pop_list(vm, "find")?;
+    let block = pop_block(vm, "find", (1, 1))?;
+
+    for value in values {
+        if test(vm, "find", &block, value.clone())? {
+            vm.push(value);
+            return Ok(());
+        }
+    }
+    vm.push(Value::Boolean(false));
+    Ok(())
+}
+
+// Pair up the values in two lists, stopping at the end of the shorter one
+fn builtin_zip(vm: &mut VM) -> Result<(), RuntimeError> {
+    let b = pop_list(vm, "zip")?;
+    let a = pop_list(vm, "zip")?;
+
+    let pairs = a
+        .into_iter()
+        .zip(b)
+        .map(|(a, b)| Value::Stack(Rc::new(RefCell::new(vec![a, b]))))
+        .collect();
+    push_list(vm, pairs);
+    Ok(())
+}
+
+// Pop an integer argument for a list operation
+fn pop_integer(vm: &mut VM, name: &str) -> Result<i64, RuntimeError> {
+    match vm.pop()? {
+        Value::Number(Number::Integer(n)) => Ok(n),
+        value => fail!(Type, "{} expects an integer, got {}", name, value),
+    }
+}
+
+// A list of integers from start up to end
+fn builtin_range(vm: &mut VM) -> Result<(), RuntimeError> {
+    let end = pop_integer(vm, "range")?;
+    let start = pop_integer(vm, "range")?;
+    vm.check_size("range", end.saturating_sub(start).max(0) as usize)?;
+
+    push_list(
+        vm,
+        (start..end)
+            .map(|i| Value::Number(Number::Integer(i)))
+            .collect(),
+    );
+    Ok(())
+}
+
+// The values in a list from start up to end
+fn builtin_slice(vm: &mut VM) -> Result<(), RuntimeError> {
+    let end = pop_integer(vm, "slice")?;
+    let start = pop_integer(vm, "slice")?;
+    let values = pop_list(vm, "slice")?;
+
+    if start < 0 || end < start || end as usize > values.len() {
+        fail!(
+            Index,
+            "slice: cannot slice {}..{} from a list of {} values",
+            start,
+            end,
+            values.len()
+        );
+    }
+
+    push_list(vm, values[start as usize..end as usize].to_vec());
+    Ok(())
+}
+
+// Sort a list by the value a block returns for each value
+fn builtin_sort_by(vm: &mut VM) -> Result<(), RuntimeError> {
+    let values = pop_list(vm, "sort-by")?;
+    let block = pop_block(vm, "sort-by", (1, 1))?;
+
+    let mut keys = vec![];
+    for value in values.iter() {
+        keys.push(call(vm, &block, vec![value.clone()])?);
+    }
+    let sorted = sort_by_keys("sort-by", keys, values)?;
+    push_list(vm, sorted);
+    Ok(())
+}
+
+fn builtin_hash_has(vm: &mut VM) -> Result<(), RuntimeError> {
+    let key = vm.pop()?;
+    let hash = vm.pop()?;
+
+    match hash {
+        Value::Hash(h) => match key {
+            Value::String(s) => vm.push(Value::Boolean(h.borrow().contains_key(&s))),
+            _ => fail!(Type, "hash-has?: Hash key must be a string, got {}", key),
+        },
+        Value::IntHash(h) => match key {
+            Value::Number(Number::Integer(v)) => {
+                vm.push(Value::Boolean(h.borrow().contains_key(&v)))
+            }
+            _ => fail!(
+                Type,
+                "hash-has?: IntHash key must be an integer, got {}",
+                key
+            ),
+        },
+        _ => fail!(Type, "hash-has?: hash must be a hash, got {}", hash),
+    }
+    Ok(())
+}
+
+fn builtin_hash_get(vm: &mut VM) -> Result<(), RuntimeError> {
+    let key = vm.pop()?;
+    let hash = vm.pop()?;
+
+    let value = match hash {
+        Value::Hash(h) => match key {
+            Value::String(s) => match h.borrow().get(&s) {
+                Some(value) => value.clone(),
+                None => fail!(Key, "hash-get: key not found: {}", s),
+            },
+            _ => fail!(Type, "hash-get: Hash key must be a string, got {}", key),
+        },
+        Value::IntHash(h) => match key {
+            Value::Number(Number::Integer(v)) => match h.borrow().get(&v) {
+                Some(value) => value.clone(),
+                None => fail!(Key, "hash-get: key not found: {}", v),
+            },
+            _ => fail!(
+                Type,
+                "hash-get: IntHash key must be an integer, got {}",
+                key
+            ),
+        },
+        _ => fail!(Type, "hash-get: hash must be a hash, got {}", hash),
+    };
+
+    vm.push(value);
+    Ok(())
+}
+
+fn builtin_hash_set(vm: &mut VM) -> Result<(), RuntimeError> {
+    let value = vm.pop()?;
+    let key = vm.pop()?;
+    let hash = vm.pop()?;
+
+    match hash {
+        Value::Hash(h) => match key {
+            Value::String(s) => {
+                vm.check_size("hash-set!", h.borrow().len() + 1)?;
+                h.borrow_mut().insert(s, value);
+            }
+            _ => fail!(Type, "hash-set!: Hash key must be a string, got {}", key),
+        },
+        Value::IntHash(h) => match key {
+            Value::Number(Number::Integer(v)) => {
+                vm.check_size("hash-set!", h.borrow().len() + 1)?;
+                h.borrow_mut().insert(v, value);
+            }
+            _ => fail!(
+                Type,
+                "hash-set!: IntHash key must be an integer, got {}",
+                key
+            ),
+        },
+        _ => fail!(Type, "hash-set!: hash must be a hash, got {}", hash),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_names_are_unique() {
+        for (i, a) in BUILTINS.iter().enumerate() {
+            for b in BUILTINS.iter().skip(i + 1) {
+                assert_ne!(a.name, b.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("+").unwrap().arity, Some((2, 1)));
+        assert_eq!(lookup("stack-set!").unwrap().arity, Some((3, 0)));
+        assert_eq!(lookup("if").unwrap().arity, None);
+        assert!(lookup("==").is_none());
+        assert!(lookup("not-a-builtin").is_none());
+    }
+
+    #[test]
+    fn test_vm_implementation() {
+        let mut vm = VM::new();
+        vm.push(Value::Number(Number::Integer(8)));
+        vm.push(Value::Number(Number::Integer(5)));
+        (lookup("-").unwrap().vm)(&mut vm).unwrap();
+        vm.push(Value::Number(Number::Integer(3)));
+        (lookup("=").unwrap().vm)(&mut vm).unwrap();
+
+        assert_eq!(vm.pop(), Ok(Value::Boolean(true)));
+        assert!(vm.pop().is_err());
+    }
+
+    #[test]
+    fn test_shuffles() {
+        let shuffled = |program: &str| {
+            let mut vm = VM::new();
+            vm.evaluate_str(program).unwrap();
+
+            let mut values = vec![];
+            while let Ok(value) = vm.pop() {
+                values.insert(0, value.to_string());
+            }
+            values.join(" ")
+        };
+
+        assert_eq!(shuffled("1 2 dup"), "1 2 2");
+        assert_eq!(shuffled("1 2 drop"), "1");
+        assert_eq!(shuffled("1 2 swap"), "2 1");
+        assert_eq!(shuffled("1 2 over"), "1 2 1");
+        assert_eq!(shuffled("1 2 3 rot"), "2 3 1");
+        assert_eq!(shuffled("1 2 3 -rot"), "3 1 2");
+        assert_eq!(shuffled("1 2 nip"), "2");
+        assert_eq!(shuffled("1 2 tuck"), "2 1 2");
+        assert_eq!(shuffled("1 2 3 2 pick"), "1 2 3 1");
+        assert_eq!(shuffled("1 2 3 2 roll"), "2 3 1");
+        assert_eq!(shuffled("1 2 depth"), "1 2 2");
+
+        let mut vm = VM::new();
+        assert!(vm.evaluate_str("1 swap").is_err());
+        assert!(vm.evaluate_str("1 5 pick").is_err());
+        assert!(vm.evaluate_str("1 -1 roll").is_err());
+    }
+
+    #[test]
+    fn test_loop_control() {
+        let evaluated = |program: &str| {
+            let mut vm = VM::new();
+            vm.evaluate_str(program).unwrap();
+            vm.pop().unwrap().to_string()
+        };
+
+        assert_eq!(evaluated("1 { dup 100 < } { 2 * } while"), "128");
+        assert_eq!(
+            evaluated("1 { @x { x break } x 50 > when x 2 * } forever"),
+            "64"
+        );
+        assert_eq!(
+            evaluated("{ @i { i continue } i 2 % 0 = when i 10 * } 5 generate-stack"),
+            "[0, 10, 2, 30, 4]"
+        );
+
+        // break returns from each block on the way out, keeping their outputs
+        assert_eq!(
+            evaluated("0 { @[acc i] { acc break } i 3 = when acc i + } 10 loop"),
+            "3"
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.evaluate_str("break").unwrap_err().to_string(),
+            "break outside of a loop"
+        );
+        assert!(vm.evaluate_str("{ 1 } { } while").is_err());
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let evaluated = |program: &str| {
+            let mut vm = VM::new();
+            vm.evaluate_str(program).unwrap();
+            vm.pop().unwrap().to_string()
+        };
+
+        assert_eq!(evaluated("{ 42 throw } { 1 + } try"), "43");
+        assert_eq!(evaluated("{ 1 } { drop 2 } try"), "1");
+        assert_eq!(
+            evaluated("{ make-hash \"a\" hash-get } { \"kind\" hash-get } try"),
+            "key"
+        );
+        assert_eq!(
+            evaluated("{ [] 3 stack-ref } { \"message\" hash-get } try"),
+            "stack-ref: index out of bounds: 3"
+        );
+
+        assert_eq!(
+            evaluated("{ 1 0 / } { \"message\" hash-get } try"),
+            "cannot divide 1 by zero"
+        );
+        assert_eq!(evaluated("{ 1 0 % } { \"kind\" hash-get } try"), "value");
+        assert_eq!(evaluated("1.0 0 /"), "inf");
+        assert_eq!(
+            evaluated("{ 9223372036854775807 1 + } { \"message\" hash-get } try"),
+            "integer overflow computing with 9223372036854775807 and 1"
+        );
+        assert_eq!(
+            evaluated("{ -9223372036854775808 -1 / } { \"kind\" hash-get } try"),
+            "value"
+        );
+        assert_eq!(evaluated("{ 4611686018427387904 2 * } { drop 0 } try"), "0");
+        assert_eq!(
+            evaluated("9223372036854775807 0.5 *"),
+            "4611686018427388000"
+        );
+
+        // Frames and values from the body are dropped, along with the body's inputs
+        assert_eq!(
+            evaluated("1 2 3 { @[a b] { a b \"c\" throw } apply } { drop } try depth"),
+            "1"
+        );
+
+        let mut vm = VM::new();
+        let error = vm.evaluate_str("[1] \"x\" throw").unwrap_err();
+        assert_eq!(error.to_string(), "uncaught throw: x");
+        assert!(vm.evaluate_str("{ 1 2 + } 5 try").is_err());
+    }
+
+    #[test]
+    fn test_assertions() {
+        let mut vm = VM::new();
+        assert!(vm.evaluate_str("1 2 < assert 2 2.0 assert-eq").is_ok());
+        assert!(vm.evaluate_str("[1 \"a\"] [1 \"a\"] assert-eq").is_ok());
+
+        let error = vm.evaluate_str("{ 1 2 + 4 assert-eq } apply").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "assert-eq failed: 3 != 4 at <unknown>:1:10:9"
+        );
+        assert_eq!(
+            vm.evaluate_str("false assert").unwrap_err().to_string(),
+            "assert failed at <unknown>:1:6:6"
+        );
+    }
+
+    #[test]
+    fn test_list_operations() {
+        let evaluated = |program: &str| {
+            let mut vm = VM::new();
+            vm.evaluate_str(program).unwrap();
+            vm.pop().unwrap().to_string()
+        };
+
+        assert_eq!(evaluated("{ 2 * } [1 2 3] map"), "[2, 4, 6]");
+        assert_eq!(evaluated("{ 2 % 0 = } 0 6 range filter"), "[0, 2, 4]");
+        assert_eq!(evaluated("{ - } 10 [1 2 3] fold"), "4");
+        assert_eq!(evaluated("{ 2 > } [1 2 3] any?"), "true");
+        assert_eq!(evaluated("{ 2 > } [1 2 3] all?"), "false");
+        assert_eq!(evaluated("{ 1 > } [1 2 3] find"), "2");
+        assert_eq!(evaluated("{ 5 > } [1 2 3] find"), "false");
+        assert_eq!(evaluated("[1 2 3] [4 5] zip"), "[[1, 4], [2, 5]]");
+        assert_eq!(evaluated("[1 2 3] reverse"), "[3, 2, 1]");
+        assert_eq!(evaluated("[1 2 3 4] 1 3 slice"), "[2, 3]");
+        assert_eq!(evaluated("[1 2] [3] concat"), "[1, 2, 3]");
+        assert_eq!(evaluated("[3 1.5 2] sort"), "[1.5, 2, 3]");
+        assert_eq!(evaluated("[\"b\" \"c\" \"a\"] sort"), "[a, b, c]");
+        assert_eq!(evaluated("{ -1 * } [1 3 2] sort-by"), "[3, 2, 1]");
+
+        // The original list is left alone
+        assert_eq!(evaluated("[3 1 2] @l l sort drop l"), "[3, 1, 2]");
+
+        let mut vm = VM::new();
+        assert!(vm.evaluate_str("[1 \"a\"] sort").is_err());
+        assert!(vm.evaluate_str("{ 2 * } [1 2] filter").is_err());
+        assert!(vm.evaluate_str("{ + } [1 2] map").is_err());
+        assert!(vm.evaluate_str("[1 2] 1 3 slice").is_err());
+    }
+}
@@ -1,27 +1,19 @@
-use std::collections::HashMap;
-
-use regex::Regex;
-use sha2::{Digest, Sha256};
-
 use crate::arity::calculate_arity;
+use crate::builtins::{self, CCode};
 use crate::debug;
 use crate::numbers::Number;
-use crate::types::{Expression, Value};
+use crate::resolver::captures;
+use crate::types::{Expression, ExpressionKind, Span, Value};
 
-/// A helper macro to generate functions that operate on two integers and floats
-macro_rules! numeric_binop {
-    ($lines:expr, $op:literal) => {{
-        let op = stringify!($op).to_string().trim_matches('"').to_string();
-
-        $lines.push(format!("
+/// Generate C code for a binary operator on two integers or two floats
+fn numeric_binop(op: &str) -> String {
+    format!(
+        "
     {{
         Value *b = stack_ptr--;
         Value *a = stack_ptr--;
+        coerce(a, b);
 
-        if (a->type != b->type) {{
-            
-        }}
-        
         if (a->type == TAG_NUMBER_INTEGER) {{
             Value result = {{.type=TAG_NUMBER_INTEGER, .as_integer=a->as_integer {op} b->as_integer}};
             *(++stack_ptr) = result;
@@ -30,16 +22,14 @@ macro_rules! numeric_binop {
             *(++stack_ptr) = result;
         }}
     }}
-"));
-    }};
+"
+    )
 }
 
-/// A helper macro to generate functions that operate on two integers and floats
-macro_rules! numeric_compare {
-    ($lines:expr, $op:literal) => {{
-        let op = stringify!($op).to_string().trim_matches('"').to_string();
-
-        $lines.push(format!("
+/// Generate C code for a comparison operator on two numbers
+fn numeric_compare(op: &str) -> String {
+    format!(
+        "
     {{
         Value *b = stack_ptr--;
         Value *a = stack_ptr--;
@@ -53,93 +43,66 @@ macro_rules! numeric_compare {
             *(++stack_ptr) = result;
         }}
     }}
-"));
-    }};
-}
-
-/// Sanitize names
-fn sanitize_name(name: &str) -> String {
-    let re = Regex::new(r"[^a-zA-Z0-9_]").unwrap();
-    let cleaned_name = re.replace_all(name, "").to_string();
-
-    if cleaned_name == name {
-        cleaned_name
-    } else {
-        let mut hasher = Sha256::new();
-        hasher.update(name);
-        let result = hasher.finalize();
-        let hash_hex = format!("{:x}", result);
-        format!("{}_{}", cleaned_name, &hash_hex[0..4])
-    }
+"
+    )
 }
 
-/// Collect the names used so we can assign each an integer value
-fn collect_names(ast: &Expression) -> HashMap<String, usize> {
-    let mut names = HashMap::new();
-
-    fn collect_names_expr(expr: &Expression, names: &mut HashMap<String, usize>) {
-        match expr {
-            Expression::Identifier(_)
-            | Expression::DottedIdentifier(_)
-            | Expression::Literal(_)
-            | Expression::Bang(_)
-            | Expression::Dollar(_) => {
-                // Do nothing, no names possible
-            }
-            Expression::List(values) => {
-                for value in values {
-                    collect_names_expr(value, names);
-                }
-            }
-            Expression::Block(exprs) => {
-                for expr in exprs {
-                    collect_names_expr(expr, names);
-                }
-            }
-            Expression::Group(exprs) => {
-                for expr in exprs {
-                    collect_names_expr(expr, names);
-                }
-            }
-            Expression::At(expr) => {
-                match expr.as_ref() {
-                    Expression::Identifier(id) => {
-                        let id = sanitize_name(id);
-                        if !names.contains_key(&id) {
-                            log::debug!("Adding name: {} @ {}", id, names.len());
-                            names.insert(id.clone(), names.len());
+/// Collect the names of the slots bound directly in a block (not in nested blocks), in slot order
+fn collect_slots(body: &[Expression]) -> Vec<String> {
+    let mut slots = vec![];
+
+    fn collect_slots_expr(expr: &Expression, slots: &mut Vec<String>) {
+        match &expr.kind {
+            ExpressionKind::At(inner) => {
+                let locals = match &inner.kind {
+                    ExpressionKind::List(values) => values.iter().collect(),
+                    _ => vec![inner.as_ref()],
+                };
+
+                for local in locals {
+                    if let ExpressionKind::Local { name, slot, .. } = &local.kind {
+                        if slots.len() <= *slot {
+                            slots.resize(slot + 1, String::new());
                         }
+                        slots[*slot] = name.clone();
                     }
-                    Expression::List(id_exprs) => {
-                        for id_expr in id_exprs {
-                            match id_expr {
-                                Expression::Identifier(id) => {
-                                    let id = sanitize_name(id);
-                                    if !names.contains_key(&id) {
-                                        log::debug!("Adding name: {} @ {}", id, names.len());
-                                        names.insert(id.clone(), names.len());
-                                    }
-                                }
-                                _ => panic!(
-                                    "Unexpected @ expression when collecting names: {}",
-                                    expr
-                                ),
-                            }
-                        }
-                    }
-                    Expression::Literal(Value::Number(Number::Integer(_))) => {} // ignore numeric @ expressions
-                    _ => panic!("Unexpected @ expression when collecting names: {}", expr),
                 }
             }
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                for child in children {
+                    collect_slots_expr(child, slots);
+                }
+            }
+            _ => {}
         }
     }
 
-    collect_names_expr(ast, &mut names);
-    names
+    for expr in body {
+        collect_slots_expr(expr, &mut slots);
+    }
+    slots
+}
+
+/// Check if a program uses break or continue anywhere
+fn uses_loop_control(expr: &Expression) -> bool {
+    match &expr.kind {
+        ExpressionKind::Builtin(name) => name == "break" || name == "continue",
+        ExpressionKind::Block { children, .. }
+        | ExpressionKind::List(children)
+        | ExpressionKind::Group(children) => children.iter().any(uses_loop_control),
+        _ => false,
+    }
 }
 
 /// Compile the AST into C code
 pub fn compile(ast: Expression) -> String {
+    compile_with_profile(ast, false)
+}
+
+/// Compile the AST into C code, optionally counting calls and time in each named block and builtin
+///
+/// With profile set, the program prints a table of the counts to stderr when it exits
+pub fn compile_with_profile(ast: Expression, profile: bool) -> String {
     let mut template = include_str!("../compile_c_includes/template.c").to_string();
 
     // Debug flag
@@ -153,32 +116,11 @@ pub fn compile(ast: Expression) -> String {
         }
     }
 
-    // Generate the NAMES
-    {
-        let names = collect_names(&ast);
-        log::debug!("collected names: {:?}", names);
-
-        let mut names_block = String::new();
-
-        for (name, index) in names.iter() {
-            names_block.push_str(&format!("#define NAME_{name} {index}\n").to_string());
-        }
-        names_block.push('\n');
-
-        for (name, index) in names.iter() {
-            names_block.push_str(&format!("#define NAME_INDEX_{index} {name}\n").to_string());
-        }
-        names_block.push('\n');
-
-        names_block.push_str("char* get_name(uint32_t index) {\n");
-        names_block.push_str("\tswitch (index) {\n");
-        for (name, index) in names.iter() {
-            names_block.push_str(&format!("\t\tcase {index}: return \"{name}\";\n").to_string());
-        }
-        names_block.push_str("\t\tdefault:\n\t\t\tfprintf(stderr, \"Undefined name for index %u\", index);\n\t\t\texit(1);\n");
-        names_block.push_str("\t}\n}\n");
-
-        template = template.replace("/*{NAMES}*/", &names_block);
+    // Profile flag
+    if profile {
+        template = template.replace("/*{PROFILE}*/", "#define PROFILE 1");
+    } else {
+        template = template.replace("/*{PROFILE}*/", "// #define PROFILE 1");
     }
 
     // Generate the BLOCKS
@@ -187,8 +129,11 @@ pub fn compile(ast: Expression) -> String {
             /// Helper function to compile a specific block to be output later
             fn compile_block(
                 arity: (usize, usize),
+                span: &Span,
                 body: &Vec<Expression>,
                 blocks: &mut Vec<Vec<String>>,
+                control: bool,
+                profile: bool,
             ) -> usize {
                 log::debug!("compile_block({arity:?}, {body:?})");
 
@@ -205,164 +150,158 @@ pub fn compile(ast: Expression) -> String {
                         .collect::<Vec<String>>()
                         .join(" ")
                 ));
+                // Create a scope for this block with a slot for each name bound in it
+                let slots = collect_slots(body);
+                lines.push(format!(
+                    "    Value *slots[{}] = {{NULL}};",
+                    slots.len().max(1)
+                ));
+                lines.push(format!(
+                    "    static char *names[] = {{{}}};",
+                    if slots.is_empty() {
+                        "NULL".to_string()
+                    } else {
+                        slots
+                            .iter()
+                            .map(|name| format!("{name:?}"))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    }
+                ));
+                lines.push(format!(
+                    "    Scope block_scope = {{.parent = parent, .id = next_scope_id++, .size = {}, .slots = slots, .names = names}};",
+                    slots.len()
+                ));
+                lines.push("    Scope *scope = &block_scope;".to_string());
+                lines.push(format!("    call_enter({:?}, scope);", span.to_string()));
+                lines.push(format!(
+                    "
+    #ifdef DEBUG
+    fprintf(stderr, \"[DEBUG] block_{index} called --\");
+    stack_dump(scope);
+    #endif"
+                ));
+
                 lines.push(format!(
                     "\n    // Store the current stack pointer with arity_in={arity_in}"
                 ));
+                lines.push(format!(
+                    "    assert_arity_in({:?}, {arity_in});",
+                    span.to_string()
+                ));
                 lines.push(format!("    *(++frame_ptr) = (stack_ptr - {arity_in});\n"));
+                if profile {
+                    lines.push("    size_t profile_mark = profile_depth;".to_string());
+                }
 
                 // Compile the block itself
+                // If the program uses break or continue, return early after anything that might have called one
                 for expr in body {
-                    for line in compile_expr(expr.clone(), blocks) {
+                    for line in compile_expr(expr, blocks, control, profile) {
                         lines.push(line);
                     }
+                    if control
+                        && matches!(
+                            expr.kind,
+                            ExpressionKind::Builtin(_) | ExpressionKind::Local { .. }
+                        )
+                    {
+                        lines.push("    if (loop_control) goto block_return;".to_string());
+                    }
                 }
 
                 // Pop the block off the stack
                 lines.push(format!(
-                    "    // Pop the block off the stack, preserving arity_out={arity_out} values"
+                    "block_return:\n    // Pop the block off the stack, preserving arity_out={arity_out} values"
+                ));
+                if profile {
+                    // Builtins that break or continue jump here without returning
+                    lines.push("    profile_unwind(profile_mark);".to_string());
+                }
+                lines.push(format!(
+                    "    assert_arity_out({:?}, {arity_out});",
+                    span.to_string()
                 ));
                 lines.push(format!(
                     "    Value* return_ptr = (stack_ptr - {arity_out});"
                 ));
-                lines.push(format!("    stack_ptr =  *(frame_ptr--);"));
+                lines.push("    stack_ptr =  *(frame_ptr--);".to_string());
                 for _ in 0..arity_out {
-                    lines.push(format!("    *(++stack_ptr) = *(++return_ptr);"));
+                    lines.push("    *(++stack_ptr) = *(++return_ptr);".to_string());
                 }
+                lines.push("    call_exit();".to_string());
 
                 blocks[index] = lines;
                 index
             }
 
             /// Compile a single expression into strings
-            fn compile_expr(expr: Expression, blocks: &mut Vec<Vec<String>>) -> Vec<String> {
+            fn compile_expr(
+                expr: &Expression,
+                blocks: &mut Vec<Vec<String>>,
+                control: bool,
+                profile: bool,
+            ) -> Vec<String> {
                 log::debug!("compile_expr({expr})");
 
                 let mut lines = vec![];
                 lines.push(format!("    // {expr}")); // TODO: Flag for verbose mode
 
-                match expr {
-                    Expression::Identifier(id) => {
-                        match id.as_str() {
-                            // Built in numeric functions
-                            "+" => numeric_binop!(lines, "+"),
-                            "-" => numeric_binop!(lines, "-"),
-                            "*" => numeric_binop!(lines, "*"),
-                            "/" => numeric_binop!(lines, "/"),
-
-                            // % only allows integers
-                            "%" => lines.push(
-                                include_str!("../compile_c_includes/builtins/mod.c").to_string(),
-                            ),
-
-                            // Built in numeric comparisons
-                            "<" => numeric_compare!(lines, "<"),
-                            "<=" => numeric_compare!(lines, "<="),
-                            "=" => numeric_compare!(lines, "=="),
-                            "!=" => numeric_compare!(lines, "!="),
-                            ">=" => numeric_compare!(lines, ">="),
-                            ">" => numeric_compare!(lines, ">"),
-
-                            // Built ins
-                            "and" => lines.push(
-                                include_str!("../compile_c_includes/builtins/and.c").to_string(),
-                            ),
-                            "or" => lines.push(
-                                include_str!("../compile_c_includes/builtins/or.c").to_string(),
-                            ),
-                            "xor" => lines.push(
-                                include_str!("../compile_c_includes/builtins/xor.c").to_string(),
-                            ),
-                            "nand" => lines.push(
-                                include_str!("../compile_c_includes/builtins/nand.c").to_string(),
-                            ),
-                            "not" => lines.push(
-                                include_str!("../compile_c_includes/builtins/not.c").to_string(),
-                            ),
-                            "read" => lines.push(
-                                include_str!("../compile_c_includes/builtins/read.c").to_string(),
-                            ),
-                            "write" => lines.push(
-                                include_str!("../compile_c_includes/builtins/write.c").to_string(),
-                            ),
-                            "writeln" => {
-                                lines.push(
-                                    include_str!("../compile_c_includes/builtins/write.c")
-                                        .to_string(),
-                                );
-                                lines.push("printf(\"\\n\");".to_string());
-                            }
-                            "newline" => lines.push("printf(\"\\n\");".to_string()),
-                            "loop" => lines.push(
-                                include_str!("../compile_c_includes/builtins/loop.c").to_string(),
-                            ),
-                            "if" => lines.push(
-                                include_str!("../compile_c_includes/builtins/if.c").to_string(),
-                            ),
-                            "when" => lines.push(
-                                include_str!("../compile_c_includes/builtins/when.c").to_string(),
-                            ),
-                            "cond" => lines.push(
-                                include_str!("../compile_c_includes/builtins/cond.c").to_string(),
-                            ),
-                            "to_float" => lines.push(
-                                include_str!("../compile_c_includes/builtins/to_float.c")
-                                    .to_string(),
-                            ),
-                            "to_int" => lines.push(
-                                include_str!("../compile_c_includes/builtins/to_int.c").to_string(),
-                            ),
-                            "make-stack" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-new.c")
-                                    .to_string(),
-                            ),
-                            "generate-stack" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-generate.c")
-                                    .to_string(),
-                            ),
-                            "stack-ref" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-ref.c")
-                                    .to_string(),
-                            ),
-                            "stack-set!" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-set.c")
-                                    .to_string(),
-                            ),
-                            "stack-push!" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-push.c")
-                                    .to_string(),
-                            ),
-                            "stack-pop!" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-pop.c")
-                                    .to_string(),
-                            ),
-                            "stack-size" => lines.push(
-                                include_str!("../compile_c_includes/builtins/stack-size.c")
-                                    .to_string(),
+                match &expr.kind {
+                    ExpressionKind::Builtin(id) => {
+                        if profile {
+                            lines.push(format!("    PROFILE_ENTER({id:?}, \"builtin\")"));
+                        }
+                        match builtins::lookup(id).and_then(|b| b.c) {
+                            Some(CCode::Snippet(code)) => lines.push(code.to_string()),
+                            Some(CCode::NumericBinop(op)) => lines.push(numeric_binop(op)),
+                            Some(CCode::NumericCompare(op)) => lines.push(numeric_compare(op)),
+                            Some(CCode::SpannedSnippet(code)) => lines.push(
+                                code.replace("SPAN", &format!("{:?}", expr.span.to_string())),
                             ),
-
-                            // Attempt to lookup in names table
-                            id => {
-                                let id = sanitize_name(id);
-                                lines.push(format!(
-                                    "
+                            None => panic!("Builtin {} is not supported when compiling to C", id),
+                        }
+                        if profile {
+                            lines.push("    profile_exit();".to_string());
+                        }
+                    }
+                    ExpressionKind::Local { name, depth, slot } => {
+                        let site = expr.span.to_string();
+                        let call = if profile {
+                            format!(
+                                "calling_name = {name:?};
+                calling_site = {site:?};
+                PROFILE_ENTER({name:?}, \"block\")
+                call_block(v->as_block);
+                profile_exit();"
+                            )
+                        } else {
+                            format!(
+                                "calling_name = {name:?};
+                calling_site = {site:?};
+                call_block(v->as_block);"
+                            )
+                        };
+                        lines.push(format!(
+                            "
         {{
-            Value* v = names_lookup(names, NAME_{id});
+            Value* v = scope_lookup(scope, {depth}, {slot});
             if (v->type == TAG_BLOCK) {{
-                void *f = v->as_block;
-                ((void (*)(Name*))f)(names);
+                {call}
             }} else {{
                 *(++stack_ptr) = *v;
             }}
         }}
                     "
-                                ));
-                            }
-                        }
+                        ));
+                    }
+                    ExpressionKind::Identifier(id) => {
+                        panic!("Unresolved identifier when compiling: {}", id)
                     }
-                    Expression::DottedIdentifier(ids) => {
+                    ExpressionKind::DottedIdentifier(ids) => {
                         unimplemented!("compile_expr for dotted identifiers: {:?}", ids)
                     }
-                    Expression::Literal(value) => {
+                    ExpressionKind::Literal(value) => {
                         let (tag, field, value) = match value {
                             // TODO: additional numeric tyhpes
                             Value::Number(Number::Integer(v)) => {
@@ -371,8 +310,9 @@ pub fn compile(ast: Expression) -> String {
                             Value::Number(Number::Rational { .. }) => {
                                 unimplemented!()
                             }
+                            // Debug always writes a float literal (1.0 or 1e20), Display can write an integer
                             Value::Number(Number::Float(v)) => {
-                                ("TAG_NUMBER_FLOAT", "float", v.to_string())
+                                ("TAG_NUMBER_FLOAT", "float", format!("{v:?}"))
                             }
                             Value::Number(Number::Complex { .. }) => {
                                 unimplemented!()
@@ -394,25 +334,30 @@ pub fn compile(ast: Expression) -> String {
     "
                         ));
                     }
-                    Expression::Block(ref body) => {
-                        let arity = calculate_arity(&expr);
-                        let index = compile_block(arity, body, blocks);
+                    ExpressionKind::Block { children: body, .. } => {
+                        let arity = calculate_arity(expr);
+                        let index =
+                            compile_block(arity, &expr.span, body, blocks, control, profile);
+                        let (arity_in, arity_out) = arity;
+                        let site = expr.span.to_string();
+                        // Blocks that don't use names from this one don't need its scope, so can outlive it
+                        let scope = if captures(body) { "scope" } else { "NULL" };
                         lines.push(format!(
                             "
         {{
-            Value v = {{.type=TAG_BLOCK, .as_block=(void*)block_{index}}};
+            Value v = {{.type=TAG_BLOCK, .as_block={{.call=block_{index}, .scope={scope}, .scope_depth=call_depth - 1, .scope_id=scope->id, .site={site:?}, .arity_in={arity_in}, .arity_out={arity_out}}}}};
             *(++stack_ptr) = v;
         }}
     "
                         ));
                     }
-                    Expression::List(values) => {
+                    ExpressionKind::List(values) => {
                         lines.push("\t{{".to_string());
                         lines.push(
                             "\t\tValue s = {.type=TAG_STACK, .as_stack=vs_init()};".to_string(),
                         );
                         for value in values {
-                            for line in compile_expr(value.clone(), blocks) {
+                            for line in compile_expr(value, blocks, control, profile) {
                                 lines.push(line);
                             }
                             lines.push(
@@ -426,38 +371,35 @@ pub fn compile(ast: Expression) -> String {
                         lines.push("\t\t*(++stack_ptr) = s;".to_string());
                         lines.push("\n\t}}".to_string());
                     }
-                    Expression::Group(exprs) => {
+                    ExpressionKind::Group(exprs) => {
                         for expr in exprs {
-                            for line in compile_expr(expr, blocks) {
+                            for line in compile_expr(expr, blocks, control, profile) {
                                 lines.push(line);
                             }
                         }
                     }
-                    Expression::At(expr) => {
-                        match expr.as_ref() {
-                            Expression::Identifier(id) => {
-                                let id = sanitize_name(id);
+                    ExpressionKind::At(expr) => {
+                        match &expr.kind {
+                            ExpressionKind::Local { slot, .. } => {
                                 lines.push(format!(
                                     "
         {{
             Value *p = stack_ptr;
-            names = names_bind(names, NAME_{id}, p);
+            scope_bind(scope, {slot}, p);
         }}
     "
                                 ));
                             }
-                            Expression::List(id_exprs) => {
+                            ExpressionKind::List(id_exprs) => {
                                 let id_count = id_exprs.len();
                                 for (i, id_expr) in id_exprs.iter().enumerate() {
-                                    match id_expr {
-                                        Expression::Identifier(id) => {
-                                            let id = sanitize_name(id);
-
+                                    match &id_expr.kind {
+                                        ExpressionKind::Local { slot, .. } => {
                                             lines.push(format!(
                                                 "
         {{ 
             Value *p = (stack_ptr - {id_count} + {i} + 1);
-            names = names_bind(names, NAME_{id}, p);
+            scope_bind(scope, {slot}, p);
         }}
     "
                                             ));
@@ -469,20 +411,19 @@ pub fn compile(ast: Expression) -> String {
                                     }
                                 }
                             }
-                            Expression::Literal(Value::Number(Number::Integer(_))) => {} // ignore numeric @ expressions
+                            ExpressionKind::Literal(Value::Number(Number::Integer(_))) => {} // ignore numeric @ expressions
                             _ => panic!("Unexpected @ expression when compiling: {}", expr),
                         }
                     }
-                    Expression::Bang(ref v) => {
-                        match v.as_ref() {
-                            Expression::Literal(Value::Number(Number::Integer(_))) => {} // Used only for arity out expressions
-                            Expression::Identifier(id) => {
-                                let id = sanitize_name(id);
+                    ExpressionKind::Bang(v) => {
+                        match &v.kind {
+                            ExpressionKind::Literal(Value::Number(Number::Integer(_))) => {} // Used only for arity out expressions
+                            ExpressionKind::Local { depth, slot, .. } => {
                                 lines.push(format!(
                                     "
         {{ 
             Value *v = stack_ptr--;
-            names_update(names, NAME_{id}, v);
+            scope_update(scope, {depth}, {slot}, v);
         }}
     "
                                 ));
@@ -490,12 +431,12 @@ pub fn compile(ast: Expression) -> String {
                             _ => panic!("Unexpected ! expression when compiling: {}", expr),
                         }
                     }
-                    Expression::Dollar(expr) => match expr.as_ref() {
-                        Expression::Identifier(id) => {
+                    ExpressionKind::Dollar(expr) => match &expr.kind {
+                        ExpressionKind::Local { depth, slot, .. } => {
                             lines.push(format!(
                                 "
         {{
-            Value* v = names_lookup(names, NAME_{id});
+            Value* v = scope_lookup(scope, {depth}, {slot});
             *(++stack_ptr) = *v;
         }}
             "
@@ -510,9 +451,10 @@ pub fn compile(ast: Expression) -> String {
 
             // Compile the top level expression
             let mut blocks = vec![];
-            match ast {
-                Expression::Group(body) => {
-                    compile_block((0, 0), &body, &mut blocks);
+            let control = uses_loop_control(&ast);
+            match ast.kind {
+                ExpressionKind::Group(body) => {
+                    compile_block((0, 0), &ast.span, &body, &mut blocks, control, profile);
                 }
                 _ => panic!("Unexpected top level expression: {:?}", ast),
             }
@@ -525,7 +467,7 @@ pub fn compile(ast: Expression) -> String {
         // Forward declarations
         {
             for (i, _) in blocks.iter().enumerate() {
-                block_block.push_str(&format!("void block_{i}(Name *block_names);\n").to_string());
+                block_block.push_str(&format!("void block_{i}(Scope *parent);\n").to_string());
             }
         }
 
@@ -539,14 +481,7 @@ pub fn compile(ast: Expression) -> String {
 
                 block_block.push_str(&format!(
                     "
-void block_{i}(Name *block_names) {{
-    if (block_names != NULL) block_names->boundary = true;
-    Name* names = block_names;
-
-    #ifdef DEBUG
-    fprintf(stderr, \"[DEBUG] block_{i} called --\");
-    stack_dump(names);
-    #endif
+void block_{i}(Scope *parent) {{
 "
                 ));
 
@@ -561,15 +496,8 @@ void block_{i}(Name *block_names) {{
                     "
     #ifdef DEBUG
     fprintf(stderr, \"[DEBUG] block_{i} returning --\");
-    stack_dump(names);
+    stack_dump(scope);
     #endif
-
-    // Free names bound in this block
-    while (names != NULL && block_names != names) {{
-        Name *next = names->prev;
-        free(names);
-        names = next;
-    }}
 }}
 "
                 ));
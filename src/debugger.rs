@@ -0,0 +1,362 @@
+//! An interactive debugger for the VM, stepping through a program one expression at a time
+//!
+//! Lines are numbered from 1 (as in an editor) in commands and output
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+
+use stacklang::error::RuntimeError;
+use stacklang::types::{Expression, ExpressionKind};
+use stacklang::{Event, VM};
+
+const HELP: &str = "\
+commands:
+  s, step          run until the next expression (stepping into blocks)
+  n, next          run until the next expression in this block (stepping over blocks)
+  o, out           run until the block this expression is in returns
+  c, continue      run until the next breakpoint
+  b, break LINE    stop whenever a line is reached
+  d, delete LINE   remove a breakpoint
+  p, stack         print the stack, with | between frames and @ for names
+  e, eval EXPR     evaluate an expression in the current block and print what it returns
+  l, list          print the lines around the current one
+  q, quit          stop the program
+  h, help          print this message
+an empty line repeats the last command";
+
+/// When to stop next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // At the next expression
+    Step,
+    // At the next expression in a block no deeper than this
+    Next(usize),
+    // At the next expression in a block shallower than this
+    Out(usize),
+    // Only at breakpoints
+    Continue,
+}
+
+pub struct Debugger {
+    // The file being debugged (as named in spans) and its lines
+    name: Option<String>,
+    lines: Vec<String>,
+    // The lines of imported files, read when the program first stops in them
+    imported: HashMap<String, Vec<String>>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    mode: Mode,
+    breakpoints: BTreeSet<usize>,
+    // The line and depth of the last expression, breakpoints only stop when this changes
+    last: Option<(usize, usize)>,
+    last_command: String,
+    /// Set once the user quits, the program is stopped with an error
+    pub quit: bool,
+}
+
+impl Debugger {
+    /// Create a debugger for a program, reading commands from input
+    ///
+    /// The debugger stops before the first expression, lines and breakpoints are in the file with this name
+    pub fn new(
+        name: Option<String>,
+        source: &str,
+        input: Box<dyn BufRead>,
+        output: Box<dyn Write>,
+    ) -> Self {
+        Debugger {
+            name,
+            lines: source.lines().map(String::from).collect(),
+            imported: HashMap::new(),
+            input,
+            output,
+            mode: Mode::Step,
+            breakpoints: BTreeSet::new(),
+            last: None,
+            last_command: String::new(),
+            quit: false,
+        }
+    }
+
+    /// Called as the VM runs, stopping before expressions, see VM::set_hook
+    pub fn event(&mut self, vm: &mut VM, event: Event) -> Result<(), RuntimeError> {
+        if self.quit {
+            return Err(RuntimeError::new("quit from the debugger"));
+        }
+
+        let Event::Evaluate(expression) = event else {
+            return Ok(());
+        };
+
+        // Groups are the bodies of blocks (and the whole program), stop at what's in them instead
+        if let ExpressionKind::Group(_) = expression.kind {
+            return Ok(());
+        }
+
+        // Expressions from imported files can be stepped through, but breakpoints are only in this one
+        let depth = vm.stack().depth();
+        let line = expression.span.row + 1;
+        let imported = expression.span.name != self.name;
+        let moved = !imported && self.last != Some((line, depth));
+        if !imported {
+            self.last = Some((line, depth));
+        }
+
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(d) => depth <= d,
+            Mode::Out(d) => depth < d,
+            Mode::Continue => false,
+        } || (moved && self.breakpoints.contains(&line));
+        if !stop {
+            return Ok(());
+        }
+
+        self.show(expression);
+        self.prompt(vm, depth)
+    }
+
+    /// Write where the program is stopped, highlighting the expression
+    fn show(&mut self, expression: &Expression) {
+        let span = &expression.span;
+        let (prefix, lines) = match &span.name {
+            Some(name) if span.name != self.name => {
+                let lines = self.imported.entry(name.clone()).or_insert_with(|| {
+                    let source = std::fs::read_to_string(name).unwrap_or_default();
+                    source.lines().map(String::from).collect()
+                });
+                (format!("{} line {}: ", name, span.row + 1), &*lines)
+            }
+            _ => (format!("line {}: ", span.row + 1), &self.lines),
+        };
+
+        let text = lines.get(span.row).cloned().unwrap_or_default();
+        let _ = writeln!(self.output, "{}{}", prefix, text);
+        let _ = writeln!(
+            self.output,
+            "{}{}",
+            " ".repeat(prefix.len() + span.column),
+            "^".repeat(span.length.max(1))
+        );
+    }
+
+    /// Read and run commands until one continues the program
+    fn prompt(&mut self, vm: &mut VM, depth: usize) -> Result<(), RuntimeError> {
+        loop {
+            let _ = write!(self.output, "(debug) ");
+            let _ = self.output.flush();
+
+            let mut command = String::new();
+            match self.input.read_line(&mut command) {
+                // Out of commands, run the rest of the program
+                Ok(0) | Err(_) => {
+                    let _ = writeln!(self.output);
+                    self.mode = Mode::Continue;
+                    self.breakpoints.clear();
+                    return Ok(());
+                }
+                Ok(_) => {}
+            }
+
+            let mut command = command.trim().to_string();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            }
+            self.last_command = command.clone();
+
+            let (name, argument) = match command.split_once(char::is_whitespace) {
+                Some((name, argument)) => (name, argument.trim()),
+                None => (command.as_str(), ""),
+            };
+            let line = argument.parse::<usize>();
+
+            match name {
+                "s" | "step" => {
+                    self.mode = Mode::Step;
+                    return Ok(());
+                }
+                "n" | "next" => {
+                    self.mode = Mode::Next(depth);
+                    return Ok(());
+                }
+                "o" | "out" => {
+                    self.mode = Mode::Out(depth);
+                    return Ok(());
+                }
+                "c" | "continue" => {
+                    self.mode = Mode::Continue;
+                    return Ok(());
+                }
+                "b" | "break" => match line {
+                    Ok(line) if line >= 1 && line <= self.lines.len() => {
+                        self.breakpoints.insert(line);
+                        let _ = writeln!(self.output, "breakpoint at line {}", line);
+                    }
+                    _ => {
+                        let _ = writeln!(
+                            self.output,
+                            "expected a line from 1 to {}",
+                            self.lines.len()
+                        );
+                    }
+                },
+                "d" | "delete" => match line {
+                    Ok(line) if self.breakpoints.remove(&line) => {
+                        let _ = writeln!(self.output, "removed breakpoint at line {}", line);
+                    }
+                    _ => {
+                        let _ = writeln!(self.output, "no breakpoint at line {:?}", argument);
+                    }
+                },
+                "p" | "stack" => {
+                    let _ = writeln!(self.output, "depth {}: {}", depth, vm.stack());
+                }
+                "e" | "eval" => match vm.evaluate_in_scope(argument) {
+                    Ok(values) => {
+                        let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                        let _ = writeln!(self.output, "[{}]", values.join(", "));
+                    }
+                    Err(e) => {
+                        let _ = writeln!(self.output, "error: {}", e);
+                    }
+                },
+                "l" | "list" => {
+                    let current = self.last.map(|(line, _)| line).unwrap_or(1);
+                    let start = current.saturating_sub(3).max(1);
+                    let end = (current + 2).min(self.lines.len());
+                    for line in start..=end {
+                        let marker = match (line == current, self.breakpoints.contains(&line)) {
+                            (true, _) => ">",
+                            (false, true) => "*",
+                            _ => " ",
+                        };
+                        let _ = writeln!(
+                            self.output,
+                            "{} {:4} {}",
+                            marker,
+                            line,
+                            self.lines[line - 1]
+                        );
+                    }
+                }
+                "q" | "quit" => {
+                    self.quit = true;
+                    return Err(RuntimeError::new("quit from the debugger"));
+                }
+                "h" | "help" => {
+                    let _ = writeln!(self.output, "{}", HELP);
+                }
+                _ => {
+                    let _ = writeln!(self.output, "unknown command {:?}, try help", command);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Debugger;
+    use stacklang::lexer::tokenize;
+    use stacklang::parser::parse;
+    use stacklang::VM;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    // Output written by the debugger, shared with the test
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Run a program in the debugger with a script of commands, returning what it printed
+    fn debug(source: &str, commands: &str) -> String {
+        let output = Output::default();
+        let mut debugger = Debugger::new(
+            None,
+            source,
+            Box::new(std::io::Cursor::new(commands.to_string())),
+            Box::new(output.clone()),
+        );
+
+        let mut vm = VM::new();
+        vm.set_hook(move |vm, event| debugger.event(vm, event));
+        let _ = vm.evaluate(parse(tokenize(source.as_bytes())));
+
+        let output = output.0.borrow();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    const SOURCE: &str = "\
+{ @n
+  n 2 *
+} @double
+3 double
+4 double";
+
+    #[test]
+    fn test_step() {
+        let output = debug(SOURCE, "s\ns\ns\ns\ns\np\ne n 1 +\nq\n");
+        assert!(
+            output.starts_with("line 1: { @n\n        ^\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("line 4: 3 double\n          ^^^^^^\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("line 2:   n 2 *\n          ^\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("depth 1: [{1->1}@double | 3@n]"),
+            "{}",
+            output
+        );
+        assert!(output.contains("[4]"), "{}", output);
+        assert!(!output.contains("line 5"), "{}", output);
+    }
+
+    #[test]
+    fn test_next_and_out() {
+        // Step over the first call to double, then into the second one and back out
+        let source = format!("{}\n+", SOURCE);
+        let output = debug(&source, "n\nn\nn\nn\np\nn\ns\ns\no\np\nq\n");
+        assert!(output.contains("depth 0: [{1->1}@double, 6]"), "{}", output);
+        assert!(
+            output.contains("depth 0: [{1->1}@double, 6, 8]"),
+            "{}",
+            output
+        );
+        assert_eq!(output.matches("line 2:").count(), 1, "{}", output);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let output = debug(SOURCE, "b 2\nc\np\nc\np\nc\n");
+        assert!(output.contains("breakpoint at line 2"), "{}", output);
+        assert!(
+            output.contains("depth 1: [{1->1}@double | 3@n]"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("depth 1: [{1->1}@double, 6 | 4@n]"),
+            "{}",
+            output
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Differential testing, running the same program with the VM and C backends and comparing what they do
+
+use stacklang::golden::{self, Backend, Outcome};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Run a program with both backends, returning where they first differ (if they do)
+pub fn diff(stacklang: &Path, path: &Path, timeout: Duration) -> Result<Option<String>, String> {
+    let vm = golden::run(Command::new(stacklang), Backend::Vm, path, timeout)?;
+    let c = golden::run(Command::new(stacklang), Backend::C, path, timeout)?;
+    Ok(first_difference(&vm, &c))
+}
+
+/// Describe the first output line where two outcomes differ and if only one of them failed
+pub fn first_difference(vm: &Outcome, c: &Outcome) -> Option<String> {
+    let mut differences = vec![];
+
+    let describe = |output: Option<&str>| match output {
+        Some(output) => format!("{:?}", output),
+        None => "nothing".to_string(),
+    };
+    let mut vm_lines = vm.stdout.lines();
+    let mut c_lines = c.stdout.lines();
+    for line in 1.. {
+        match (vm_lines.next(), c_lines.next()) {
+            (None, None) => break,
+            (a, b) if a == b => continue,
+            (a, b) => {
+                differences.push(format!(
+                    "line {}: vm wrote {}, c wrote {}",
+                    line,
+                    describe(a),
+                    describe(b)
+                ));
+                break;
+            }
+        }
+    }
+
+    // Error messages differ between the backends, so they're only shown if exactly one failed
+    if (vm.status == 0) != (c.status == 0) {
+        differences.push(format!(
+            "vm exited with {}, c exited with {}\n--- vm stderr\n{}--- c stderr\n{}",
+            vm.status, c.status, vm.stderr, c.stderr
+        ));
+    }
+
+    if differences.is_empty() {
+        None
+    } else {
+        Some(differences.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::first_difference;
+    use stacklang::golden::Outcome;
+
+    fn outcome(stdout: &str, status: i32) -> Outcome {
+        Outcome {
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_first_difference() {
+        assert_eq!(
+            first_difference(&outcome("1\n2\n", 0), &outcome("1\n2\n", 0)),
+            None
+        );
+        assert_eq!(
+            first_difference(
+                &outcome("1\n8.100000000000001\n", 0),
+                &outcome("1\n8.100000\n", 0)
+            ),
+            Some("line 2: vm wrote \"8.100000000000001\", c wrote \"8.100000\"".to_string())
+        );
+        assert!(first_difference(&outcome("1\n", 0), &outcome("1.0\n", 0)).is_some());
+        assert!(first_difference(&outcome("[1, 2]\n", 0), &outcome("[1 2]\n", 0)).is_some());
+        assert_eq!(
+            first_difference(&outcome("1\n2\n", 0), &outcome("1\n", 0)),
+            Some("line 2: vm wrote \"2\", c wrote nothing".to_string())
+        );
+        assert!(first_difference(&outcome("1\n", 1), &outcome("1\n", 0))
+            .unwrap()
+            .starts_with("vm exited with 1, c exited with 0"));
+    }
+}
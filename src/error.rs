@@ -0,0 +1,296 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use crate::resolver::ResolveError;
+use crate::types::{Span, Value};
+
+/// Early exits from a loop body, raised by break and continue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Break,
+    Continue,
+}
+
+/// The kinds of errors raised while running a program, so handlers can tell them apart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A value had the wrong type
+    Type,
+    /// There weren't enough values on the stack
+    Stack,
+    /// An index was outside of a list
+    Index,
+    /// A key wasn't in a hash
+    Key,
+    /// A name couldn't be found or wasn't bound yet
+    Name,
+    /// A value had the right type but couldn't be used (parsing, empty lists, etc)
+    Value,
+    /// An assert or assert-eq failed
+    Assertion,
+    /// A value thrown by the program, see throw
+    Thrown,
+    /// A limit set on the VM was exceeded or a disabled builtin was called, see vm::Limits
+    Limit,
+    /// Anything else
+    Other,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorKind::Type => "type",
+            ErrorKind::Stack => "stack",
+            ErrorKind::Index => "index",
+            ErrorKind::Key => "key",
+            ErrorKind::Name => "name",
+            ErrorKind::Value => "value",
+            ErrorKind::Assertion => "assertion",
+            ErrorKind::Thrown => "thrown",
+            ErrorKind::Limit => "limit",
+            ErrorKind::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A block that was called and hasn't returned yet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Call {
+    /// The name the block was called by, or the builtin that called it (such as if or loop)
+    pub by: String,
+    /// Where the block was called by name, None if a builtin called it
+    pub site: Option<Span>,
+    /// Where the block is defined
+    pub block: Span,
+}
+
+impl Display for Call {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.site {
+            Some(site) => write!(f, "{} at {} (block at {})", self.by, site, self.block),
+            None => write!(f, "block at {} called by {}", self.block, self.by),
+        }
+    }
+}
+
+// Backtraces longer than this only show the calls at each end
+const BACKTRACE_ENDS: usize = 10;
+
+/// Where a runtime error was raised: the blocks that were called and the stack at the time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backtrace {
+    /// Outermost first
+    pub calls: Vec<Call>,
+    /// The stack (see Stack's Display), with | between frames and @ for names
+    pub stack: String,
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "stack: {}", self.stack)?;
+        if self.calls.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(f, "backtrace (most recent call first):")?;
+        let count = self.calls.len();
+        for (i, call) in self.calls.iter().rev().enumerate() {
+            if count > 2 * BACKTRACE_ENDS && i >= BACKTRACE_ENDS && i < count - BACKTRACE_ENDS {
+                if i == BACKTRACE_ENDS {
+                    writeln!(f, "  ... {} more calls", count - 2 * BACKTRACE_ENDS)?;
+                }
+                continue;
+            }
+            writeln!(f, "  {}", call)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error raised while running a program
+///
+/// break and continue are also returned as errors (with control set), so they pass up through
+/// any blocks and builtins until the enclosing loop handles them
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// The value passed to throw, if this error was thrown by the program
+    pub thrown: Option<Value>,
+    pub control: Option<Control>,
+    /// Where the error was raised, set by the VM as it leaves the block the error was raised in
+    pub backtrace: Option<Box<Backtrace>>,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError::of_kind(ErrorKind::Other, message)
+    }
+
+    pub fn of_kind(kind: ErrorKind, message: impl Into<String>) -> Self {
+        RuntimeError {
+            kind,
+            message: message.into(),
+            thrown: None,
+            control: None,
+            backtrace: None,
+        }
+    }
+
+    /// A break or continue, the message is used if there's no loop to handle it
+    pub fn control(control: Control) -> Self {
+        let name = match control {
+            Control::Break => "break",
+            Control::Continue => "continue",
+        };
+        RuntimeError {
+            control: Some(control),
+            ..RuntimeError::new(format!("{} outside of a loop", name))
+        }
+    }
+
+    /// A value thrown by the program, the message is used if nothing catches it
+    pub fn thrown(value: Value) -> Self {
+        RuntimeError {
+            thrown: Some(value.clone()),
+            ..RuntimeError::of_kind(ErrorKind::Thrown, format!("uncaught throw: {}", value))
+        }
+    }
+
+    /// The value passed to a try handler
+    ///
+    /// Thrown values are passed as is, anything else is a hash with the kind and message
+    pub fn to_value(&self) -> Value {
+        match &self.thrown {
+            Some(value) => value.clone(),
+            None => {
+                let mut hash = HashMap::new();
+                hash.insert("kind".to_string(), Value::from(self.kind.to_string()));
+                hash.insert("message".to_string(), Value::from(self.message.clone()));
+                Value::Hash(Rc::new(RefCell::new(hash)))
+            }
+        }
+    }
+}
+
+// Errors are the same no matter where they were raised, so backtraces aren't compared
+impl PartialEq for RuntimeError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.message == other.message
+            && self.thrown == other.thrown
+            && self.control == other.control
+    }
+}
+
+impl Eq for RuntimeError {}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Source that couldn't be tokenized or parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/// Any error that can happen while evaluating a program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Source that couldn't be tokenized or parsed
+    Syntax(SyntaxError),
+    /// Names that couldn't be resolved, found before the program is run
+    Resolve(Vec<ResolveError>),
+    /// An error while the program was running
+    Runtime(RuntimeError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Syntax(error) => write!(f, "{}", error),
+            Error::Resolve(errors) => write!(
+                f,
+                "{}",
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            Error::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error {
+    /// Where a runtime error was raised, see VM::run
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match self {
+            Error::Runtime(error) => error.backtrace.as_deref(),
+            Error::Syntax(_) | Error::Resolve(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        Error::Runtime(error)
+    }
+}
+
+impl From<SyntaxError> for Error {
+    fn from(error: SyntaxError) -> Self {
+        Error::Syntax(error)
+    }
+}
+
+impl From<Vec<ResolveError>> for Error {
+    fn from(errors: Vec<ResolveError>) -> Self {
+        Error::Resolve(errors)
+    }
+}
+
+/// The message of a caught panic
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+/// Return early with a RuntimeError, formatted like panic!
+///
+/// The kind of error can be given first, fail!(Type, ...), otherwise it is ErrorKind::Other
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)*) => {
+        return Err($crate::error::RuntimeError::of_kind(
+            $crate::error::ErrorKind::$kind,
+            format!($($arg)*),
+        ))
+    };
+    ($($arg:tt)*) => {
+        return Err($crate::error::RuntimeError::new(format!($($arg)*)))
+    };
+}
+pub(crate) use fail;
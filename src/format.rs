@@ -0,0 +1,392 @@
+//! Formatting source code, re-emitting it from the AST with comments kept
+//!
+//! Line breaks (and single blank lines) between expressions are kept from the source
+//! Blocks and lists that were on one line stay on one line if they fit, otherwise their contents are indented
+//! The header of a block (@names and !N) always goes on the same line as its {
+
+use std::collections::HashMap;
+
+use crate::error::SyntaxError;
+use crate::lexer::{match_brackets, try_tokenize_named};
+use crate::parser::try_parse;
+use crate::types::{Expression, ExpressionKind, Token, Value};
+
+/// Lines longer than this are wrapped
+pub const WIDTH: usize = 100;
+
+const INDENT: &str = "  ";
+
+/// Format a program, the result is parsed to the same AST
+///
+/// name is used for the spans of syntax errors, see lexer::tokenize_named
+pub fn format(source: &str, name: Option<String>) -> Result<String, SyntaxError> {
+    let tokens = try_tokenize_named(source.as_bytes(), name)?;
+    let ast = try_parse(tokens.clone())?;
+
+    let mut formatter = Formatter::new(&tokens);
+    if let ExpressionKind::Group(children) = &ast.kind {
+        for child in children {
+            formatter.item(child);
+        }
+    }
+    formatter.comments_before((usize::MAX, usize::MAX));
+    formatter.newline();
+
+    Ok(formatter.out)
+}
+
+// A position in the source as (row, column)
+type Position = (usize, usize);
+
+fn position(expression: &Expression) -> Position {
+    (expression.span.row, expression.span.column)
+}
+
+struct Formatter<'a> {
+    // The source text of each token
+    texts: HashMap<Position, &'a str>,
+    // The closing bracket for each opening bracket
+    closers: HashMap<Position, Position>,
+    comments: Vec<&'a Token>,
+    next_comment: usize,
+
+    out: String,
+    // The line being written and its indentation
+    line: String,
+    line_indent: usize,
+    indent: usize,
+    // The next word is written without a space before it
+    glue: bool,
+    // A comment was written, so the line has to end
+    broken: bool,
+    // The source row of the last thing written
+    last_row: Option<usize>,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(tokens: &'a [Token]) -> Self {
+        let (closers, _) = match_brackets(tokens);
+        let mut texts = HashMap::new();
+        let mut comments = vec![];
+
+        for token in tokens {
+            if token.is_comment() {
+                comments.push(token);
+            }
+            texts.insert((token.span.row, token.span.column), token.token.as_str());
+        }
+
+        Formatter {
+            texts,
+            closers,
+            comments,
+            next_comment: 0,
+            out: String::new(),
+            line: String::new(),
+            line_indent: 0,
+            indent: 0,
+            glue: false,
+            broken: false,
+            last_row: None,
+        }
+    }
+
+    /// Write a single word, wrapping if it would go past the width
+    fn word(&mut self, text: &str, row: usize) {
+        let width = self.line_indent * INDENT.len() + self.line.len() + 1 + text.len();
+        if self.broken || (!self.glue && !self.line.is_empty() && width > WIDTH) {
+            self.newline();
+        }
+
+        if self.line.is_empty() {
+            self.line_indent = self.indent;
+        } else if !self.glue {
+            self.line.push(' ');
+        }
+        self.line.push_str(text);
+
+        self.glue = false;
+        self.last_row = Some(row);
+    }
+
+    /// End the current line (if anything is on it)
+    fn newline(&mut self) {
+        if !self.line.is_empty() {
+            self.out.push_str(&INDENT.repeat(self.line_indent));
+            self.out.push_str(&self.line);
+            self.out.push('\n');
+            self.line.clear();
+        }
+        self.glue = false;
+        self.broken = false;
+    }
+
+    /// Start a new line if row was after the last thing written, keeping (at most) one blank line
+    fn break_before(&mut self, row: usize) {
+        let Some(last) = self.last_row else {
+            return;
+        };
+
+        if row > last {
+            self.newline();
+        }
+        if row > last + 1 && !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    /// Write any comments before the given position
+    fn comments_before(&mut self, before: Position) {
+        while let Some(comment) = self.comments.get(self.next_comment).copied() {
+            let at = (comment.span.row, comment.span.column);
+            if at >= before {
+                break;
+            }
+            self.next_comment += 1;
+
+            // Comments on the same line as code stay there, others get a line of their own
+            if self.last_row != Some(at.0) || self.line.is_empty() {
+                self.break_before(at.0);
+                self.newline();
+            }
+            self.broken = false;
+            self.word(&comment.token, at.0);
+            self.broken = true;
+        }
+    }
+
+    /// Write an expression within a sequence split over lines
+    fn item(&mut self, expression: &Expression) {
+        self.comments_before(position(expression));
+        self.break_before(expression.span.row);
+        self.expression(expression);
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match &expression.kind {
+            ExpressionKind::Block { children, .. } => self.sequence(expression, children, "{", "}"),
+            ExpressionKind::List(children) => self.sequence(expression, children, "[", "]"),
+            ExpressionKind::Group(children) => self.sequence(expression, children, "(", ")"),
+            ExpressionKind::At(inner) => self.prefixed("@", expression, inner),
+            ExpressionKind::Bang(inner) => self.prefixed("!", expression, inner),
+            ExpressionKind::Dollar(inner) => self.prefixed("$", expression, inner),
+            _ => {
+                let text = self.text(expression);
+                self.word(&text, expression.span.row);
+            }
+        }
+    }
+
+    fn prefixed(&mut self, prefix: &str, expression: &Expression, inner: &Expression) {
+        self.word(prefix, expression.span.row);
+        self.glue = self.gluable(inner);
+        self.expression(inner);
+    }
+
+    // Whether an expression can follow a prefix without a space and still be read as two tokens
+    fn gluable(&self, expression: &Expression) -> bool {
+        match &expression.kind {
+            ExpressionKind::Block { .. } | ExpressionKind::List(_) | ExpressionKind::Group(_) => {
+                true
+            }
+            _ => self
+                .text(expression)
+                .starts_with(|c: char| c.is_ascii_alphanumeric()),
+        }
+    }
+
+    // The source text of a single token expression
+    fn text(&self, expression: &Expression) -> String {
+        match self.texts.get(&position(expression)) {
+            Some(text) => text.to_string(),
+            None => expression.to_string(),
+        }
+    }
+
+    fn sequence(
+        &mut self,
+        expression: &Expression,
+        children: &[Expression],
+        open: &str,
+        close: &str,
+    ) {
+        let row = expression.span.row;
+        let closer = self.closers.get(&position(expression)).copied();
+        let is_block = open == "{";
+
+        if let Some(inline) = self.inline(expression, closer) {
+            let indent = if self.line.is_empty() {
+                self.indent
+            } else {
+                self.line_indent
+            };
+            let width = indent * INDENT.len() + self.line.len() + 1 + inline.len();
+            if width <= WIDTH {
+                self.word(&inline, closer.map_or(row, |(row, _)| row));
+                return;
+            }
+        }
+
+        self.word(open, row);
+
+        // Blocks keep their header on the first line
+        let mut body = children;
+        if is_block {
+            while let Some((first, rest)) = body.split_first() {
+                if !is_header(first) {
+                    break;
+                }
+                self.expression(first);
+                body = rest;
+            }
+        }
+
+        self.indent += 1;
+        for (index, child) in body.iter().enumerate() {
+            if index == 0 {
+                // The body always starts on a new line
+                self.comments_before(position(child));
+                self.break_before(child.span.row);
+                self.newline();
+                self.expression(child);
+            } else {
+                self.item(child);
+            }
+        }
+        self.comments_before(closer.unwrap_or((usize::MAX, usize::MAX)));
+        self.indent -= 1;
+
+        self.newline();
+        self.word(close, closer.map_or(row, |(row, _)| row));
+    }
+
+    /// The whole sequence on one line, if it was on one line in the source without comments
+    fn inline(&self, expression: &Expression, closer: Option<Position>) -> Option<String> {
+        let closer = closer?;
+        if closer.0 != expression.span.row {
+            return None;
+        }
+
+        let start = position(expression);
+        if self.comments.iter().any(|comment| {
+            let at = (comment.span.row, comment.span.column);
+            start < at && at < closer
+        }) {
+            return None;
+        }
+
+        Some(self.flat(expression))
+    }
+
+    // Format an expression on a single line
+    fn flat(&self, expression: &Expression) -> String {
+        let join = |children: &[Expression]| {
+            children
+                .iter()
+                .map(|child| self.flat(child))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let prefixed = |prefix: &str, inner: &Expression| {
+            let space = if self.gluable(inner) { "" } else { " " };
+            format!("{}{}{}", prefix, space, self.flat(inner))
+        };
+
+        match &expression.kind {
+            ExpressionKind::Block { children, .. } if children.is_empty() => "{ }".to_string(),
+            ExpressionKind::Block { children, .. } => format!("{{ {} }}", join(children)),
+            ExpressionKind::List(children) => format!("[{}]", join(children)),
+            ExpressionKind::Group(children) => format!("({})", join(children)),
+            ExpressionKind::At(inner) => prefixed("@", inner),
+            ExpressionKind::Bang(inner) => prefixed("!", inner),
+            ExpressionKind::Dollar(inner) => prefixed("$", inner),
+            _ => self.text(expression),
+        }
+    }
+}
+
+// Names and arity at the start of a block: @name, @[names], @N or !N
+fn is_header(expression: &Expression) -> bool {
+    match &expression.kind {
+        ExpressionKind::At(_) => true,
+        ExpressionKind::Bang(inner) => {
+            matches!(inner.kind, ExpressionKind::Literal(Value::Number(_)))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::format;
+    use crate::golden::examples;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use std::path::Path;
+
+    #[test]
+    fn test_headers() {
+        let source = "{\n    @n\n  n 2 *\n} @double\n3 double\n";
+        assert_eq!(
+            format(source, None).unwrap(),
+            "{ @n\n  n 2 *\n} @double\n3 double\n"
+        );
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(
+            format("{   1 }  {n 1 - fact n *}  n 1 <=   if", None).unwrap(),
+            "{ 1 } { n 1 - fact n * } n 1 <= if\n"
+        );
+        assert_eq!(
+            format("@ [a b]  ! 0 $ fact @ -rot", None).unwrap(),
+            "@[a b] !0 $fact @ -rot\n"
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = "# header\n\n\n\n{ @n # the input\n  # double it\n  n 2 *\n  # done\n} @double # trailing\n";
+        assert_eq!(
+            format(source, None).unwrap(),
+            "# header\n\n{ @n # the input\n  # double it\n  n 2 *\n  # done\n} @double # trailing\n"
+        );
+    }
+
+    #[test]
+    fn test_wrap_lists() {
+        let items = (0..60).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        let formatted = format(&format!("[{}] sum", items), None).unwrap();
+        assert!(formatted.starts_with("[\n  0 1 2"));
+        assert!(formatted.ends_with("\n] sum\n"));
+        assert!(formatted.lines().all(|line| line.len() <= super::WIDTH));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        // Unclosed blocks aren't closed for you
+        assert!(format("{ 1 2\n3 writeln", None).is_err());
+    }
+
+    #[test]
+    fn test_examples() {
+        for path in examples(Path::new("examples")) {
+            let source = std::fs::read_to_string(&path).unwrap();
+
+            let formatted = format(&source, None).unwrap();
+            assert_eq!(
+                format(&formatted, None).unwrap(),
+                formatted,
+                "{} is not stable",
+                path.display()
+            );
+            assert_eq!(
+                parse(tokenize(formatted.as_bytes())).to_string(),
+                parse(tokenize(source.as_bytes())).to_string(),
+                "{} changed",
+                path.display()
+            );
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Fuzz targets for each stage, with a small mutation based driver so they can run with plain cargo
+//!
+//! Each target feeds arbitrary bytes to a stage, any panic is a crash
+//! Inputs start from a corpus of programs and are mutated at random, see fuzz
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Once;
+
+use crate::generate::Rng;
+use crate::lexer::try_tokenize_named;
+use crate::parser::try_parse;
+use crate::vm::{Limits, VM};
+
+/// The limits the evaluate target runs programs with, so that they finish quickly and don't read stdin
+pub fn limits() -> Limits {
+    Limits {
+        max_steps: Some(10_000),
+        max_stack_depth: Some(10_000),
+        max_call_depth: Some(1_000),
+        max_collection_size: Some(10_000),
+        disable_io: true,
+    }
+}
+
+/// The stages that can be fuzzed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Tokenize,
+    Parse,
+    Evaluate,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::Tokenize, Target::Parse, Target::Evaluate];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::Tokenize => "tokenize",
+            Target::Parse => "parse",
+            Target::Evaluate => "evaluate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Target> {
+        Target::ALL.into_iter().find(|target| target.name() == name)
+    }
+
+    /// Run a single input through the target, this panics if the input crashes it
+    ///
+    /// Errors returned by a stage are fine, only panics count
+    pub fn run(&self, data: &[u8]) {
+        match self {
+            Target::Tokenize => {
+                let _ = try_tokenize_named(data, None);
+            }
+            Target::Parse => {
+                if let Ok(tokens) = try_tokenize_named(data, None) {
+                    let _ = try_parse(tokens);
+                }
+            }
+            Target::Evaluate => {
+                if let Ok(ast) = try_tokenize_named(data, None).and_then(try_parse) {
+                    let mut vm = VM::new();
+                    vm.set_limits(limits());
+                    let _ = vm.evaluate(ast);
+                }
+            }
+        }
+    }
+}
+
+/// An input that crashed a target
+#[derive(Debug, Clone)]
+pub struct Crash {
+    /// Where the panic happened, crashes at the same place are treated as the same bug
+    pub location: String,
+    /// The panic message
+    pub message: String,
+    /// The smallest input found that crashes here
+    pub input: Vec<u8>,
+}
+
+thread_local! {
+    // Set while running a target on this thread, the last panic while it is set
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static LAST_PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+/// Record panics in targets instead of printing them, panics anywhere else are printed as usual
+fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(|catching| catching.get()) {
+                return previous(info);
+            }
+
+            let location = info
+                .location()
+                .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+                .unwrap_or_default();
+            let message = if let Some(s) = info.payload().downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = info.payload().downcast_ref::<String>() {
+                s.clone()
+            } else {
+                String::new()
+            };
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some((location, message)));
+        }));
+    });
+}
+
+/// Run a target on a single input, returning the location and message if it panics
+pub fn catch(target: Target, data: &[u8]) -> Option<(String, String)> {
+    install_panic_hook();
+
+    CATCHING.with(|catching| catching.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| target.run(data)));
+    CATCHING.with(|catching| catching.set(false));
+
+    match result {
+        Ok(()) => None,
+        Err(_) => LAST_PANIC
+            .with(|last| last.borrow_mut().take())
+            .or(Some(("<unknown>".to_string(), "<unknown>".to_string()))),
+    }
+}
+
+/// Load every file in a directory as a corpus entry, in sorted order
+pub fn load_corpus(dir: &Path) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter(|path| path.is_file())
+        .map(std::fs::read)
+        .collect()
+}
+
+// Fragments that are more likely to reach interesting code than random bytes
+const DICTIONARY: [&[u8]; 25] = [
+    b"{",
+    b"}",
+    b"[",
+    b"]",
+    b"(",
+    b")",
+    b"@",
+    b"!",
+    b"$",
+    b"@[a b]",
+    b"!2",
+    b"\"",
+    b"#",
+    b"-",
+    b".",
+    b"0",
+    b"-1",
+    b"1.5",
+    b"9999999999999999999999",
+    b"if",
+    b"loop",
+    b"apply",
+    b"pick",
+    b"\xff",
+    "é".as_bytes(),
+];
+
+/// Randomly change an input, possibly splicing in part of another corpus entry
+pub fn mutate(rng: &mut Rng, data: &[u8], corpus: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = data.to_vec();
+
+    for _ in 0..=rng.below(4) {
+        let at = rng.below(data.len() + 1);
+        match rng.below(6) {
+            // Replace a byte
+            0 if at < data.len() => data[at] = rng.next_u64() as u8,
+            // Remove a range
+            1 if at < data.len() => {
+                let end = (at + 1 + rng.below(8)).min(data.len());
+                data.drain(at..end);
+            }
+            // Duplicate a range
+            2 if at < data.len() => {
+                let end = (at + 1 + rng.below(16)).min(data.len());
+                let copy = data[at..end].to_vec();
+                data.splice(at..at, copy);
+            }
+            // Splice in part of another input
+            3 if !corpus.is_empty() => {
+                let other = rng.pick(corpus);
+                let start = rng.below(other.len() + 1);
+                let end = (start + rng.below(32)).min(other.len());
+                data.splice(at..at, other[start..end].iter().copied());
+            }
+            // Insert something from the dictionary
+            _ => {
+                let fragment = rng.pick(&DICTIONARY);
+                data.splice(at..at, fragment.iter().copied());
+            }
+        }
+    }
+
+    data
+}
+
+/// Fuzz a target, starting from a corpus and trying iterations mutated inputs
+///
+/// Returns one crash per location, with the smallest input that crashed there
+pub fn fuzz(target: Target, corpus: &[Vec<u8>], iterations: usize, seed: u64) -> Vec<Crash> {
+    let mut rng = Rng::new(seed);
+    let mut crashes: Vec<Crash> = vec![];
+
+    let inputs = corpus
+        .iter()
+        .cloned()
+        .chain((0..iterations).map(|_| {
+            let base = if corpus.is_empty() {
+                vec![]
+            } else {
+                rng.pick(corpus).clone()
+            };
+            mutate(&mut rng, &base, corpus)
+        }))
+        .collect::<Vec<_>>();
+
+    for input in inputs {
+        if let Some((location, message)) = catch(target, &input) {
+            match crashes.iter_mut().find(|crash| crash.location == location) {
+                Some(crash) if input.len() < crash.input.len() => {
+                    crash.message = message;
+                    crash.input = input;
+                }
+                Some(_) => {}
+                None => crashes.push(Crash {
+                    location,
+                    message,
+                    input,
+                }),
+            }
+        }
+    }
+
+    crashes
+}
+
+#[cfg(test)]
+mod test {
+    use super::{catch, fuzz, load_corpus, Target};
+    use std::path::Path;
+
+    #[test]
+    fn test_catch() {
+        assert_eq!(catch(Target::Tokenize, b"1 2 +"), None);
+        assert_eq!(catch(Target::Evaluate, b"{ } forever"), None);
+
+        // Invalid UTF-8 and a prefix at the end are errors, not crashes
+        assert_eq!(catch(Target::Tokenize, b"\xff"), None);
+        assert_eq!(catch(Target::Evaluate, b"1 @"), None);
+    }
+
+    #[test]
+    fn test_corpus_does_not_crash() {
+        let corpus = load_corpus(Path::new("fuzz/corpus")).unwrap();
+        assert!(!corpus.is_empty());
+
+        for target in [Target::Tokenize, Target::Parse] {
+            for input in &corpus {
+                assert_eq!(catch(target, input), None, "{}", target.name());
+            }
+        }
+    }
+
+    // These used to find crashes in the parser (a prefix at the end of the input) with this seed
+    #[test]
+    fn test_fuzz_parse() {
+        let corpus = vec![b"{ @n n 1 + } @inc 5 inc writeln".to_vec()];
+        let crashes = fuzz(Target::Parse, &corpus, 200, 1);
+        assert!(crashes.is_empty(), "{:?}", crashes);
+    }
+}
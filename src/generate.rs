@@ -0,0 +1,256 @@
+//! Generate random programs, for testing the backends against each other
+//!
+//! Programs only use features both the VM and C support and always write what they compute,
+//! so any difference in the output of the two is a bug in one of them.
+
+/// A small random number generator (xorshift), so that a seed always generates the same program
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Mix the seed (splitmix64) so that nearby seeds don't start in nearby states
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A random number in [0, n)
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// A random integer in [low, high]
+    pub fn between(&mut self, low: i64, high: i64) -> i64 {
+        low + self.below((high - low + 1) as usize) as i64
+    }
+
+    pub fn pick<'a, T>(&mut self, values: &'a [T]) -> &'a T {
+        &values[self.below(values.len())]
+    }
+}
+
+/// The types of values generated programs work with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Integer,
+    Float,
+    Boolean,
+    String,
+}
+
+const TYPES: [Type; 4] = [Type::Integer, Type::Float, Type::Boolean, Type::String];
+const WORDS: [&str; 6] = ["apple", "banana", "cherry", "hello world", "", "stack"];
+
+// Expressions nest at most this deep, which keeps integers from overflowing
+const MAX_DEPTH: usize = 3;
+
+/// Generates a single program, tracking which names are bound (and to what type) as it goes
+struct Generator {
+    rng: Rng,
+    names: Vec<(String, Type)>,
+    next_name: usize,
+}
+
+impl Generator {
+    fn fresh_name(&mut self) -> String {
+        self.next_name += 1;
+        format!("v{}", self.next_name)
+    }
+
+    fn statement(&mut self, out: &mut Vec<String>) {
+        match self.rng.below(6) {
+            // Bind a value to a new name, to be used by later expressions
+            0 => {
+                let t = *self.rng.pick(&TYPES);
+                let name = self.fresh_name();
+                out.push(format!("{} @{}", self.expression(t, 0), name));
+                self.names.push((name, t));
+            }
+            // Write the index of a loop with a value computed from it
+            1 => {
+                let name = self.fresh_name();
+                self.names.push((name.clone(), Type::Integer));
+                let body = self.expression(Type::Integer, 1);
+                self.names.pop();
+                out.push(format!(
+                    "{{ @{} {} writeln }} {} loop",
+                    name,
+                    body,
+                    self.rng.between(0, 4)
+                ));
+            }
+            // Write one of two values
+            2 => {
+                let t = *self.rng.pick(&TYPES);
+                out.push(format!(
+                    "{{ {} writeln }} {{ {} writeln }} {} if",
+                    self.expression(t, 1),
+                    self.expression(t, 1),
+                    self.expression(Type::Boolean, 1)
+                ));
+            }
+            // Write a value without a newline
+            3 => {
+                let t = *self.rng.pick(&TYPES);
+                out.push(format!("{} write newline", self.expression(t, 0)));
+            }
+            _ => {
+                let t = *self.rng.pick(&TYPES);
+                out.push(format!("{} writeln", self.expression(t, 0)));
+            }
+        }
+    }
+
+    fn expression(&mut self, t: Type, depth: usize) -> String {
+        // Use a name of the right type some of the time
+        let names = self
+            .names
+            .iter()
+            .filter(|(_, nt)| *nt == t)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        if !names.is_empty() && self.rng.below(4) == 0 {
+            return self.rng.pick(&names).clone();
+        }
+
+        if depth >= MAX_DEPTH || self.rng.below(3) == 0 {
+            return self.literal(t);
+        }
+
+        let depth = depth + 1;
+        match t {
+            Type::Integer => match self.rng.below(5) {
+                0 => format!(
+                    "{} {} {}",
+                    self.expression(Type::Integer, depth),
+                    self.expression(Type::Integer, depth),
+                    self.rng.pick(&["+", "-", "*"])
+                ),
+                // Only divide by (non-zero) literals
+                1 => format!(
+                    "{} {} {}",
+                    self.expression(Type::Integer, depth),
+                    self.rng.between(1, 9),
+                    self.rng.pick(&["/", "%"])
+                ),
+                2 => format!("{} to_int", self.expression(Type::Float, depth)),
+                3 => self.branches(Type::Integer, depth),
+                _ => format!(
+                    "{} {} -",
+                    self.literal(Type::Integer),
+                    self.literal(Type::Integer)
+                ),
+            },
+            Type::Float => match self.rng.below(4) {
+                // Mixing floats and integers coerces to a float
+                0 => {
+                    let (a, b) = if self.rng.below(2) == 0 {
+                        (Type::Float, Type::Integer)
+                    } else {
+                        (Type::Float, Type::Float)
+                    };
+                    format!(
+                        "{} {} {}",
+                        self.expression(a, depth),
+                        self.expression(b, depth),
+                        self.rng.pick(&["+", "-", "*"])
+                    )
+                }
+                1 => format!("{} to_float", self.expression(Type::Integer, depth)),
+                2 => self.branches(Type::Float, depth),
+                _ => self.literal(Type::Float),
+            },
+            Type::Boolean => match self.rng.below(4) {
+                0 => {
+                    let operand = *self.rng.pick(&[Type::Integer, Type::Float]);
+                    format!(
+                        "{} {} {}",
+                        self.expression(operand, depth),
+                        self.expression(operand, depth),
+                        self.rng.pick(&["<", "<=", "=", "!=", ">=", ">"])
+                    )
+                }
+                1 => format!(
+                    "{} {} {}",
+                    self.expression(Type::Boolean, depth),
+                    self.expression(Type::Boolean, depth),
+                    self.rng.pick(&["and", "or", "xor", "nand"])
+                ),
+                2 => format!("{} not", self.expression(Type::Boolean, depth)),
+                _ => self.branches(Type::Boolean, depth),
+            },
+            Type::String => match self.rng.below(2) {
+                0 => self.branches(Type::String, depth),
+                _ => self.literal(Type::String),
+            },
+        }
+    }
+
+    /// An if that returns one of two values of a type
+    fn branches(&mut self, t: Type, depth: usize) -> String {
+        format!(
+            "{{ {} }} {{ {} }} {} if",
+            self.expression(t, depth),
+            self.expression(t, depth),
+            self.expression(Type::Boolean, depth)
+        )
+    }
+
+    fn literal(&mut self, t: Type) -> String {
+        match t {
+            Type::Integer => self.rng.between(0, 100).to_string(),
+            // Quarters are exact as floats, so they can't round differently
+            Type::Float => format!("{:.2}", self.rng.between(0, 400) as f64 / 4.0),
+            Type::Boolean => self.rng.pick(&["true", "false"]).to_string(),
+            Type::String => format!("{:?}", self.rng.pick(&WORDS)),
+        }
+    }
+}
+
+/// Generate a random program from a seed, the same seed always generates the same program
+pub fn program(seed: u64) -> String {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+        names: vec![],
+        next_name: 0,
+    };
+
+    let mut lines = vec![];
+    let count = generator.rng.between(3, 10);
+    for _ in 0..count {
+        generator.statement(&mut lines);
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use super::program;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_program_is_deterministic() {
+        assert_eq!(program(1), program(1));
+        assert_ne!(program(1), program(2));
+    }
+
+    #[test]
+    fn test_programs_run() {
+        for seed in 0..50 {
+            let source = program(seed);
+            let mut vm = VM::new();
+            if let Err(e) = vm.evaluate_str(&source) {
+                panic!("seed {} failed: {}\n{}", seed, e, source);
+            }
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Golden output tests for the examples
+//!
+//! Each example can have sidecar files next to it with what running it should do:
+//! - `.stdin`: passed to the program on stdin
+//! - `.stdout`: the expected output (required unless the example is skipped)
+//! - `.stderr`: the expected errors, empty if missing
+//! - `.status`: the expected exit code, 0 if missing
+//! - `.skip`: skip the example, the contents are the reason why
+//!
+//! Any of these can be specific to a backend, such as `add2.c.stdout`, which is used instead of `add2.stdout` for C.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// The ways an example can be run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Vm,
+    C,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 2] = [Backend::Vm, Backend::C];
+
+    /// The name used in backend specific sidecar files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Vm => "vm",
+            Backend::C => "c",
+        }
+    }
+}
+
+/// What happened when running an example
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: i32,
+}
+
+/// The path to a sidecar file for an example, for example `examples/add2.c.stdout`
+fn sidecar_path(path: &Path, backend: Option<Backend>, kind: &str) -> PathBuf {
+    let extension = match backend {
+        Some(backend) => format!("{}.{}", backend.name(), kind),
+        None => kind.to_string(),
+    };
+    path.with_extension(extension)
+}
+
+/// Read a sidecar file, preferring the one specific to a backend
+fn read_sidecar(path: &Path, backend: Backend, kind: &str) -> Option<String> {
+    std::fs::read_to_string(sidecar_path(path, Some(backend), kind))
+        .or_else(|_| std::fs::read_to_string(sidecar_path(path, None, kind)))
+        .ok()
+}
+
+/// Find all examples (.stack files) in a directory, recursively and in sorted order
+///
+/// A path that isn't a directory is returned as is, so this also collects the files passed to test, lint and fmt
+pub fn examples(dir: &Path) -> Vec<PathBuf> {
+    let mut entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>(),
+        Err(_) => return vec![dir.to_path_buf()],
+    };
+    entries.sort();
+
+    let mut examples = vec![];
+    for entry in entries {
+        if entry.is_dir() {
+            examples.extend(self::examples(&entry));
+        } else if entry.extension().is_some_and(|ext| ext == "stack") {
+            examples.push(entry);
+        }
+    }
+    examples
+}
+
+/// The reason an example is skipped for a backend, if it is
+pub fn skipped(path: &Path, backend: Backend) -> Option<String> {
+    read_sidecar(path, backend, "skip").map(|reason| reason.trim().to_string())
+}
+
+/// The output an example is expected to write with a backend, if it has any
+pub fn expected_stdout(path: &Path, backend: Backend) -> Option<String> {
+    read_sidecar(path, backend, "stdout")
+}
+
+/// Run an example with a backend
+///
+/// stacklang is the command to run this program, arguments for the backend are added to it
+/// The program is killed if it runs for longer than timeout
+pub fn run(
+    mut stacklang: Command,
+    backend: Backend,
+    path: &Path,
+    timeout: Duration,
+) -> Result<Outcome, String> {
+    match backend {
+        Backend::Vm => stacklang.arg("vm").arg(path),
+        Backend::C => {
+            let output = std::env::temp_dir()
+                .join("stacklang-golden")
+                .join(path.to_string_lossy().replace(['/', '\\'], "-"))
+                .with_extension("c");
+            std::fs::create_dir_all(output.parent().unwrap()).map_err(|e| e.to_string())?;
+            stacklang
+                .arg("compile")
+                .arg(path)
+                .arg("--output")
+                .arg(output)
+                .arg("--run")
+        }
+    };
+
+    let mut child = stacklang
+        .env("RUST_BACKTRACE", "0")
+        .env_remove("RUST_LOG")
+        .env_remove("STACKLANG_DEBUG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", path.display(), e))?;
+
+    // Read output on other threads, so a full pipe can't block the program
+    let stdin = read_sidecar(path, backend, "stdin").unwrap_or_default();
+    let mut input = child.stdin.take().unwrap();
+    std::thread::spawn(move || input.write_all(stdin.as_bytes()));
+    let read_all = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut s = String::new();
+            pipe.read_to_string(&mut s).map(|_| s)
+        })
+    };
+    let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {:?}", timeout));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    Ok(Outcome {
+        stdout: stdout.join().unwrap().map_err(|e| e.to_string())?,
+        stderr: stderr.join().unwrap().map_err(|e| e.to_string())?,
+        status: status.code().unwrap_or(-1),
+    })
+}
+
+/// Compare what running an example did to its sidecar files, returning a description of each difference
+pub fn check(path: &Path, backend: Backend, outcome: &Outcome) -> Vec<String> {
+    let mut differences = vec![];
+
+    match expected_stdout(path, backend) {
+        Some(expected) if expected == outcome.stdout => {}
+        Some(expected) => differences.push(format!(
+            "stdout differs\n--- expected\n{}--- actual\n{}",
+            expected, outcome.stdout
+        )),
+        None => differences.push(format!(
+            "missing {}",
+            sidecar_path(path, None, "stdout").display()
+        )),
+    }
+
+    let expected = read_sidecar(path, backend, "stderr").unwrap_or_default();
+    if expected != outcome.stderr {
+        differences.push(format!(
+            "stderr differs\n--- expected\n{}--- actual\n{}",
+            expected, outcome.stderr
+        ));
+    }
+
+    let expected = read_sidecar(path, backend, "status")
+        .and_then(|status| status.trim().parse().ok())
+        .unwrap_or(0);
+    if expected != outcome.status {
+        differences.push(format!(
+            "exit code differs, expected {}, got {}",
+            expected, outcome.status
+        ));
+    }
+
+    differences
+}
+
+/// Write sidecar files so the example expects outcome
+///
+/// The VM's outcome is written to the shared files (or its own files, if it has them),
+/// other backends only write files where they differ from those
+pub fn bless(path: &Path, backend: Backend, outcome: &Outcome) -> std::io::Result<()> {
+    let status = if outcome.status == 0 {
+        String::new()
+    } else {
+        format!("{}\n", outcome.status)
+    };
+
+    // Empty stderr and status are the defaults, so they don't need a file
+    for (kind, value, default) in [
+        ("stdout", &outcome.stdout, None),
+        ("stderr", &outcome.stderr, Some("")),
+        ("status", &status, Some("")),
+    ] {
+        let shared = sidecar_path(path, None, kind);
+        let specific = sidecar_path(path, Some(backend), kind);
+
+        let target = match backend {
+            // A file specific to the VM overrides the shared one, so it's kept (even if it's the default)
+            Backend::Vm if specific.exists() => {
+                std::fs::write(&specific, value)?;
+                continue;
+            }
+            Backend::Vm => shared,
+            _ => {
+                let expected = std::fs::read_to_string(&shared).ok();
+                if expected.as_deref().or(default) == Some(value.as_str()) {
+                    remove_if_exists(&specific)?;
+                    continue;
+                }
+                specific
+            }
+        };
+
+        if default == Some(value.as_str()) {
+            remove_if_exists(&target)?;
+        } else {
+            std::fs::write(&target, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bless, Backend, Outcome};
+
+    #[test]
+    fn test_bless_vm_override() {
+        let dir = std::env::temp_dir().join(format!("stacklang-bless-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.stack");
+        std::fs::write(dir.join("example.stdout"), "shared\n").unwrap();
+        std::fs::write(dir.join("example.vm.stdout"), "vm\n").unwrap();
+        std::fs::write(dir.join("example.vm.stderr"), "error\n").unwrap();
+
+        let outcome = Outcome {
+            stdout: "new\n".to_string(),
+            stderr: String::new(),
+            status: 0,
+        };
+        bless(&path, Backend::Vm, &outcome).unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("example.stdout").as_deref(), Some("shared\n"));
+        assert_eq!(read("example.vm.stdout").as_deref(), Some("new\n"));
+        assert_eq!(read("example.vm.stderr").as_deref(), Some(""));
+        assert_eq!(read("example.stderr"), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Syntax highlighting grammars generated from the lexer's token patterns and the builtins
+//!
+//! Editors match patterns anywhere in a line rather than a token at a time like the lexer does,
+//! so patterns that could match within a longer token (comments and builtins) only match between tokens
+
+use serde_json::{json, Value};
+
+use crate::builtins::BUILTINS;
+use crate::import::IMPORT;
+use crate::lexer::{TokenKind, COMMENT_START, TOKEN_PATTERNS};
+
+/// The file checked in for editors to use, see test_checked_in
+pub const TEXTMATE_PATH: &str = "editors/stacklang.tmLanguage.json";
+
+// Only match at the start or end of a token
+const START: &str = r"(?<![^\s{}()\[\]])";
+const END: &str = r"(?=[\s{}()\[\]]|$)";
+
+/// The TextMate scope for each kind of token
+fn scope(kind: TokenKind) -> &'static str {
+    match kind {
+        TokenKind::Bracket => "punctuation.section.brackets.stacklang",
+        TokenKind::Complex => "constant.numeric.complex.stacklang",
+        TokenKind::Float => "constant.numeric.float.stacklang",
+        TokenKind::Rational => "constant.numeric.rational.stacklang",
+        TokenKind::Hex => "constant.numeric.hex.stacklang",
+        TokenKind::Binary => "constant.numeric.binary.stacklang",
+        TokenKind::Integer => "constant.numeric.integer.stacklang",
+        TokenKind::String => "string.quoted.double.stacklang",
+        TokenKind::Identifier => "variable.other.stacklang",
+        TokenKind::Symbol => "keyword.operator.stacklang",
+    }
+}
+
+/// The tree-sitter rule for each kind of token, brackets are part of the block, list and group rules instead
+fn rule(kind: TokenKind) -> Option<&'static str> {
+    match kind {
+        TokenKind::Bracket => None,
+        TokenKind::Complex
+        | TokenKind::Float
+        | TokenKind::Rational
+        | TokenKind::Hex
+        | TokenKind::Binary
+        | TokenKind::Integer => Some("number"),
+        TokenKind::String => Some("string"),
+        TokenKind::Identifier => Some("identifier"),
+        TokenKind::Symbol => Some("symbol"),
+    }
+}
+
+/// Every builtin name as a regex, longest first so that <= is tried before <
+fn builtin_pattern() -> String {
+    let mut names = BUILTINS.iter().map(|b| b.name).collect::<Vec<_>>();
+    names.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    names
+        .iter()
+        .map(|name| regex::escape(name))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// A TextMate grammar (as used by VS Code, Sublime Text and others)
+pub fn textmate() -> Value {
+    let mut patterns = vec![
+        json!({
+            "name": "comment.line.number-sign.stacklang",
+            "match": format!("{}{}.*$", START, regex::escape(&COMMENT_START.to_string())),
+        }),
+        json!({
+            "name": "keyword.control.import.stacklang",
+            "match": format!("{}{}{}", START, IMPORT, END),
+        }),
+        json!({
+            "name": "keyword.other.builtin.stacklang",
+            "match": format!("{}(?:{}){}", START, builtin_pattern(), END),
+        }),
+    ];
+
+    // Strings go first, so a string with a builtin or # in it is still a string
+    let (strings, others): (Vec<_>, Vec<_>) = TOKEN_PATTERNS
+        .iter()
+        .partition(|(kind, _)| *kind == TokenKind::String);
+    for &(kind, pattern) in strings {
+        patterns.insert(0, json!({ "name": scope(kind), "match": pattern }));
+    }
+    for &(kind, pattern) in others {
+        patterns.push(json!({ "name": scope(kind), "match": pattern }));
+    }
+
+    json!({
+        "name": "StackLang",
+        "scopeName": "source.stacklang",
+        "fileTypes": ["stack"],
+        "patterns": patterns,
+    })
+}
+
+/// A tree-sitter grammar.js
+pub fn tree_sitter() -> String {
+    let mut rules = vec![];
+    for name in ["number", "string", "identifier", "symbol"] {
+        let patterns = TOKEN_PATTERNS
+            .iter()
+            .filter(|(kind, _)| rule(*kind) == Some(name))
+            .map(|(_, pattern)| format!("/{}/", pattern.replace('/', r"\/")))
+            .collect::<Vec<_>>();
+        rules.push(format!(
+            "    {}: $ => token(choice({})),",
+            name,
+            patterns.join(", ")
+        ));
+    }
+
+    // The import keyword takes precedence over identifiers and builtins
+    rules.push(format!(
+        "    keyword: $ => token(prec(2, {})),",
+        Value::from(IMPORT)
+    ));
+
+    // Builtins take precedence over identifiers and symbols of the same length
+    let builtins = BUILTINS
+        .iter()
+        .map(|b| Value::from(b.name).to_string())
+        .collect::<Vec<_>>();
+    rules.push(format!(
+        "    builtin: $ => token(prec(1, choice({}))),",
+        builtins.join(", ")
+    ));
+
+    format!(
+        r#"// Generated by `stacklang grammar --format tree-sitter` from the lexer, do not edit
+module.exports = grammar({{
+  name: 'stacklang',
+  extras: $ => [/\s/, $.comment],
+  rules: {{
+    source_file: $ => repeat($._expression),
+    _expression: $ => choice($.block, $.list, $.group, $.keyword, $.builtin, $.number, $.string, $.identifier, $.symbol),
+    block: $ => seq('{{', repeat($._expression), '}}'),
+    list: $ => seq('[', repeat($._expression), ']'),
+    group: $ => seq('(', repeat($._expression), ')'),
+    comment: $ => token(seq('{}', /.*/)),
+{}
+  }}
+}});
+"#,
+        COMMENT_START,
+        rules.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{textmate, tree_sitter, TEXTMATE_PATH};
+    use crate::lexer::TOKEN_PATTERNS;
+
+    #[test]
+    fn test_textmate() {
+        let grammar = textmate();
+        let patterns = grammar["patterns"].as_array().unwrap();
+
+        assert_eq!(patterns[0]["name"], "string.quoted.double.stacklang");
+        assert!(patterns[1]["match"].as_str().unwrap().ends_with("#.*$"));
+        assert_eq!(patterns[2]["name"], "keyword.control.import.stacklang");
+        let builtins = patterns[3]["match"].as_str().unwrap();
+        assert!(builtins.contains("|dup|") && builtins.contains(r"\+"));
+
+        for (_, pattern) in TOKEN_PATTERNS {
+            assert!(
+                patterns.iter().any(|p| p["match"] == *pattern),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_tree_sitter() {
+        let grammar = tree_sitter();
+        assert!(grammar.contains(r"number: $ => token(choice(/-?\d+(\.\d*)?[+-]"));
+        assert!(grammar.contains(r"/-?\d+\/\d+/"));
+        assert!(grammar.contains(r#""dup""#));
+        assert!(grammar.contains(r#"keyword: $ => token(prec(2, "import")),"#));
+    }
+
+    #[test]
+    fn test_checked_in() {
+        let expected = serde_json::to_string_pretty(&textmate()).unwrap() + "\n";
+        let actual = std::fs::read_to_string(TEXTMATE_PATH).unwrap_or_default();
+        assert!(
+            actual == expected,
+            "{} is out of date, run: cargo run -- grammar > {}",
+            TEXTMATE_PATH,
+            TEXTMATE_PATH
+        );
+    }
+}
@@ -0,0 +1,505 @@
+//! Importing other files with `import "path.stack"`, done before names are resolved
+//!
+//! Each file is evaluated once, where it is first imported, with paths relative to the importing file
+//! The top level names in an imported file are renamed to namespace.name, where the namespace is the file name
+//! without .stack, so that the named blocks in math.stack can be called as math.gcd by any file that imports it
+//! Since this happens before names are resolved, the VM and C backend both run imported files as part of the program
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::lexer::try_tokenize_named;
+use crate::parser::try_parse;
+use crate::resolver::Resolver;
+use crate::types::{Expression, ExpressionKind, Span, Value};
+
+/// The identifier that starts an import, followed by the path as a string
+pub const IMPORT: &str = "import";
+
+/// An error found while importing files, reported before the program is run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/// Replace each import in a program with the file it imports (if it hasn't been imported already)
+///
+/// path is the file the program was read from, imports are relative to it
+pub fn expand(ast: Expression, path: &Path) -> Result<Expression, Vec<ImportError>> {
+    let children = match ast.kind {
+        ExpressionKind::Group(children) => children,
+        kind => vec![Expression::new(kind, ast.span.clone())],
+    };
+
+    let mut importer = Importer::default();
+    if let Ok(canonical) = path.canonicalize() {
+        importer.loading.push((canonical, path.to_path_buf()));
+    }
+    let (children, _) = importer.file(children, path, None);
+
+    if importer.errors.is_empty() {
+        Ok(Expression::new(ExpressionKind::Group(children), ast.span))
+    } else {
+        Err(importer.errors)
+    }
+}
+
+// A file that has been imported
+#[derive(Debug)]
+struct Module {
+    namespace: String,
+    path: PathBuf,
+    // The named blocks at its top level
+    exports: HashSet<String>,
+}
+
+// Part of a file, either its own code or the code of a file it imports
+enum Part {
+    Own(Expression),
+    Imported(Vec<Expression>),
+}
+
+#[derive(Debug, Default)]
+struct Importer {
+    modules: HashMap<PathBuf, Module>,
+    // The files being imported (canonical and as written), for finding cycles
+    loading: Vec<(PathBuf, PathBuf)>,
+    errors: Vec<ImportError>,
+}
+
+impl Importer {
+    fn error(&mut self, span: &Span, message: String) {
+        self.errors.push(ImportError {
+            span: span.clone(),
+            message,
+        });
+    }
+
+    /// Expand the imports at the top level of a file and rename its names (if it has a namespace)
+    ///
+    /// Returns the expanded file and its named blocks
+    fn file(
+        &mut self,
+        children: Vec<Expression>,
+        path: &Path,
+        namespace: Option<&str>,
+    ) -> (Vec<Expression>, HashSet<String>) {
+        let directory = path.parent().unwrap_or(Path::new(""));
+
+        // The exports of each file this one imports, by namespace
+        let mut imports = HashMap::new();
+        let mut parts = vec![];
+
+        let mut children = children.into_iter();
+        while let Some(child) = children.next() {
+            if !matches!(&child.kind, ExpressionKind::Identifier(id) if id == IMPORT) {
+                parts.push(Part::Own(child));
+                continue;
+            }
+
+            let target = match children.next() {
+                Some(Expression {
+                    kind: ExpressionKind::Literal(Value::String(target)),
+                    ..
+                }) => target,
+                _ => {
+                    self.error(
+                        &child.span,
+                        format!("{} must be followed by a path in quotes", IMPORT),
+                    );
+                    continue;
+                }
+            };
+
+            if let Some((namespace, exports, code)) =
+                self.import(&child.span, &directory.join(target))
+            {
+                imports.insert(namespace, exports);
+                parts.push(Part::Imported(code));
+            }
+        }
+
+        let mut exports = HashSet::new();
+        for pair in parts.windows(2) {
+            if let [Part::Own(block), Part::Own(name)] = pair {
+                if let (ExpressionKind::Block { .. }, ExpressionKind::At(name)) =
+                    (&block.kind, &name.kind)
+                {
+                    if let ExpressionKind::Identifier(name) = &name.kind {
+                        exports.insert(name.clone());
+                    }
+                }
+            }
+        }
+
+        // Names are resolved as the VM would, so the names bound at the top level are found wherever they're used
+        let own = parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Own(child) => Some(child.clone()),
+                Part::Imported(_) => None,
+            })
+            .collect();
+        let (resolved, _) = Resolver::new()
+            .resolve_partial(Expression::new(ExpressionKind::Group(own), Span::default()));
+        let ExpressionKind::Group(resolved) = resolved.kind else {
+            unreachable!("resolving a group returns a group");
+        };
+        let mut resolved = resolved.into_iter();
+
+        let mut renamer = Renamer {
+            namespace,
+            imports: &imports,
+            depth: 0,
+            errors: vec![],
+        };
+        let mut expanded = vec![];
+        for part in parts {
+            match part {
+                Part::Own(_) => expanded.push(renamer.rename(resolved.next().unwrap())),
+                Part::Imported(code) => expanded.extend(code),
+            }
+        }
+        self.errors.append(&mut renamer.errors);
+
+        (expanded, exports)
+    }
+
+    /// Import a file, returning its namespace, named blocks and code (which is empty if it was already imported)
+    fn import(
+        &mut self,
+        span: &Span,
+        path: &Path,
+    ) -> Option<(String, HashSet<String>, Vec<Expression>)> {
+        let canonical = match path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(error) => {
+                self.error(span, format!("Cannot import {}: {}", path.display(), error));
+                return None;
+            }
+        };
+
+        if let Some(index) = self
+            .loading
+            .iter()
+            .position(|(loading, _)| *loading == canonical)
+        {
+            let cycle = self.loading[index..]
+                .iter()
+                .map(|(_, path)| path.display().to_string())
+                .chain(std::iter::once(path.display().to_string()))
+                .collect::<Vec<_>>();
+            self.error(span, format!("Import cycle: {}", cycle.join(" -> ")));
+            return None;
+        }
+
+        if let Some(module) = self.modules.get(&canonical) {
+            return Some((module.namespace.clone(), module.exports.clone(), vec![]));
+        }
+
+        let namespace = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !namespace.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !namespace
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.error(
+                span,
+                format!(
+                    "Cannot import {}, {:?} is not a valid namespace",
+                    path.display(),
+                    namespace
+                ),
+            );
+            return None;
+        }
+        if let Some(other) = self.modules.values().find(|m| m.namespace == namespace) {
+            self.error(
+                span,
+                format!(
+                    "Cannot import {}, the namespace {} is already used by {}",
+                    path.display(),
+                    namespace,
+                    other.path.display()
+                ),
+            );
+            return None;
+        }
+
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                self.error(span, format!("Cannot import {}: {}", path.display(), error));
+                return None;
+            }
+        };
+        let ast = match try_tokenize_named(source.as_bytes(), Some(path.display().to_string()))
+            .and_then(try_parse)
+        {
+            Ok(ast) => ast,
+            Err(error) => {
+                self.error(span, format!("Cannot import {}: {}", path.display(), error));
+                return None;
+            }
+        };
+        let children = match ast.kind {
+            ExpressionKind::Group(children) => children,
+            kind => vec![Expression::new(kind, ast.span)],
+        };
+
+        self.loading.push((canonical.clone(), path.to_path_buf()));
+        let (code, exports) = self.file(children, path, Some(&namespace));
+        self.loading.pop();
+
+        self.modules.insert(
+            canonical,
+            Module {
+                namespace: namespace.clone(),
+                path: path.to_path_buf(),
+                exports: exports.clone(),
+            },
+        );
+        Some((namespace, exports, code))
+    }
+}
+
+// Renames the names in a single file, turning the resolved names back into identifiers
+struct Renamer<'a> {
+    // The namespace of the file, None for the program itself (which isn't renamed)
+    namespace: Option<&'a str>,
+    // The exports of each file this one imports, by namespace
+    imports: &'a HashMap<String, HashSet<String>>,
+    // How many blocks are around the current expression
+    depth: usize,
+    errors: Vec<ImportError>,
+}
+
+impl Renamer<'_> {
+    fn rename(&mut self, expr: Expression) -> Expression {
+        let span = expr.span;
+
+        let kind = match expr.kind {
+            ExpressionKind::Identifier(name) if name == IMPORT => {
+                self.errors.push(ImportError {
+                    span: span.clone(),
+                    message: format!("{} is only allowed at the top level", IMPORT),
+                });
+                ExpressionKind::Identifier(name)
+            }
+            ExpressionKind::Local { name, depth, .. } => {
+                ExpressionKind::Identifier(self.name(name, depth))
+            }
+            ExpressionKind::Builtin(name) => ExpressionKind::Identifier(name),
+            ExpressionKind::DottedIdentifier(ids) => match self.imports.get(&ids[0]) {
+                Some(exports) if ids.len() == 2 && exports.contains(&ids[1]) => {
+                    ExpressionKind::Identifier(ids.join("."))
+                }
+                Some(_) => {
+                    self.errors.push(ImportError {
+                        span: span.clone(),
+                        message: format!("{} has no named block {}", ids[0], ids[1..].join(".")),
+                    });
+                    ExpressionKind::DottedIdentifier(ids)
+                }
+                None => ExpressionKind::DottedIdentifier(ids),
+            },
+            ExpressionKind::At(inner) => ExpressionKind::At(Box::new(self.rename(*inner))),
+            ExpressionKind::Bang(inner) => ExpressionKind::Bang(Box::new(self.rename(*inner))),
+            ExpressionKind::Dollar(inner) => ExpressionKind::Dollar(Box::new(self.rename(*inner))),
+            ExpressionKind::Block { children, arity } => {
+                self.depth += 1;
+                let children = children
+                    .into_iter()
+                    .map(|child| self.rename(child))
+                    .collect();
+                self.depth -= 1;
+                ExpressionKind::Block { children, arity }
+            }
+            ExpressionKind::List(children) => ExpressionKind::List(
+                children
+                    .into_iter()
+                    .map(|child| self.rename(child))
+                    .collect(),
+            ),
+            ExpressionKind::Group(children) => ExpressionKind::Group(
+                children
+                    .into_iter()
+                    .map(|child| self.rename(child))
+                    .collect(),
+            ),
+            kind => kind,
+        };
+
+        Expression::new(kind, span)
+    }
+
+    // A name that refers to the top level of a module (depth blocks out) is renamed
+    fn name(&self, name: String, depth: usize) -> String {
+        match self.namespace {
+            Some(namespace) if depth == self.depth => format!("{}.{}", namespace, name),
+            _ => name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use crate::vm::VM;
+    use std::path::{Path, PathBuf};
+
+    // Write files to a new directory for a test, returning the path to the first
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("stacklang-import-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (name, source) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    // Run a program, returning the top count values on the stack
+    fn run(path: &Path, count: usize) -> Result<Vec<String>, String> {
+        let source = std::fs::read_to_string(path).unwrap();
+        let ast = expand(parse(tokenize(source.as_bytes())), path).map_err(|errors| {
+            errors
+                .iter()
+                .map(|e| e.message.clone())
+                .collect::<Vec<_>>()
+                .join("\n")
+        })?;
+
+        let mut vm = VM::new();
+        vm.evaluate(ast).map_err(|e| e.to_string())?;
+        let mut values = (0..count)
+            .map(|_| vm.pop().map(|v| v.to_string()).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        values.reverse();
+        Ok(values)
+    }
+
+    #[test]
+    fn test_namespace() {
+        let path = files(
+            "namespace",
+            &[
+                (
+                    "main.stack",
+                    "import \"lib/math.stack\"\n{ @x x } @square\n3 math.square 4 square",
+                ),
+                (
+                    "lib/math.stack",
+                    "{ @x x x * } @square\n{ @n n square square } @fourth",
+                ),
+            ],
+        );
+        assert_eq!(run(&path, 2), Ok(vec!["9".to_string(), "4".to_string()]));
+
+        let ast = std::fs::read_to_string(&path).unwrap();
+        let ast = expand(parse(tokenize(ast.as_bytes())), &path).unwrap();
+        assert_eq!(
+            ast.to_string(),
+            "({@x x x *} @math.square {@n n math.square math.square} @math.fourth {@x x} @square 3 math.square 4 square)"
+        );
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let path = files(
+            "shadowing",
+            &[
+                ("main.stack", "import \"m.stack\"\nm.twice"),
+                ("m.stack", "5 @n\n{ n @n n + } @twice"),
+            ],
+        );
+        assert_eq!(run(&path, 1), Ok(vec!["10".to_string()]));
+
+        // Used before the inner binding, n refers to the top level n (as the resolver has it)
+        let ast = std::fs::read_to_string(&path).unwrap();
+        let ast = expand(parse(tokenize(ast.as_bytes())), &path).unwrap();
+        assert_eq!(ast.to_string(), "(5 @m.n {m.n @n n +} @m.twice m.twice)");
+    }
+
+    #[test]
+    fn test_imported_once() {
+        let path = files(
+            "once",
+            &[
+                (
+                    "main.stack",
+                    "import \"a.stack\"\nimport \"b.stack\"\na.one b.two",
+                ),
+                ("a.stack", "{ 1 } @one"),
+                ("b.stack", "import \"a.stack\"\n{ a.one 1 + } @two"),
+            ],
+        );
+        assert_eq!(run(&path, 2), Ok(vec!["1".to_string(), "2".to_string()]));
+
+        let source = std::fs::read_to_string(&path).unwrap();
+        let ast = expand(parse(tokenize(source.as_bytes())), &path).unwrap();
+        assert_eq!(ast.to_string().matches("@a.one").count(), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let path = files(
+            "cycle",
+            &[
+                ("main.stack", "import \"a.stack\""),
+                ("a.stack", "import \"main.stack\""),
+            ],
+        );
+        let error = run(&path, 0).unwrap_err();
+        assert!(error.starts_with("Import cycle: "), "{}", error);
+        assert!(error.ends_with("main.stack"), "{}", error);
+
+        let path = files(
+            "missing",
+            &[
+                (
+                    "main.stack",
+                    "import \"lib.stack\" lib.nope { import \"x.stack\" }",
+                ),
+                ("lib.stack", "{ 1 } @one 2 @value"),
+            ],
+        );
+        assert_eq!(
+            run(&path, 0),
+            Err("lib has no named block nope\nimport is only allowed at the top level".to_string())
+        );
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let path = files(
+            "syntax",
+            &[
+                ("main.stack", "import \"broken.stack\" broken.one"),
+                ("broken.stack", "{ 1 } @one @"),
+            ],
+        );
+        let error = run(&path, 0).unwrap_err();
+        assert!(error.starts_with("Cannot import "), "{}", error);
+        assert!(
+            error.ends_with("broken.stack:1:11:1: @ must be followed by a value"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,46 +1,100 @@
+use std::collections::HashMap;
 use std::io::BufRead;
 
 use regex::Regex;
 use substring::Substring;
 
+use crate::error::SyntaxError;
 use crate::types::{Span, Token};
 
+/// What a token pattern matches, see TOKEN_PATTERNS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Bracket,
+    Complex,
+    Float,
+    Rational,
+    Hex,
+    Binary,
+    Integer,
+    String,
+    Identifier,
+    Symbol,
+}
+
+/// The pattern for each kind of token, the first that matches is used
+///
+/// These are also used to generate syntax highlighting grammars, see grammar
+pub const TOKEN_PATTERNS: &[(TokenKind, &str)] = &[
+    // single characters
+    (TokenKind::Bracket, r"[\{}()\[\]]"),
+    // <numbers>
+    // complex numbers
+    (TokenKind::Complex, r"-?\d+(\.\d*)?[+-]-?\d+(\.\d*)?i"),
+    // floats (including scientific notation)
+    (TokenKind::Float, r"-?\d+(\.\d*)?[eE]-?\d+(\.\d*)?"),
+    // rationals
+    (TokenKind::Rational, r"-?\d+/\d+"),
+    // hex literals
+    (TokenKind::Hex, r"0x[0-9a-fA-F]+"),
+    // binary literals
+    (TokenKind::Binary, r"0b[01]+"),
+    // integers
+    (TokenKind::Integer, r"-?\d+(\.\d*)?"),
+    // </numbers>
+
+    // strings
+    (TokenKind::String, "\"(\\.|[^\"])*\""),
+    // basic identifiers, must start with a letter or _
+    (TokenKind::Identifier, r"[a-zA-Z][^\{}()\[\]\s]*"),
+    // identifiers that start with - followed by a letter (like -rot)
+    (TokenKind::Identifier, r"-[a-zA-Z][^\{}()\[\]\s]*"),
+    // purely symbolic identifiers, cannot contain letters or numbers
+    (TokenKind::Symbol, r"[^a-zA-Z0-9\{}()\[\]\s]+"),
+];
+
+/// A token starting with this is a comment, which runs to the end of the line
+pub const COMMENT_START: char = '#';
+
 /// Tokenizes a stream of characters into a vector of tokens.
 pub fn tokenize(reader: impl BufRead) -> Vec<Token> {
-    log::debug!("tokenize()");
+    tokenize_named(reader, None)
+}
+
+/// Tokenizes a stream of characters, naming the source in each token's span (generally a filename)
+///
+/// Panics if the source can't be tokenized, see try_tokenize_named
+pub fn tokenize_named(reader: impl BufRead, name: Option<String>) -> Vec<Token> {
+    try_tokenize_named(reader, name).unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// Tokenizes a stream of characters, returning an error for invalid UTF-8 or text that isn't a token
+pub fn try_tokenize_named(
+    reader: impl BufRead,
+    name: Option<String>,
+) -> Result<Vec<Token>, SyntaxError> {
+    log::debug!("tokenize({:?})", name);
 
     let mut tokens = vec![];
-    let token_patterns = vec![
-        // single characters
-        r"[\{}()\[\]]",
-        // <numbers>
-        // complex numbers
-        r"-?\d+(\.\d*)?[+-]-?\d+(\.\d*)?i",
-        // floats (including scientific notation)
-        r"-?\d+(\.\d*)?[eE]-?\d+(\.\d*)?",
-        // rationals
-        r"-?\d+/\d+",
-        // hex literals
-        r"0x[0-9a-fA-F]+",
-        // binary literals
-        r"0b[01]+",
-        // integers
-        r"-?\d+(\.\d*)?",
-        // </numbers>
-
-        // strings
-        "\"(\\.|[^\"])*\"",
-        // basic identifiers, must start with a letter or _
-        r"[a-zA-Z][^\{}()\[\]\s]*",
-        // purely symbolic identifiers, cannot contain letters or numbers
-        r"[^a-zA-Z0-9\{}()\[\]\s]+",
-    ];
+    let token_patterns = TOKEN_PATTERNS
+        .iter()
+        .map(|(_, pattern)| *pattern)
+        .collect::<Vec<_>>();
     let token_regex = Regex::new(format!("^({})", token_patterns.join("|")).as_str()).unwrap();
     let whitespace_regex = Regex::new(r"^\s+").unwrap();
 
     // Scan the input line by line tracking rows
     for (row, line) in reader.lines().enumerate() {
-        let line = line.unwrap();
+        let error = |column: usize, message: String| SyntaxError {
+            span: Span {
+                name: name.clone(),
+                row,
+                column,
+                length: 1,
+            },
+            message,
+        };
+        let line = line.map_err(|e| error(0, format!("Cannot read line: {}", e)))?;
         let mut line = line.as_str();
         let mut column = 0;
 
@@ -56,14 +110,23 @@ pub fn tokenize(reader: impl BufRead) -> Vec<Token> {
 
             // Read the next token (patterns above)
             if let Some(c) = token_regex.captures(line) {
-                // Ignore comments
-                if c[0].starts_with('#') {
+                // Comments run to the end of the line
+                if c[0].starts_with(COMMENT_START) {
+                    tokens.push(Token {
+                        span: Span {
+                            name: name.clone(),
+                            row,
+                            column,
+                            length: line.trim_end().len(),
+                        },
+                        token: line.trim_end().to_string(),
+                    });
                     break;
                 }
 
                 tokens.push(Token {
                     span: Span {
-                        name: None, // TODO: set this
+                        name: name.clone(),
                         row,
                         column,
                         length: c[0].len(),
@@ -71,18 +134,60 @@ pub fn tokenize(reader: impl BufRead) -> Vec<Token> {
                     token: c[0].to_string(),
                 });
                 line = line.substring(c[0].len(), line.len());
-                column = c[0].len();
-            } else if line.len() != 0 {
-                panic!("no token found at {row}:{column} = {line:?}");
+                column += c[0].len();
+            } else if !line.is_empty() {
+                return Err(error(column, format!("No token found at {:?}", line)));
             }
 
-            if line.len() == 0 {
+            if line.is_empty() {
                 break;
             }
         }
     }
 
-    tokens
+    Ok(tokens)
+}
+
+/// Where each opening bracket is closed, both as (row, column)
+pub type Brackets = HashMap<(usize, usize), (usize, usize)>;
+
+/// Match each opening bracket to its closing bracket by (row, column)
+///
+/// Also returns any brackets that don't match: closing brackets with no (or a different) opening bracket and unclosed opening brackets
+pub fn match_brackets(tokens: &[Token]) -> (Brackets, Vec<&Token>) {
+    let mut pairs = HashMap::new();
+    let mut unmatched = vec![];
+    let mut openers: Vec<&Token> = vec![];
+
+    for token in tokens {
+        let close = match token.token.as_str() {
+            "{" | "[" | "(" => {
+                openers.push(token);
+                continue;
+            }
+            "}" => "{",
+            "]" => "[",
+            ")" => "(",
+            _ => continue,
+        };
+
+        // If the bracket closes one further out, the ones inside of that were never closed
+        match openers.iter().rposition(|opener| opener.token == close) {
+            Some(index) => {
+                unmatched.extend(openers.drain(index + 1..));
+                let opener = openers.pop().unwrap();
+                pairs.insert(
+                    (opener.span.row, opener.span.column),
+                    (token.span.row, token.span.column),
+                );
+            }
+            None => unmatched.push(token),
+        }
+    }
+
+    unmatched.extend(openers);
+    unmatched.sort_by_key(|token| (token.span.row, token.span.column));
+    (pairs, unmatched)
 }
 
 #[cfg(test)]
@@ -199,14 +304,15 @@ mod test {
 
     #[test]
     fn test_identifiers() {
-        let input = "test fact camelCase snake_case with-symbols?";
+        let input = "test fact camelCase snake_case with-symbols? -rot";
         let tokens = super::tokenize(input.as_bytes());
-        assert_eq!(tokens.len(), 5);
+        assert_eq!(tokens.len(), 6);
         assert_eq!(tokens[0].token, "test");
         assert_eq!(tokens[1].token, "fact");
         assert_eq!(tokens[2].token, "camelCase");
         assert_eq!(tokens[3].token, "snake_case");
         assert_eq!(tokens[4].token, "with-symbols?");
+        assert_eq!(tokens[5].token, "-rot");
     }
 
     #[test]
@@ -234,4 +340,48 @@ mod test {
         assert_eq!(tokens[2].token, "!");
         assert_eq!(tokens[3].token, "fact");
     }
+
+    #[test]
+    fn test_comments() {
+        let input = "1 # one\n# two  \n2";
+        let tokens = super::tokenize(input.as_bytes());
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[1].token, "# one");
+        assert!(tokens[1].is_comment());
+        assert_eq!((tokens[2].span.row, tokens[2].span.column), (1, 0));
+        assert_eq!(tokens[2].token, "# two");
+        assert!(!tokens[3].is_comment());
+    }
+
+    #[test]
+    fn test_match_brackets() {
+        let tokens = super::tokenize("{ [1 2] ] ( }".as_bytes());
+        let (pairs, unmatched) = super::match_brackets(&tokens);
+        assert_eq!(pairs.get(&(0, 0)), Some(&(0, 12)));
+        assert_eq!(pairs.get(&(0, 2)), Some(&(0, 6)));
+        assert_eq!(
+            unmatched.iter().map(|t| t.span.column).collect::<Vec<_>>(),
+            vec![8, 10]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let input = "1 fact\n  @n";
+        let tokens = super::tokenize(input.as_bytes());
+        assert_eq!(tokens.len(), 4);
+        assert_eq!((tokens[0].span.row, tokens[0].span.column), (0, 0));
+        assert_eq!((tokens[1].span.row, tokens[1].span.column), (0, 2));
+        assert_eq!(tokens[1].span.length, 4);
+        assert_eq!((tokens[2].span.row, tokens[2].span.column), (1, 2));
+        assert_eq!((tokens[3].span.row, tokens[3].span.column), (1, 3));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let error = super::try_tokenize_named(&b"1 2\n3 \xff"[..], Some("bad".to_string()));
+        let error = error.unwrap_err();
+        assert_eq!((error.span.row, error.span.column), (1, 0));
+        assert!(error.message.starts_with("Cannot read line"), "{}", error);
+    }
 }
@@ -0,0 +1,46 @@
+//! StackLang, a simple stack-based programming language
+//!
+//! Programs can be run with the [VM] or compiled to C with [compile_c]
+//!
+//! ```
+//! use stacklang::{Value, VM};
+//!
+//! let mut vm = VM::new();
+//! vm.register("answer", 0, 1, |vm| {
+//!     vm.push(Value::from(42));
+//!     Ok(())
+//! });
+//! vm.evaluate_str("answer 2 *").unwrap();
+//! assert_eq!(vm.pop(), Ok(Value::from(84)));
+//! ```
+
+pub mod debug;
+
+pub mod numbers;
+pub mod stack;
+pub mod types;
+
+pub mod arity;
+pub mod builtins;
+pub mod compile_c;
+pub mod error;
+pub mod format;
+pub mod fuzz;
+pub mod generate;
+pub mod golden;
+pub mod grammar;
+pub mod import;
+pub mod lexer;
+pub mod lint;
+pub mod lsp;
+pub mod parser;
+pub mod profile;
+pub mod resolver;
+pub mod test_runner;
+pub mod trace;
+pub mod typecheck;
+pub mod vm;
+
+pub use error::{Backtrace, Call, Error, ErrorKind, RuntimeError};
+pub use types::{Expression, Value};
+pub use vm::{Event, Limits, VM};
@@ -0,0 +1,309 @@
+//! Linting programs for common mistakes that aren't errors (or are only errors when run)
+//!
+//! Each rule has an ID, rules can be turned off for a file with a comment like `# lint: allow unused-name`
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::arity::declared_arity;
+use crate::builtins;
+use crate::error::SyntaxError;
+use crate::lexer::try_tokenize_named;
+use crate::numbers::Number;
+use crate::parser::try_parse;
+use crate::resolver::{bindings, Resolver};
+use crate::types::{Expression, ExpressionKind, Span, Value};
+
+/// The comment that turns off rules for a file
+const ALLOW: &str = "lint: allow";
+
+/// A mistake the linter looks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// A name bound with @ that is never used
+    UnusedName,
+    /// !name for a name that is never bound with @
+    UnboundSet,
+    /// @N or !N that isn't at the start of a block, these are ignored
+    MisplacedArity,
+    /// @name for the name of a builtin, which can then no longer be called
+    ShadowedBuiltin,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 4] = [
+        Rule::UnusedName,
+        Rule::UnboundSet,
+        Rule::MisplacedArity,
+        Rule::ShadowedBuiltin,
+    ];
+
+    /// The ID used in output and to allow the rule
+    pub fn id(&self) -> &'static str {
+        match self {
+            Rule::UnusedName => "unused-name",
+            Rule::UnboundSet => "unbound-set",
+            Rule::MisplacedArity => "misplaced-arity",
+            Rule::ShadowedBuiltin => "shadowed-builtin",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Rule> {
+        Rule::ALL.into_iter().find(|rule| rule.id() == id)
+    }
+}
+
+/// A single mistake found by the linter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lint {
+    pub rule: Rule,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} [{}]", self.span, self.message, self.rule.id())
+    }
+}
+
+/// Lint a program from source, leaving out rules allowed by comments in it
+pub fn lint_source(source: &str, name: Option<String>) -> Result<Vec<Lint>, SyntaxError> {
+    let tokens = try_tokenize_named(source.as_bytes(), name)?;
+
+    let mut allowed = HashSet::new();
+    for token in tokens.iter().filter(|token| token.is_comment()) {
+        let comment = token.token.trim_start_matches('#').trim();
+        let Some(ids) = comment.strip_prefix(ALLOW) else {
+            continue;
+        };
+
+        for id in ids.split_whitespace() {
+            match Rule::from_id(id) {
+                Some(rule) => {
+                    allowed.insert(rule);
+                }
+                None => log::warn!("{}: unknown lint rule {:?}", token.span, id),
+            }
+        }
+    }
+
+    Ok(lint(&try_parse(tokens)?)
+        .into_iter()
+        .filter(|lint| !allowed.contains(&lint.rule))
+        .collect())
+}
+
+/// Lint a parsed program, in order by where they are in the source
+///
+/// Names are resolved as the VM would, but names that can't be resolved aren't reported here
+pub fn lint(ast: &Expression) -> Vec<Lint> {
+    let (ast, _) = Resolver::new().resolve_partial(ast.clone());
+    let mut linter = Linter::default();
+
+    linter.frames.push(Frame::new(std::slice::from_ref(&ast)));
+    linter.walk(&ast);
+    linter.unused(true);
+
+    linter
+        .lints
+        .sort_by_key(|lint| (lint.span.row, lint.span.column));
+    linter.lints
+}
+
+/// The names bound directly in a single block (or at the top level), by slot
+#[derive(Debug, Default)]
+struct Frame {
+    // Each name with where it was first bound and if it has been used
+    names: HashMap<usize, (String, Span, bool)>,
+}
+
+impl Frame {
+    fn new(body: &[Expression]) -> Self {
+        let names = bindings(body)
+            .into_iter()
+            .map(|(slot, (name, span))| (slot, (name, span, false)))
+            .collect();
+        Frame { names }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Linter {
+    frames: Vec<Frame>,
+    lints: Vec<Lint>,
+}
+
+impl Linter {
+    fn report(&mut self, rule: Rule, span: &Span, message: String) {
+        self.lints.push(Lint {
+            rule,
+            span: span.clone(),
+            message,
+        });
+    }
+
+    /// Mark the slot a resolved name refers to as used
+    fn use_local(&mut self, depth: usize, slot: usize) {
+        let index = self.frames.len() - 1 - depth;
+        if let Some(name) = self.frames[index].names.get_mut(&slot) {
+            name.2 = true;
+        }
+    }
+
+    /// Report the unused names in the innermost frame and remove it
+    ///
+    /// At the top level, test- blocks are used by the test runner
+    fn unused(&mut self, top_level: bool) {
+        let frame = self.frames.pop().unwrap();
+        for (name, span, used) in frame.names.into_values() {
+            let test = top_level && name.starts_with("test-");
+            if !used && !test {
+                self.report(
+                    Rule::UnusedName,
+                    &span,
+                    format!("{:?} is bound but never used", name),
+                );
+            }
+        }
+    }
+
+    fn walk(&mut self, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::Local { depth, slot, .. } => self.use_local(*depth, *slot),
+            ExpressionKind::DottedIdentifier(ids) => {
+                // Dotted identifiers aren't resolved, the first part uses the innermost name it matches
+                for frame in self.frames.iter_mut().rev() {
+                    if let Some(name) = frame.names.values_mut().find(|name| name.0 == ids[0]) {
+                        name.2 = true;
+                        break;
+                    }
+                }
+            }
+            ExpressionKind::Dollar(inner) => self.walk(inner),
+            // Names that are set are resolved, unless they are never bound
+            ExpressionKind::Bang(inner) => {
+                if let ExpressionKind::Identifier(name) = &inner.kind {
+                    self.report(
+                        Rule::UnboundSet,
+                        &expr.span,
+                        format!("Cannot set {:?}, it is never bound with @", name),
+                    );
+                }
+            }
+            ExpressionKind::At(inner) => {
+                let names = match &inner.kind {
+                    ExpressionKind::List(names) => names.as_slice(),
+                    _ => std::slice::from_ref(inner.as_ref()),
+                };
+                for name in names {
+                    if let ExpressionKind::Local { name: id, .. } = &name.kind {
+                        if builtins::lookup(id).is_some() {
+                            self.report(
+                                Rule::ShadowedBuiltin,
+                                &name.span,
+                                format!("{:?} shadows the builtin of the same name", id),
+                            );
+                        }
+                    }
+                }
+            }
+            ExpressionKind::Block { children, .. } => {
+                // Arity markers are only read from the first two children, see declared_arity
+                let (arity_in, arity_out) = declared_arity(children);
+                let header = arity_in.is_some() as usize + arity_out.is_some() as usize;
+
+                self.frames.push(Frame::new(children));
+                self.children(children, header);
+                self.unused(false);
+            }
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                self.children(children, 0)
+            }
+            _ => {}
+        }
+    }
+
+    // Walk expressions, any arity markers after the first header of them are misplaced
+    fn children(&mut self, children: &[Expression], header: usize) {
+        for child in children.iter().skip(header) {
+            if let ExpressionKind::At(inner) | ExpressionKind::Bang(inner) = &child.kind {
+                if let ExpressionKind::Literal(Value::Number(Number::Integer(_))) = inner.kind {
+                    self.report(
+                        Rule::MisplacedArity,
+                        &child.span,
+                        format!(
+                            "{} is ignored, arity must be at the start of a block",
+                            child
+                        ),
+                    );
+                }
+            }
+        }
+
+        for child in children {
+            self.walk(child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lint_source, Rule};
+
+    fn rules(source: &str) -> Vec<(Rule, usize, usize)> {
+        lint_source(source, None)
+            .unwrap()
+            .into_iter()
+            .map(|lint| (lint.rule, lint.span.row, lint.span.column))
+            .collect()
+    }
+
+    #[test]
+    fn test_unused_name() {
+        assert_eq!(
+            rules("{ @[a b] a } @f 1 2 f"),
+            vec![(Rule::UnusedName, 0, 6)]
+        );
+        assert_eq!(rules("{ @n { n } } @f 1 f call"), vec![]);
+        assert_eq!(rules("{ 1 } @test-one"), vec![]);
+        assert_eq!(rules("1 @x 2 !x"), vec![(Rule::UnusedName, 0, 3)]);
+        assert_eq!(rules("{ @p p.x } @f"), vec![(Rule::UnusedName, 0, 12)]);
+
+        // Names are scoped as the resolver has them, n is used before the inner one is bound
+        assert_eq!(rules("1 @n { n @n n } apply"), vec![]);
+        assert_eq!(
+            rules("1 @n { n @n } apply"),
+            vec![(Rule::UnusedName, 0, 10)]
+        );
+    }
+
+    #[test]
+    fn test_unbound_set() {
+        assert_eq!(rules("5 !x"), vec![(Rule::UnboundSet, 0, 2)]);
+        assert_eq!(rules("0 @x { x 1 + !x } 5 loop"), vec![]);
+    }
+
+    #[test]
+    fn test_misplaced_arity() {
+        assert_eq!(rules("{ @n !0 n writeln } 3 loop"), vec![]);
+        assert_eq!(
+            rules("{ @a @b !1 a b + } @add 1 2 add"),
+            vec![(Rule::MisplacedArity, 0, 8)]
+        );
+    }
+
+    #[test]
+    fn test_shadowed_builtin() {
+        assert_eq!(
+            rules("{ @dup dup } @f 1 f"),
+            vec![(Rule::ShadowedBuiltin, 0, 3)]
+        );
+    }
+
+    #[test]
+    fn test_allow() {
+        let source = "# lint: allow unused-name shadowed-builtin\n{ @[dup x] 1 } @f";
+        assert_eq!(rules(source), vec![]);
+    }
+}
@@ -0,0 +1,819 @@
+//! A language server for StackLang, speaking LSP (JSON-RPC with Content-Length headers) over stdio
+//!
+//! Documents are analyzed from scratch whenever they change, see Analysis
+//! Positions are (row, column) as in Span, which matches LSP's (line, character) for ASCII sources
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::arity::{self, calculate_arity};
+use crate::builtins;
+use crate::error::panic_message;
+use crate::import;
+use crate::lexer::{match_brackets, try_tokenize_named};
+use crate::parser::try_parse;
+use crate::resolver::{bindings, Resolver};
+use crate::types::{Expression, ExpressionKind, Span, Token};
+
+// A position in a document as (row, column)
+type Position = (usize, usize);
+
+// The name bound to each slot in a block (or the top level) and where it's first bound, see resolver::bindings
+type Frame = HashMap<usize, (String, Span)>;
+
+fn start(span: &Span) -> Position {
+    (span.row, span.column)
+}
+
+/// Close any unclosed brackets and drop closing brackets that don't match, as match_brackets finds them
+fn balance(tokens: Vec<Token>) -> Vec<Token> {
+    fn closing(opener: &Token) -> Token {
+        let token = match opener.token.as_str() {
+            "{" => "}",
+            "[" => "]",
+            _ => ")",
+        };
+        Token {
+            span: opener.span.clone(),
+            token: token.to_string(),
+        }
+    }
+
+    let mut balanced = vec![];
+    let mut openers: Vec<Token> = vec![];
+    for token in tokens {
+        let open = match token.token.as_str() {
+            "{" | "[" | "(" => {
+                openers.push(token.clone());
+                balanced.push(token);
+                continue;
+            }
+            "}" => "{",
+            "]" => "[",
+            ")" => "(",
+            _ => {
+                balanced.push(token);
+                continue;
+            }
+        };
+
+        if let Some(index) = openers.iter().rposition(|opener| opener.token == open) {
+            for opener in openers.drain(index + 1..).rev() {
+                balanced.push(closing(&opener));
+            }
+            openers.pop();
+            balanced.push(token);
+        }
+    }
+
+    for opener in openers.iter().rev() {
+        balanced.push(closing(opener));
+    }
+    balanced
+}
+
+/// How bad a diagnostic is, the values are LSP's DiagnosticSeverity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+}
+
+/// A problem found in a document
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+}
+
+// A name in the source (where it's bound or used) and where it was first bound
+#[derive(Clone, Debug)]
+struct Reference {
+    span: Span,
+    name: String,
+    definition: Option<Span>,
+}
+
+/// Everything known about a single document
+#[derive(Debug, Default)]
+pub struct Analysis {
+    tokens: Vec<Token>,
+    ast: Option<Expression>,
+    /// Problems from the lexer, parser, resolver and arity checker
+    pub diagnostics: Vec<Diagnostic>,
+    closers: HashMap<Position, Position>,
+    references: Vec<Reference>,
+    // The (arity_in, arity_out) of each block by its {, if the program resolved
+    arities: HashMap<Position, (usize, usize)>,
+    // The block (by its {) each name is bound to, by where the name is bound
+    blocks: HashMap<Position, Position>,
+}
+
+/// A name that can be completed, with a short description
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Completion {
+    pub name: String,
+    pub detail: String,
+    pub builtin: bool,
+}
+
+/// A named block at the top level of a document
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub detail: String,
+    /// From the { to the name
+    pub range: (Position, Position),
+    pub name_span: Span,
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Self {
+        Analysis::analyze(source, None)
+    }
+
+    /// Analyze a file that has been saved to path, so its imports can be read
+    pub fn for_file(source: &str, path: &Path) -> Self {
+        Analysis::analyze(source, Some(path))
+    }
+
+    fn analyze(source: &str, path: Option<&Path>) -> Self {
+        let mut analysis = Analysis::default();
+
+        analysis.tokens = match try_tokenize_named(source.as_bytes(), None) {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                analysis.error(error.span, error.message);
+                return analysis;
+            }
+        };
+
+        let tokens = analysis.tokens.clone();
+        let (closers, unmatched) = match_brackets(&tokens);
+        analysis.closers = closers;
+        for token in unmatched {
+            let message = match token.token.as_str() {
+                "{" | "[" | "(" => format!("Unclosed {}", token.token),
+                _ => format!("Unexpected {}", token.token),
+            };
+            analysis.error(token.span.clone(), message);
+        }
+
+        // Documents being edited often have unmatched brackets, so fix them up to keep analyzing
+        let ast = match try_parse(balance(tokens)) {
+            Ok(ast) => ast,
+            Err(error) => {
+                analysis.error(error.span, error.message);
+                return analysis;
+            }
+        };
+
+        // Names are found as the resolver finds them, even if some of them can't be resolved
+        let (resolved, _) = Resolver::new().resolve_partial(ast.clone());
+        let mut frames = vec![bindings(std::slice::from_ref(&resolved))];
+        analysis.walk(&resolved, &mut frames);
+
+        analysis.check(ast, path);
+        analysis.ast = Some(resolved);
+        analysis
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    /// Expand imports, resolve names and infer arity, recording errors and the arity of each block
+    ///
+    /// Imported files are named in their spans (this one isn't), problems in them are reported at the start of this one
+    fn check(&mut self, ast: Expression, path: Option<&Path>) {
+        let ast = match path.map(|path| import::expand(ast.clone(), path)) {
+            None => ast,
+            Some(Ok(ast)) => ast,
+            Some(Err(errors)) => {
+                for error in errors {
+                    match error.span.name {
+                        None => self.error(error.span, error.message),
+                        Some(_) => self.error(Span::default(), error.to_string()),
+                    }
+                }
+                return;
+            }
+        };
+
+        let ast = match Resolver::new().resolve(ast) {
+            Ok(ast) => ast,
+            Err(errors) => {
+                for error in errors {
+                    match error.span.name {
+                        None => self.error(error.span, error.message),
+                        Some(_) => self.error(Span::default(), error.to_string()),
+                    }
+                }
+                return;
+            }
+        };
+
+        let (ast, warnings) = match panic::catch_unwind(AssertUnwindSafe(|| {
+            arity::infer(ast, &arity::HostArities::new())
+        })) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.error(Span::default(), panic_message(payload));
+                return;
+            }
+        };
+        for warning in warnings.into_iter().filter(|w| w.span.name.is_none()) {
+            self.diagnostics.push(Diagnostic {
+                span: warning.span,
+                severity: Severity::Warning,
+                message: warning.message,
+            });
+        }
+
+        self.record_arities(&ast);
+    }
+
+    fn record_arities(&mut self, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::Block { children, .. } => {
+                if expr.span.name.is_none() {
+                    self.arities
+                        .insert(start(&expr.span), calculate_arity(expr));
+                }
+                children.iter().for_each(|child| self.record_arities(child));
+            }
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                children.iter().for_each(|child| self.record_arities(child));
+            }
+            _ => {}
+        }
+    }
+
+    // Record a resolved name and where its slot is first bound
+    fn reference(&mut self, frames: &[Frame], expr: &Expression) {
+        let (name, definition) = match &expr.kind {
+            ExpressionKind::Local { name, depth, slot } => {
+                let frame = &frames[frames.len() - 1 - depth];
+                (name, frame.get(slot).map(|(_, span)| span.clone()))
+            }
+            ExpressionKind::Builtin(name) | ExpressionKind::Identifier(name) => (name, None),
+            _ => return,
+        };
+        self.references.push(Reference {
+            span: expr.span.clone(),
+            name: name.clone(),
+            definition,
+        });
+    }
+
+    fn walk(&mut self, expr: &Expression, frames: &mut Vec<Frame>) {
+        match &expr.kind {
+            ExpressionKind::Local { .. }
+            | ExpressionKind::Builtin(_)
+            | ExpressionKind::Identifier(_) => self.reference(frames, expr),
+            ExpressionKind::At(inner)
+            | ExpressionKind::Bang(inner)
+            | ExpressionKind::Dollar(inner) => {
+                let names = match (&expr.kind, &inner.kind) {
+                    (ExpressionKind::At(_), ExpressionKind::List(names)) => names.as_slice(),
+                    _ => std::slice::from_ref(inner.as_ref()),
+                };
+                for name in names {
+                    self.reference(frames, name);
+                }
+            }
+            ExpressionKind::Block { children, .. } => {
+                frames.push(bindings(children));
+                self.sequence(children, frames);
+                frames.pop();
+            }
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                self.sequence(children, frames)
+            }
+            _ => {}
+        }
+    }
+
+    fn sequence(&mut self, children: &[Expression], frames: &mut Vec<Frame>) {
+        // A block followed by @name binds the name to that block
+        for pair in children.windows(2) {
+            if let (ExpressionKind::Block { .. }, ExpressionKind::At(name)) =
+                (&pair[0].kind, &pair[1].kind)
+            {
+                if let ExpressionKind::Local { .. } = name.kind {
+                    self.blocks.insert(start(&name.span), start(&pair[0].span));
+                }
+            }
+        }
+
+        for child in children {
+            self.walk(child, frames);
+        }
+    }
+
+    /// The token at a position (including just after it, where the cursor is after typing it)
+    fn token_at(&self, at: Position) -> Option<&Token> {
+        self.tokens.iter().find(|token| {
+            token.span.row == at.0
+                && token.span.column <= at.1
+                && at.1 <= token.span.column + token.span.length
+        })
+    }
+
+    fn reference_at(&self, at: Position) -> Option<&Reference> {
+        self.references.iter().find(|reference| {
+            reference.span.row == at.0
+                && reference.span.column <= at.1
+                && at.1 <= reference.span.column + reference.span.length
+        })
+    }
+
+    /// Where the name at a position is bound
+    pub fn definition(&self, at: Position) -> Option<Span> {
+        self.reference_at(at)?.definition.clone()
+    }
+
+    fn describe_block(&self, block: Position) -> String {
+        match self.arities.get(&block) {
+            Some((arity_in, arity_out)) => format!("block ({} -- {})", arity_in, arity_out),
+            None => "block".to_string(),
+        }
+    }
+
+    /// A description of the name or block at a position
+    pub fn hover(&self, at: Position) -> Option<String> {
+        if let Some(reference) = self.reference_at(at) {
+            return match &reference.definition {
+                Some(definition) => match self.blocks.get(&start(definition)) {
+                    Some(block) => Some(format!(
+                        "{}: {}",
+                        reference.name,
+                        self.describe_block(*block)
+                    )),
+                    None => Some(format!("{}: bound at {}", reference.name, definition)),
+                },
+                None => builtins::lookup(&reference.name).map(|builtin| builtin.to_string()),
+            };
+        }
+
+        let token = self.token_at(at)?;
+        if token.token == "{" {
+            return Some(self.describe_block(start(&token.span)));
+        }
+        None
+    }
+
+    /// Builtins and the names in scope at a position
+    pub fn completions(&self, at: Position) -> Vec<Completion> {
+        let mut completions = vec![];
+
+        if let Some(ast) = &self.ast {
+            let mut frames = vec![];
+            self.frames_at(ast, at, &mut frames);
+            for frame in frames.iter().rev() {
+                let mut names = frame.values().collect::<Vec<_>>();
+                names.sort_by_key(|(_, span)| start(span));
+
+                for (name, span) in names {
+                    if completions.iter().any(|c: &Completion| &c.name == name) {
+                        continue;
+                    }
+                    let detail = match self.blocks.get(&start(span)) {
+                        Some(block) => self.describe_block(*block),
+                        None => "value".to_string(),
+                    };
+                    completions.push(Completion {
+                        name: name.clone(),
+                        detail,
+                        builtin: false,
+                    });
+                }
+            }
+        }
+
+        for builtin in builtins::BUILTINS {
+            completions.push(Completion {
+                name: builtin.name.to_string(),
+                detail: builtin.doc.to_string(),
+                builtin: true,
+            });
+        }
+        completions
+    }
+
+    // The frames of the top level and each block containing a position, outermost first
+    fn frames_at(&self, expr: &Expression, at: Position, frames: &mut Vec<Frame>) {
+        let children = match &expr.kind {
+            ExpressionKind::Group(children) if frames.is_empty() => {
+                frames.push(bindings(std::slice::from_ref(expr)));
+                children
+            }
+            ExpressionKind::Block { children, .. } => {
+                let end = self.closers.get(&start(&expr.span)).copied();
+                if at <= start(&expr.span) || end.is_some_and(|end| at > end) {
+                    return;
+                }
+                frames.push(bindings(children));
+                children
+            }
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => children,
+            _ => return,
+        };
+
+        for child in children {
+            self.frames_at(child, at, frames);
+        }
+    }
+
+    /// Blocks bound to names at the top level
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let Some(ExpressionKind::Group(children)) = self.ast.as_ref().map(|ast| &ast.kind) else {
+            return vec![];
+        };
+
+        let mut symbols = vec![];
+        for pair in children.windows(2) {
+            if let (ExpressionKind::Block { .. }, ExpressionKind::At(name)) =
+                (&pair[0].kind, &pair[1].kind)
+            {
+                if let ExpressionKind::Local { name: id, .. } = &name.kind {
+                    let end = (name.span.row, name.span.column + name.span.length);
+                    symbols.push(Symbol {
+                        name: id.clone(),
+                        detail: self.describe_block(start(&pair[0].span)),
+                        range: (start(&pair[0].span), end),
+                        name_span: name.span.clone(),
+                    });
+                }
+            }
+        }
+        symbols
+    }
+}
+
+fn range(from: Position, to: Position) -> Value {
+    json!({
+        "start": { "line": from.0, "character": from.1 },
+        "end": { "line": to.0, "character": to.1 },
+    })
+}
+
+fn span_range(span: &Span) -> Value {
+    range(start(span), (span.row, span.column + span.length))
+}
+
+/// The open documents and their analysis
+#[derive(Debug, Default)]
+struct Server {
+    documents: HashMap<String, Analysis>,
+    // Notifications to send after handling a message
+    outbox: Vec<Value>,
+}
+
+impl Server {
+    fn open(&mut self, uri: &str, text: &str) {
+        let analysis = match uri.strip_prefix("file://") {
+            Some(path) => Analysis::for_file(text, Path::new(path)),
+            None => Analysis::new(text),
+        };
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                json!({
+                    "range": span_range(&diagnostic.span),
+                    "severity": diagnostic.severity as u8,
+                    "source": "stacklang",
+                    "message": diagnostic.message,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.publish(uri, diagnostics);
+        self.documents.insert(uri.to_string(), analysis);
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) {
+        self.outbox.push(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }));
+    }
+
+    /// Handle a request or notification, None if the method isn't supported
+    fn handle(&mut self, method: &str, params: &Value) -> Option<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let at = (
+            params["position"]["line"].as_u64().unwrap_or_default() as usize,
+            params["position"]["character"].as_u64().unwrap_or_default() as usize,
+        );
+        let analysis = self.documents.get(uri);
+
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "stacklang" },
+            }),
+            "initialized" | "shutdown" => Value::Null,
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.open(uri, text);
+                Value::Null
+            }
+            "textDocument/didChange" => {
+                // Only full changes are supported (textDocumentSync 1), so the last is the whole document
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    self.open(uri, text);
+                }
+                Value::Null
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.publish(uri, vec![]);
+                Value::Null
+            }
+            "textDocument/definition" => match analysis.and_then(|a| a.definition(at)) {
+                Some(span) => json!({ "uri": uri, "range": span_range(&span) }),
+                None => Value::Null,
+            },
+            "textDocument/hover" => match analysis.and_then(|a| a.hover(at)) {
+                Some(text) => json!({ "contents": { "kind": "markdown", "value": text } }),
+                None => Value::Null,
+            },
+            "textDocument/completion" => analysis
+                .map(|a| a.completions(at))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|completion| {
+                    // CompletionItemKind Function or Variable
+                    let kind = if completion.builtin || completion.detail.starts_with("block") {
+                        3
+                    } else {
+                        6
+                    };
+                    json!({ "label": completion.name, "kind": kind, "detail": completion.detail })
+                })
+                .collect(),
+            "textDocument/documentSymbol" => analysis
+                .map(|a| a.symbols())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|symbol| {
+                    // SymbolKind Function
+                    json!({
+                        "name": symbol.name,
+                        "detail": symbol.detail,
+                        "kind": 12,
+                        "range": range(symbol.range.0, symbol.range.1),
+                        "selectionRange": span_range(&symbol.name_span),
+                    })
+                })
+                .collect(),
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+/// Read a single message, None at the end of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing Content-Length",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(io::Error::from)
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Run the language server until the client sends exit (or closes the input)
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        log::debug!("lsp: {}", method);
+        if method == "exit" {
+            break;
+        }
+
+        let result = server.handle(method, &message["params"]);
+        for notification in server.outbox.drain(..) {
+            write_message(&mut output, &notification)?;
+        }
+
+        // Messages without an id are notifications and don't get a response
+        let Some(id) = message.get("id") else {
+            continue;
+        };
+        let response = match result {
+            Some(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": format!("Unsupported method {}", method) },
+            }),
+        };
+        write_message(&mut output, &response)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{serve, Analysis, Severity};
+    use serde_json::{json, Value};
+
+    const SOURCE: &str = "{ @n\n  n 2 *\n} @double\n\n5 double writeln";
+
+    fn definition(source: &str, at: (usize, usize)) -> Option<(usize, usize)> {
+        let span = Analysis::new(source).definition(at)?;
+        Some((span.row, span.column))
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        assert!(Analysis::new(SOURCE).diagnostics.is_empty());
+
+        let analysis = Analysis::new("{ 1 2 +\nfoo");
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.severity, d.span.row, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (Severity::Error, 0, "Unclosed {"),
+                (Severity::Error, 1, "Unknown identifier \"foo\"")
+            ]
+        );
+
+        // Unmatched brackets are fixed up for the rest of the analysis
+        let analysis = Analysis::new("{ [ 1 } ) @x\nx");
+        let messages = analysis
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["Unclosed [", "Unexpected )"]);
+
+        // A prefix needs something after it
+        assert_eq!(Analysis::new("1 @").diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_imports() {
+        let path = std::path::Path::new("examples/modules/lcm-range.stack");
+        let source = std::fs::read_to_string(path).unwrap();
+        assert!(Analysis::for_file(&source, path).diagnostics.is_empty());
+
+        let analysis = Analysis::for_file("import \"lib/math.stack\" 4 math.half", path);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(
+            analysis.diagnostics[0].message,
+            "math has no named block half"
+        );
+    }
+
+    #[test]
+    fn test_definition_and_hover() {
+        let analysis = Analysis::new(SOURCE);
+
+        let definition = analysis.definition((4, 3)).unwrap();
+        assert_eq!((definition.row, definition.column), (2, 3));
+        let n = analysis.definition((1, 2)).unwrap();
+        assert_eq!((n.row, n.column), (0, 3));
+
+        assert_eq!(analysis.hover((4, 4)).unwrap(), "double: block (1 -- 1)");
+        assert_eq!(analysis.hover((0, 0)).unwrap(), "block (1 -- 1)");
+        assert!(analysis.hover((4, 10)).unwrap().starts_with("writeln"));
+    }
+
+    #[test]
+    fn test_definition_scoping() {
+        // Used before the inner binding, n refers to the outer one (as the resolver has it)
+        let source = "1 @n\n{ n @n n } apply";
+        assert_eq!(definition(source, (1, 2)), Some((0, 3)));
+        assert_eq!(definition(source, (1, 5)), Some((1, 5)));
+        assert_eq!(definition(source, (1, 7)), Some((1, 5)));
+
+        // Names used in nested blocks before they are bound (for recursion)
+        let source = "{ @n { n 1 - fact } } @fact";
+        assert_eq!(definition(source, (0, 7)), Some((0, 3)));
+        assert_eq!(definition(source, (0, 13)), Some((0, 23)));
+
+        // Names are still found when other names can't be resolved
+        assert_eq!(definition("1 @x foo x", (0, 9)), Some((0, 3)));
+        assert_eq!(definition("1 @x foo x", (0, 5)), None);
+    }
+
+    #[test]
+    fn test_completions_and_symbols() {
+        let analysis = Analysis::new(SOURCE);
+
+        let inside = analysis.completions((1, 2));
+        assert_eq!(inside[0].name, "n");
+        assert!(inside.iter().any(|c| c.name == "double"));
+        assert!(inside.iter().any(|c| c.name == "dup" && c.builtin));
+
+        let outside = analysis.completions((4, 0));
+        assert!(!outside.iter().any(|c| c.name == "n"));
+
+        let symbols = analysis.symbols();
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "double");
+        assert_eq!(symbols[0].range, ((0, 0), (2, 9)));
+    }
+
+    #[test]
+    fn test_session() {
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": { "uri": "file:///a.stack", "text": "1 foo" }
+            }}),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/documentSymbol", "params": {
+                "textDocument": { "uri": "file:///a.stack" }
+            }}),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "unknown", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ];
+        let input = messages
+            .iter()
+            .map(|m| format!("Content-Length: {}\r\n\r\n{}", m.to_string().len(), m))
+            .collect::<String>();
+
+        let mut output = vec![];
+        serve(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let responses = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|m| serde_json::from_str::<Value>(m.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(responses.len(), 4);
+        assert_eq!(
+            responses[0]["result"]["capabilities"]["hoverProvider"],
+            true
+        );
+        assert_eq!(responses[1]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(
+            responses[1]["params"]["diagnostics"][0]["message"],
+            "Unknown identifier \"foo\""
+        );
+        assert_eq!(responses[2]["result"], json!([]));
+        assert_eq!(responses[3]["error"]["code"], -32601);
+    }
+}
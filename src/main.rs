@@ -1,19 +1,17 @@
 use clap::*;
-use log;
-use std::{env, io::BufReader, path::PathBuf};
+use std::{
+    env,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 
-mod debug;
-
-mod numbers;
-mod stack;
-mod types;
-
-mod arity;
-mod compile_c;
-mod lexer;
-mod parser;
-mod vm;
+use stacklang::{
+    arity, builtins, compile_c, debug, format, fuzz, golden, grammar, import, lexer, lint, lsp,
+    parser, profile, resolver, test_runner, trace, typecheck, types, vm,
+};
 
+mod debugger;
+mod diff_test;
 mod example_tests;
 
 // The top-level application
@@ -34,11 +32,99 @@ struct GlobalArgs {
     debug: bool,
 }
 
+// Limits on the VM, for running programs that aren't trusted
+#[derive(Args, Debug)]
+struct LimitArgs {
+    /// Stop after evaluating this many expressions
+    #[clap(long)]
+    max_steps: Option<usize>,
+
+    /// Stop if there are more than this many values on the stack
+    #[clap(long)]
+    max_stack_depth: Option<usize>,
+
+    /// Stop if blocks are nested more than this deep (including recursion)
+    #[clap(long)]
+    max_call_depth: Option<usize>,
+
+    /// Stop if a list or hash would have more than this many values
+    #[clap(long)]
+    max_collection_size: Option<usize>,
+
+    /// Disable builtins that read input
+    #[clap(long)]
+    no_io: bool,
+}
+
+impl LimitArgs {
+    fn limits(&self) -> vm::Limits {
+        vm::Limits {
+            max_steps: self.max_steps,
+            max_stack_depth: self.max_stack_depth,
+            max_call_depth: self.max_call_depth,
+            max_collection_size: self.max_collection_size,
+            disable_io: self.no_io,
+        }
+    }
+}
+
+// Writing a trace of the VM, see trace::Tracer
+#[derive(Args, Debug)]
+struct TraceArgs {
+    /// Write a JSON record for each expression evaluated and block entered or exited to this file
+    #[clap(long)]
+    trace: Option<PathBuf>,
+
+    /// Only trace in blocks with this name (can be repeated)
+    #[clap(long, requires = "trace")]
+    trace_block: Vec<String>,
+
+    /// Only trace expressions on this line (can be repeated)
+    #[clap(long, requires = "trace")]
+    trace_line: Vec<usize>,
+}
+
+// Profiling the VM, see profile::Profiler
+#[derive(Args, Debug)]
+struct ProfileArgs {
+    /// Print calls and time for each named block and builtin to stderr when the program finishes
+    #[clap(long)]
+    profile: bool,
+
+    /// Write folded stacks (for flamegraph tools) to this file, implies --profile
+    #[clap(long)]
+    profile_folded: Option<PathBuf>,
+}
+
 /// The specific subcommands that can be run
 #[derive(Subcommand, Debug)]
 enum Command {
     #[clap(name = "vm", about = "Run a StackLang program using the VM")]
     Run {
+        #[clap(flatten)]
+        limits: LimitArgs,
+
+        #[clap(flatten)]
+        trace: TraceArgs,
+
+        #[clap(flatten)]
+        profile: ProfileArgs,
+
+        /// Input filename
+        path: PathBuf,
+    },
+
+    #[clap(
+        name = "trace-summary",
+        about = "Summarize a trace written by vm --trace: calls and steps per block and the busiest lines"
+    )]
+    TraceSummary {
+        /// Trace filename
+        path: PathBuf,
+    },
+
+    #[clap(name = "debug", about = "Step through a StackLang program in the VM")]
+    Debug {
         /// Input filename
         path: PathBuf,
     },
@@ -53,9 +139,153 @@ enum Command {
         #[clap(long, short = 'o')]
         output: Option<PathBuf>,
 
+        /// Count calls and time for each named block and builtin, printed to stderr when the program exits
+        #[clap(long)]
+        profile: bool,
+
+        /// Input filename
+        path: PathBuf,
+    },
+
+    #[clap(
+        name = "check",
+        about = "Check the types in a StackLang program without running it"
+    )]
+    Check {
+        /// Print the type of each name bound at the top level
+        #[clap(long, short = 't')]
+        types: bool,
+
+        /// Input filename
+        path: PathBuf,
+    },
+
+    #[clap(name = "builtins", about = "List the built in functions")]
+    Builtins,
+
+    #[clap(
+        name = "golden",
+        about = "Run examples and compare their output to the expected output stored next to them"
+    )]
+    Golden {
+        /// Write the current output as the expected output instead of comparing them
+        #[clap(long)]
+        bless: bool,
+
+        /// Only run with this backend (vm or c), defaults to both
+        #[clap(long, short = 'b')]
+        backend: Option<String>,
+
+        /// Seconds to let each example run before it fails
+        #[clap(long, short = 't', default_value = "10")]
+        timeout: u64,
+
+        /// Examples or directories of examples to run
+        #[clap(default_value = "examples")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[clap(
+        name = "diff-test",
+        about = "Run programs with both the VM and C and report where their output first differs"
+    )]
+    DiffTest {
+        /// Programs to run
+        paths: Vec<PathBuf>,
+
+        /// Also run this many randomly generated programs
+        #[clap(long, short = 'r', default_value = "0")]
+        random: u64,
+
+        /// The seed for the first random program, each one after adds one (defaults to the time)
+        #[clap(long, short = 's')]
+        seed: Option<u64>,
+
+        /// Seconds to let each program run before it fails
+        #[clap(long, short = 't', default_value = "10")]
+        timeout: u64,
+    },
+
+    #[clap(
+        name = "fuzz",
+        about = "Run mutated programs through the lexer, parser or VM, looking for panics"
+    )]
+    Fuzz {
+        /// The stage to fuzz: tokenize, parse, evaluate or all
+        #[clap(default_value = "all")]
+        target: String,
+
+        /// How many mutated inputs to try for each target
+        #[clap(long, short = 'n', default_value = "10000")]
+        iterations: usize,
+
+        /// The seed for mutating inputs (defaults to the time)
+        #[clap(long, short = 's')]
+        seed: Option<u64>,
+
+        /// Directory of inputs to start from
+        #[clap(long, default_value = "fuzz/corpus")]
+        corpus: PathBuf,
+
+        /// Directory to write the smallest input for each crash to
+        #[clap(long, default_value = "fuzz/crashes")]
+        crashes: PathBuf,
+    },
+
+    #[clap(name = "test", about = "Run the test-* blocks in StackLang programs")]
+    Test {
+        /// Files or directories (searched for .stack files) to test
+        #[clap(default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
+    #[clap(name = "lint", about = "Report common mistakes in StackLang programs")]
+    Lint {
+        /// Files or directories (searched for .stack files) to lint
+        #[clap(default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
+    #[clap(
+        name = "tokens",
+        about = "Print the tokens in a StackLang program with their spans"
+    )]
+    Tokens {
+        /// Print as JSON instead of text
+        #[clap(long)]
+        json: bool,
+
         /// Input filename
         path: PathBuf,
     },
+    #[clap(name = "ast", about = "Print the parsed AST of a StackLang program")]
+    Ast {
+        /// Print as JSON instead of text
+        #[clap(long)]
+        json: bool,
+
+        /// Input filename
+        path: PathBuf,
+    },
+    #[clap(name = "lsp", about = "Run a language server for StackLang over stdio")]
+    Lsp,
+    #[clap(
+        name = "grammar",
+        about = "Print a syntax highlighting grammar generated from the lexer"
+    )]
+    Grammar {
+        /// The kind of grammar: textmate or tree-sitter
+        #[clap(long, short = 'f', default_value = "textmate")]
+        format: String,
+    },
+    #[clap(name = "fmt", about = "Format StackLang programs in place")]
+    Fmt {
+        /// Don't write anything, exit with an error if any file isn't formatted
+        #[clap(long)]
+        check: bool,
+
+        /// Files or directories (searched for .stack files) to format
+        #[clap(default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
 }
 
 fn main() {
@@ -84,27 +314,138 @@ fn main() {
 
     // Run specified subcommand
     match args.command {
-        Command::Run { path } => {
-            let file = std::fs::File::open(path).unwrap();
-
-            let tokens = lexer::tokenize(BufReader::new(file));
+        Command::Run {
+            limits,
+            trace,
+            profile,
+            path,
+        } => {
+            let tokens = read_tokens(&path);
             log::info!("Tokens: {:#?}", tokens);
 
-            let ast = parser::parse(tokens);
+            let ast = parse_or_exit(tokens);
             log::info!("AST:\n{:#?}", ast);
 
-            vm::VM::new().evaluate(ast);
+            let ast = import_or_exit(ast, &path);
+
+            let mut vm = vm::VM::new();
+            vm.set_limits(limits.limits());
+
+            let tracer = trace.trace.map(|trace_path| {
+                let file = std::fs::File::create(&trace_path).unwrap_or_else(|e| {
+                    eprintln!("failed to create {}: {}", trace_path.display(), e);
+                    std::process::exit(1);
+                });
+                let mut tracer = trace::Tracer::new(Box::new(std::io::BufWriter::new(file)));
+                tracer.filter_blocks(trace.trace_block);
+                tracer.filter_lines(trace.trace_line);
+                std::rc::Rc::new(std::cell::RefCell::new(tracer))
+            });
+            let profiler = (profile.profile || profile.profile_folded.is_some())
+                .then(|| std::rc::Rc::new(std::cell::RefCell::new(profile::Profiler::new())));
+
+            if tracer.is_some() || profiler.is_some() {
+                let (tracer, profiler) = (tracer.clone(), profiler.clone());
+                vm.set_hook(move |vm, event| {
+                    if let Some(profiler) = &profiler {
+                        profiler.borrow_mut().event(event);
+                    }
+                    match &tracer {
+                        Some(tracer) => tracer.borrow_mut().event(vm, event),
+                        None => Ok(()),
+                    }
+                });
+            }
+
+            let result = vm.prepare(ast).and_then(|(ast, warnings)| {
+                for warning in warnings {
+                    eprintln!("warning: {}", warning);
+                }
+                vm.run(&ast)
+            });
+
+            if let Some(tracer) = tracer {
+                if let Err(e) = tracer.borrow_mut().flush() {
+                    eprintln!("failed to write trace: {}", e);
+                }
+            }
+
+            if let Some(profiler) = profiler {
+                let mut profiler = profiler.borrow_mut();
+                profiler.finish();
+                eprint!("{}", profiler);
+
+                if let Some(folded_path) = profile.profile_folded {
+                    if let Err(e) = std::fs::write(&folded_path, profiler.folded()) {
+                        eprintln!("failed to write {}: {}", folded_path.display(), e);
+                    }
+                }
+            }
+
+            if let Err(error) = result {
+                eprintln!("{}", error);
+                if let Some(backtrace) = error.backtrace() {
+                    eprint!("{}", backtrace);
+                }
+                std::process::exit(1);
+            }
         }
-        Command::Compile { run, output, path } => {
-            let file = std::fs::File::open(path.clone()).unwrap();
+        Command::TraceSummary { path } => {
+            let file = std::fs::File::open(&path).unwrap_or_else(|e| {
+                eprintln!("failed to open {}: {}", path.display(), e);
+                std::process::exit(1);
+            });
+            match trace::summarize(BufReader::new(file)) {
+                Ok(summary) => print!("{}", summary),
+                Err(e) => {
+                    eprintln!("{}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Debug { path } => {
+            let source = std::fs::read_to_string(&path).unwrap();
+            let name = path.to_string_lossy().to_string();
+            let ast = import_or_exit(parse_or_exit(read_tokens(&path)), &path);
+
+            println!("type help for a list of commands");
+            let debugger = std::rc::Rc::new(std::cell::RefCell::new(debugger::Debugger::new(
+                Some(name),
+                &source,
+                Box::new(std::io::stdin().lock()),
+                Box::new(std::io::stdout()),
+            )));
 
-            let tokens = lexer::tokenize(BufReader::new(file));
+            let mut vm = vm::VM::new();
+            let hook = debugger.clone();
+            vm.set_hook(move |vm, event| hook.borrow_mut().event(vm, event));
+
+            match vm.evaluate(ast) {
+                Ok(()) => println!("finished with stack {}", vm.stack()),
+                Err(_) if debugger.borrow().quit => {}
+                Err(error) => {
+                    eprintln!("{}", error);
+                    if let Some(backtrace) = error.backtrace() {
+                        eprint!("{}", backtrace);
+                    }
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Compile {
+            run,
+            output,
+            profile,
+            path,
+        } => {
+            let tokens = read_tokens(&path);
             log::info!("Tokens: {:#?}", tokens);
 
-            let ast = parser::parse(tokens);
+            let ast = parse_or_exit(tokens);
             log::info!("AST:\n{:#?}", ast);
 
-            let c_code = compile_c::compile(ast);
+            let ast = prepare_or_exit(import_or_exit(ast, &path));
+            let c_code = compile_c::compile_with_profile(ast, profile);
 
             // Set output path if not specified
             let c_path = match output {
@@ -135,12 +476,412 @@ fn main() {
                     panic!("clang failed");
                 }
 
+                // Exit with the same code as the program, so this can be used like the vm
                 let mut cmd = std::process::Command::new(exe_path);
                 let status = cmd.status().unwrap();
                 if !status.success() {
-                    panic!("program failed");
+                    std::process::exit(status.code().unwrap_or(1));
+                }
+            }
+        }
+        Command::Check { types, path } => {
+            let ast = parse_or_exit(read_tokens(&path));
+            let ast = prepare_or_exit(import_or_exit(ast, &path));
+            let (globals, errors) = typecheck::check(&ast, &arity::HostArities::new());
+
+            if types {
+                for (name, t) in globals {
+                    println!("{}: {}", name, t);
                 }
             }
+
+            for error in errors.iter() {
+                eprintln!("{}", error);
+            }
+            if !errors.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Builtins => {
+            for builtin in builtins::BUILTINS {
+                println!("{}", builtin);
+            }
+        }
+        Command::Golden {
+            bless,
+            backend,
+            timeout,
+            paths,
+        } => {
+            let backends = match backend.as_deref() {
+                None => golden::Backend::ALL.to_vec(),
+                Some("vm") => vec![golden::Backend::Vm],
+                Some("c") => vec![golden::Backend::C],
+                Some(other) => {
+                    eprintln!("unknown backend {:?}, expected vm or c", other);
+                    std::process::exit(1);
+                }
+            };
+            let timeout = std::time::Duration::from_secs(timeout);
+            let exe = env::current_exe().unwrap();
+
+            let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+            for path in paths.iter().flat_map(|path| golden::examples(path)) {
+                for backend in backends.iter().copied() {
+                    let name = format!("{} [{}]", path.display(), backend.name());
+
+                    if let Some(reason) = golden::skipped(&path, backend) {
+                        println!("{} ... skipped ({})", name, reason);
+                        skipped += 1;
+                        continue;
+                    }
+
+                    let differences = match golden::run(
+                        std::process::Command::new(&exe),
+                        backend,
+                        &path,
+                        timeout,
+                    ) {
+                        Ok(outcome) if bless => {
+                            golden::bless(&path, backend, &outcome).unwrap();
+                            vec![]
+                        }
+                        Ok(outcome) => golden::check(&path, backend, &outcome),
+                        Err(error) => vec![error],
+                    };
+
+                    if differences.is_empty() {
+                        println!("{} ... {}", name, if bless { "blessed" } else { "ok" });
+                        passed += 1;
+                    } else {
+                        println!("{} ... FAILED", name);
+                        for difference in differences {
+                            println!("{}", difference);
+                        }
+                        failed += 1;
+                    }
+                }
+            }
+
+            println!(
+                "\n{} passed, {} failed, {} skipped",
+                passed, failed, skipped
+            );
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::DiffTest {
+            paths,
+            random,
+            seed,
+            timeout,
+        } => {
+            if paths.is_empty() && random == 0 {
+                eprintln!("nothing to test, pass programs to run or --random N");
+                std::process::exit(1);
+            }
+
+            let timeout = std::time::Duration::from_secs(timeout);
+            let exe = env::current_exe().unwrap();
+
+            // Random programs are written to files, so a difference can be rerun
+            let seed = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            });
+            let dir = env::temp_dir().join("stacklang-diff");
+            let mut programs = paths;
+            for seed in seed..seed + random {
+                std::fs::create_dir_all(&dir).unwrap();
+                let path = dir.join(format!("random-{}.stack", seed));
+                std::fs::write(&path, stacklang::generate::program(seed)).unwrap();
+                programs.push(path);
+            }
+
+            let (mut same, mut different) = (0, 0);
+            for path in programs {
+                match diff_test::diff(&exe, &path, timeout) {
+                    Ok(None) => {
+                        println!("{} ... same", path.display());
+                        same += 1;
+                    }
+                    Ok(Some(difference)) => {
+                        println!("{} ... DIFFERENT", path.display());
+                        println!("{}", difference);
+                        different += 1;
+                    }
+                    Err(error) => {
+                        println!("{} ... FAILED", path.display());
+                        println!("{}", error);
+                        different += 1;
+                    }
+                }
+            }
+
+            println!("\n{} same, {} different", same, different);
+            if different > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Fuzz {
+            target,
+            iterations,
+            seed,
+            corpus,
+            crashes,
+        } => {
+            let targets = match target.as_str() {
+                "all" => fuzz::Target::ALL.to_vec(),
+                name => match fuzz::Target::from_name(name) {
+                    Some(target) => vec![target],
+                    None => {
+                        eprintln!(
+                            "unknown target {:?}, expected tokenize, parse, evaluate or all",
+                            name
+                        );
+                        std::process::exit(1);
+                    }
+                },
+            };
+            let seed = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            });
+            let corpus = fuzz::load_corpus(&corpus).unwrap_or_else(|e| {
+                eprintln!("failed to load corpus from {}: {}", corpus.display(), e);
+                std::process::exit(1);
+            });
+
+            // Deep recursion in a program recurses in the VM as well, so give it plenty of stack
+            let found = std::thread::Builder::new()
+                .stack_size(256 * 1024 * 1024)
+                .spawn(move || {
+                    targets
+                        .into_iter()
+                        .map(|target| (target, fuzz::fuzz(target, &corpus, iterations, seed)))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+
+            // Programs run by the evaluate target write to stdout, so report on stderr
+            let mut total = 0;
+            for (target, target_crashes) in found {
+                eprintln!(
+                    "{}: {} crash{} (seed {})",
+                    target.name(),
+                    target_crashes.len(),
+                    if target_crashes.len() == 1 { "" } else { "es" },
+                    seed
+                );
+                for (i, crash) in target_crashes.iter().enumerate() {
+                    std::fs::create_dir_all(&crashes).unwrap();
+                    let path = crashes.join(format!("{}-{}.stack", target.name(), i + 1));
+                    std::fs::write(&path, &crash.input).unwrap();
+                    eprintln!(
+                        "  {}: {} ({})",
+                        crash.location,
+                        crash.message.lines().next().unwrap_or_default(),
+                        path.display()
+                    );
+                }
+                total += target_crashes.len();
+            }
+
+            if total > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Test { paths } => {
+            let files = paths.iter().flat_map(|path| golden::examples(path));
+
+            let (mut passed, mut failed) = (0, 0);
+            for path in files {
+                let source = std::fs::read_to_string(&path).unwrap();
+
+                let results = match test_runner::run_tests(&path, &source) {
+                    Ok(results) => results,
+                    Err(error) => {
+                        println!("{} ... FAILED", path.display());
+                        println!("    {}", error);
+                        failed += 1;
+                        continue;
+                    }
+                };
+                for result in results {
+                    match result.error {
+                        None => {
+                            println!("{} {} ... ok", result.path.display(), result.name);
+                            passed += 1;
+                        }
+                        Some(error) => {
+                            println!("{} {} ... FAILED", result.path.display(), result.name);
+                            println!("    {}", error);
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+
+            println!("\n{} passed, {} failed", passed, failed);
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Lint { paths } => {
+            let files = paths.iter().flat_map(|path| golden::examples(path));
+
+            let mut count = 0;
+            for path in files {
+                let source = std::fs::read_to_string(&path).unwrap();
+                match lint::lint_source(&source, Some(path.display().to_string())) {
+                    Ok(lints) => {
+                        for lint in lints {
+                            println!("{}", lint);
+                            count += 1;
+                        }
+                    }
+                    Err(error) => {
+                        println!("{}", error);
+                        count += 1;
+                    }
+                }
+            }
+
+            if count > 0 {
+                println!("\n{} problems found", count);
+                std::process::exit(1);
+            }
+        }
+        Command::Tokens { json, path } => {
+            let tokens = read_tokens(&path);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&tokens).unwrap());
+            } else {
+                for token in tokens {
+                    println!("{}\t{}", token.span, token.token);
+                }
+            }
+        }
+        Command::Ast { json, path } => {
+            let ast = parse_or_exit(read_tokens(&path));
+            if json {
+                println!("{}", serde_json::to_string_pretty(&ast).unwrap());
+            } else {
+                print!("{}", ast.tree());
+            }
+        }
+        Command::Lsp => {
+            let stdin = std::io::stdin();
+            if let Err(error) = lsp::serve(stdin.lock(), std::io::stdout().lock()) {
+                eprintln!("lsp: {}", error);
+                std::process::exit(1);
+            }
+        }
+        Command::Grammar { format } => match format.as_str() {
+            "textmate" => println!(
+                "{}",
+                serde_json::to_string_pretty(&grammar::textmate()).unwrap()
+            ),
+            "tree-sitter" => print!("{}", grammar::tree_sitter()),
+            _ => {
+                eprintln!(
+                    "unknown format {:?}, expected textmate or tree-sitter",
+                    format
+                );
+                std::process::exit(1);
+            }
+        },
+        Command::Fmt { check, paths } => {
+            let files = paths.iter().flat_map(|path| golden::examples(path));
+
+            let mut unformatted = 0;
+            for path in files {
+                let source = std::fs::read_to_string(&path).unwrap();
+                let formatted = match format::format(&source, Some(path.display().to_string())) {
+                    Ok(formatted) => formatted,
+                    Err(error) => {
+                        eprintln!("{}", error);
+                        unformatted += 1;
+                        continue;
+                    }
+                };
+                if formatted == source {
+                    continue;
+                }
+
+                if check {
+                    println!("{} is not formatted", path.display());
+                    unformatted += 1;
+                } else {
+                    std::fs::write(&path, formatted).unwrap();
+                    println!("formatted {}", path.display());
+                }
+            }
+
+            if unformatted > 0 {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Tokenize a file, naming spans with its path, printing the error and exiting if it can't be read or tokenized
+fn read_tokens(path: &Path) -> Vec<types::Token> {
+    let source = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("cannot read {}: {}", path.display(), e);
+        std::process::exit(1);
+    });
+    lexer::try_tokenize_named(source.as_bytes(), Some(path.display().to_string())).unwrap_or_else(
+        |error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        },
+    )
+}
+
+/// Parse tokens, printing the error and exiting if they can't be parsed
+fn parse_or_exit(tokens: Vec<types::Token>) -> types::Expression {
+    parser::try_parse(tokens).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    })
+}
+
+/// Expand the imports in a program read from path, printing any errors and exiting if there are any
+fn import_or_exit(ast: types::Expression, path: &Path) -> types::Expression {
+    match import::expand(ast, path) {
+        Ok(ast) => ast,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Resolve names and infer arity in a program, printing any errors and exiting if there are any
+fn prepare_or_exit(ast: types::Expression) -> types::Expression {
+    match resolver::Resolver::new().resolve(ast) {
+        Ok(ast) => {
+            let (ast, warnings) = arity::infer(ast, &arity::HostArities::new());
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+            ast
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            std::process::exit(1);
         }
     }
 }
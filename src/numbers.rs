@@ -1,9 +1,10 @@
+use serde::{Deserialize, Serialize};
 use std::ops::*;
 
-/// ----- Shared numeric tower implementation -----
+// ----- Shared numeric tower implementation -----
 
 /// The numeric tower
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum Number {
     Integer(i64),
     Rational { numerator: i64, denominator: u64 },
@@ -157,6 +158,34 @@ do_op!(Mul, mul, *);
 do_op!(Div, div, /);
 do_op!(Rem, rem, %);
 
+macro_rules! do_checked_op {
+    ($f:ident, $checked:ident, $op:tt) => {
+        /// Like the operator, but None if an integer or rational part overflows
+        pub fn $f(self, rhs: Self) -> Option<Number> {
+            let (a, b) = Number::coerce(self, rhs);
+            Some(match (a, b) {
+                (Number::Integer(av), Number::Integer(bv)) => Number::Integer(av.$checked(bv)?),
+                (
+                    Number::Rational { numerator: an, denominator: ad },
+                    Number::Rational { numerator: bn, denominator: bd }
+                ) => Number::Rational {
+                    numerator: an.$checked(bn)?,
+                    denominator: ad.$checked(bd)?
+                },
+                _ => a $op b,
+            })
+        }
+    };
+}
+
+impl Number {
+    do_checked_op!(checked_add, checked_add, +);
+    do_checked_op!(checked_sub, checked_sub, -);
+    do_checked_op!(checked_mul, checked_mul, *);
+    do_checked_op!(checked_div, checked_div, /);
+    do_checked_op!(checked_rem, checked_rem, %);
+}
+
 impl PartialEq for Number {
     fn eq(&self, other: &Self) -> bool {
         let (a, b) = Number::coerce(*self, *other);
@@ -1,117 +1,198 @@
+use crate::error::SyntaxError;
 use crate::numbers::Number;
-use crate::types::{Expression, Token, Value};
+use crate::types::{Expression, ExpressionKind, Token, Value};
 
 /// Parses a vector of tokens into a vector of expressions.
+///
+/// Panics if the tokens can't be parsed, see try_parse
 pub fn parse(tokens: Vec<Token>) -> Expression {
+    try_parse(tokens).unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// Parses a vector of tokens, returning an error if a prefix (@, ! or $) isn't followed by anything
+/// or a bracket isn't matched
+pub fn try_parse(tokens: Vec<Token>) -> Result<Expression, SyntaxError> {
     log::debug!("parse({:?})", tokens);
+    let tokens = tokens
+        .into_iter()
+        .filter(|token| !token.is_comment())
+        .collect::<Vec<_>>();
 
     // A helper to parse a single expression from the current position in the token stream
-    fn parse_one(tokens: &[Token]) -> (Expression, &[Token]) {
-        if tokens[0].token == "@" {
+    fn parse_one(tokens: &[Token]) -> Result<(Expression, &[Token]), SyntaxError> {
+        let span = tokens[0].span.clone();
+
+        let (kind, tokens) = if tokens[0].token == "@" {
             // @ expressions prefix the next value (naming)
-            let (next, tokens) = parse_one(&tokens[1..]);
-            (Expression::At(Box::new(next)), tokens)
+            let (next, tokens) = parse_prefixed(tokens)?;
+            (ExpressionKind::At(Box::new(next)), tokens)
         } else if tokens[0].token == "!" {
             // ! expressions prefix the next value (assignment)
-            let (next, tokens) = parse_one(&tokens[1..]);
-            (Expression::Bang(Box::new(next)), tokens)
+            let (next, tokens) = parse_prefixed(tokens)?;
+            (ExpressionKind::Bang(Box::new(next)), tokens)
         } else if tokens[0].token == "$" {
             // $ expressions allow pushing a block to the stack
-            let (next, tokens) = parse_one(&tokens[1..]);
-            (Expression::Dollar(Box::new(next)), tokens)
+            let (next, tokens) = parse_prefixed(tokens)?;
+            (ExpressionKind::Dollar(Box::new(next)), tokens)
         } else if tokens[0].token == "{" {
             // { expressions are blocks
-            let (children, tokens) = parse_until(&tokens[1..], Some(String::from("}")));
-            (Expression::Block(children), tokens)
+            let (children, tokens) = parse_until(&tokens[1..], Some((&tokens[0], "}")))?;
+            (
+                ExpressionKind::Block {
+                    children,
+                    arity: None,
+                },
+                tokens,
+            )
         } else if tokens[0].token == "[" {
             // [ expressions are lists
-            let (children, tokens) = parse_until(&tokens[1..], Some(String::from("]")));
-            (Expression::List(children), tokens)
+            let (children, tokens) = parse_until(&tokens[1..], Some((&tokens[0], "]")))?;
+            (ExpressionKind::List(children), tokens)
         } else if tokens[0].token == "(" {
             // ( expressions are groups
-            let (children, tokens) = parse_until(&tokens[1..], Some(String::from(")")));
-            (Expression::Group(children), tokens)
+            let (children, tokens) = parse_until(&tokens[1..], Some((&tokens[0], ")")))?;
+            (ExpressionKind::Group(children), tokens)
+        } else if ["}", "]", ")"].contains(&tokens[0].token.as_str()) {
+            // Closing brackets are consumed by parse_until, so any others don't close anything
+            return Err(SyntaxError {
+                span,
+                message: format!("Unexpected {}", tokens[0].token),
+            });
         } else {
             // Try to parse each literal value, if none match assume it's an identifier
-            if let Some(v) = tokens[0].token.parse::<i64>().ok() {
+            if let Ok(v) = tokens[0].token.parse::<i64>() {
                 (
-                    Expression::Literal(Value::Number(Number::Integer(v))),
+                    ExpressionKind::Literal(Value::Number(Number::Integer(v))),
                     &tokens[1..],
                 )
-            } else if let Some(v) = tokens[0].token.parse::<f64>().ok() {
+            } else if let Ok(v) = tokens[0].token.parse::<f64>() {
                 (
-                    Expression::Literal(Value::Number(Number::Float(v))),
+                    ExpressionKind::Literal(Value::Number(Number::Float(v))),
                     &tokens[1..],
                 )
-            } else if tokens[0].token.starts_with("\"") {
+            } else if tokens[0].token.starts_with('"') {
                 (
-                    Expression::Literal(Value::String(
+                    ExpressionKind::Literal(Value::String(
                         tokens[0].token.trim_matches('"').to_string(),
                     )),
                     &tokens[1..],
                 )
             } else if tokens[0].token == "true" || tokens[0].token == "false" {
                 (
-                    Expression::Literal(Value::Boolean(tokens[0].token == "true")),
+                    ExpressionKind::Literal(Value::Boolean(tokens[0].token == "true")),
                     &tokens[1..],
                 )
-            } else if tokens[0].token.contains(".") {
+            } else if tokens[0].token.contains('.') {
                 (
-                    Expression::DottedIdentifier(
-                        tokens[0].token.split(".").map(|s| s.to_string()).collect(),
+                    ExpressionKind::DottedIdentifier(
+                        tokens[0].token.split('.').map(|s| s.to_string()).collect(),
                     ),
                     &tokens[1..],
                 )
             } else {
                 (
-                    Expression::Identifier(tokens[0].token.clone()),
+                    ExpressionKind::Identifier(tokens[0].token.clone()),
                     &tokens[1..],
                 )
             }
+        };
+
+        Ok((Expression::new(kind, span), tokens))
+    }
+
+    // Parse the value after a prefix
+    fn parse_prefixed(tokens: &[Token]) -> Result<(Expression, &[Token]), SyntaxError> {
+        if tokens.len() < 2 {
+            return Err(SyntaxError {
+                span: tokens[0].span.clone(),
+                message: format!("{} must be followed by a value", tokens[0].token),
+            });
         }
+        parse_one(&tokens[1..])
     }
 
-    // A helper to parse a list of expressions until a given ending token
-    // If ending is not set, parse until end of stream
-    fn parse_until(tokens: &[Token], ending: Option<String>) -> (Vec<Expression>, &[Token]) {
+    // A helper to parse a list of expressions until the bracket closing the given opening one
+    // If there is no opening bracket, parse until end of stream
+    fn parse_until<'a>(
+        tokens: &'a [Token],
+        opening: Option<(&Token, &str)>,
+    ) -> Result<(Vec<Expression>, &'a [Token]), SyntaxError> {
         let mut tokens = tokens;
         let mut expressions = vec![];
 
-        while !tokens.is_empty() && Some(tokens[0].token.clone()) != ending {
-            let (expression, next_tokens) = parse_one(tokens);
+        while !tokens.is_empty() && Some(tokens[0].token.as_str()) != opening.map(|(_, c)| c) {
+            let (expression, next_tokens) = parse_one(tokens)?;
             expressions.push(expression);
             tokens = next_tokens;
         }
 
-        if !tokens.is_empty() && Some(tokens[0].token.clone()) == ending {
-            (expressions, &tokens[1..])
-        } else {
-            (expressions, tokens)
+        match opening {
+            Some((opener, _)) if tokens.is_empty() => Err(SyntaxError {
+                span: opener.span.clone(),
+                message: format!("Unclosed {}", opener.token),
+            }),
+            Some(_) => Ok((expressions, &tokens[1..])),
+            None => Ok((expressions, tokens)),
         }
     }
 
     // Parse the entire stream
-    // TODO: This should be an exception if the stream is not empty after this
-    Expression::Group(parse_until(tokens.as_slice(), None).0)
+    let span = tokens.first().map(|t| t.span.clone()).unwrap_or_default();
+    Ok(Expression::new(
+        ExpressionKind::Group(parse_until(tokens.as_slice(), None)?.0),
+        span,
+    ))
 }
 
 #[cfg(test)]
 mod test {
     use crate::lexer::tokenize;
     use crate::numbers::Number;
-    use crate::parser::parse;
-    use crate::types::{Expression, Value};
+    use crate::parser::{parse, try_parse};
+    use crate::types::{Expression, ExpressionKind, Value};
+
+    // Helpers to build expected expressions, spans are ignored when comparing
+    fn group(children: Vec<Expression>) -> Expression {
+        ExpressionKind::Group(children).into()
+    }
+
+    fn block(children: Vec<Expression>) -> Expression {
+        ExpressionKind::Block {
+            children,
+            arity: None,
+        }
+        .into()
+    }
+
+    fn list(children: Vec<Expression>) -> Expression {
+        ExpressionKind::List(children).into()
+    }
+
+    fn at(child: Expression) -> Expression {
+        ExpressionKind::At(Box::new(child)).into()
+    }
+
+    fn bang(child: Expression) -> Expression {
+        ExpressionKind::Bang(Box::new(child)).into()
+    }
+
+    fn dollar(child: Expression) -> Expression {
+        ExpressionKind::Dollar(Box::new(child)).into()
+    }
+
+    fn id(name: &str) -> Expression {
+        ExpressionKind::Identifier(String::from(name)).into()
+    }
+
+    fn int(v: i64) -> Expression {
+        ExpressionKind::Literal(Value::Number(Number::Integer(v))).into()
+    }
 
     #[test]
     fn test_integer() {
         let input = tokenize("123".as_bytes());
         let output = parse(input);
-        assert_eq!(
-            output,
-            Expression::Group(vec![Expression::Literal(Value::Number(Number::Integer(
-                123
-            )))])
-        );
+        assert_eq!(output, group(vec![int(123)]));
     }
 
     #[test]
@@ -120,9 +201,10 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![Expression::Literal(Value::Number(Number::Float(
+            group(vec![ExpressionKind::Literal(Value::Number(Number::Float(
                 123.456
-            )))])
+            )))
+            .into()])
         );
     }
 
@@ -132,9 +214,10 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![Expression::Literal(Value::String(String::from(
+            group(vec![ExpressionKind::Literal(Value::String(String::from(
                 "hello world"
-            )))])
+            )))
+            .into()])
         );
     }
 
@@ -144,7 +227,7 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![Expression::Literal(Value::Boolean(true))])
+            group(vec![ExpressionKind::Literal(Value::Boolean(true)).into()])
         );
     }
 
@@ -152,34 +235,21 @@ mod test {
     fn test_simple_addition() {
         let input = tokenize("1 2 +".as_bytes());
         let output = parse(input);
-        assert_eq!(
-            output,
-            Expression::Group(vec![
-                Expression::Literal(Value::Number(Number::Integer(1))),
-                Expression::Literal(Value::Number(Number::Integer(2))),
-                Expression::Identifier(String::from("+")),
-            ])
-        );
+        assert_eq!(output, group(vec![int(1), int(2), id("+")]));
     }
 
     #[test]
     fn test_identifier() {
         let input = tokenize("a".as_bytes());
         let output = parse(input);
-        assert_eq!(
-            output,
-            Expression::Group(vec![Expression::Identifier(String::from("a"))])
-        );
+        assert_eq!(output, group(vec![id("a")]));
     }
 
     #[test]
     fn test_symbolic_identifier() {
         let input = tokenize("<=".as_bytes());
         let output = parse(input);
-        assert_eq!(
-            output,
-            Expression::Group(vec![Expression::Identifier(String::from("<="))])
-        );
+        assert_eq!(output, group(vec![id("<=")]));
     }
 
     #[test]
@@ -188,11 +258,12 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![Expression::DottedIdentifier(vec![
+            group(vec![ExpressionKind::DottedIdentifier(vec![
                 String::from("a"),
                 String::from("b"),
                 String::from("c"),
-            ])])
+            ])
+            .into()])
         );
     }
 
@@ -200,12 +271,7 @@ mod test {
     fn test_naming() {
         let input = tokenize("@a".as_bytes());
         let output = parse(input);
-        assert_eq!(
-            output,
-            Expression::Group(vec![Expression::At(Box::new(Expression::Identifier(
-                String::from("a")
-            )))])
-        );
+        assert_eq!(output, group(vec![at(id("a"))]));
     }
 
     #[test]
@@ -214,11 +280,7 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![Expression::At(Box::new(Expression::List(vec![
-                Expression::Identifier(String::from("a")),
-                Expression::Identifier(String::from("b")),
-                Expression::Identifier(String::from("c")),
-            ])))])
+            group(vec![at(list(vec![id("a"), id("b"), id("c")]))])
         );
     }
 
@@ -226,14 +288,7 @@ mod test {
     fn test_simple_block() {
         let input = tokenize("{ 1 2 + }".as_bytes());
         let output = parse(input);
-        assert_eq!(
-            output,
-            Expression::Group(vec![Expression::Block(vec![
-                Expression::Literal(Value::Number(Number::Integer(1))),
-                Expression::Literal(Value::Number(Number::Integer(2))),
-                Expression::Identifier(String::from("+")),
-            ])])
-        );
+        assert_eq!(output, group(vec![block(vec![int(1), int(2), id("+")])]));
     }
 
     #[test]
@@ -242,16 +297,28 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![
-                Expression::Literal(Value::Number(Number::Integer(1))),
-                Expression::Bang(Box::new(Expression::Identifier(String::from("a")))),
-                Expression::Identifier(String::from("a")),
-                Expression::Identifier(String::from("a")),
-                Expression::Identifier(String::from("+")),
-            ])
+            group(vec![int(1), bang(id("a")), id("a"), id("a"), id("+")])
         );
     }
 
+    #[test]
+    fn test_spans() {
+        let input = tokenize("1 2\n{ @n n }".as_bytes());
+        let output = parse(input);
+
+        let ExpressionKind::Group(children) = output.kind else {
+            panic!("expected a group, got {}", output);
+        };
+        assert_eq!((children[1].span.row, children[1].span.column), (0, 2));
+        assert_eq!((children[2].span.row, children[2].span.column), (1, 0));
+
+        let ExpressionKind::Block { children: body, .. } = &children[2].kind else {
+            panic!("expected a block, got {}", children[2]);
+        };
+        assert_eq!((body[0].span.row, body[0].span.column), (1, 2));
+        assert_eq!((body[1].span.row, body[1].span.column), (1, 5));
+    }
+
     #[test]
     fn test_factorial() {
         let input = tokenize(
@@ -269,36 +336,71 @@ mod test {
         let output = parse(input);
         assert_eq!(
             output,
-            Expression::Group(vec![
-                Expression::Block(vec![
-                    Expression::At(Box::new(Expression::List(vec![
-                        Expression::Identifier(String::from("n")),
-                        Expression::Identifier(String::from("fact")),
-                    ]))),
-                    Expression::Literal(Value::Number(Number::Integer(1))),
-                    Expression::Block(vec![
-                        Expression::At(Box::new(Expression::Literal(Value::Number(
-                            Number::Integer(0)
-                        )),)),
-                        Expression::Identifier(String::from("n")),
-                        Expression::Literal(Value::Number(Number::Integer(1))),
-                        Expression::Identifier(String::from("-")),
-                        Expression::Dollar(Box::new(Expression::Identifier(String::from("fact")))),
-                        Expression::Identifier(String::from("fact")),
-                        Expression::Identifier(String::from("n")),
-                        Expression::Identifier(String::from("*")),
+            group(vec![
+                block(vec![
+                    at(list(vec![id("n"), id("fact")])),
+                    int(1),
+                    block(vec![
+                        at(int(0)),
+                        id("n"),
+                        int(1),
+                        id("-"),
+                        dollar(id("fact")),
+                        id("fact"),
+                        id("n"),
+                        id("*"),
                     ]),
-                    Expression::Identifier(String::from("n")),
-                    Expression::Literal(Value::Number(Number::Integer(1))),
-                    Expression::Identifier(String::from("<")),
-                    Expression::Identifier(String::from("if")),
+                    id("n"),
+                    int(1),
+                    id("<"),
+                    id("if"),
                 ]),
-                Expression::At(Box::new(Expression::Identifier(String::from("fact")))),
-                Expression::Literal(Value::Number(Number::Integer(5))),
-                Expression::Dollar(Box::new(Expression::Identifier(String::from("fact")))),
-                Expression::Identifier(String::from("fact")),
-                Expression::Identifier(String::from("writeln")),
+                at(id("fact")),
+                int(5),
+                dollar(id("fact")),
+                id("fact"),
+                id("writeln"),
             ])
         );
     }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let source = "{ @[a b] a.x \"s\" 1.5 3/4 true $b } @f 1 2 f";
+        let ast = parse(tokenize(source.as_bytes()));
+
+        let json = serde_json::to_string(&ast).unwrap();
+        let parsed: Expression = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, ast);
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", ast));
+    }
+
+    #[test]
+    fn test_unmatched_brackets() {
+        let error = |input: &str| {
+            try_parse(tokenize(input.as_bytes()))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("{ 1 2"), "<unknown>:1:0:1: Unclosed {");
+        assert_eq!(error("[ ( 1 ]"), "<unknown>:1:6:1: Unexpected ]");
+        assert_eq!(error("1 }"), "<unknown>:1:2:1: Unexpected }");
+        assert_eq!(error("{ @ } @test-b"), "<unknown>:1:4:1: Unexpected }");
+    }
+
+    #[test]
+    fn test_tree() {
+        let ast = parse(tokenize("{ @n \"a\" } @f".as_bytes()));
+        assert_eq!(
+            ast.tree(),
+            "Group <unknown>:1:0:1
+  Block <unknown>:1:0:1
+    At <unknown>:1:2:1
+      Identifier n <unknown>:1:3:1
+    Literal \"a\" <unknown>:1:5:3
+  At <unknown>:1:11:1
+    Identifier f <unknown>:1:12:1
+"
+        );
+    }
 }
@@ -0,0 +1,300 @@
+//! Profiling the VM, counting calls and time spent in each named block and builtin
+//!
+//! Inclusive time is from a call until it returns, exclusive time leaves out named blocks and builtins it calls
+//! Anonymous blocks (such as the body of a loop) count towards whatever called them
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::types::ExpressionKind;
+use crate::vm::Event;
+
+// The name of the frame for anything outside of a named block or builtin
+const ROOT: &str = "main";
+
+/// What was called
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Block,
+    Builtin,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Block => "block",
+            Kind::Builtin => "builtin",
+        }
+    }
+}
+
+/// Counts for a single named block or builtin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub kind: Kind,
+    pub calls: usize,
+    /// Time from each call until it returned, recursive calls are only counted once
+    pub inclusive: Duration,
+    /// Time spent in this and not something it called
+    pub exclusive: Duration,
+}
+
+// A call that hasn't returned yet
+#[derive(Debug)]
+struct Frame {
+    entry: usize,
+    start: Instant,
+    // Inclusive time of the calls this made, subtracted for exclusive time
+    children: Duration,
+    // The names of every frame down to this one, separated by ;
+    path: String,
+}
+
+/// Collects counts from the events of a VM, see VM::set_hook
+#[derive(Debug, Default)]
+pub struct Profiler {
+    entries: Vec<Entry>,
+    index: HashMap<(Kind, String), usize>,
+    // How many calls to each entry haven't returned, for recursion
+    active: Vec<usize>,
+    frames: Vec<Frame>,
+    // For each block entered, true if it was named and so has a frame
+    named: Vec<bool>,
+    // Exclusive time for each stack of calls
+    folded: HashMap<String, Duration>,
+    start: Option<Instant>,
+    // Time at the top level not in any call
+    root_children: Duration,
+    total: Duration,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    /// Called for each event, see VM::set_hook
+    pub fn event(&mut self, event: Event) {
+        let now = Instant::now();
+        self.start.get_or_insert(now);
+
+        match event {
+            Event::Evaluate(expression) => {
+                if let ExpressionKind::Builtin(name) = &expression.kind {
+                    self.push(Kind::Builtin, name, now);
+                }
+            }
+            Event::Return(_) => self.pop(now),
+            Event::Enter { name, .. } => {
+                self.named.push(name.is_some());
+                if let Some(name) = name {
+                    self.push(Kind::Block, name, now);
+                }
+            }
+            Event::Exit { .. } => {
+                if self.named.pop() == Some(true) {
+                    self.pop(now);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, kind: Kind, name: &str, now: Instant) {
+        let entry = match self.index.get(&(kind, name.to_string())) {
+            Some(entry) => *entry,
+            None => {
+                self.entries.push(Entry {
+                    name: name.to_string(),
+                    kind,
+                    calls: 0,
+                    inclusive: Duration::ZERO,
+                    exclusive: Duration::ZERO,
+                });
+                self.active.push(0);
+                self.index
+                    .insert((kind, name.to_string()), self.entries.len() - 1);
+                self.entries.len() - 1
+            }
+        };
+
+        self.entries[entry].calls += 1;
+        self.active[entry] += 1;
+
+        let parent = self.frames.last().map_or(ROOT, |frame| frame.path.as_str());
+        let path = format!("{};{}", parent, name);
+        self.frames.push(Frame {
+            entry,
+            start: now,
+            children: Duration::ZERO,
+            path,
+        });
+    }
+
+    fn pop(&mut self, now: Instant) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+
+        let inclusive = now - frame.start;
+        let exclusive = inclusive.saturating_sub(frame.children);
+
+        self.active[frame.entry] -= 1;
+        let entry = &mut self.entries[frame.entry];
+        if self.active[frame.entry] == 0 {
+            entry.inclusive += inclusive;
+        }
+        entry.exclusive += exclusive;
+        *self.folded.entry(frame.path).or_default() += exclusive;
+
+        match self.frames.last_mut() {
+            Some(parent) => parent.children += inclusive,
+            None => self.root_children += inclusive,
+        }
+    }
+
+    /// Stop timing, returning from any calls still running (if the program stopped with an error)
+    pub fn finish(&mut self) {
+        let now = Instant::now();
+        while !self.frames.is_empty() {
+            self.pop(now);
+        }
+        self.named.clear();
+
+        if let Some(start) = self.start {
+            self.total = now - start;
+        }
+    }
+
+    /// Every block and builtin called, the most exclusive time first
+    pub fn entries(&self) -> Vec<&Entry> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            b.exclusive
+                .cmp(&a.exclusive)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        entries
+    }
+
+    /// Stacks of calls with the exclusive time in each (in microseconds), one per line
+    ///
+    /// This is the folded format that flamegraph tools (such as inferno or flamegraph.pl) read
+    pub fn folded(&self) -> String {
+        let root = self.total.saturating_sub(self.root_children);
+        let mut lines = self
+            .folded
+            .iter()
+            .map(|(path, time)| (path.as_str(), *time))
+            .chain(std::iter::once((ROOT, root)))
+            .filter(|(_, time)| time.as_micros() > 0)
+            .map(|(path, time)| format!("{} {}\n", path, time.as_micros()))
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+/// Format a duration in milliseconds
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+impl Display for Profiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "total: {}", millis(self.total))?;
+        writeln!(
+            f,
+            "{:>12} {:>6} {:>12} {:>10}  {:<7}  name",
+            "exclusive", "%", "inclusive", "calls", "kind"
+        )?;
+
+        let total = self.total.as_secs_f64().max(f64::EPSILON);
+        for entry in self.entries() {
+            writeln!(
+                f,
+                "{:>12} {:>5.1}% {:>12} {:>10}  {:<7}  {}",
+                millis(entry.exclusive),
+                100.0 * entry.exclusive.as_secs_f64() / total,
+                millis(entry.inclusive),
+                entry.calls,
+                entry.kind.name(),
+                entry.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Kind, Profiler};
+    use crate::vm::VM;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn profile(source: &str) -> Profiler {
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let hook = profiler.clone();
+
+        let mut vm = VM::new();
+        vm.set_hook(move |_, event| {
+            hook.borrow_mut().event(event);
+            Ok(())
+        });
+        let _ = vm.evaluate_str(source);
+        drop(vm);
+
+        let mut profiler = Rc::try_unwrap(profiler).unwrap().into_inner();
+        profiler.finish();
+        profiler
+    }
+
+    const FACTORIAL: &str = "{ @n { 1 } { n 1 - fact n * } n 1 <= if } @fact 5 fact";
+
+    #[test]
+    fn test_counts() {
+        let profiler = profile(FACTORIAL);
+        let entries = profiler.entries();
+
+        let count = |kind: Kind, name: &str| {
+            entries
+                .iter()
+                .find(|e| e.kind == kind && e.name == name)
+                .map(|e| e.calls)
+        };
+        assert_eq!(count(Kind::Block, "fact"), Some(5));
+        assert_eq!(count(Kind::Builtin, "if"), Some(5));
+        assert_eq!(count(Kind::Builtin, "*"), Some(4));
+        assert_eq!(count(Kind::Block, "n"), None);
+
+        for entry in entries {
+            assert!(entry.inclusive >= entry.exclusive, "{:?}", entry);
+        }
+        assert!(profiler.to_string().contains("block    fact"));
+    }
+
+    #[test]
+    fn test_folded() {
+        let profiler = profile(FACTORIAL);
+
+        // Anonymous blocks count towards the if that called them
+        assert!(profiler.folded.contains_key("main;fact;if;*"));
+        assert!(profiler.folded.contains_key("main;fact;if;fact;if"));
+
+        for line in profiler.folded().lines() {
+            let (path, micros) = line.rsplit_once(' ').unwrap();
+            assert!(path == "main" || path.starts_with("main;"), "{}", line);
+            assert!(micros.parse::<u128>().unwrap() > 0, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_errors_finish_calls() {
+        let profiler = profile("{ @n n \"a\" + } @add 1 add");
+        let entries = profiler.entries();
+        assert!(entries.iter().any(|e| e.name == "add" && e.calls == 1));
+        assert!(profiler.frames.is_empty());
+    }
+}
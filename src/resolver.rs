@@ -0,0 +1,627 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::builtins;
+use crate::numbers::Number;
+use crate::types::{Expression, ExpressionKind, Span, Value};
+
+/// An error found while resolving names, reported before the program is run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolveError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/// The names bound directly in a single block (or at the top level)
+///
+/// Each distinct name gets a slot in the frame for that block, in the order they are first bound
+#[derive(Clone, Debug, Default)]
+struct Scope {
+    // The slot assigned to each name
+    slots: HashMap<String, usize>,
+    // Whether each slot has been bound yet while walking through the block in order
+    bound: Vec<bool>,
+}
+
+impl Scope {
+    /// Create a scope for the names bound directly in the given expressions
+    fn new(body: &[Expression]) -> Self {
+        let mut scope = Scope::default();
+        for expr in body {
+            scope.collect(expr);
+        }
+        scope
+    }
+
+    /// Assign a slot to each name bound by this expression, without looking inside of nested blocks
+    fn collect(&mut self, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::At(inner) => match &inner.kind {
+                ExpressionKind::Identifier(name) => self.add(name),
+                ExpressionKind::List(names) => {
+                    for name in names {
+                        if let ExpressionKind::Identifier(name) = &name.kind {
+                            self.add(name);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                for child in children {
+                    self.collect(child);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Add a name to the scope if it doesn't already have a slot
+    fn add(&mut self, name: &str) {
+        if !self.slots.contains_key(name) {
+            self.slots.insert(name.to_string(), self.slots.len());
+            self.bound.push(false);
+        }
+    }
+}
+
+/// Resolves names to frame slots (or builtins) ahead of time
+///
+/// Names are lexically scoped: a name refers to the innermost enclosing block that binds it.
+/// Within a single block, a name can only be used after it is bound; in nested blocks a name
+/// can be used before it is bound, since the block might be called later (for recursion).
+#[derive(Clone, Debug, Default)]
+pub struct Resolver {
+    // Names bound at the top level, kept between calls to resolve
+    globals: Scope,
+    // Names of functions defined by the host program, these are treated like builtins
+    host: HashSet<String>,
+}
+
+impl Resolver {
+    /// Creates a new resolver with no globals defined
+    pub fn new() -> Self {
+        Resolver::default()
+    }
+
+    /// Adds a function defined by the host program, these resolve like builtins
+    pub fn define_host(&mut self, name: &str) {
+        self.host.insert(name.to_string());
+    }
+
+    /// The slot a top level name is bound to, if it has been bound
+    pub fn global_slot(&self, name: &str) -> Option<usize> {
+        self.globals
+            .slots
+            .get(name)
+            .copied()
+            .filter(|slot| self.globals.bound[*slot])
+    }
+
+    /// Resolve every name in a top level expression
+    ///
+    /// Top level names are added to the globals if there are no errors.
+    pub fn resolve(&mut self, ast: Expression) -> Result<Expression, Vec<ResolveError>> {
+        log::debug!("resolve({})", ast);
+
+        let (ast, globals, errors) = self.resolve_top_level(ast);
+        if errors.is_empty() {
+            self.globals = globals;
+            Ok(ast)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Resolve every name in a top level expression, returning it along with any errors
+    ///
+    /// Names that can't be resolved are left as identifiers, so tools can follow the same scoping
+    /// rules as the VM in programs with mistakes in them. This doesn't change the globals
+    pub fn resolve_partial(&self, ast: Expression) -> (Expression, Vec<ResolveError>) {
+        let (ast, _, errors) = self.resolve_top_level(ast);
+        (ast, errors)
+    }
+
+    fn resolve_top_level(&self, ast: Expression) -> (Expression, Scope, Vec<ResolveError>) {
+        let mut globals = self.globals.clone();
+        globals.collect(&ast);
+
+        let mut scopes = vec![globals];
+        let mut errors = vec![];
+        let ast = resolve_expr(ast, &mut scopes, &self.host, &mut errors);
+        (ast, scopes.pop().unwrap(), errors)
+    }
+
+    /// Resolve an expression as if it were a block nested within blocks that have bound the given names
+    ///
+    /// enclosing has the (slot, name) of each bound name in each enclosing block, from the top level in
+    /// This doesn't change the globals, see Stack::visible_names
+    pub fn resolve_nested(
+        &self,
+        ast: Expression,
+        enclosing: &[Vec<(usize, String)>],
+    ) -> Result<Expression, Vec<ResolveError>> {
+        let mut scopes = enclosing
+            .iter()
+            .map(|names| {
+                let mut scope = Scope::default();
+                for (slot, name) in names {
+                    if scope.bound.len() <= *slot {
+                        scope.bound.resize(slot + 1, false);
+                    }
+                    scope.slots.insert(name.clone(), *slot);
+                    scope.bound[*slot] = true;
+                }
+                scope
+            })
+            .collect::<Vec<_>>();
+
+        let mut scope = Scope::default();
+        scope.collect(&ast);
+        scopes.push(scope);
+
+        let mut errors = vec![];
+        let ast = resolve_expr(ast, &mut scopes, &self.host, &mut errors);
+        if errors.is_empty() {
+            Ok(ast)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Resolve a single expression within the given (nested) scopes
+fn resolve_expr(
+    expr: Expression,
+    scopes: &mut Vec<Scope>,
+    host: &HashSet<String>,
+    errors: &mut Vec<ResolveError>,
+) -> Expression {
+    let span = expr.span.clone();
+
+    let kind = match expr.kind {
+        ExpressionKind::Identifier(name) => match lookup(&name, scopes, host) {
+            Ok(Some((depth, slot))) => ExpressionKind::Local { name, depth, slot },
+            Ok(None) if is_builtin(&name, host) => ExpressionKind::Builtin(name),
+            Ok(None) => {
+                errors.push(ResolveError {
+                    span: span.clone(),
+                    message: format!("Unknown identifier {:?}", name),
+                });
+                ExpressionKind::Identifier(name)
+            }
+            Err(message) => {
+                errors.push(ResolveError {
+                    span: span.clone(),
+                    message,
+                });
+                ExpressionKind::Identifier(name)
+            }
+        },
+        ExpressionKind::DottedIdentifier(ids) => {
+            errors.push(ResolveError {
+                span: span.clone(),
+                message: format!("Dotted identifiers are not supported: {}", ids.join(".")),
+            });
+            ExpressionKind::DottedIdentifier(ids)
+        }
+        ExpressionKind::Block { children, arity } => {
+            scopes.push(Scope::new(&children));
+            let children = children
+                .into_iter()
+                .map(|child| resolve_expr(child, scopes, host, errors))
+                .collect();
+            scopes.pop();
+
+            ExpressionKind::Block { children, arity }
+        }
+        ExpressionKind::List(children) => ExpressionKind::List(
+            children
+                .into_iter()
+                .map(|child| resolve_expr(child, scopes, host, errors))
+                .collect(),
+        ),
+        ExpressionKind::Group(children) => ExpressionKind::Group(
+            children
+                .into_iter()
+                .map(|child| resolve_expr(child, scopes, host, errors))
+                .collect(),
+        ),
+        // @ expressions bind names in the current block
+        ExpressionKind::At(inner) => match inner.kind {
+            ExpressionKind::Literal(Value::Number(Number::Integer(_))) => ExpressionKind::At(inner),
+            ExpressionKind::Identifier(_) => {
+                ExpressionKind::At(Box::new(bind(*inner, scopes.last_mut().unwrap())))
+            }
+            ExpressionKind::List(names) => {
+                let mut bound = vec![];
+                for name in names {
+                    if let ExpressionKind::Identifier(_) = name.kind {
+                        bound.push(bind(name, scopes.last_mut().unwrap()));
+                    } else {
+                        errors.push(ResolveError {
+                            span: name.span.clone(),
+                            message: format!(
+                                "Invalid @ expression, @[list] must contain only names, got {}",
+                                name
+                            ),
+                        });
+                    }
+                }
+                ExpressionKind::At(Box::new(Expression::new(
+                    ExpressionKind::List(bound),
+                    inner.span,
+                )))
+            }
+            _ => {
+                errors.push(ResolveError {
+                    span: span.clone(),
+                    message: format!(
+                        "Invalid @ expression, must be @name or @[list], got @{}",
+                        inner
+                    ),
+                });
+                ExpressionKind::At(inner)
+            }
+        },
+        // ! expressions set names that have already been bound
+        ExpressionKind::Bang(inner) => match inner.kind {
+            ExpressionKind::Literal(Value::Number(Number::Integer(_))) => {
+                ExpressionKind::Bang(inner)
+            }
+            ExpressionKind::Identifier(ref name) => match lookup(name, scopes, host) {
+                Ok(Some((depth, slot))) => ExpressionKind::Bang(Box::new(Expression::new(
+                    ExpressionKind::Local {
+                        name: name.clone(),
+                        depth,
+                        slot,
+                    },
+                    inner.span,
+                ))),
+                Ok(None) => {
+                    errors.push(ResolveError {
+                        span: span.clone(),
+                        message: format!("Cannot set {:?}, it is never bound with @", name),
+                    });
+                    ExpressionKind::Bang(inner)
+                }
+                Err(message) => {
+                    errors.push(ResolveError {
+                        span: span.clone(),
+                        message,
+                    });
+                    ExpressionKind::Bang(inner)
+                }
+            },
+            _ => {
+                errors.push(ResolveError {
+                    span: span.clone(),
+                    message: format!("Invalid ! expression, must be !# or !name, got !{}", inner),
+                });
+                ExpressionKind::Bang(inner)
+            }
+        },
+        // $ expressions push named values without evaluating them
+        ExpressionKind::Dollar(inner) => match inner.kind {
+            ExpressionKind::Identifier(ref name) => match lookup(name, scopes, host) {
+                Ok(Some((depth, slot))) => ExpressionKind::Dollar(Box::new(Expression::new(
+                    ExpressionKind::Local {
+                        name: name.clone(),
+                        depth,
+                        slot,
+                    },
+                    inner.span,
+                ))),
+                Ok(None) => {
+                    errors.push(ResolveError {
+                        span: span.clone(),
+                        message: format!("Unknown identifier {:?}", name),
+                    });
+                    ExpressionKind::Dollar(inner)
+                }
+                Err(message) => {
+                    errors.push(ResolveError {
+                        span: span.clone(),
+                        message,
+                    });
+                    ExpressionKind::Dollar(inner)
+                }
+            },
+            _ => {
+                errors.push(ResolveError {
+                    span: span.clone(),
+                    message: format!("Invalid $ expression, must be $name, got ${}", inner),
+                });
+                ExpressionKind::Dollar(inner)
+            }
+        },
+        // Anything else (literals or already resolved names) doesn't change
+        kind => kind,
+    };
+
+    Expression::new(kind, span)
+}
+
+/// Bind an identifier in the current scope, returning the resolved local
+fn bind(expr: Expression, scope: &mut Scope) -> Expression {
+    let ExpressionKind::Identifier(name) = expr.kind else {
+        unreachable!("only identifiers can be bound");
+    };
+
+    let slot = scope.slots[&name];
+    scope.bound[slot] = true;
+
+    Expression::new(
+        ExpressionKind::Local {
+            name,
+            depth: 0,
+            slot,
+        },
+        expr.span,
+    )
+}
+
+/// Check if a name is a builtin or a host defined function
+fn is_builtin(name: &str, host: &HashSet<String>) -> bool {
+    host.contains(name) || builtins::lookup(name).is_some()
+}
+
+/// Find the (depth, slot) a name refers to
+///
+/// Returns Ok(None) if the name isn't bound in any enclosing scope (it may be a builtin)
+/// Returns an Err if the name is bound in the current block, but only later on
+fn lookup(
+    name: &str,
+    scopes: &[Scope],
+    host: &HashSet<String>,
+) -> Result<Option<(usize, usize)>, String> {
+    for (depth, scope) in scopes.iter().rev().enumerate() {
+        if let Some(slot) = scope.slots.get(name) {
+            // In the current block, names must already be bound; otherwise check the parent
+            if depth == 0 && !scope.bound[*slot] {
+                continue;
+            }
+
+            return Ok(Some((depth, *slot)));
+        }
+    }
+
+    if scopes.last().unwrap().slots.contains_key(name) && !is_builtin(name, host) {
+        return Err(format!("{:?} is used before it is bound", name));
+    }
+
+    Ok(None)
+}
+
+/// The name of each slot bound directly in a resolved block (or the top level) and where it is first bound
+///
+/// This doesn't look inside nested blocks, see Scope::collect
+pub fn bindings(children: &[Expression]) -> HashMap<usize, (String, Span)> {
+    fn scan(children: &[Expression], bindings: &mut HashMap<usize, (String, Span)>) {
+        for child in children {
+            match &child.kind {
+                ExpressionKind::At(inner) => {
+                    let names = match &inner.kind {
+                        ExpressionKind::List(names) => names.as_slice(),
+                        _ => std::slice::from_ref(inner.as_ref()),
+                    };
+                    for name in names {
+                        if let ExpressionKind::Local { name: id, slot, .. } = &name.kind {
+                            bindings
+                                .entry(*slot)
+                                .or_insert_with(|| (id.clone(), name.span.clone()));
+                        }
+                    }
+                }
+                ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                    scan(children, bindings)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut bindings = HashMap::new();
+    scan(children, &mut bindings);
+    bindings
+}
+
+/// Check if a resolved block uses names from the blocks it is defined in (including from nested blocks)
+///
+/// Blocks that don't can be called after the block they were defined in returns
+pub fn captures(children: &[Expression]) -> bool {
+    fn scan(children: &[Expression], nesting: usize) -> bool {
+        children.iter().any(|child| match &child.kind {
+            ExpressionKind::Local { depth, .. } => *depth > nesting,
+            ExpressionKind::At(inner)
+            | ExpressionKind::Bang(inner)
+            | ExpressionKind::Dollar(inner) => scan(std::slice::from_ref(inner), nesting),
+            ExpressionKind::Block { children, .. } => scan(children, nesting + 1),
+            ExpressionKind::List(children) | ExpressionKind::Group(children) => {
+                scan(children, nesting)
+            }
+            _ => false,
+        })
+    }
+
+    scan(children, 0)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use crate::resolver::{bindings, captures, Resolver};
+    use crate::types::{Expression, ExpressionKind};
+
+    fn resolve(input: &str) -> Result<Expression, Vec<String>> {
+        Resolver::new()
+            .resolve(parse(tokenize(input.as_bytes())))
+            .map_err(|errors| errors.into_iter().map(|e| e.message).collect())
+    }
+
+    fn children(expr: &Expression) -> &Vec<Expression> {
+        match &expr.kind {
+            ExpressionKind::Group(children)
+            | ExpressionKind::Block { children, .. }
+            | ExpressionKind::List(children) => children,
+            _ => panic!("expected children, got {}", expr),
+        }
+    }
+
+    fn local(expr: &Expression) -> (usize, usize) {
+        match &expr.kind {
+            ExpressionKind::Local { depth, slot, .. } => (*depth, *slot),
+            ExpressionKind::At(inner)
+            | ExpressionKind::Bang(inner)
+            | ExpressionKind::Dollar(inner) => local(inner),
+            _ => panic!("expected a local, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_globals() {
+        let ast = resolve("1 @a 2 @b a b +").unwrap();
+        let top = children(&ast);
+        assert_eq!(local(&top[1]), (0, 0));
+        assert_eq!(local(&top[3]), (0, 1));
+        assert_eq!(local(&top[4]), (0, 0));
+        assert_eq!(local(&top[5]), (0, 1));
+        assert_eq!(top[6].kind, ExpressionKind::Builtin(String::from("+")));
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        let ast = resolve("{ @n { n 1 - fact } } @fact").unwrap();
+        let top = children(&ast);
+        let fact = children(&top[0]);
+        assert_eq!(local(&fact[0]), (0, 0));
+
+        let inner = children(&fact[1]);
+        assert_eq!(local(&inner[0]), (1, 0));
+        assert_eq!(local(&inner[3]), (2, 0));
+    }
+
+    #[test]
+    fn test_list_naming() {
+        let ast = resolve("1 2 3 @[a b c] c").unwrap();
+        let top = children(&ast);
+        match &top[3].kind {
+            ExpressionKind::At(inner) => {
+                let names = children(inner);
+                assert_eq!(local(&names[0]), (0, 0));
+                assert_eq!(local(&names[2]), (0, 2));
+            }
+            _ => panic!("expected @[a b c], got {}", top[3]),
+        }
+        assert_eq!(local(&top[4]), (0, 2));
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let ast = resolve("1 @n { n @n n } apply").unwrap();
+        let top = children(&ast);
+        let block = children(&top[2]);
+
+        // Used before the inner binding, refers to the outer n
+        assert_eq!(local(&block[0]), (1, 0));
+        assert_eq!(local(&block[2]), (0, 0));
+    }
+
+    #[test]
+    fn test_resolve_partial() {
+        let ast = parse(tokenize("1 @n foo { n @n n } apply".as_bytes()));
+        let (ast, errors) = Resolver::new().resolve_partial(ast);
+        assert_eq!(errors.len(), 1);
+
+        let top = children(&ast);
+        assert_eq!(top[2].kind, ExpressionKind::Identifier(String::from("foo")));
+        assert_eq!(local(&children(&top[3])[0]), (1, 0));
+    }
+
+    #[test]
+    fn test_bindings() {
+        let ast = resolve("1 2 @[a b] { @c } [ 3 @d ] 4 @a").unwrap();
+        let mut names = bindings(children(&ast))
+            .into_iter()
+            .map(|(slot, (name, span))| (slot, name, span.column))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                (0, "a".to_string(), 6),
+                (1, "b".to_string(), 8),
+                (2, "d".to_string(), 23)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_captures() {
+        let ast =
+            resolve("1 @n { 42 } { @m m } { n } { { n } } { @m { { m } } } { 2 !n }").unwrap();
+        let captured = children(&ast)
+            .iter()
+            .filter(|child| matches!(child.kind, ExpressionKind::Block { .. }))
+            .map(|block| captures(children(block)))
+            .collect::<Vec<_>>();
+        assert_eq!(captured, vec![false, false, true, true, false, true]);
+    }
+
+    #[test]
+    fn test_names_shadow_builtins() {
+        let ast = resolve("{ @v !2 v } @dup 5 dup").unwrap();
+        let top = children(&ast);
+        assert_eq!(local(&top[3]), (0, 0));
+    }
+
+    #[test]
+    fn test_forward_reference_in_block() {
+        assert!(resolve("{ odd? } @even? { even? } @odd?").is_ok());
+    }
+
+    #[test]
+    fn test_unknown_identifier() {
+        assert_eq!(
+            resolve("1 x +").unwrap_err(),
+            vec![String::from("Unknown identifier \"x\"")]
+        );
+    }
+
+    #[test]
+    fn test_used_before_bound() {
+        assert_eq!(
+            resolve("x writeln 5 @x").unwrap_err(),
+            vec![String::from("\"x\" is used before it is bound")]
+        );
+    }
+
+    #[test]
+    fn test_set_unbound() {
+        assert_eq!(
+            resolve("5 !x").unwrap_err(),
+            vec![String::from("Cannot set \"x\", it is never bound with @")]
+        );
+    }
+
+    #[test]
+    fn test_globals_persist() {
+        let mut resolver = Resolver::new();
+        resolver
+            .resolve(parse(tokenize("5 @x".as_bytes())))
+            .unwrap();
+        let ast = resolver
+            .resolve(parse(tokenize("x 1 @y y".as_bytes())))
+            .unwrap();
+        let top = children(&ast);
+        assert_eq!(local(&top[0]), (0, 0));
+        assert_eq!(local(&top[3]), (0, 1));
+    }
+}
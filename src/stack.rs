@@ -1,48 +1,120 @@
 #![allow(dead_code)]
 
+use crate::error::{fail, RuntimeError};
 use crate::types::Value;
-use std::{collections::HashMap, fmt::Display};
+use std::fmt::Display;
+
+/// A reference to a frame on the stack, captured by blocks when they are created
+///
+/// The id distinguishes between frames that have reused the same index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRef {
+    index: usize,
+    id: usize,
+}
+
+/// A single frame, created each time a block is entered
+#[derive(Debug, Clone, Default)]
+struct Frame {
+    // A unique id for this frame, see FrameRef
+    id: usize,
+    // The index into the data where this frame starts
+    base: usize,
+    // The frame of the block that lexically encloses this one (the frame it was created in)
+    parent: Option<usize>,
+    // Indices into the data for each named slot; slots are assigned by the resolver
+    slots: Vec<Option<usize>>,
+    // The name of each slot, for debugging
+    names: Vec<String>,
+}
 
 /// A stack in the context of the VM
 ///
-/// This will actually have a stack of data, and a map of names to stack indices
-/// These are also nested by block; when a new block is entered, a new stack is created
+/// This will actually have a stack of data, and a stack of frames mapping named slots to stack indices
+/// These are also nested by block; when a new block is entered, a new frame is created
 #[derive(Debug, Clone, Default)]
 pub struct Stack {
     // The values on the stack
     data: Vec<Value>,
-    // A stack of return indexes to the stack
-    stack_pointers: Vec<usize>,
-    // A scoped mapping of names to indices in the data
-    names: Vec<HashMap<String, usize>>,
+    // A frame for each block currently being evaluated
+    frames: Vec<Frame>,
+    // The id to assign to the next frame
+    next_id: usize,
 }
 
 impl Stack {
     /// Creates a new top level stack
     pub fn new() -> Self {
         let mut s = Stack::default();
-        s.extend(0);
+        s.frames.push(Frame::default());
+        s.next_id = 1;
         s
     }
 
+    /// A reference to the current frame, used when creating blocks
+    pub fn current_frame(&self) -> FrameRef {
+        let index = self.frames.len() - 1;
+        FrameRef {
+            index,
+            id: self.frames[index].id,
+        }
+    }
+
     /// Creates a new scope
     ///
     /// arity is the number of values to pop from the parent stack and push onto this one
-    pub fn extend(&mut self, arity: usize) {
-        self.stack_pointers.push(self.data.len() - arity);
-        self.names.push(HashMap::new());
+    /// parent is the frame the block being entered was defined in
+    /// It is an error if there are fewer than arity values on the stack, or if parent has returned
+    pub fn extend(&mut self, arity: usize, parent: Option<FrameRef>) -> Result<(), RuntimeError> {
+        if self.data.len() < arity {
+            fail!(
+                Stack,
+                "expects {} values on entry, but only {} are on the stack",
+                arity,
+                self.data.len()
+            );
+        }
+
+        let parent = match parent {
+            Some(p) => match self.frames.get(p.index) {
+                Some(frame) if frame.id == p.id => Some(p.index),
+                _ => fail!("called after the block it was defined in returned"),
+            },
+            None => None,
+        };
+
+        self.frames.push(Frame {
+            id: self.next_id,
+            base: self.data.len() - arity,
+            parent,
+            ..Frame::default()
+        });
+        self.next_id += 1;
+        Ok(())
     }
 
     /// Returns from a scope
     ///
     /// arity is the number of values to pop from this stack and push onto the parent
-    pub fn contract(&mut self, arity: usize) {
+    /// It is an error if there are fewer than arity values in this scope (the scope is still dropped)
+    pub fn contract(&mut self, arity: usize) -> Result<(), RuntimeError> {
         // Drop this scope
-        let return_index = self.stack_pointers.pop().unwrap();
-        self.names.pop();
+        let return_index = self.frames.pop().unwrap().base.min(self.data.len());
+
+        // Values named below the scope may have been consumed, so the stack can end up below the base
+        let available = self.data.len() - return_index;
+        if available < arity {
+            self.data.truncate(return_index);
+            fail!(
+                Stack,
+                "expects {} values on exit, but only {} are left",
+                arity,
+                available
+            );
+        }
 
         // Any value that are on the stack outside of arity are dropped
-        let to_drop = self.data.len() - return_index - arity;
+        let to_drop = available - arity;
 
         // Copy the return values
         let mut to_push = vec![];
@@ -59,6 +131,25 @@ impl Stack {
         for v in to_push.into_iter().rev() {
             self.data.push(v);
         }
+        Ok(())
+    }
+
+    /// The number of frames above the global one, one for each block being evaluated
+    pub fn depth(&self) -> usize {
+        self.frames.len() - 1
+    }
+
+    /// The current number of frames and values, see unwind
+    pub fn mark(&self) -> (usize, usize) {
+        (self.frames.len(), self.data.len())
+    }
+
+    /// Drops any frames and values added since mark was called
+    ///
+    /// Used to recover from an error that left blocks part way through
+    pub fn unwind(&mut self, (frames, len): (usize, usize)) {
+        self.frames.truncate(frames);
+        self.data.truncate(len);
     }
 
     /// Pushes a value onto the stack
@@ -73,91 +164,186 @@ impl Stack {
         self.data.pop()
     }
 
-    /// Assign a new name to the top value on the stack
+    /// Replaces the top arity_in values with the values at the given indices (0 is the deepest of them)
     ///
-    /// A single stack can have multiple names for the same value
-    pub fn name(&mut self, name: String) {
-        self.names
-            .last_mut()
-            .unwrap()
-            .insert(name, self.data.len() - 1);
+    /// There must be at least arity_in values on the stack
+    pub fn shuffle(&mut self, arity_in: usize, outputs: &[usize]) {
+        let base = self.data.len() - arity_in;
+        for index in outputs {
+            self.data.push(self.data[base + index].clone());
+        }
+        self.data.drain(base..base + arity_in);
+    }
+
+    /// Gets the value n from the top of the stack (0 is the top)
+    pub fn peek(&self, n: usize) -> Option<Value> {
+        self.data
+            .len()
+            .checked_sub(n + 1)
+            .map(|index| self.data[index].clone())
     }
 
-    /// Assigns a new name to the top N values of the stack (from bottom to top)
+    /// Moves the value n from the top of the stack (0 is the top) to the top
     ///
-    /// If the stack is [8, 6, 7, 5], name_many("A", "B") would result in [8, 6, 7@A, 5@B]
-    pub fn name_many(&mut self, names: Vec<String>) {
-        for (i, name) in names.iter().enumerate() {
-            self.names
-                .last_mut()
-                .unwrap()
-                .insert(name.clone(), self.data.len() - names.len() + i);
+    /// Returns false if there aren't enough values on the stack
+    pub fn roll(&mut self, n: usize) -> bool {
+        match self.data.len().checked_sub(n + 1) {
+            Some(index) => {
+                let value = self.data.remove(index);
+                self.data.push(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The number of values on the stack, across all frames
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if there are no values on the stack
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Binds a slot in the current frame to a given index in the data
+    fn bind(&mut self, slot: usize, name: &str, index: usize) {
+        let frame = self.frames.last_mut().unwrap();
+
+        if frame.slots.len() <= slot {
+            frame.slots.resize(slot + 1, None);
+            frame.names.resize(slot + 1, String::new());
+        }
+
+        frame.slots[slot] = Some(index);
+        if frame.names[slot].is_empty() {
+            frame.names[slot] = name.to_string();
         }
     }
 
-    /// Get a named value from this stack (including the parent) if it exists
+    /// Assign a slot in the current frame to the top value on the stack
     ///
-    /// If this stack doesn't have it, check the parent
-    pub fn get_named(&self, name: String) -> Option<Value> {
-        log::debug!("get_named({}) from {}", name, self);
+    /// A single stack can have multiple names for the same value
+    pub fn name(&mut self, slot: usize, name: &str) {
+        self.bind(slot, name, self.data.len() - 1);
+    }
 
-        for names in self.names.iter().rev() {
-            if names.contains_key(&name) {
-                return Some(self.data[names[&name]].clone());
-            }
+    /// Assigns slots in the current frame to the top N values of the stack (from bottom to top)
+    ///
+    /// If the stack is [8, 6, 7, 5], name_many(A, B) would result in [8, 6, 7@A, 5@B]
+    pub fn name_many(&mut self, slots: &[(usize, &str)]) {
+        for (i, (slot, name)) in slots.iter().enumerate() {
+            self.bind(*slot, name, self.data.len() - slots.len() + i);
         }
+    }
 
-        None
+    /// Find the index into the data for a slot in the frame `depth` blocks out from the current one
+    fn index_of(&self, depth: usize, slot: usize) -> Option<usize> {
+        let mut frame = self.frames.len() - 1;
+        for _ in 0..depth {
+            frame = self.frames[frame].parent?;
+        }
+
+        self.frames[frame].slots.get(slot).copied().flatten()
     }
 
-    /// Set a named value on this stack (including the parent)
+    /// Get a named value from the frame `depth` blocks out from the current one, if it has been bound
+    pub fn get_named(&self, depth: usize, slot: usize) -> Option<Value> {
+        log::debug!("get_named({}, {}) from {}", depth, slot, self);
+
+        self.index_of(depth, slot)
+            .and_then(|index| self.data.get(index))
+            .cloned()
+    }
+
+    /// The (slot, name) of each bound name visible from the current frame
     ///
-    /// If this stack doesn't have it, check the parent
-    /// If it's not found, panic!
-    pub fn set_named(&mut self, name: String, value: Value) {
-        log::debug!("set_named({}, {}) on {}", name, value, self);
-
-        for names in self.names.iter_mut().rev() {
-            if names.contains_key(&name) {
-                let index = names[&name];
-                self.data[index] = value;
-                return;
-            }
+    /// One list for each frame from the top level to the current one, following the frames blocks were defined in
+    pub fn visible_names(&self) -> Vec<Vec<(usize, String)>> {
+        let mut scopes = vec![];
+        let mut frame = Some(self.frames.len() - 1);
+        while let Some(index) = frame {
+            let names = self.frames[index]
+                .slots
+                .iter()
+                .zip(self.frames[index].names.iter())
+                .enumerate()
+                .filter(|(_, (index, _))| index.is_some())
+                .map(|(slot, (_, name))| (slot, name.clone()))
+                .collect();
+            scopes.push(names);
+            frame = self.frames[index].parent;
         }
+        scopes.reverse();
+        scopes
+    }
 
-        panic!(
-            "set_named({}, {}) on {} couldn't find name",
-            name, value, self
-        );
+    /// Get a named value from the top level frame, if it has been bound
+    pub fn get_global(&self, slot: usize) -> Option<Value> {
+        self.frames[0]
+            .slots
+            .get(slot)
+            .copied()
+            .flatten()
+            .and_then(|index| self.data.get(index))
+            .cloned()
+    }
+
+    /// Set a named value in the frame `depth` blocks out from the current one
+    ///
+    /// If the name hasn't been bound yet, this is an error
+    pub fn set_named(
+        &mut self,
+        depth: usize,
+        slot: usize,
+        value: Value,
+    ) -> Result<(), RuntimeError> {
+        log::debug!("set_named({}, {}, {}) on {}", depth, slot, value, self);
+
+        match self.index_of(depth, slot) {
+            Some(index) if index < self.data.len() => self.data[index] = value,
+            _ => fail!(
+                Name,
+                "set_named({}, {}, {}) on {} couldn't find name",
+                depth,
+                slot,
+                value,
+                self
+            ),
+        }
+        Ok(())
     }
 }
 
 impl Display for Stack {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
-        s.push_str("[");
+        s.push('[');
+
+        let is_base = |i: usize| self.frames.iter().any(|frame| frame.base == i);
 
         for (i, value) in self.data.iter().enumerate() {
-            if i > 0 && self.stack_pointers.contains(&i) {
+            if i > 0 && is_base(i) {
                 s.push_str(" | ");
             }
 
             s.push_str(format!("{}", value).as_str());
 
-            for names in self.names.iter().rev() {
-                for (k, v) in names.iter() {
-                    if *v == i {
-                        s.push_str(&format!("@{}", k));
+            for frame in self.frames.iter().rev() {
+                for (slot, index) in frame.slots.iter().enumerate() {
+                    if *index == Some(i) {
+                        s.push_str(&format!("@{}", frame.names[slot]));
                     }
                 }
             }
 
-            if i + 1 < self.data.len() && !self.stack_pointers.contains(&(i + 1)) {
+            if i + 1 < self.data.len() && !is_base(i + 1) {
                 s.push_str(", ");
             }
         }
 
-        s.push_str("]");
+        s.push(']');
         write!(f, "{}", s)
     }
 }
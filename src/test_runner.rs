@@ -0,0 +1,108 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::error::{panic_message, Error, RuntimeError, SyntaxError};
+use crate::import::expand;
+use crate::lexer::try_tokenize_named;
+use crate::parser::try_parse;
+use crate::types::{Expression, ExpressionKind};
+use crate::vm::VM;
+
+/// The result of running a single test block
+#[derive(Debug)]
+pub struct TestResult {
+    /// The file the test is defined in
+    pub path: PathBuf,
+    /// The name the test block is bound to
+    pub name: String,
+    /// Why the test failed, if it did
+    pub error: Option<Error>,
+}
+
+/// Find the names of test blocks in a program, blocks bound at the top level to a name starting with test-
+pub fn discover(ast: &Expression) -> Vec<String> {
+    let children = match &ast.kind {
+        ExpressionKind::Group(children) => children,
+        _ => return vec![],
+    };
+
+    children
+        .windows(2)
+        .filter_map(|pair| match (&pair[0].kind, &pair[1].kind) {
+            (ExpressionKind::Block { .. }, ExpressionKind::At(name)) => match &name.kind {
+                ExpressionKind::Identifier(name) if name.starts_with("test-") => Some(name.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Run each test block in a program
+///
+/// Each test gets a fresh VM, which runs the whole program (to define everything) then calls the test,
+/// so anything the program does at the top level (such as writing output) is done again for each test
+/// If the program's imports can't be expanded, every test fails with the import errors
+/// A test that panics fails with the panic's message, the rest still run
+/// If the program can't be parsed, none are run
+pub fn run_tests(path: &Path, source: &str) -> Result<Vec<TestResult>, SyntaxError> {
+    let name = path.to_string_lossy().to_string();
+    let ast = try_parse(try_tokenize_named(source.as_bytes(), Some(name))?)?;
+    let names = discover(&ast);
+
+    let ast = expand(ast, path).map_err(|errors| {
+        let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        Error::from(RuntimeError::new(errors.join("\n")))
+    });
+
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut vm = VM::new();
+                ast.clone()
+                    .and_then(|ast| vm.evaluate(ast))
+                    .and_then(|_| vm.evaluate_str(&name))
+            }));
+            let error = match result {
+                Ok(result) => result.err(),
+                Err(payload) => Some(Error::from(RuntimeError::new(format!(
+                    "panicked: {}",
+                    panic_message(payload)
+                )))),
+            };
+
+            TestResult {
+                path: path.to_path_buf(),
+                name,
+                error,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_tests;
+    use std::path::Path;
+
+    #[test]
+    fn test_run_tests() {
+        let source = "
+            { @n n n + } @double
+            { 4 double 8 assert-eq } @test-double
+            { 3 double 7 assert-eq } @test-wrong
+            { 1 2 } @helper
+        ";
+        let results = run_tests(Path::new("double.stack"), source).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "test-double");
+        assert!(results[0].error.is_none());
+        assert_eq!(results[1].name, "test-wrong");
+        assert_eq!(
+            results[1].error.as_ref().unwrap().to_string(),
+            "assert-eq failed: 6 != 7 at double.stack:4:25:9"
+        );
+    }
+}
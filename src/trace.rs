@@ -0,0 +1,372 @@
+//! Tracing the VM, writing one JSON record per line for each expression evaluated and block entered or exited
+//!
+//! Records can be filtered to some blocks or lines, and a trace can be summarized to find where a program spends its time
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Display;
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RuntimeError;
+use crate::types::{Expression, ExpressionKind};
+use crate::vm::{Event, VM};
+
+// Expressions (mostly blocks) longer than this are cut short in records
+const MAX_EXPRESSION_LENGTH: usize = 40;
+
+// How many values from the top of the stack are included in each record
+const TOP_VALUES: usize = 3;
+
+/// A single line of a trace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// evaluate, enter or exit
+    pub event: String,
+    /// The span of the expression (name:row:col:len, 0 based)
+    pub span: String,
+    /// The line of the expression, from 1
+    pub line: usize,
+    /// The expression, cut short if it's long
+    pub expression: String,
+    /// How many blocks deep the VM is
+    pub depth: usize,
+    /// The number of values on the stack
+    pub stack: usize,
+    /// Up to the top three values on the stack, the top first
+    pub top: Vec<String>,
+    /// The innermost named block (for enter and exit, the block itself), anonymous blocks use the one they're in
+    pub block: Option<String>,
+}
+
+/// Writes records for each event from the VM, see VM::set_hook
+pub struct Tracer {
+    output: Box<dyn Write>,
+    // The name of each block that has been entered, anonymous blocks repeat the one they're in
+    blocks: Vec<Option<String>>,
+    // Only write records in these blocks, if any are set
+    block_filter: BTreeSet<String>,
+    // Only write records on these lines, if any are set
+    line_filter: BTreeSet<usize>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Tracer {
+            output,
+            blocks: vec![],
+            block_filter: BTreeSet::new(),
+            line_filter: BTreeSet::new(),
+        }
+    }
+
+    /// Only write records in a block with one of these names (including anything it calls that isn't named)
+    pub fn filter_blocks<I: IntoIterator<Item = String>>(&mut self, names: I) {
+        self.block_filter.extend(names);
+    }
+
+    /// Only write records for expressions on one of these lines (from 1)
+    pub fn filter_lines<I: IntoIterator<Item = usize>>(&mut self, lines: I) {
+        self.line_filter.extend(lines);
+    }
+
+    /// Called for each event, see VM::set_hook
+    pub fn event(&mut self, vm: &mut VM, event: Event) -> Result<(), RuntimeError> {
+        let (kind, expression) = match event {
+            // Groups are the bodies of blocks, which already have enter records
+            Event::Evaluate(Expression {
+                kind: ExpressionKind::Group(_),
+                ..
+            }) => return Ok(()),
+            Event::Evaluate(expression) => ("evaluate", expression),
+            Event::Enter { block, name } => {
+                let name = name
+                    .map(String::from)
+                    .or_else(|| self.blocks.last().cloned().flatten());
+                self.blocks.push(name);
+                ("enter", block)
+            }
+            Event::Exit { block, .. } => ("exit", block),
+            Event::Return(_) => return Ok(()),
+        };
+
+        let record = self.record(vm, kind, expression);
+        if let Event::Exit { .. } = event {
+            self.blocks.pop();
+        }
+
+        if self.included(&record) {
+            self.write(&record)
+                .map_err(|e| RuntimeError::new(format!("failed to write trace: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, vm: &VM, event: &str, expression: &Expression) -> Record {
+        let mut text = expression.to_string();
+        if text.chars().count() > MAX_EXPRESSION_LENGTH {
+            text = text
+                .chars()
+                .take(MAX_EXPRESSION_LENGTH - 3)
+                .collect::<String>()
+                + "...";
+        }
+
+        Record {
+            event: event.to_string(),
+            span: expression.span.to_string(),
+            line: expression.span.row + 1,
+            expression: text,
+            depth: vm.stack().depth(),
+            stack: vm.stack().len(),
+            top: (0..TOP_VALUES)
+                .map_while(|n| vm.stack().peek(n))
+                .map(|value| value.to_string())
+                .collect(),
+            block: self.blocks.last().cloned().flatten(),
+        }
+    }
+
+    fn included(&self, record: &Record) -> bool {
+        let block = self.block_filter.is_empty()
+            || record
+                .block
+                .as_ref()
+                .is_some_and(|name| self.block_filter.contains(name));
+        let line = self.line_filter.is_empty() || self.line_filter.contains(&record.line);
+        block && line
+    }
+
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.output, record)?;
+        writeln!(self.output)
+    }
+
+    /// Write anything still buffered, call this once the program is done
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Counts for one named block in a trace
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockSummary {
+    /// How many times the block was entered
+    pub calls: usize,
+    /// How many expressions were evaluated in it (including anonymous blocks it calls)
+    pub steps: usize,
+}
+
+/// What a trace adds up to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of expressions evaluated
+    pub steps: usize,
+    /// The number of blocks entered
+    pub calls: usize,
+    /// The deepest blocks were nested
+    pub max_depth: usize,
+    /// The most values on the stack at once
+    pub max_stack: usize,
+    pub blocks: HashMap<String, BlockSummary>,
+    /// How many expressions were evaluated on each line
+    pub lines: HashMap<usize, usize>,
+    /// How many times each expression was evaluated, by span
+    pub expressions: HashMap<String, (String, usize)>,
+}
+
+// How many of the busiest lines and expressions are shown
+const SUMMARY_ROWS: usize = 10;
+
+/// Add up a trace, one JSON record per line
+pub fn summarize<R: BufRead>(reader: R) -> Result<Summary, String> {
+    let mut summary = Summary::default();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", index + 1, e))?;
+
+        summary.max_depth = summary.max_depth.max(record.depth);
+        summary.max_stack = summary.max_stack.max(record.stack);
+
+        match record.event.as_str() {
+            "evaluate" => {
+                summary.steps += 1;
+                *summary.lines.entry(record.line).or_default() += 1;
+                summary
+                    .expressions
+                    .entry(record.span)
+                    .or_insert_with(|| (record.expression, 0))
+                    .1 += 1;
+                if let Some(block) = record.block {
+                    summary.blocks.entry(block).or_default().steps += 1;
+                }
+            }
+            "enter" => {
+                summary.calls += 1;
+                if let Some(block) = record.block {
+                    summary.blocks.entry(block).or_default().calls += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Sort counts, largest first (ties by key) and keep the first few
+fn busiest<K: Ord + Clone, V>(counts: impl Iterator<Item = (K, V, usize)>) -> Vec<(K, V, usize)> {
+    let mut counts = counts.collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(SUMMARY_ROWS);
+    counts
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "steps: {}", self.steps)?;
+        writeln!(f, "calls: {}", self.calls)?;
+        writeln!(f, "max depth: {}", self.max_depth)?;
+        writeln!(f, "max stack: {}", self.max_stack)?;
+
+        let mut blocks = self.blocks.iter().collect::<Vec<_>>();
+        blocks.sort_by(|a, b| b.1.steps.cmp(&a.1.steps).then_with(|| a.0.cmp(b.0)));
+        if !blocks.is_empty() {
+            writeln!(f, "\nblocks (calls, steps):")?;
+            for (name, block) in blocks {
+                writeln!(f, "  {:>8} {:>10}  {}", block.calls, block.steps, name)?;
+            }
+        }
+
+        let lines = busiest(self.lines.iter().map(|(line, count)| (*line, (), *count)));
+        if !lines.is_empty() {
+            writeln!(f, "\nbusiest lines (steps):")?;
+            for (line, _, count) in lines {
+                writeln!(f, "  {:>10}  line {}", count, line)?;
+            }
+        }
+
+        let expressions = busiest(
+            self.expressions
+                .iter()
+                .map(|(span, (expression, count))| (span.clone(), expression, *count)),
+        );
+        if !expressions.is_empty() {
+            writeln!(f, "\nbusiest expressions (steps):")?;
+            for (span, expression, count) in expressions {
+                writeln!(f, "  {:>10}  {} at {}", count, expression, span)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{summarize, Record, Tracer};
+    use crate::vm::VM;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    // Output written by the tracer, shared with the test
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const SOURCE: &str = "{ @n\n  n 2 *\n} @double\n3 double\n{ 4 double } 1 loop";
+
+    // Trace a program, returning the trace as text
+    fn trace(source: &str, setup: impl FnOnce(&mut Tracer)) -> String {
+        let output = Output::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()));
+        setup(&mut tracer);
+
+        let mut vm = VM::new();
+        vm.set_hook(move |vm, event| tracer.event(vm, event));
+        vm.evaluate_str(source).unwrap();
+
+        let output = output.0.borrow();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    fn records(trace: &str) -> Vec<Record> {
+        trace
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_trace() {
+        let records = records(&trace(SOURCE, |_| {}));
+
+        let enter = records
+            .iter()
+            .find(|r| r.event == "enter" && r.block.as_deref() == Some("double"))
+            .unwrap();
+        assert_eq!(enter.line, 1);
+        assert_eq!(enter.depth, 1);
+        assert_eq!(enter.top[0], "3");
+
+        let multiply = records.iter().find(|r| r.expression == "*").unwrap();
+        assert_eq!(multiply.line, 2);
+        assert_eq!(multiply.block.as_deref(), Some("double"));
+        assert_eq!(multiply.top, vec!["2", "3", "3"]);
+
+        // Both calls to double exit, including the one inside the anonymous loop body
+        let exits = records
+            .iter()
+            .filter(|r| r.event == "exit" && r.block.as_deref() == Some("double"))
+            .count();
+        assert_eq!(exits, 2);
+        assert_eq!(
+            records.iter().filter(|r| r.event == "enter").count(),
+            records.iter().filter(|r| r.event == "exit").count()
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let blocks = records(&trace(SOURCE, |tracer| {
+            tracer.filter_blocks(["double".to_string()])
+        }));
+        assert!(!blocks.is_empty());
+        assert!(blocks.iter().all(|r| r.block.as_deref() == Some("double")));
+
+        let lines = trace(SOURCE, |tracer| tracer.filter_lines([4]));
+        assert!(records(&lines).iter().all(|r| r.line == 4));
+        assert!(lines.contains("\"expression\":\"double\""));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let summary = summarize(trace(SOURCE, |_| {}).as_bytes()).unwrap();
+        assert_eq!(summary.blocks["double"].calls, 2);
+        // @n, then n 2 * (the inferred arity doesn't add any steps)
+        assert_eq!(summary.blocks["double"].steps, 8);
+        assert_eq!(summary.lines[&2], 6);
+        assert_eq!(summary.max_depth, 2);
+
+        let text = summary.to_string();
+        assert!(text.contains("blocks (calls, steps):"), "{}", text);
+        assert!(text.contains("busiest lines (steps):"), "{}", text);
+
+        assert!(summarize("not json".as_bytes()).is_err());
+    }
+}
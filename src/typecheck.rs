@@ -0,0 +1,882 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::arity::{calculate_arity, declared_blocks, dollar_slots, HostArities};
+use crate::builtins;
+use crate::numbers::Number;
+use crate::types::{Expression, ExpressionKind, Span, Value};
+
+/// The type of a value on the stack
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Integer,
+    Rational,
+    Float,
+    Complex,
+    /// A number that could be any of the above
+    Number,
+    String,
+    Boolean,
+    /// A block that takes arity_in values and returns values of the given types
+    Block {
+        arity_in: usize,
+        outputs: Vec<Type>,
+    },
+    Stack,
+    Hash,
+    IntHash,
+    /// A value that could be of any type
+    Any,
+}
+
+impl Type {
+    /// The type of a literal value
+    pub fn of(value: &Value) -> Type {
+        match value {
+            Value::Number(Number::Integer(_)) => Type::Integer,
+            Value::Number(Number::Rational { .. }) => Type::Rational,
+            Value::Number(Number::Float(_)) => Type::Float,
+            Value::Number(Number::Complex { .. }) => Type::Complex,
+            Value::String(_) => Type::String,
+            Value::Boolean(_) => Type::Boolean,
+            Value::Block {
+                arity_in,
+                arity_out,
+                ..
+            } => Type::Block {
+                arity_in: *arity_in,
+                outputs: vec![Type::Any; *arity_out],
+            },
+            Value::Stack(_) => Type::Stack,
+            Value::Hash(_) => Type::Hash,
+            Value::IntHash(_) => Type::IntHash,
+        }
+    }
+
+    /// Position in the numeric tower, see Number::coerce
+    fn rank(&self) -> Option<usize> {
+        match self {
+            Type::Integer => Some(0),
+            Type::Rational => Some(1),
+            Type::Float => Some(2),
+            Type::Complex => Some(3),
+            _ => None,
+        }
+    }
+
+    /// Could a value of this type be a number
+    fn is_numeric(&self) -> bool {
+        self.rank().is_some() || matches!(self, Type::Number | Type::Any)
+    }
+
+    /// Could a value of this type be an integer
+    fn is_integer(&self) -> bool {
+        matches!(self, Type::Integer | Type::Number | Type::Any)
+    }
+
+    /// Could a value of this type be the given type
+    fn could_be(&self, other: &Type) -> bool {
+        self == other || *self == Type::Any
+    }
+
+    /// The type of the result of arithmetic on two numbers
+    fn promote(a: &Type, b: &Type) -> Type {
+        match (a.rank(), b.rank()) {
+            (Some(ra), Some(rb)) if ra >= rb => a.clone(),
+            (Some(_), Some(_)) => b.clone(),
+            _ => Type::Number,
+        }
+    }
+
+    /// A type that values of either type could have
+    fn join(a: &Type, b: &Type) -> Type {
+        if a == b {
+            a.clone()
+        } else if a.is_numeric() && b.is_numeric() && *a != Type::Any && *b != Type::Any {
+            Type::Number
+        } else {
+            Type::Any
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Integer => write!(f, "int"),
+            Type::Rational => write!(f, "rational"),
+            Type::Float => write!(f, "float"),
+            Type::Complex => write!(f, "complex"),
+            Type::Number => write!(f, "number"),
+            Type::String => write!(f, "string"),
+            Type::Boolean => write!(f, "bool"),
+            Type::Block { arity_in, outputs } => write!(
+                f,
+                "{{{} -> {}}}",
+                arity_in,
+                outputs
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ),
+            Type::Stack => write!(f, "stack"),
+            Type::Hash => write!(f, "hash"),
+            Type::IntHash => write!(f, "int-hash"),
+            Type::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// A value that will have the wrong type when the program is run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/// Check the types in a program (after names are resolved and arity is inferred), without running it
+///
+/// Returns the type of each name bound at the top level and any type errors found
+/// Values that can't be determined (such as inputs to a block) are Type::Any and never cause errors
+/// Host functions (see arity::infer) take and return values of any type
+pub fn check(ast: &Expression, host: &HostArities) -> (Vec<(String, Type)>, Vec<TypeError>) {
+    let mut checker = Checker {
+        host: host.clone(),
+        ..Checker::default()
+    };
+
+    let children = match &ast.kind {
+        ExpressionKind::Group(children) => children.as_slice(),
+        _ => std::slice::from_ref(ast),
+    };
+
+    checker.frames.push(Frame::new(children));
+    for child in children {
+        checker.walk(child);
+    }
+    let frame = checker.frames.pop().unwrap();
+
+    let mut globals = frame
+        .names
+        .into_iter()
+        .map(|(slot, name)| {
+            (
+                slot,
+                name,
+                frame.slots.get(&slot).cloned().unwrap_or(Type::Any),
+            )
+        })
+        .collect::<Vec<_>>();
+    globals.sort_by_key(|(slot, ..)| *slot);
+
+    (
+        globals.into_iter().map(|(_, name, t)| (name, t)).collect(),
+        checker.errors,
+    )
+}
+
+/// The types known in a single block (or the top level)
+///
+/// Only the top of the stack is tracked, anything below it is an input to the block (Type::Any)
+#[derive(Debug, Default)]
+struct Frame {
+    stack: Vec<Type>,
+    // The types of list literals on the stack by index, used to check cond
+    lists: HashMap<usize, Vec<Type>>,
+    // The current type of each bound slot
+    slots: HashMap<usize, Type>,
+    // The name of each bound slot
+    names: HashMap<usize, String>,
+    // Slots used with $, these might be bound to blocks
+    dollar: HashSet<usize>,
+    // Blocks with declared arity bound to a slot, so they can be called before they're bound
+    forward: HashMap<usize, (usize, usize)>,
+}
+
+impl Frame {
+    fn new(children: &[Expression]) -> Self {
+        Frame {
+            dollar: dollar_slots(children),
+            forward: declared_blocks(children),
+            ..Frame::default()
+        }
+    }
+
+    fn pop(&mut self) -> Type {
+        self.lists.remove(&self.stack.len().saturating_sub(1));
+        self.stack.pop().unwrap_or(Type::Any)
+    }
+
+    /// The type n values from the top of the stack
+    fn peek(&self, n: usize) -> Type {
+        match self.stack.len().checked_sub(n + 1) {
+            Some(index) => self.stack[index].clone(),
+            None => Type::Any,
+        }
+    }
+
+    /// The effect of a block (or other value) on the stack isn't known, so nothing is known about the stack
+    fn unknown(&mut self) {
+        self.stack.clear();
+        self.lists.clear();
+    }
+
+    /// Pop arity_in values and push the outputs
+    fn apply(&mut self, arity_in: usize, outputs: &[Type]) {
+        for _ in 0..arity_in {
+            self.pop();
+        }
+        self.stack.extend(outputs.iter().cloned());
+    }
+
+    /// Replace the top values with outputs for a block that might not be run, so either could be left
+    fn maybe_apply(&mut self, outputs: &[Type]) {
+        let outputs = outputs
+            .iter()
+            .enumerate()
+            .map(|(i, t)| Type::join(t, &self.peek(outputs.len() - 1 - i)))
+            .collect::<Vec<_>>();
+        self.apply(outputs.len(), &outputs);
+    }
+
+    /// The type currently bound to a slot
+    fn get(&self, slot: usize) -> Type {
+        match self.slots.get(&slot) {
+            Some(t) => t.clone(),
+            None => match self.forward.get(&slot) {
+                Some((arity_in, arity_out)) => Type::Block {
+                    arity_in: *arity_in,
+                    outputs: vec![Type::Any; *arity_out],
+                },
+                None => Type::Any,
+            },
+        }
+    }
+}
+
+/// The effect of evaluating a value, see VM::evaluate_value
+fn effect(t: &Type) -> Option<(usize, Vec<Type>)> {
+    match t {
+        Type::Block { arity_in, outputs } => Some((*arity_in, outputs.clone())),
+        Type::Any => None,
+        t => Some((0, vec![t.clone()])),
+    }
+}
+
+/// Walks a program, tracking the type of each value on the stack
+#[derive(Debug, Default)]
+struct Checker {
+    // A frame for each block currently being checked, innermost last
+    frames: Vec<Frame>,
+    errors: Vec<TypeError>,
+    host: HostArities,
+}
+
+impl Checker {
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn error(&mut self, span: &Span, message: String) {
+        self.errors.push(TypeError {
+            span: span.clone(),
+            message,
+        });
+    }
+
+    /// Pop a value, reporting an error if it can't be of the expected type
+    fn expect(&mut self, span: &Span, name: &str, expected: &str, ok: fn(&Type) -> bool) -> Type {
+        let t = self.frame().pop();
+        if !ok(&t) {
+            self.error(span, format!("{} expects {}, got {}", name, expected, t));
+        }
+        t
+    }
+
+    /// Evaluate a value with the given type (blocks are called, anything else is pushed)
+    fn evaluate(&mut self, t: Type) {
+        match effect(&t) {
+            Some((arity_in, outputs)) => self.frame().apply(arity_in, &outputs),
+            None => self.frame().unknown(),
+        }
+    }
+
+    /// Check a call to a builtin
+    fn builtin(&mut self, name: &str, span: &Span) {
+        if let Some(&(arity_in, arity_out)) = self.host.get(name) {
+            self.frame().apply(arity_in, &vec![Type::Any; arity_out]);
+            return;
+        }
+
+        match name {
+            "+" | "-" | "*" | "/" | "%" => {
+                let b = self.expect(span, name, "a number", Type::is_numeric);
+                let a = self.expect(span, name, "a number", Type::is_numeric);
+                self.frame().stack.push(Type::promote(&a, &b));
+            }
+            "<" | "<=" | "=" | "!=" | ">=" | ">" => {
+                self.expect(span, name, "a number", Type::is_numeric);
+                self.expect(span, name, "a number", Type::is_numeric);
+                self.frame().stack.push(Type::Boolean);
+            }
+            "and" | "or" | "xor" | "nand" | "not" => {
+                let count = if name == "not" { 1 } else { 2 };
+                for _ in 0..count {
+                    self.expect(span, name, "a bool", |t| t.could_be(&Type::Boolean));
+                }
+                self.frame().stack.push(Type::Boolean);
+            }
+            "to_int" | "to_float" => {
+                self.expect(span, name, "a number or string", |t| {
+                    t.is_numeric() || *t == Type::String
+                });
+                let t = if name == "to_int" {
+                    Type::Integer
+                } else {
+                    Type::Float
+                };
+                self.frame().stack.push(t);
+            }
+            "dup" | "drop" | "swap" | "over" | "rot" | "-rot" | "nip" | "tuck" => {
+                // The values each output is copied from, 0 is the deepest input
+                let (arity_in, outputs): (usize, &[usize]) = match name {
+                    "dup" => (1, &[0, 0]),
+                    "drop" => (1, &[]),
+                    "swap" => (2, &[1, 0]),
+                    "over" => (2, &[0, 1, 0]),
+                    "rot" => (3, &[1, 2, 0]),
+                    "-rot" => (3, &[2, 0, 1]),
+                    "nip" => (2, &[1]),
+                    _ => (2, &[1, 0, 1]),
+                };
+
+                let frame = self.frame();
+                let inputs = (0..arity_in)
+                    .rev()
+                    .map(|i| frame.peek(i))
+                    .collect::<Vec<_>>();
+                let outputs = outputs
+                    .iter()
+                    .map(|i| inputs[*i].clone())
+                    .collect::<Vec<_>>();
+                frame.apply(arity_in, &outputs);
+            }
+            "pick" | "roll" => {
+                self.expect(span, name, "an int index", Type::is_integer);
+
+                // The index usually isn't known, so neither is the value picked (or the values rolled)
+                if name == "pick" {
+                    self.frame().stack.push(Type::Any);
+                } else {
+                    self.frame().unknown();
+                }
+            }
+            "depth" => self.frame().stack.push(Type::Integer),
+            "apply" => {
+                let t = self.expect(span, name, "a block", |t| {
+                    matches!(t, Type::Block { .. } | Type::Any)
+                });
+                self.evaluate(t);
+            }
+            "if" => {
+                self.expect(span, name, "a bool condition", |t| {
+                    t.could_be(&Type::Boolean)
+                });
+                let false_branch = self.frame().pop();
+                let true_branch = self.frame().pop();
+
+                match (effect(&true_branch), effect(&false_branch)) {
+                    (Some((ti, to)), Some((fi, fo))) if ti == fi && to.len() == fo.len() => {
+                        let outputs = to
+                            .iter()
+                            .zip(fo.iter())
+                            .map(|(a, b)| Type::join(a, b))
+                            .collect::<Vec<_>>();
+                        self.frame().apply(ti, &outputs);
+                    }
+                    _ => self.frame().unknown(),
+                }
+            }
+            "when" => {
+                self.expect(span, name, "a bool condition", |t| {
+                    t.could_be(&Type::Boolean)
+                });
+
+                match self.frame().pop() {
+                    Type::Block { arity_in, outputs } if arity_in == outputs.len() => {
+                        self.frame().maybe_apply(&outputs)
+                    }
+                    _ => self.frame().unknown(),
+                }
+            }
+            "cond" => {
+                let index = self.frame().stack.len().saturating_sub(1);
+                let items = self.frame().lists.get(&index).cloned();
+                let t = self.expect(span, name, "a list", |t| t.could_be(&Type::Stack));
+
+                match items {
+                    Some(items) if t == Type::Stack && items.len() % 2 == 1 => {
+                        self.cond(&items, span)
+                    }
+                    _ => self.frame().unknown(),
+                }
+            }
+            "loop" | "generate-stack" => {
+                self.expect(span, name, "an int, string, or stack", |t| {
+                    t.is_integer() || t.could_be(&Type::String) || t.could_be(&Type::Stack)
+                });
+                let block = self.expect(span, name, "a block", |t| {
+                    matches!(t, Type::Block { .. } | Type::Any)
+                });
+
+                // Each iteration pushes a value and calls the block, which replaces the values below it
+                // For generate-stack, the top value is then collected into the result
+                let collect = usize::from(name == "generate-stack");
+                match block {
+                    Type::Block { arity_in, outputs }
+                        if arity_in > 0 && arity_in + collect == outputs.len() + 1 =>
+                    {
+                        self.frame().maybe_apply(&outputs[..arity_in - 1])
+                    }
+                    _ => self.frame().unknown(),
+                }
+
+                if collect == 1 {
+                    self.frame().stack.push(Type::Stack);
+                }
+            }
+            "while" | "forever" => {
+                let body = self.expect(span, name, "a block", |t| {
+                    matches!(t, Type::Block { .. } | Type::Any)
+                });
+                let condition = match name {
+                    "while" => self.expect(span, name, "a condition block", |t| {
+                        matches!(t, Type::Block { .. } | Type::Any)
+                    }),
+                    _ => Type::Block {
+                        arity_in: 0,
+                        outputs: vec![Type::Boolean],
+                    },
+                };
+
+                if let Type::Block { arity_in, outputs } = &condition {
+                    if outputs.len() == arity_in + 1 && !outputs[*arity_in].could_be(&Type::Boolean)
+                    {
+                        self.error(
+                            span,
+                            format!(
+                                "while condition must return a bool, got {}",
+                                outputs[*arity_in]
+                            ),
+                        );
+                    }
+                }
+
+                // The body replaces the values it takes each time through, if it runs at all
+                match body {
+                    Type::Block { arity_in, outputs } if arity_in == outputs.len() => {
+                        self.frame().maybe_apply(&outputs)
+                    }
+                    _ => self.frame().unknown(),
+                }
+            }
+            "try" => {
+                let handler = self.expect(span, name, "a handler block", |t| {
+                    matches!(t, Type::Block { .. } | Type::Any)
+                });
+                let body = self.expect(span, name, "a body block", |t| {
+                    matches!(t, Type::Block { .. } | Type::Any)
+                });
+
+                // Either the body or the handler returns, so each output could be from either
+                match (body, handler) {
+                    (
+                        Type::Block { arity_in, outputs },
+                        Type::Block {
+                            arity_in: 1,
+                            outputs: handled,
+                        },
+                    ) if outputs.len() == handled.len() => {
+                        let outputs = outputs
+                            .iter()
+                            .zip(handled.iter())
+                            .map(|(a, b)| Type::join(a, b))
+                            .collect::<Vec<_>>();
+                        self.frame().apply(arity_in, &outputs)
+                    }
+                    _ => self.frame().unknown(),
+                }
+            }
+            "assert" => {
+                self.expect(span, name, "a bool", |t| t.could_be(&Type::Boolean));
+            }
+            "read" => self.frame().stack.push(Type::String),
+            "make-stack" => self.frame().stack.push(Type::Stack),
+            "make-hash" => self.frame().stack.push(Type::Hash),
+            "make-int-hash" => self.frame().stack.push(Type::IntHash),
+            "stack-size" | "stack-push!" | "stack-pop!" | "stack-ref" | "stack-set!" => {
+                if name == "stack-push!" || name == "stack-set!" {
+                    self.frame().pop();
+                }
+                if name == "stack-ref" || name == "stack-set!" {
+                    self.expect(span, name, "an int index", Type::is_integer);
+                }
+                self.expect(span, name, "a stack", |t| t.could_be(&Type::Stack));
+
+                match name {
+                    "stack-size" => self.frame().stack.push(Type::Integer),
+                    "stack-pop!" | "stack-ref" => self.frame().stack.push(Type::Any),
+                    _ => {}
+                }
+            }
+            "map" | "filter" | "fold" | "any?" | "all?" | "find" | "sort-by" => {
+                self.expect(span, name, "a stack", |t| t.could_be(&Type::Stack));
+                if name == "fold" {
+                    self.frame().pop();
+                }
+                self.expect(span, name, "a block", |t| {
+                    matches!(t, Type::Block { .. } | Type::Any)
+                });
+
+                match name {
+                    "any?" | "all?" => self.frame().stack.push(Type::Boolean),
+                    "fold" | "find" => self.frame().stack.push(Type::Any),
+                    _ => self.frame().stack.push(Type::Stack),
+                }
+            }
+            "zip" | "reverse" | "range" | "slice" | "concat" | "sort" => {
+                if name == "range" || name == "slice" {
+                    self.expect(span, name, "an int", Type::is_integer);
+                    self.expect(span, name, "an int", Type::is_integer);
+                }
+                if name != "range" {
+                    self.expect(span, name, "a stack", |t| t.could_be(&Type::Stack));
+                }
+                if name == "zip" || name == "concat" {
+                    self.expect(span, name, "a stack", |t| t.could_be(&Type::Stack));
+                }
+                self.frame().stack.push(Type::Stack);
+            }
+            "hash-has?" | "hash-get" | "hash-set!" => {
+                if name == "hash-set!" {
+                    self.frame().pop();
+                }
+                let key = self.frame().pop();
+                let hash = self.expect(span, name, "a hash", |t| {
+                    matches!(t, Type::Hash | Type::IntHash | Type::Any)
+                });
+
+                match hash {
+                    Type::Hash if !key.could_be(&Type::String) => self.error(
+                        span,
+                        format!("{} on a hash expects a string key, got {}", name, key),
+                    ),
+                    Type::IntHash if !key.is_integer() => self.error(
+                        span,
+                        format!("{} on an int-hash expects an int key, got {}", name, key),
+                    ),
+                    _ => {}
+                }
+
+                match name {
+                    "hash-has?" => self.frame().stack.push(Type::Boolean),
+                    "hash-get" => self.frame().stack.push(Type::Any),
+                    _ => {}
+                }
+            }
+            // Anything else uses the arity from the registry, but any types
+            _ => match builtins::lookup(name).and_then(|b| b.arity) {
+                Some((arity_in, arity_out)) => {
+                    self.frame().apply(arity_in, &vec![Type::Any; arity_out])
+                }
+                None => self.frame().unknown(),
+            },
+        }
+    }
+
+    /// Check a cond with the types of each test and value in the list
+    fn cond(&mut self, items: &[Type], span: &Span) {
+        // Each test must be a block that returns a boolean on top of what it takes
+        let mut tests_in = 0;
+        for test in items.iter().step_by(2).take(items.len() / 2) {
+            match test {
+                Type::Block { arity_in, outputs } if outputs.len() == arity_in + 1 => {
+                    if !outputs.last().unwrap().could_be(&Type::Boolean) {
+                        self.error(
+                            span,
+                            format!(
+                                "cond test must return a bool, got {}",
+                                outputs.last().unwrap()
+                            ),
+                        );
+                    }
+                    tests_in = tests_in.max(*arity_in);
+                }
+                Type::Block { .. } | Type::Any => return self.frame().unknown(),
+                t => {
+                    self.error(span, format!("cond test must be a block, got {}", t));
+                    return self.frame().unknown();
+                }
+            }
+        }
+
+        // Each value (and the default) must have the same effect
+        let mut effects = items
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .chain(items.last())
+            .map(effect);
+
+        let Some(Some((arity_in, mut outputs))) = effects.next() else {
+            return self.frame().unknown();
+        };
+
+        for other in effects {
+            match other {
+                Some((i, o)) if i == arity_in && o.len() == outputs.len() => {
+                    outputs = outputs
+                        .iter()
+                        .zip(o.iter())
+                        .map(|(a, b)| Type::join(a, b))
+                        .collect();
+                }
+                _ => return self.frame().unknown(),
+            }
+        }
+
+        self.frame().apply(arity_in, &outputs);
+    }
+
+    /// Check a block, returning its type
+    fn block(&mut self, expr: &Expression, children: &[Expression]) -> Type {
+        let (arity_in, arity_out) = calculate_arity(expr);
+
+        self.frames.push(Frame::new(children));
+        for child in children {
+            self.walk(child);
+        }
+        let frame = self.frames.pop().unwrap();
+
+        // The values returned are the top of the stack, anything missing was an input
+        let outputs = (0..arity_out).rev().map(|i| frame.peek(i)).collect();
+        Type::Block { arity_in, outputs }
+    }
+
+    /// Check an expression, updating the types on the stack
+    fn walk(&mut self, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::Literal(value) => self.frame().stack.push(Type::of(value)),
+            ExpressionKind::Builtin(name) => self.builtin(name, &expr.span),
+            ExpressionKind::Local { depth, slot, .. } => {
+                let index = self.frames.len() - 1 - depth;
+                let frame = &self.frames[index];
+                let t = frame.get(*slot);
+
+                if t == Type::Any && frame.dollar.contains(slot) {
+                    self.frame().unknown();
+                } else if t == Type::Any {
+                    self.frame().stack.push(Type::Any);
+                } else {
+                    self.evaluate(t);
+                }
+            }
+            ExpressionKind::Block { children, .. } => {
+                let t = self.block(expr, children);
+                self.frame().stack.push(t);
+            }
+            ExpressionKind::List(children) => {
+                let mut items = vec![];
+                for child in children {
+                    self.walk(child);
+                    items.push(self.frame().pop());
+                }
+
+                let frame = self.frame();
+                frame.lists.insert(frame.stack.len(), items);
+                frame.stack.push(Type::Stack);
+            }
+            ExpressionKind::Group(children) => {
+                for child in children {
+                    self.walk(child);
+                }
+            }
+            ExpressionKind::At(inner) => {
+                let names = match &inner.kind {
+                    ExpressionKind::Local { .. } => std::slice::from_ref(inner.as_ref()),
+                    ExpressionKind::List(names) => names.as_slice(),
+                    // Arity markers
+                    _ => &[],
+                };
+
+                let frame = self.frame();
+                for (i, name) in names.iter().enumerate() {
+                    if let ExpressionKind::Local { name, slot, .. } = &name.kind {
+                        let t = frame.peek(names.len() - 1 - i);
+                        frame.slots.insert(*slot, t);
+                        frame.names.insert(*slot, name.clone());
+                    }
+                }
+            }
+            ExpressionKind::Bang(inner) => {
+                if let ExpressionKind::Local { depth, slot, .. } = inner.kind {
+                    let t = self.frame().pop();
+                    let index = self.frames.len() - 1 - depth;
+                    self.frames[index].slots.insert(slot, t);
+                }
+            }
+            ExpressionKind::Dollar(inner) => {
+                if let ExpressionKind::Local { depth, slot, .. } = inner.kind {
+                    let t = self.frames[self.frames.len() - 1 - depth].get(slot);
+                    self.frame().stack.push(t);
+                }
+            }
+            // Names should already have been resolved
+            ExpressionKind::Identifier(_) | ExpressionKind::DottedIdentifier(_) => {
+                self.frame().unknown()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, Type};
+    use crate::arity::{infer, HostArities};
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use crate::resolver::Resolver;
+
+    /// Check a program, returning the type of each global and the error messages
+    fn types(input: &str) -> (Vec<(String, Type)>, Vec<String>) {
+        let ast = Resolver::new()
+            .resolve(parse(tokenize(input.as_bytes())))
+            .unwrap();
+        let (ast, _) = infer(ast, &HostArities::new());
+        let (globals, errors) = check(&ast, &HostArities::new());
+        (globals, errors.into_iter().map(|e| e.message).collect())
+    }
+
+    fn global(input: &str) -> Type {
+        types(input).0.pop().unwrap().1
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(global("1 @x"), Type::Integer);
+        assert_eq!(global("1.5 @x"), Type::Float);
+        assert_eq!(global("\"hi\" @x"), Type::String);
+        assert_eq!(global("true @x"), Type::Boolean);
+        assert_eq!(global("[1 2] @x"), Type::Stack);
+        assert_eq!(global("make-int-hash @x"), Type::IntHash);
+    }
+
+    #[test]
+    fn test_list_operations() {
+        assert_eq!(global("{ 2 * } [1 2] map @x"), Type::Stack);
+        assert_eq!(global("{ 1 > } [1 2] all? @x"), Type::Boolean);
+        assert_eq!(global("0 5 range reverse @x"), Type::Stack);
+        assert_eq!(
+            types("[1 2] \"a\" 2 slice").1,
+            vec!["slice expects an int, got string"]
+        );
+        assert_eq!(types("1 [1 2] map").1, vec!["map expects a block, got int"]);
+    }
+
+    #[test]
+    fn test_numeric_tower() {
+        assert_eq!(global("1 2 + @x"), Type::Integer);
+        assert_eq!(global("1 2.0 * @x"), Type::Float);
+        assert_eq!(global("1 2 < @x"), Type::Boolean);
+        assert_eq!(global("read to_int @x"), Type::Integer);
+        assert_eq!(global("1 2.0 swap @x"), Type::Integer);
+        assert_eq!(global("1 \"a\" 2.0 rot @x"), Type::Integer);
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(
+            global("{ @n n to_float 1.0 * } @f"),
+            Type::Block {
+                arity_in: 1,
+                outputs: vec![Type::Float]
+            }
+        );
+        assert_eq!(global("{ 2 3 * } @f f @x"), Type::Integer);
+
+        // Inputs could be any type, so the result could be any number
+        assert_eq!(global("{ @n n 2 * } @f 5 f @x"), Type::Number);
+        assert_eq!(global("{ 1 } { 2.5 } true if @x"), Type::Number);
+        assert_eq!(global("{ 1 } { \"a\" } true if @x"), Type::Any);
+        assert_eq!(global("{ 1 } { drop 2.5 } try @x"), Type::Number);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(types("\"a\" 1 +").1, vec!["+ expects a number, got string"]);
+        assert_eq!(
+            types("{ 1 } { 2 } 3 if").1,
+            vec!["if expects a bool condition, got int"]
+        );
+        assert_eq!(
+            types("make-int-hash \"a\" hash-get").1,
+            vec!["hash-get on an int-hash expects an int key, got string"]
+        );
+        assert_eq!(
+            types("{ @n \"x\" n + } @f").1,
+            vec!["+ expects a number, got string"]
+        );
+        assert_eq!(
+            types("[ 1 2 3 ] cond").1,
+            vec!["cond test must be a block, got int"]
+        );
+        assert_eq!(
+            types("{ 1 } { \"x\" writeln } while").1,
+            vec!["while condition must return a bool, got int"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_values_are_not_errors() {
+        assert!(types("{ @[a b] a b + } @f \"x\" \"y\" f").1.is_empty());
+        assert_eq!(types("read to_int \"a\" + writeln").1.len(), 1);
+        assert!(types("{ @[f n] n $f f 1 + } @g").1.is_empty());
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let host = HostArities::from([("pair".to_string(), (0, 2))]);
+        let mut resolver = Resolver::new();
+        resolver.define_host("pair");
+        let ast = resolver
+            .resolve(parse(tokenize(
+                "{ pair } @f 1 pair drop drop @n".as_bytes(),
+            )))
+            .unwrap();
+        let (ast, _) = infer(ast, &host);
+        let (globals, errors) = check(&ast, &host);
+
+        assert_eq!(globals[0].1.to_string(), "{0 -> any any}");
+        assert_eq!(globals[1].1, Type::Integer);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            Type::Block {
+                arity_in: 2,
+                outputs: vec![Type::Integer, Type::Any]
+            }
+            .to_string(),
+            "{2 -> int any}"
+        );
+    }
+}
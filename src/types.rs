@@ -1,10 +1,14 @@
 #![allow(dead_code)]
 
 use crate::numbers::Number;
+use crate::stack::FrameRef;
+use serde::{Deserialize, Serialize};
 use std::{cell::RefCell, collections::HashMap, fmt::Display, rc::Rc};
 
 /// A span is a location in the source code.
-#[derive(Clone, Debug, PartialEq, Eq)]
+///
+/// Rows and columns are counted from 0, but rows are displayed from 1 (as in an editor)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Span {
     pub name: Option<String>,
     pub row: usize,
@@ -18,7 +22,7 @@ impl Display for Span {
             f,
             "{}:{}:{}:{}",
             self.name.as_ref().unwrap_or(&"<unknown>".to_string()),
-            self.row,
+            self.row + 1,
             self.column,
             self.length,
         )
@@ -26,12 +30,19 @@ impl Display for Span {
 }
 
 /// A token is a single unit of a program.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Token {
     pub span: Span,
     pub token: String,
 }
 
+impl Token {
+    /// Comments are kept as tokens (for formatting) but ignored by the parser
+    pub fn is_comment(&self) -> bool {
+        self.token.starts_with(crate::lexer::COMMENT_START)
+    }
+}
+
 impl Display for Number {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -65,7 +76,10 @@ pub enum Value {
     Block {
         arity_in: usize,
         arity_out: usize,
-        expression: Box<Expression>,
+        expression: Rc<Expression>,
+        /// The frame the block was created in, used to look up names from enclosing blocks
+        /// None if the block doesn't use any, see resolver::captures
+        frame: Option<FrameRef>,
     },
     Stack(Rc<RefCell<Vec<Value>>>),
     Hash(Rc<RefCell<HashMap<String, Value>>>),
@@ -124,18 +138,121 @@ impl Display for Value {
     }
 }
 
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Number(Number::Integer(v))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Number(Number::Float(v))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Boolean(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::String(v)
+    }
+}
+
 /// An expression is a single unit of a program, part of the AST
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum Expression {
+///
+/// Each expression remembers the span it was parsed from, for error reporting.
+/// Spans are ignored when comparing expressions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    /// Creates a new expression at the given span
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        Expression { kind, span }
+    }
+
+    /// The expression as an indented tree, one expression per line with its span
+    pub fn tree(&self) -> String {
+        let mut out = String::new();
+        self.write_tree(&mut out, 0);
+        out
+    }
+
+    fn write_tree(&self, out: &mut String, depth: usize) {
+        use ExpressionKind::*;
+
+        let (label, children) = match &self.kind {
+            Identifier(name) => (format!("Identifier {}", name), &[][..]),
+            DottedIdentifier(ids) => (format!("DottedIdentifier {}", ids.join(".")), &[][..]),
+            Literal(Value::String(v)) => (format!("Literal {:?}", v), &[][..]),
+            Literal(v) => (format!("Literal {}", v), &[][..]),
+            Block { children, .. } => ("Block".to_string(), children.as_slice()),
+            List(children) => ("List".to_string(), children.as_slice()),
+            Group(children) => ("Group".to_string(), children.as_slice()),
+            At(inner) => ("At".to_string(), std::slice::from_ref(inner.as_ref())),
+            Bang(inner) => ("Bang".to_string(), std::slice::from_ref(inner.as_ref())),
+            Dollar(inner) => ("Dollar".to_string(), std::slice::from_ref(inner.as_ref())),
+            Local { name, depth, slot } => (
+                format!("Local {} (depth {}, slot {})", name, depth, slot),
+                &[][..],
+            ),
+            ExpressionKind::Builtin(name) => (format!("Builtin {}", name), &[][..]),
+        };
+
+        out.push_str(&format!("{}{} {}\n", "  ".repeat(depth), label, self.span));
+        for child in children {
+            child.write_tree(out, depth + 1);
+        }
+    }
+}
+
+impl From<ExpressionKind> for Expression {
+    fn from(kind: ExpressionKind) -> Self {
+        Expression {
+            kind,
+            span: Span::default(),
+        }
+    }
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Expression {}
+
+/// The different kinds of expressions
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpressionKind {
     /// An identifier/variable, used to lookup a named value or global
     Identifier(String),
     /// A dotted expression, used to lookup fields in structs
     DottedIdentifier(Vec<String>),
     /// A literal value
-    Literal(Value),
+    Literal(#[serde(with = "literal")] Value),
 
     /// A function definition, generally delimited with {}
-    Block(Vec<Expression>),
+    /// The arity is set by arity::infer, otherwise it comes from markers in the body (see calculate_arity)
+    Block {
+        children: Vec<Expression>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        arity: Option<(usize, usize)>,
+    },
     /// A list of values, generally delimited with []
     List(Vec<Expression>),
     /// A group of values, generally delimited with (), currently used only for clean code
@@ -148,6 +265,53 @@ pub enum Expression {
     Bang(Box<Expression>),
     /// A $ prefixed expression, used to pass to the stack (only really needed for blocks)
     Dollar(Box<Expression>),
+
+    /// A name resolved to a slot in the frame of a block `depth` blocks out from this one
+    /// Identifiers are replaced with these by the resolver
+    Local {
+        name: String,
+        depth: usize,
+        slot: usize,
+    },
+    /// An identifier resolved to a built in function
+    Builtin(String),
+}
+
+/// Literals are serialized as the values the parser can produce, values only created when running can't be
+mod literal {
+    use super::Value;
+    use crate::numbers::Number;
+    use serde::{de::Deserializer, ser::Error, Deserialize, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    enum Literal {
+        Number(Number),
+        String(String),
+        Boolean(bool),
+    }
+
+    pub fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+        let literal = match value {
+            Value::Number(v) => Literal::Number(*v),
+            Value::String(v) => Literal::String(v.clone()),
+            Value::Boolean(v) => Literal::Boolean(*v),
+            _ => {
+                return Err(S::Error::custom(format!(
+                    "cannot serialize {} in an expression",
+                    value
+                )))
+            }
+        };
+        literal.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        Ok(match Literal::deserialize(deserializer)? {
+            Literal::Number(v) => Value::Number(v),
+            Literal::String(v) => Value::String(v),
+            Literal::Boolean(v) => Value::Boolean(v),
+        })
+    }
 }
 
 macro_rules! write_children {
@@ -166,17 +330,25 @@ macro_rules! write_children {
 }
 
 impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for ExpressionKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expression::Identifier(id) => write!(f, "{}", id),
-            Expression::DottedIdentifier(ids) => write!(f, "{}", ids.join(".")),
-            Expression::Literal(value) => write!(f, "{}", value),
-            Expression::Block(children) => write_children! {f '{' children '}'},
-            Expression::List(children) => write_children! {f '[' children ']'},
-            Expression::Group(children) => write_children! {f '(' children ')'},
-            Expression::At(expr) => write!(f, "@{}", expr),
-            Expression::Bang(expr) => write!(f, "!{}", expr),
-            Expression::Dollar(expr) => write!(f, "${}", expr),
+            ExpressionKind::Identifier(id) => write!(f, "{}", id),
+            ExpressionKind::DottedIdentifier(ids) => write!(f, "{}", ids.join(".")),
+            ExpressionKind::Literal(value) => write!(f, "{}", value),
+            ExpressionKind::Block { children, .. } => write_children! {f '{' children '}'},
+            ExpressionKind::List(children) => write_children! {f '[' children ']'},
+            ExpressionKind::Group(children) => write_children! {f '(' children ')'},
+            ExpressionKind::At(expr) => write!(f, "@{}", expr),
+            ExpressionKind::Bang(expr) => write!(f, "!{}", expr),
+            ExpressionKind::Dollar(expr) => write!(f, "${}", expr),
+            ExpressionKind::Local { name, .. } => write!(f, "{}", name),
+            ExpressionKind::Builtin(name) => write!(f, "{}", name),
         }
     }
 }
@@ -2,14 +2,97 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::arity::calculate_arity;
+use crate::arity::{calculate_arity, infer, ArityError, HostArities};
+use crate::builtins;
+use crate::error::{fail, Backtrace, Call, Error, RuntimeError};
+use crate::lexer::try_tokenize_named;
 use crate::numbers::Number;
-use crate::stack::Stack;
-use crate::types::{Expression, Value};
+use crate::parser::try_parse;
+use crate::resolver::{self, Resolver};
+use crate::stack::{FrameRef, Stack};
+use crate::types::{Expression, ExpressionKind, Span, Value};
+
+/// The signature of a function defined by the host program, see VM::register
+pub type HostFn = dyn Fn(&mut VM) -> Result<(), RuntimeError>;
+
+/// A function defined by the host program
+#[derive(Clone)]
+struct HostFunction {
+    arity_in: usize,
+    arity_out: usize,
+    f: Rc<HostFn>,
+}
+
+impl std::fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HostFunction({}, {})", self.arity_in, self.arity_out)
+    }
+}
+
+/// Something the VM is doing, passed to the hook (see VM::set_hook)
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// About to evaluate an expression
+    Evaluate(&'a Expression),
+    /// Finished evaluating a builtin (or host function), including any blocks it called
+    Return(&'a Expression),
+    /// Entered a block (its frame is on the stack), name is set if it was called by name
+    Enter {
+        block: &'a Expression,
+        name: Option<&'a str>,
+    },
+    /// Returned from a block, or left it because of an error
+    Exit {
+        block: &'a Expression,
+        name: Option<&'a str>,
+    },
+}
+
+/// A function called as the VM runs, see VM::set_hook
+pub type HookFn = dyn FnMut(&mut VM, Event) -> Result<(), RuntimeError>;
+
+struct Hook(Box<HookFn>);
 
-#[derive(Debug)]
+impl std::fmt::Debug for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hook")
+    }
+}
+
+/// Limits on how much work a VM will do, see VM::set_limits
+///
+/// Exceeding a limit raises an error of kind ErrorKind::Limit, which try does not catch
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// The most expressions to evaluate, over every program the VM runs
+    pub max_steps: Option<usize>,
+    /// The most values on the stack at once
+    pub max_stack_depth: Option<usize>,
+    /// The most blocks called (and not yet returned from) at once
+    pub max_call_depth: Option<usize>,
+    /// The most values in a single list or hash
+    pub max_collection_size: Option<usize>,
+    /// Disable builtins that read input, such as read
+    pub disable_io: bool,
+}
+
+#[derive(Debug, Default)]
 pub struct VM {
-    stack: Stack,
+    pub(crate) stack: Stack,
+    // Resolves names in each program evaluated, keeping track of globals between them
+    resolver: Resolver,
+    // Functions defined by the host program, these are called like builtins
+    host: HashMap<String, HostFunction>,
+    // Limits on how much work to do and how much has been done so far
+    limits: Limits,
+    steps: usize,
+    // Called before each expression and around each block, for debugging and tracing
+    hook: Option<Hook>,
+    // Blocks that have been called and not returned, for backtraces
+    calls: Vec<Call>,
+    // The name and span a block is being called by, or the builtin being evaluated (which might call blocks)
+    calling: Option<(String, Span)>,
+    builtin: Option<&'static str>,
 }
 
 impl VM {
@@ -17,748 +100,825 @@ impl VM {
     pub fn new() -> VM {
         VM {
             stack: Stack::new(),
+            ..VM::default()
         }
     }
 
-    /// Evaluates a single block
-    /// This does not actually return anything, but instead mutates the self.stack
-    fn evaluate_block(&mut self, arity_in: usize, expression: Box<Expression>, arity_out: usize) {
-        self.stack.extend(arity_in);
-        self.evaluate(expression.as_ref().clone());
-        self.stack.contract(arity_out);
+    /// Registers a function defined by the host program
+    ///
+    /// The function will be callable by name (as a builtin) from any program evaluated after this
+    /// It must pop arity_in values and push arity_out values, this is checked each time it's called
+    pub fn register<F>(&mut self, name: &str, arity_in: usize, arity_out: usize, f: F)
+    where
+        F: Fn(&mut VM) -> Result<(), RuntimeError> + 'static,
+    {
+        self.resolver.define_host(name);
+        self.host.insert(
+            name.to_string(),
+            HostFunction {
+                arity_in,
+                arity_out,
+                f: Rc::new(f),
+            },
+        );
     }
 
-    /// Evaluates a vector of expressions
-    /// This does not actually return anything, but instead mutates the self.stack
-    #[allow(dead_code)]
-    pub fn evaluate(&mut self, ast: Expression) {
-        log::debug!("evaluate({}) on {}", ast, self.stack);
+    /// The arity of each function registered with VM::register, for arity::infer and typecheck::check
+    pub fn host_arities(&self) -> HostArities {
+        self.host
+            .iter()
+            .map(|(name, host)| (name.clone(), (host.arity_in, host.arity_out)))
+            .collect()
+    }
+
+    /// Sets limits on how much work the VM will do, exceeding one is a runtime error
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
 
-        /// A helper macro to generate functions that operate on two integers and floats
-        macro_rules! numeric_binop {
-            ($stack:expr, $f:expr) => {{
-                // TODO: Check we have enough values
-                let b = $stack.pop().unwrap();
-                let a = $stack.pop().unwrap();
+    /// Sets a function to call before each expression is evaluated and as blocks are entered and exited
+    ///
+    /// The hook isn't called for anything it evaluates itself; returning an error stops the program
+    pub fn set_hook<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut VM, Event) -> Result<(), RuntimeError> + 'static,
+    {
+        self.hook = Some(Hook(Box::new(f)));
+    }
 
-                match (a.clone(), b.clone()) {
-                    (Value::Number(a), Value::Number(b)) => {
-                        $stack.push(Value::Number($f(a, b)));
-                    }
-                    _ => panic!(
-                        "cannot perform numeric operation on non-numeric values, got {} and {}",
-                        a, b
-                    ),
-                };
-            }};
+    /// Calls the hook (if there is one) with an event
+    fn call_hook(&mut self, event: Event) -> Result<(), RuntimeError> {
+        match self.hook.take() {
+            Some(mut hook) => {
+                let result = (hook.0)(self, event);
+                self.hook = Some(hook);
+                result
+            }
+            None => Ok(()),
         }
+    }
 
-        /// A helper macro to generate functions that operate on two integers and floats
-        macro_rules! comparison_binop {
-        ($stack:expr, $f:expr) => {{
-            // TODO: Check we have enough values
-            let b = $stack.pop().unwrap();
-            let a = $stack.pop().unwrap();
+    /// The current stack, including the frame of each block being evaluated
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
 
-            match (a.clone(), b.clone()) {
-                (Value::Number(a), Value::Number(b)) => {
-                    $stack.push(Value::Boolean($f(a, b)));
-                }
-                // TODO: Handle other types
-                _ => panic!(
-                    "cannot perform comparison operation on non-numeric values, got {} and {}",
-                    a, b
-                ),
-            };
-        }};
-    }
-
-        // Cloned for debug printing
-        match ast.clone() {
-            // Identifiers are globals are named expressions
-            // TODO: Extract globals into their own module
-            Expression::Identifier(id) => {
-                match id.as_str() {
-                    // Built in numeric functions
-                    "+" => numeric_binop!(self.stack, |a, b| { a + b }),
-                    "-" => numeric_binop!(self.stack, |a, b| { a - b }),
-                    "*" => numeric_binop!(self.stack, |a, b| { a * b }),
-                    "/" => numeric_binop!(self.stack, |a, b| { a / b }),
-                    "%" => numeric_binop!(self.stack, |a, b| { a % b }),
-                    // Built in comparisons
-                    "<" => comparison_binop!(self.stack, |a, b| { a < b }),
-                    "<=" => comparison_binop!(self.stack, |a, b| { a <= b }),
-                    "=" => comparison_binop!(self.stack, |a, b| { a == b }),
-                    ">=" => comparison_binop!(self.stack, |a, b| { a >= b }),
-                    ">" => comparison_binop!(self.stack, |a, b| { a > b }),
-                    // Logical operators
-                    "and" => {
-                        let b = self.stack.pop().unwrap();
-                        let a = self.stack.pop().unwrap();
-
-                        match (a.clone(), b.clone()) {
-                            (Value::Boolean(a), Value::Boolean(b)) => {
-                                self.stack.push(Value::Boolean(a && b));
-                            }
-                            _ => panic!(
-                                "cannot perform logical operation on non-boolean values, got {} and {}",
-                                a, b
-                            ),
-                        };
-                    }
-                    "or" => {
-                        let b = self.stack.pop().unwrap();
-                        let a = self.stack.pop().unwrap();
+    /// Checks that a builtin is allowed to read input, see Limits::disable_io
+    pub(crate) fn check_io(&self, name: &str) -> Result<(), RuntimeError> {
+        if self.limits.disable_io {
+            fail!(Limit, "{} is disabled, it reads input", name);
+        }
+        Ok(())
+    }
 
-                        match (a.clone(), b.clone()) {
-                            (Value::Boolean(a), Value::Boolean(b)) => {
-                                self.stack.push(Value::Boolean(a || b));
-                            }
-                            _ => panic!(
-                                "cannot perform logical operation on non-boolean values, got {} and {}",
-                                a, b
-                            ),
-                        };
-                    }
-                    "xor" => {
-                        let b = self.stack.pop().unwrap();
-                        let a = self.stack.pop().unwrap();
+    /// Checks that a builtin can create a collection with size values, see Limits::max_collection_size
+    pub(crate) fn check_size(&self, name: &str, size: usize) -> Result<(), RuntimeError> {
+        match self.limits.max_collection_size {
+            Some(max) if size > max => fail!(
+                Limit,
+                "{}: {} values exceeds the limit of {} values in a collection",
+                name,
+                size,
+                max
+            ),
+            _ => Ok(()),
+        }
+    }
 
-                        match (a.clone(), b.clone()) {
-                            (Value::Boolean(a), Value::Boolean(b)) => {
-                                self.stack.push(Value::Boolean(a && !b || !a && b));
-                            }
-                            _ => panic!(
-                                "cannot perform logical operation on non-boolean values, got {} and {}",
-                                a, b
-                            ),
-                        };
-                    }
-                    "nand" => {
-                        let b = self.stack.pop().unwrap();
-                        let a = self.stack.pop().unwrap();
+    /// The blocks that have been called and not yet returned, outermost first
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
 
-                        match (a.clone(), b.clone()) {
-                            (Value::Boolean(a), Value::Boolean(b)) => {
-                                self.stack.push(Value::Boolean(!(a && b)));
-                            }
-                            _ => panic!(
-                                "cannot perform logical operation on non-boolean values, got {} and {}",
-                                a, b
-                            ),
-                        };
-                    }
-                    "not" => {
-                        let a = self.stack.pop().unwrap();
+    /// Pushes a value onto the stack
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
 
-                        match a.clone() {
-                            Value::Boolean(a) => {
-                                self.stack.push(Value::Boolean(!a));
-                            }
-                            _ => panic!(
-                                "cannot perform logical operation on non-boolean values, got {}",
-                                a
-                            ),
-                        };
-                    }
-                    // Convert a value to an int if possible
-                    "to_int" => {
-                        let value = self.stack.pop().unwrap();
-                        match value {
-                            Value::String(s) => self
-                                .stack
-                                .push(Value::Number(Number::Integer(s.parse().unwrap()))),
-                            Value::Number(n) => self.stack.push(Value::Number(n.to_integer())),
-                            _ => panic!("int cannot, got {}", value),
-                        }
-                    }
-                    // Convert a value to a float if possible
-                    "to_float" => {
-                        let value = self.stack.pop().unwrap();
-                        match value {
-                            Value::String(s) => self
-                                .stack
-                                .push(Value::Number(Number::Float(s.parse().unwrap()))),
-                            Value::Number(n) => self.stack.push(Value::Number(n.to_float())),
-                            _ => panic!("int cannot, got {}", value),
-                        }
-                    }
-                    // Apply a block to the self.stack
-                    "apply" => {
-                        let block = self.stack.pop().unwrap();
-                        match block {
-                            Value::Block {
-                                arity_in,
-                                arity_out,
-                                expression,
-                            } => {
-                                self.evaluate_block(arity_in, expression, arity_out);
-                            }
-                            _ => panic!("apply expects a block, got {}", block),
-                        }
-                    }
-                    // Read a line from stdin as a string
-                    "read" => {
-                        let mut input = String::new();
-                        match std::io::stdin().read_line(&mut input) {
-                            Ok(_) => {
-                                self.stack
-                                    .push(Value::String(input.trim_end_matches('\n').to_string()));
-                            }
-                            Err(e) => {
-                                panic!("failed to read from stdin: {e}");
-                            }
-                        };
-                    }
-                    // Pop and write a value to stdout
-                    "write" => {
-                        print!("{}", self.stack.pop().unwrap());
-                    }
-                    // Pop and write a value to stdout with a newline
-                    "writeln" => {
-                        println!("{}", self.stack.pop().unwrap());
-                    }
-                    // Write a newline
-                    "newline" => {
-                        println!();
-                    }
-                    // Loop over an iterable, expects a block and an iterable
-                    "loop" => {
-                        let iterable = self.stack.pop().unwrap();
-                        let block = self.stack.pop().unwrap();
-
-                        match iterable {
-                        Value::Number(Number::Integer(n)) => {
-                            if n < 0 {
-                                panic!("numeric loops must have a positive integer, got {}", n);
-                            }
+    /// Pops a value off the stack, it is an error if the stack is empty
+    pub fn pop(&mut self) -> Result<Value, RuntimeError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => fail!(Stack, "cannot pop from an empty stack"),
+        }
+    }
 
-                            for i in 0..n {
-                                self.stack.push(Value::Number(Number::Integer(i)));
-                                match block.clone() {
-                                    // Blocks get evaluated lazily (now)
-                                    Value::Block { arity_in, arity_out, expression } => {
-                                        self.evaluate_block(arity_in, expression, arity_out);
-                                    },
-                                    // Loops must have a block
-                                    _ => panic!("loop must have a block, got {}", block),
-                                }
-                            }
-                        },
-                        Value::String(s) => {
-                            for c in s.chars() {
-                                self.stack.push(Value::String(c.to_string()));
-                                match block.clone() {
-                                    Value::Block { arity_in, arity_out, expression } => {
-                                        self.evaluate_block(arity_in, expression, arity_out);
-                                    },
-                                    _ => panic!("loop must have a block, got {}", block),
-                                }
-                            }
-                        },
-                        Value::Stack(l) => {
-                            for value in l.clone().borrow().iter() {
-                                self.stack.push(value.clone());
-                                match block.clone() {
-                                    Value::Block { arity_in, arity_out, expression } => {
-                                        self.evaluate_block(arity_in, expression, arity_out);
-                                    },
-                                    _ => panic!("loop must have a block, got {}", block),
-                                }
-                            }
-                        }
-                        _ => panic!("loop must have an iterable (currently an integer or string), got {}", iterable),
-                    };
-                    }
-                    // Loop over an iterable and store the results as a list
-                    "generate-stack" => {
-                        let iterable = self.stack.pop().unwrap();
-                        let block = self.stack.pop().unwrap();
-                        let mut result = vec![];
-
-                        match iterable {
-                        Value::Number(Number::Integer(n)) => {
-                            if n < 0 {
-                                panic!("numeric loops must have a positive integer, got {}", n);
-                            }
+    /// Gets the current value of a name bound at the top level
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.resolver
+            .global_slot(name)
+            .and_then(|slot| self.stack.get_global(slot))
+    }
 
-                            for i in 0..n {
-                                self.stack.push(Value::Number(Number::Integer(i)));
-                                match block.clone() {
-                                    // Blocks get evaluated lazily (now)
-                                    Value::Block { arity_in, arity_out, expression } => {
-                                        self.evaluate_block(arity_in, expression, arity_out);
-                                        result.push(self.stack.pop().unwrap());
-                                    },
-                                    // Loops must have a block
-                                    _ => panic!("loop must have a block, got {}", block),
-                                }
-                            }
-                        },
-                        Value::String(s) => {
-                            for c in s.chars() {
-                                self.stack.push(Value::String(c.to_string()));
-                                match block.clone() {
-                                    Value::Block { arity_in, arity_out, expression } => {
-                                        self.evaluate_block(arity_in, expression, arity_out);
-                                        result.push(self.stack.pop().unwrap());
-                                    },
-                                    _ => panic!("loop must have a block, got {}", block),
-                                }
-                            }
-                        },
-                        Value::Stack(l) => {
-                            for value in l.clone().borrow().iter() {
-                                self.stack.push(value.clone());
-                                match block.clone() {
-                                    Value::Block { arity_in, arity_out, expression } => {
-                                        self.evaluate_block(arity_in, expression, arity_out);
-                                        result.push(self.stack.pop().unwrap());
-                                    },
-                                    _ => panic!("loop must have a block, got {}", block),
-                                }
-                            }
-                        }
-                        _ => panic!("loop must have an iterable (currently an integer or string), got {}", iterable),
-                    };
+    /// Lexes, parses, and evaluates a program from source
+    pub fn evaluate_str(&mut self, source: &str) -> Result<(), Error> {
+        let tokens = try_tokenize_named(source.as_bytes(), None)?;
+        self.evaluate(try_parse(tokens)?)
+    }
 
-                        self.stack.push(Value::Stack(Rc::new(RefCell::new(result))));
-                    }
-                    // If statement, expects two blocks or literals and a conditional (must be boolean)
-                    "if" => {
-                        let condition = self.stack.pop().unwrap();
-                        let false_branch = self.stack.pop().unwrap();
-                        let true_branch = self.stack.pop().unwrap();
-
-                        log::debug!(
-                            "if condition: {}, true: {}, false: {}",
-                            condition,
-                            true_branch,
-                            false_branch
-                        );
-
-                        let branch = match condition {
-                            Value::Boolean(value) => {
-                                if value {
-                                    true_branch
-                                } else {
-                                    false_branch
-                                }
-                            }
-                            _ => panic!("if condition must be a boolean, got {}", condition),
-                        };
-
-                        log::debug!("if selected: {}", branch);
-
-                        match branch {
-                            // Blocks get evaluated lazily (now)
-                            Value::Block {
-                                arity_in,
-                                arity_out,
-                                expression,
-                            } => {
-                                self.evaluate_block(arity_in, expression, arity_out);
-                            }
-                            // All literal values just get directly pushed
-                            _ => {
-                                self.stack.push(branch);
-                            }
-                        }
-                    }
-                    // One armed if statement, primarily used for side effect
-                    "when" => {
-                        let condition = self.stack.pop().unwrap();
-                        let branch = self.stack.pop().unwrap();
-
-                        match condition {
-                            Value::Boolean(value) => {
-                                if value {
-                                    match branch {
-                                        // Blocks get evaluated lazily (now)
-                                        Value::Block {
-                                            arity_in,
-                                            arity_out,
-                                            expression,
-                                        } => {
-                                            self.evaluate_block(arity_in, expression, arity_out);
-                                        }
-                                        // All literal values just get directly pushed
-                                        _ => {
-                                            self.stack.push(branch);
-                                        }
-                                    }
-                                }
-                            }
-                            _ => panic!("when condition must be a boolean, got {}", condition),
-                        };
-                    }
-                    // Cond statements are like if statements, but with multiple branches
-                    // They expect only an odd numbered list of blocks as input:
-                    // [ test1 value1 test2 value2 ... default ]
-                    // For each test/value pair. If test is true, value is returned and the cond exits
-                    // If no other block returns, return the result of the last block (default)
-                    // All tests should be blocks; values can be blocks or values
-                    "cond" => {
-                        let branches = self.stack.pop().unwrap();
-
-                        let l = match branches {
-                            Value::Stack(l) => l.clone(),
-                            _ => panic!("cond branches must be a list, got {}", branches),
-                        };
-
-                        let mut cond_returned = false;
-
-                        'cond_loop: for i in 0..(l.clone().borrow().len() / 2) {
-                            let test = &l.borrow()[i * 2];
-                            let value = &l.borrow()[i * 2 + 1];
-
-                            let test_result = match test.clone() {
-                                // Blocks get evaluated lazily (now)
-                                Value::Block {
-                                    arity_in,
-                                    arity_out,
-                                    expression,
-                                } => {
-                                    self.evaluate_block(arity_in, expression, arity_out);
-                                    self.stack.pop().unwrap()
-                                }
-                                _ => panic!("cond test must be a block, got {}", test),
-                            };
-
-                            match test_result {
-                                Value::Boolean(test_value) => {
-                                    if test_value {
-                                        match value.clone() {
-                                            // Blocks get evaluated lazily (now)
-                                            Value::Block {
-                                                arity_in,
-                                                arity_out,
-                                                expression,
-                                            } => {
-                                                self.evaluate_block(
-                                                    arity_in, expression, arity_out,
-                                                );
-                                            }
-                                            // All literal values just get directly pushed
-                                            _ => {
-                                                self.stack.push(value.clone());
-                                            }
-                                        }
-
-                                        cond_returned = true;
-                                        break 'cond_loop;
-                                    }
-                                }
-                                _ => panic!("cond test must return a boolean, got {}", test_result),
-                            }
-                        }
+    /// Resolves and evaluates a parsed program
+    ///
+    /// Globals bound by previous programs are still available
+    /// Blocks with arity that disagrees with their body are logged as warnings, see VM::prepare
+    pub fn evaluate(&mut self, ast: Expression) -> Result<(), Error> {
+        let (ast, warnings) = self.prepare(ast)?;
+        for warning in warnings {
+            log::warn!("{}", warning);
+        }
+        self.run(&ast)
+    }
 
-                        // If we didn't return from the cond, return the last value
-                        if !cond_returned {
-                            let default = &l.borrow()[l.borrow().len() - 1];
-
-                            match default.clone() {
-                                // Blocks get evaluated lazily (now)
-                                Value::Block {
-                                    arity_in,
-                                    arity_out,
-                                    expression,
-                                } => {
-                                    self.evaluate_block(arity_in, expression, arity_out);
-                                }
-                                // All literal values just get directly pushed
-                                _ => {
-                                    self.stack.push(default.clone());
-                                }
-                            }
-                        }
-                    }
-                    // List (vector) implementation
-                    "make-stack" => {
-                        let list = Value::Stack(Rc::new(RefCell::new(vec![])));
-                        self.stack.push(list);
-                    }
-                    "stack-size" => {
-                        let list = self.stack.pop().unwrap();
-
-                        match list {
-                            Value::Stack(l) => {
-                                self.stack.push(Value::Number(Number::Integer(
-                                    l.clone().borrow().len() as i64,
-                                )));
-                            }
-                            _ => panic!("stack-size: expected list, got {}", list),
-                        }
-                    }
-                    "stack-push!" => {
-                        let value = self.stack.pop().unwrap();
-                        let list = self.stack.pop().unwrap();
+    /// Evaluates source as a new block within the current one, returning the values it adds to the stack
+    ///
+    /// Names visible in the current block can be used, the stack is restored afterwards
+    /// This is used by the debugger to evaluate expressions wherever the program is stopped
+    pub fn evaluate_in_scope(&mut self, source: &str) -> Result<Vec<Value>, Error> {
+        let ast = try_parse(try_tokenize_named(source.as_bytes(), None)?)?;
+        let ast = self
+            .resolver
+            .resolve_nested(ast, &self.stack.visible_names())?;
+        let (ast, _) = infer(ast, &self.host_arities());
+
+        let saved = self.stack.clone();
+        let len = self.stack.len();
+        let frame = self.stack.current_frame();
+
+        let result = self
+            .stack
+            .extend(0, Some(frame))
+            .and_then(|_| self.evaluate_expression(&ast));
+        let mut values = vec![];
+        while self.stack.len() > len {
+            values.extend(self.stack.pop());
+        }
+        values.reverse();
+        self.stack = saved;
 
-                        match list {
-                            Value::Stack(l) => {
-                                l.clone().borrow_mut().push(value);
-                            }
-                            _ => panic!("stack-push!: expected list, got {}", list),
-                        }
-                    }
-                    "stack-pop!" => {
-                        let list = self.stack.pop().unwrap();
-
-                        match list {
-                            Value::Stack(l) => {
-                                if let Some(value) = l.clone().borrow_mut().pop() {
-                                    self.stack.push(value);
-                                } else {
-                                    panic!("stack-pop!: list is empty");
-                                }
-                            }
-                            _ => panic!("stack-pop!: expected list, got {}", list),
-                        }
-                    }
-                    "stack-ref" => {
-                        let index = self.stack.pop().unwrap();
-                        let list = self.stack.pop().unwrap();
-
-                        match list {
-                            Value::Stack(l) => match index {
-                                Value::Number(Number::Integer(i)) => {
-                                    if let Some(value) = l.clone().borrow().get(i as usize) {
-                                        self.stack.push(value.clone());
-                                    } else {
-                                        panic!("stack-ref: index out of bounds: {}", i);
-                                    }
-                                }
-                                _ => {
-                                    panic!("stack-ref: index must be an integer, got {}", index)
-                                }
-                            },
-                            _ => panic!("stack-ref: expected list, got {}", list),
-                        }
-                    }
-                    "stack-set!" => {
-                        let value = self.stack.pop().unwrap();
-                        let index = self.stack.pop().unwrap();
-                        let list = self.stack.pop().unwrap();
-
-                        match list {
-                            Value::Stack(l) => match index {
-                                Value::Number(Number::Integer(i)) => {
-                                    if let Some(old_value) =
-                                        l.clone().borrow_mut().get_mut(i as usize)
-                                    {
-                                        *old_value = value.clone();
-                                    } else {
-                                        panic!("stack-set!: index out of bounds: {}", i);
-                                    }
-                                }
-                                _ => panic!("stack-set!: index must be an integer, got {}", index),
-                            },
-                            _ => panic!("stack-set!: expected list, got {}", list),
-                        }
-                    }
-                    // Hashmap implementation
-                    "make-hash" => {
-                        let hash = Value::Hash(Rc::new(RefCell::new(HashMap::new())));
-                        self.stack.push(hash);
-                    }
-                    "make-int-hash" => {
-                        let hash = Value::IntHash(Rc::new(RefCell::new(HashMap::new())));
-                        self.stack.push(hash);
-                    }
-                    "hash-has?" => {
-                        let key = self.stack.pop().unwrap();
-                        let hash = self.stack.pop().unwrap();
-
-                        match hash {
-                            Value::Hash(h) => match key {
-                                Value::String(s) => {
-                                    self.stack
-                                        .push(Value::Boolean(h.clone().borrow().contains_key(&s)));
-                                }
-                                _ => {
-                                    panic!("hash-has?: Hash key must be a string, got {}", key)
-                                }
-                            },
-                            Value::IntHash(h) => match key {
-                                Value::Number(Number::Integer(v)) => {
-                                    self.stack
-                                        .push(Value::Boolean(h.clone().borrow().contains_key(&v)));
-                                }
-                                _ => {
-                                    panic!("hash-has?: IntHash key must be an integer, got {}", key)
-                                }
-                            },
-                            _ => panic!("hash-has?: hash must be a hash, got {}", hash),
-                        }
-                    }
-                    "hash-get" => {
-                        let key = self.stack.pop().unwrap();
-                        let hash = self.stack.pop().unwrap();
-
-                        match hash {
-                            Value::Hash(h) => match key {
-                                Value::String(s) => {
-                                    if let Some(value) = h.clone().borrow().get(&s) {
-                                        self.stack.push(value.clone());
-                                    } else {
-                                        panic!("hash-get: key not found: {}", s);
-                                    }
-                                }
-                                _ => panic!("hash-get: Hash key must be a string, got {}", key),
-                            },
-                            Value::IntHash(h) => match key {
-                                Value::Number(Number::Integer(v)) => {
-                                    if let Some(value) = h.clone().borrow().get(&v) {
-                                        self.stack.push(value.clone());
-                                    } else {
-                                        panic!("hash-get: key not found: {}", v);
-                                    }
-                                }
-                                _ => {
-                                    panic!("hash-get: IntHash key must be an integer, got {}", key)
-                                }
-                            },
-                            _ => panic!("hash-get: hash must be a hash, got {}", hash),
-                        }
-                    }
-                    "hash-set!" => {
-                        let value = self.stack.pop().unwrap();
-                        let key = self.stack.pop().unwrap();
-                        let hash = self.stack.pop().unwrap();
-
-                        match hash {
-                            Value::Hash(h) => match key {
-                                Value::String(ref s) => {
-                                    h.clone().borrow_mut().insert(s.clone(), value);
-                                }
-                                _ => {
-                                    panic!("hash-set!: Hash key must be a string, got {}", key);
-                                }
-                            },
-                            Value::IntHash(h) => match key {
-                                Value::Number(Number::Integer(v)) => {
-                                    h.clone().borrow_mut().insert(v, value);
-                                }
-                                _ => {
-                                    panic!(
-                                        "hash-set!: IntHash key must be an integer, got {}",
-                                        key
-                                    );
-                                }
-                            },
-                            _ => panic!("hash-set!: hash must be a hash, got {}", hash),
-                        }
-                    }
-                    // Anything else is a variable lookup
-                    name => {
-                        if let Some(value) = self.stack.get_named(String::from(name)) {
-                            if let Value::Block {
-                                arity_in,
-                                arity_out,
-                                expression,
-                            } = value
-                            {
-                                self.evaluate_block(arity_in, expression, arity_out);
-                            } else {
-                                self.stack.push(value.clone());
-                            }
-                        } else {
-                            panic!("Unknown identifier {:?}", name);
-                        }
-                    }
+        result?;
+        Ok(values)
+    }
+
+    /// Resolves names and infers the arity of blocks in a parsed program, without running it
+    ///
+    /// Also returns any blocks with declared arity that disagrees with their body
+    pub fn prepare(&mut self, ast: Expression) -> Result<(Expression, Vec<ArityError>), Error> {
+        let ast = self.resolver.resolve(ast)?;
+        Ok(infer(ast, &self.host_arities()))
+    }
+
+    /// Runs a program returned by VM::prepare
+    ///
+    /// Runtime errors have a backtrace of the blocks that were called when they were raised
+    pub fn run(&mut self, ast: &Expression) -> Result<(), Error> {
+        self.evaluate_expression(ast)
+            .map_err(|e| self.with_backtrace(e))?;
+        Ok(())
+    }
+
+    /// Add the current calls and stack to an error, if it doesn't have them already
+    fn with_backtrace(&self, mut error: RuntimeError) -> RuntimeError {
+        if error.backtrace.is_none() && error.control.is_none() {
+            error.backtrace = Some(Box::new(Backtrace {
+                calls: self.calls.clone(),
+                stack: self.stack.to_string(),
+            }));
+        }
+        error
+    }
+
+    /// Evaluates a single block
+    /// This does not actually return anything, but instead mutates the self.stack
+    /// It is an error if there are fewer than arity_in values on entry or arity_out values on exit
+    fn evaluate_block(
+        &mut self,
+        arity_in: usize,
+        expression: &Expression,
+        arity_out: usize,
+        frame: Option<FrameRef>,
+    ) -> Result<(), RuntimeError> {
+        let describe = |e: RuntimeError| RuntimeError {
+            message: format!("block at {} {}", expression.span, e.message),
+            ..e
+        };
+
+        if let Some(max_call_depth) = self.limits.max_call_depth {
+            if self.stack.depth() >= max_call_depth {
+                fail!(
+                    Limit,
+                    "exceeded the limit of {} nested calls",
+                    max_call_depth
+                );
+            }
+        }
+
+        self.stack.extend(arity_in, frame).map_err(describe)?;
+        let name = self.push_call(expression);
+        self.call_hook(Event::Enter {
+            block: expression,
+            name: name.as_deref(),
+        })
+        .inspect_err(|_| {
+            self.calls.pop();
+        })?;
+
+        let result = match self.evaluate_expression(expression) {
+            // break and continue return early from each block on their way out to the loop
+            Err(e) if e.control.is_some() => {
+                self.stack.contract(arity_out).map_err(describe).and(Err(e))
+            }
+            result => result.and_then(|_| self.stack.contract(arity_out).map_err(describe)),
+        };
+        let result = result.map_err(|e| self.with_backtrace(e));
+        self.calls.pop();
+
+        let exited = self.call_hook(Event::Exit {
+            block: expression,
+            name: name.as_deref(),
+        });
+        result.and(exited)
+    }
+
+    /// Records a call to a block for backtraces, returning the name it was called by for the hook
+    ///
+    /// Every level of recursion goes through evaluate_block and evaluate_expression, so anything
+    /// with large locals (like the Call built here) is kept in a separate function to keep their
+    /// stack frames small
+    fn push_call(&mut self, expression: &Expression) -> Option<String> {
+        let call = match self.calling.take() {
+            Some((name, site)) => Call {
+                by: name,
+                site: Some(site),
+                block: expression.span.clone(),
+            },
+            None => Call {
+                by: self.builtin.unwrap_or("a host function").to_string(),
+                site: None,
+                block: expression.span.clone(),
+            },
+        };
+
+        // Only copied for the hook, which most programs run without
+        let name = match &call.site {
+            Some(_) if self.hook.is_some() => Some(call.by.clone()),
+            _ => None,
+        };
+        self.calls.push(call);
+        name
+    }
+
+    /// Evaluates a value as a builtin argument would be
+    /// Blocks get evaluated lazily (now), all other values are pushed directly
+    pub(crate) fn evaluate_value(&mut self, value: Value) -> Result<(), RuntimeError> {
+        match value {
+            Value::Block {
+                arity_in,
+                arity_out,
+                expression,
+                frame,
+            } => self.evaluate_block(arity_in, &expression, arity_out, frame),
+            _ => {
+                self.stack.push(value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls a function defined by the host program, checking that it has the arity it declared
+    fn call_host(&mut self, name: &str, host: HostFunction) -> Result<(), RuntimeError> {
+        let before = self.stack.len();
+        if before < host.arity_in {
+            fail!(
+                Stack,
+                "{} expects {} values, but only {} are on the stack",
+                name,
+                host.arity_in,
+                before
+            );
+        }
+
+        (host.f)(self)?;
+
+        let expected = before - host.arity_in + host.arity_out;
+        if self.stack.len() != expected {
+            fail!(
+                "{} is declared to take {} and return {} values, but changed the stack from {} to {} values",
+                name,
+                host.arity_in,
+                host.arity_out,
+                before,
+                self.stack.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates a block value, capturing the current frame if the block uses names from it
+    ///
+    /// Split out of evaluate_expression (see push_call)
+    fn push_block(&mut self, ast: &Expression, children: &[Expression]) {
+        let (arity_in, arity_out) = calculate_arity(ast);
+
+        self.stack.push(Value::Block {
+            arity_in,
+            arity_out,
+            expression: Rc::new(Expression::new(
+                ExpressionKind::Group(children.to_vec()),
+                ast.span.clone(),
+            )),
+            frame: resolver::captures(children).then(|| self.stack.current_frame()),
+        });
+    }
+
+    /// Evaluates a builtin (or host function) expression
+    ///
+    /// Split out of evaluate_expression (see push_call) for the host function lookup and the
+    /// assertion message
+    fn evaluate_builtin(&mut self, id: &str, ast: &Expression) -> Result<(), RuntimeError> {
+        let outer = self.builtin;
+        let result = if let Some(host) = self.host.get(id).cloned() {
+            self.builtin = None;
+            self.call_host(id, host)
+        } else if let Some(builtin) = builtins::lookup(id) {
+            self.builtin = Some(builtin.name);
+            (builtin.vm)(self).map_err(|e| match id {
+                // Assertions report where they are in the source
+                "assert" | "assert-eq" => RuntimeError {
+                    message: format!("{} at {}", e.message, ast.span),
+                    ..e
+                },
+                _ => e,
+            })
+        } else {
+            fail!(Name, "Unknown builtin {:?}", id);
+        };
+        self.builtin = outer;
+
+        let returned = self.call_hook(Event::Return(ast));
+        result.and(returned)
+    }
+
+    /// Evaluates a vector of expressions
+    /// Names must already have been resolved, see resolver::Resolver
+    /// This does not actually return anything, but instead mutates the self.stack
+    fn evaluate_expression(&mut self, ast: &Expression) -> Result<(), RuntimeError> {
+        log::debug!("evaluate({}) on {}", ast, self.stack);
+
+        self.steps += 1;
+        if let Some(max_steps) = self.limits.max_steps {
+            if self.steps > max_steps {
+                fail!(Limit, "exceeded the limit of {} steps", max_steps);
+            }
+        }
+        if let Some(max_stack_depth) = self.limits.max_stack_depth {
+            if self.stack.len() > max_stack_depth {
+                fail!(
+                    Limit,
+                    "exceeded the limit of {} values on the stack",
+                    max_stack_depth
+                );
+            }
+        }
+
+        self.call_hook(Event::Evaluate(ast))?;
+
+        match &ast.kind {
+            // Builtins have been resolved ahead of time, host functions take precedence
+            ExpressionKind::Builtin(id) => self.evaluate_builtin(id, ast)?,
+            // Names have been resolved to a slot in an enclosing frame
+            ExpressionKind::Local { name, depth, slot } => {
+                if let Some(value) = self.stack.get_named(*depth, *slot) {
+                    if let Value::Block { .. } = value {
+                        self.calling = Some((name.clone(), ast.span.clone()));
+                    }
+                    let result = self.evaluate_value(value);
+                    self.calling = None;
+                    result?;
+                } else {
+                    fail!(Name, "{:?} was used before it was bound", name);
                 }
             }
+            // Identifiers should all have been resolved before evaluating
+            ExpressionKind::Identifier(id) => {
+                fail!(Name, "Unresolved identifier {:?}", id)
+            }
             // Dotted identifiers are used to access fields in structs
-            Expression::DottedIdentifier(ids) => {
-                unimplemented!("evaluate dotted identifiers: {:?}", ids)
+            ExpressionKind::DottedIdentifier(ids) => {
+                fail!("Dotted identifiers are not supported: {}", ids.join("."))
             }
             // Literal values are just pushed onto the self.stack
-            Expression::Literal(value) => self.stack.push(value.clone()),
+            ExpressionKind::Literal(value) => self.stack.push(value.clone()),
             // Blocks are parsed into block values, arity is calculated here
-            Expression::Block(children) => {
-                let (arity_in, arity_out) = calculate_arity(&ast.clone());
-
-                // TODO: Actually calculate arity
-                self.stack.push(Value::Block {
-                    arity_in,
-                    arity_out,
-                    expression: Box::new(Expression::Group(children)),
-                });
-            }
+            ExpressionKind::Block { children, .. } => self.push_block(ast, children),
             // Lists are parsed into a self.stack
-            Expression::List(children) => {
+            ExpressionKind::List(children) => {
+                self.check_size("list", children.len())?;
                 let mut values = vec![];
                 for node in children {
-                    self.evaluate(node);
-                    values.push(self.stack.pop().unwrap());
+                    self.evaluate_expression(node)?;
+                    values.push(self.pop()?);
                 }
                 self.stack.push(Value::Stack(Rc::new(RefCell::new(values))));
             }
             // Groups are just evaluated in order
-            Expression::Group(children) => {
+            ExpressionKind::Group(children) => {
                 for node in children {
-                    self.evaluate(node);
+                    self.evaluate_expression(node)?;
                 }
             }
             // @ expressions name the top value on the self.stack
             // @[] expressions name multiple values
-            Expression::At(subnode) => {
-                match subnode.as_ref() {
+            ExpressionKind::At(subnode) => {
+                match &subnode.kind {
                     // Specifying input arity, ignore
-                    Expression::Literal(Value::Number(Number::Integer(_))) => {}
+                    ExpressionKind::Literal(Value::Number(Number::Integer(_))) => {}
                     // Naming the top of the self.stack
-                    Expression::Identifier(name) => {
-                        self.stack.name(name.clone());
+                    ExpressionKind::Local { name, slot, .. } => {
+                        if self.stack.is_empty() {
+                            fail!(Stack, "cannot name an empty stack @{}", name);
+                        }
+                        self.stack.name(*slot, name);
                     }
                     // Naming several values at once on top of the self.stack
-                    Expression::List(exprs) => {
-                        let mut names = vec![];
+                    ExpressionKind::List(exprs) => {
+                        let mut slots = vec![];
                         for expr in exprs {
-                            match expr {
-                            Expression::Identifier(name) => names.push(name.clone()),
-                            _ => panic!("Invalid @ expression, @[list] must contain only names, got {:?}", ast)
+                            match &expr.kind {
+                                ExpressionKind::Local { name, slot, .. } => {
+                                    slots.push((*slot, name.as_str()))
+                                }
+                                _ => fail!(
+                                    "Invalid @ expression, @[list] must contain only names, got {}",
+                                    ast
+                                ),
+                            }
                         }
+                        if self.stack.len() < slots.len() {
+                            fail!(
+                                Stack,
+                                "cannot name {} values, only {} are on the stack: {}",
+                                slots.len(),
+                                self.stack.len(),
+                                ast
+                            );
                         }
-                        self.stack.name_many(names.clone())
+                        self.stack.name_many(&slots)
                     }
-                    _ => panic!(
-                        "Invalid @ expression, must be @name or @[list], got {:?}",
+                    _ => fail!(
+                        "Invalid @ expression, must be @name or @[list], got {}",
                         ast
                     ),
                 }
             }
             // ! expressions set (or update) the value of named expressions
-            Expression::Bang(subnode) => {
-                match subnode.as_ref() {
+            ExpressionKind::Bang(subnode) => {
+                match &subnode.kind {
                     // Output expression, ignore
-                    Expression::Literal(Value::Number(Number::Integer(_))) => {}
+                    ExpressionKind::Literal(Value::Number(Number::Integer(_))) => {}
 
                     // Write to a named variable
-                    Expression::Identifier(name) => {
-                        let value = self.stack.pop().unwrap();
-                        self.stack.set_named(name.clone(), value);
+                    ExpressionKind::Local { depth, slot, .. } => {
+                        let value = self.pop()?;
+                        self.stack.set_named(*depth, *slot, value)?;
                     }
 
                     // Anything else doesn't currently make sense
-                    _ => panic!("Invalid ! expression, must be !# or !name, got {:?}", ast),
+                    _ => fail!("Invalid ! expression, must be !# or !name, got {}", ast),
                 }
             }
             // $ expressions are used to access named expressions without evaluating
-            Expression::Dollar(subnode) => {
-                match subnode.as_ref() {
+            ExpressionKind::Dollar(subnode) => {
+                match &subnode.kind {
                     // Push to self.stack (don't evaluate)
-                    Expression::Identifier(name) => {
-                        if let Some(value) = self.stack.get_named(name.clone()) {
-                            self.stack.push(value.clone());
+                    ExpressionKind::Local { name, depth, slot } => {
+                        if let Some(value) = self.stack.get_named(*depth, *slot) {
+                            self.stack.push(value);
                         } else {
-                            panic!("Unknown identifier {:?}", name);
+                            fail!(Name, "{:?} was used before it was bound", name);
                         }
                     }
 
                     // Anything else doesn't currently make sense
-                    _ => panic!("Invalid $ expression, must be $name, got {:?}", ast),
+                    _ => fail!("Invalid $ expression, must be $name, got {}", ast),
                 }
             }
         };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, Limits, VM};
+    use crate::error::{Error, ErrorKind, RuntimeError};
+    use crate::numbers::Number;
+    use crate::types::ExpressionKind;
+    use crate::types::Value;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn int(v: i64) -> Value {
+        Value::Number(Number::Integer(v))
+    }
+
+    #[test]
+    fn test_evaluate_str() {
+        let mut vm = VM::new();
+        vm.evaluate_str("1 2 + 3 *").unwrap();
+        assert_eq!(vm.pop(), Ok(int(9)));
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        let mut vm = VM::new();
+        vm.push(int(6));
+        vm.evaluate_str("7 *").unwrap();
+        assert_eq!(vm.pop(), Ok(int(42)));
+        assert!(vm.pop().is_err());
+    }
+
+    #[test]
+    fn test_globals_persist() {
+        let mut vm = VM::new();
+        vm.evaluate_str("{ @n n n * } @square 5 @x").unwrap();
+        vm.evaluate_str("x square").unwrap();
+        assert_eq!(vm.pop(), Ok(int(25)));
+        assert_eq!(vm.get_global("x"), Some(int(5)));
+        assert_eq!(vm.get_global("y"), None);
+    }
+
+    #[test]
+    fn test_register() {
+        let mut vm = VM::new();
+        vm.register("double", 1, 1, |vm| {
+            match vm.pop()? {
+                Value::Number(n) => vm.push(Value::Number(n * Number::Integer(2))),
+                v => return Err(RuntimeError::new(format!("expected a number, got {v}"))),
+            }
+            Ok(())
+        });
+
+        vm.evaluate_str("{ @n n double 1 + } @f 20 f").unwrap();
+        assert_eq!(vm.pop(), Ok(int(41)));
+
+        let error = vm.evaluate_str("\"x\" double").unwrap_err();
+        assert_eq!(
+            error,
+            Error::Runtime(RuntimeError::new("expected a number, got x"))
+        );
+    }
+
+    #[test]
+    fn test_register_checks_arity() {
+        let mut vm = VM::new();
+        vm.register("bad", 1, 1, |vm| {
+            vm.pop()?;
+            Ok(())
+        });
+
+        assert!(vm.evaluate_str("bad").is_err());
+        assert!(vm.evaluate_str("1 bad").is_err());
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut vm = VM::new();
+        vm.set_limits(Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        });
+        vm.evaluate_str("1 2 +").unwrap();
+
+        let error = vm.evaluate_str("{ } forever").unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("exceeded the limit of 1000 steps"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limited = |limits: Limits, source: &str| {
+            let mut vm = VM::new();
+            vm.set_limits(limits);
+            match vm.evaluate_str(source) {
+                Err(Error::Runtime(e)) => Some(e.kind),
+                _ => None,
+            }
+        };
+
+        let depth = Limits {
+            max_stack_depth: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(limited(depth.clone(), "{ 1 } 50 loop"), None);
+        assert_eq!(limited(depth, "{ 1 } 500 loop"), Some(ErrorKind::Limit));
+
+        let calls = Limits {
+            max_call_depth: Some(100),
+            ..Limits::default()
+        };
+        let countdown = "{ @n { n 1 - countdown } n 0 > when } @countdown";
+        assert_eq!(
+            limited(calls.clone(), &format!("{countdown} 10 countdown")),
+            None
+        );
+        assert_eq!(
+            limited(calls, &format!("{countdown} 1000 countdown")),
+            Some(ErrorKind::Limit)
+        );
+
+        let size = Limits {
+            max_collection_size: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(limited(size.clone(), "0 100 range"), None);
+        assert_eq!(
+            limited(size.clone(), "0 1000000000000 range"),
+            Some(ErrorKind::Limit)
+        );
+        // Numeric loops don't build a collection, but generate-stack's result is checked
+        assert_eq!(limited(size.clone(), "{ drop } 1000 loop"), None);
+        assert_eq!(limited(size.clone(), "{ break } 1000000000000 loop"), None);
+        assert_eq!(
+            limited(size.clone(), "{ } 1000000000000 generate-stack"),
+            Some(ErrorKind::Limit)
+        );
+        assert_eq!(
+            limited(size, "make-stack @s { @i s i stack-push! } 200 loop"),
+            Some(ErrorKind::Limit)
+        );
+
+        let io = Limits {
+            disable_io: true,
+            ..Limits::default()
+        };
+        assert_eq!(limited(io, "read"), Some(ErrorKind::Limit));
+    }
+
+    #[test]
+    fn test_limits_are_not_caught() {
+        let mut vm = VM::new();
+        vm.set_limits(Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        });
+        let error = vm
+            .evaluate_str("{ { } forever } { drop 1 } try")
+            .unwrap_err();
+        assert!(matches!(error, Error::Runtime(e) if e.kind == ErrorKind::Limit));
+    }
+
+    #[test]
+    fn test_evaluate_in_scope() {
+        let mut vm = VM::new();
+        vm.evaluate_str("5 @x 1 2").unwrap();
+        assert_eq!(vm.evaluate_in_scope("x 2 *"), Ok(vec![int(10)]));
+        assert_eq!(
+            vm.evaluate_in_scope("10 @y y x +"),
+            Ok(vec![int(10), int(15)])
+        );
+        assert_eq!(vm.evaluate_in_scope("drop drop"), Ok(vec![]));
+        assert!(vm.evaluate_in_scope("nope").is_err());
+
+        // The stack is left as it was
+        assert_eq!(vm.pop(), Ok(int(2)));
+        assert_eq!(vm.pop(), Ok(int(1)));
+    }
+
+    #[test]
+    fn test_hook() {
+        let seen = Rc::new(RefCell::new(vec![]));
+
+        let mut vm = VM::new();
+        let hook_seen = seen.clone();
+        vm.set_hook(move |vm, event| {
+            match event {
+                Event::Evaluate(expression) => {
+                    if let ExpressionKind::Builtin(name) = &expression.kind {
+                        // Names bound in the block being called are visible to the hook
+                        let n = vm.evaluate_in_scope("n").unwrap();
+                        hook_seen.borrow_mut().push(format!("{} {}", name, n[0]));
+                    }
+                }
+                Event::Enter { name, .. } => hook_seen
+                    .borrow_mut()
+                    .push(format!("enter {}", name.unwrap_or("?"))),
+                Event::Exit { name, .. } => hook_seen
+                    .borrow_mut()
+                    .push(format!("exit {}", name.unwrap_or("?"))),
+                Event::Return(_) => {}
+            }
+            Ok(())
+        });
+        vm.evaluate_str("{ @n n 2 * } @double 3 double").unwrap();
+
+        assert_eq!(*seen.borrow(), vec!["enter double", "* 3", "exit double"]);
+        assert_eq!(vm.pop(), Ok(int(6)));
+    }
+
+    #[test]
+    fn test_resolve_error() {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.evaluate_str("1 nope"),
+            Err(Error::Resolve(errors)) if errors.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let mut vm = VM::new();
+        for source in ["1 @", "{ 1 !", "[ $", "{ 1 2", "1 ]"] {
+            assert!(
+                matches!(vm.evaluate_str(source), Err(Error::Syntax(_))),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            vm.evaluate_str("1 @").unwrap_err().to_string(),
+            "<unknown>:1:2:1: @ must be followed by a value"
+        );
+        assert!(vm.evaluate_in_scope("$").is_err());
+    }
+
+    #[test]
+    fn test_block_arity_is_checked() {
+        let mut vm = VM::new();
+        let error = vm.evaluate_str("{ @[a b c] a } @f 1 f").unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("expects 3 values on entry, but only 2 are on the stack"));
+
+        let mut vm = VM::new();
+        let error = vm.evaluate_str("{ !2 1 } @g g").unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("expects 2 values on exit, but only 1 are left"));
+
+        // Consuming values named below the block doesn't underflow
+        let mut vm = VM::new();
+        assert!(vm.evaluate_str("1 2 { @a + } apply").is_err());
+    }
+
+    #[test]
+    fn test_escaping_block() {
+        // Blocks can use names from the block they were defined in, so can't outlive it
+        let mut vm = VM::new();
+        let error = vm
+            .evaluate_str("{ @n { n } } @make 1 make @get get")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "block at <unknown>:1:5:1 called after the block it was defined in returned"
+        );
+
+        // Blocks that don't use any can
+        let mut vm = VM::new();
+        vm.evaluate_str("{ { 42 } } @make make @get get").unwrap();
+        assert_eq!(vm.pop(), Ok(int(42)));
+
+        // Blocks defined at the top level can be called from anywhere
+        let mut vm = VM::new();
+        assert!(vm.evaluate_str("{ 1 } @one { one } @f f").is_ok());
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.evaluate_str("1 \"a\" +"),
+            Err(Error::Runtime(_))
+        ));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut vm = VM::new();
+        let error = vm
+            .evaluate_str(
+                "{ @x { x \"a\" + } { x } true if } @check\n{ @n n check } @outer\n5 outer",
+            )
+            .unwrap_err();
+
+        let backtrace = error.backtrace().unwrap();
+        let calls = backtrace
+            .calls
+            .iter()
+            .map(|call| call.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                "outer at <unknown>:3:2:5 (block at <unknown>:2:0:1)",
+                "check at <unknown>:2:7:5 (block at <unknown>:1:0:1)",
+                "block at <unknown>:1:5:1 called by if",
+            ]
+        );
+        assert_eq!(backtrace.stack, "[{1->1}@check, {1->1}@outer | 5@n | 5@x]");
+        assert!(vm.calls().is_empty());
+
+        // Errors caught by try don't leave calls behind
+        vm.evaluate_str("{ { 1 \"a\" + } apply } { } try").unwrap();
+        assert!(vm.calls().is_empty());
     }
 }